@@ -10,7 +10,7 @@ use std::fmt::{self, Debug, Formatter};
 use std::io::{self, BufRead, Read};
 
 use bzip2::bufread::BzDecoder;
-use flate2::bufread::GzDecoder;
+use flate2::bufread::{GzDecoder, ZlibDecoder};
 use tokio_io::AsyncRead;
 
 use raw::RawDecoder;
@@ -27,6 +27,8 @@ where
 pub enum DecompressorType {
     Bzip2,
     Gzip,
+    /// A raw zlib stream without the gzip header, as used by Mercurial's HG10GZ bundles.
+    Zlib,
     Zstd,
 }
 
@@ -40,6 +42,7 @@ where
             inner: match dt {
                 DecompressorType::Bzip2 => Box::new(BzDecoder::new(r)),
                 DecompressorType::Gzip => Box::new(GzDecoder::new(r)),
+                DecompressorType::Zlib => Box::new(ZlibDecoder::new(r)),
                 // TODO: The zstd crate is not safe for decompressing Read input, because it is
                 // overconsuming it
                 DecompressorType::Zstd => unimplemented!(),
@@ -14,7 +14,7 @@ use tokio_io::AsyncWrite;
 
 use bzip2::bufread::BzDecoder;
 use bzip2::write::BzEncoder;
-use flate2::bufread::GzDecoder;
+use flate2::bufread::{GzDecoder, ZlibDecoder};
 use flate2::write::GzEncoder;
 use zstd::Encoder as ZstdEncoder;
 
@@ -58,6 +58,23 @@ impl<R: BufRead> RawDecoder<R> for GzDecoder<R> {
     }
 }
 
+impl<R: BufRead> RawDecoder<R> for ZlibDecoder<R> {
+    #[inline]
+    fn get_ref(&self) -> &R {
+        ZlibDecoder::get_ref(self)
+    }
+
+    #[inline]
+    fn get_mut(&mut self) -> &mut R {
+        ZlibDecoder::get_mut(self)
+    }
+
+    #[inline]
+    fn into_inner(self: Box<Self>) -> R {
+        ZlibDecoder::into_inner(*self)
+    }
+}
+
 pub trait RawEncoder<W>: AsyncWrite
 where
     W: AsyncWrite + Send,
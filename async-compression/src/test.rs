@@ -17,7 +17,7 @@ use tokio_io::io::read_to_end;
 use retry::retry_write;
 
 use compressor::{Compressor, CompressorType};
-use decompressor::Decompressor;
+use decompressor::{Decompressor, DecompressorType};
 use membuf::MemBuf;
 use metered::{MeteredRead, MeteredWrite};
 
@@ -30,6 +30,23 @@ quickcheck! {
         roundtrip(CompressorType::Gzip(cmprs.0), &input)
     }
 
+    fn test_zlib_decompress(cmprs: GzipCompression, input: Vec<u8>) -> TestResult {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), cmprs.0);
+        encoder.write_all(&input).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut decompressor = Decompressor::new(
+            BufReader::new(Cursor::new(compressed)),
+            DecompressorType::Zlib,
+        );
+        let mut result = Vec::new();
+        decompressor.read_to_end(&mut result).unwrap();
+        if result != input {
+            return TestResult::error(format!("decoding failed, result: {:?}", result));
+        }
+        TestResult::passed()
+    }
+
     fn test_bzip_overreading(
         cmprs: BzipCompression,
         compressable_input: Vec<u8>,
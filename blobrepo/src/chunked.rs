@@ -0,0 +1,194 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Storage of content blobs, splitting very large ones into chunks
+//!
+//! Content is addressed by its `BlobHash`. Small content is stored as a single `sha1-HASH` blob,
+//! exactly as before. Content of `CHUNK_THRESHOLD` bytes or more is split into `CHUNK_SIZE`
+//! pieces, each stored under its own content-addressed `chunk-sha1-HASH` key, and a
+//! `ChunkedContent` listing them is stored under `sha1-HASH.chunks`. Readers try the plain key
+//! first, so existing repos are unaffected.
+
+use std::sync::Arc;
+
+use bincode;
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, Future, IntoFuture};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use blobstore::Blobstore;
+use mercurial_types::BlobHash;
+
+use errors::*;
+
+/// Content at least this big is split into chunks.
+pub const CHUNK_THRESHOLD: usize = 16 * 1024 * 1024;
+/// Size of each chunk, except for the last one which may be smaller.
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// How many chunks to fetch ahead of the consumer when streaming content.
+const CHUNK_PREFETCH: usize = 4;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct ChunkedContent {
+    /// Total size of the reassembled content
+    pub size: u64,
+    /// Hashes of the chunks, in order
+    pub chunks: Vec<BlobHash>,
+}
+
+pub fn get_content_key(hash: &BlobHash) -> String {
+    format!("sha1-{}", hash.sha1())
+}
+
+fn get_chunked_content_key(hash: &BlobHash) -> String {
+    format!("sha1-{}.chunks", hash.sha1())
+}
+
+fn get_chunk_key(hash: &BlobHash) -> String {
+    format!("chunk-sha1-{}", hash.sha1())
+}
+
+/// Split content into the blobstore keys and values that represent it. Content below
+/// `CHUNK_THRESHOLD` maps to a single `sha1-HASH` blob.
+pub fn content_to_blobs(hash: BlobHash, content: Bytes) -> Result<Vec<(String, Bytes)>> {
+    if content.len() < CHUNK_THRESHOLD {
+        return Ok(vec![(get_content_key(&hash), content)]);
+    }
+
+    let mut blobs = Vec::with_capacity(content.len() / CHUNK_SIZE + 2);
+    let mut chunks = Vec::with_capacity(content.len() / CHUNK_SIZE + 1);
+    let mut offset = 0;
+    while offset < content.len() {
+        let end = ::std::cmp::min(offset + CHUNK_SIZE, content.len());
+        let chunk = content.slice(offset, end);
+        let chunk_hash = BlobHash::from(chunk.as_ref());
+        blobs.push((get_chunk_key(&chunk_hash), chunk));
+        chunks.push(chunk_hash);
+        offset = end;
+    }
+
+    let chunked = ChunkedContent {
+        size: content.len() as u64,
+        chunks,
+    };
+    let serialized = bincode::serialize(&chunked)
+        .map_err(|err| Error::from(ErrorKind::ChunkSerializationFailed(hash, err)))?;
+    blobs.push((get_chunked_content_key(&hash), Bytes::from(serialized)));
+
+    Ok(blobs)
+}
+
+/// Store content in the blobstore, chunking it if it's large.
+pub fn put_content(
+    blobstore: &Arc<Blobstore>,
+    hash: BlobHash,
+    content: Bytes,
+) -> BoxFuture<(), Error> {
+    let blobs = try_boxfuture!(content_to_blobs(hash, content));
+    // The chunk listing is last, so it is only written once all the chunks are in place.
+    let (listing, chunks) = blobs.split_last().expect("content always maps to a blob");
+    let (listing_key, listing_value) = listing.clone();
+
+    let chunk_puts: Vec<_> = chunks
+        .iter()
+        .cloned()
+        .map(|(key, value)| blobstore.put(key, value))
+        .collect();
+
+    let blobstore = blobstore.clone();
+    future::join_all(chunk_puts)
+        .and_then(move |_| blobstore.put(listing_key, listing_value))
+        .boxify()
+}
+
+fn fetch_chunked_content(
+    blobstore: &Arc<Blobstore>,
+    hash: BlobHash,
+) -> BoxFuture<Option<ChunkedContent>, Error> {
+    blobstore
+        .get(get_chunked_content_key(&hash))
+        .and_then(move |got| match got {
+            None => Ok(None),
+            Some(blob) => bincode::deserialize(blob.as_ref())
+                .map(Some)
+                .map_err(|err| ErrorKind::ChunkSerializationFailed(hash, err).into()),
+        })
+        .boxify()
+}
+
+fn fetch_chunk(
+    blobstore: &Arc<Blobstore>,
+    hash: BlobHash,
+    chunk: BlobHash,
+) -> BoxFuture<Bytes, Error> {
+    blobstore
+        .get(get_chunk_key(&chunk))
+        .and_then(move |got| got.ok_or(ErrorKind::ChunkMissing(hash, chunk).into()))
+        .boxify()
+}
+
+/// Stream content from the blobstore, one chunk at a time. Content that isn't chunked is
+/// returned as a single item. Resolves to `None` if the content is not in the blobstore.
+pub fn fetch_content_stream(
+    blobstore: &Arc<Blobstore>,
+    hash: BlobHash,
+) -> BoxFuture<Option<BoxStream<Bytes, Error>>, Error> {
+    fetch_sized_content_stream(blobstore, hash)
+        .map(|sized| sized.map(|(_, chunks)| chunks))
+        .boxify()
+}
+
+/// As `fetch_content_stream`, along with the total size of the content, which is known before
+/// any chunk is fetched.
+pub fn fetch_sized_content_stream(
+    blobstore: &Arc<Blobstore>,
+    hash: BlobHash,
+) -> BoxFuture<Option<(u64, BoxStream<Bytes, Error>)>, Error> {
+    blobstore
+        .get(get_content_key(&hash))
+        .and_then({
+            let blobstore = blobstore.clone();
+            move |got| match got {
+                Some(blob) => Ok(Some((blob.len() as u64, stream::once(Ok(blob)).boxify())))
+                    .into_future()
+                    .boxify(),
+                None => fetch_chunked_content(&blobstore, hash)
+                    .map(move |chunked| {
+                        chunked.map(move |chunked| {
+                            let chunks = stream::iter_ok(chunked.chunks)
+                                .map(move |chunk| fetch_chunk(&blobstore, hash, chunk))
+                                .buffered(CHUNK_PREFETCH)
+                                .boxify();
+                            (chunked.size, chunks)
+                        })
+                    })
+                    .boxify(),
+            }
+        })
+        .boxify()
+}
+
+/// Fetch the whole of some content from the blobstore, reassembling it if it was chunked.
+pub fn fetch_content(
+    blobstore: &Arc<Blobstore>,
+    hash: BlobHash,
+) -> BoxFuture<Option<Bytes>, Error> {
+    fetch_content_stream(blobstore, hash)
+        .and_then(|chunks| match chunks {
+            None => Ok(None).into_future().boxify(),
+            Some(chunks) => chunks
+                .fold(BytesMut::new(), |mut content, chunk| {
+                    content.extend_from_slice(chunk.as_ref());
+                    future::ok::<_, Error>(content)
+                })
+                .map(|content| Some(content.freeze()))
+                .boxify(),
+        })
+        .boxify()
+}
@@ -60,4 +60,10 @@ pub enum ErrorKind {
     #[fail(display = "Parents failed to complete")] ParentsFailed,
     #[fail(display = "Expected {} to be a manifest, found a {} instead", _0, _1)]
     NotAManifest(NodeHash, Type),
+    #[fail(display = "Serialization of chunk list for {:?} failed ({})", _0, _1)]
+    ChunkSerializationFailed(BlobHash, bincode::Error),
+    #[fail(display = "Chunk {:?} of content {:?} is missing", _1, _0)]
+    ChunkMissing(BlobHash, BlobHash),
+    #[fail(display = "Bookmarks of {} are still files, migrate them with blobimport first", _0)]
+    FileBookmarksNotMigrated(String),
 }
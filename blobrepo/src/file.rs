@@ -10,7 +10,8 @@ use std::sync::Arc;
 use bytes::Bytes;
 
 use futures::future::Future;
-use futures_ext::{BoxFuture, FutureExt};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use mercurial::file;
 use mercurial_types::{Blob, BlobNode, MPath, MPathElement, ManifestId, NodeHash, Parents};
@@ -19,6 +20,7 @@ use mercurial_types::nodehash::EntryId;
 
 use blobstore::Blobstore;
 
+use chunked::{fetch_content, fetch_sized_content_stream};
 use errors::*;
 
 use manifest::BlobManifest;
@@ -41,10 +43,9 @@ pub fn fetch_file_content_and_renames_from_blobstore(
         .and_then({
             let blobstore = blobstore.clone();
             move |node| {
-                let key = format!("sha1-{}", node.blob.sha1());
                 let parents = node.parents;
 
-                blobstore.get(key).and_then(move |blob| {
+                fetch_content(&blobstore, node.blob).and_then(move |blob| {
                     blob.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
                         .and_then(|blob| {
                             let (p1, p2) = parents.get_nodes();
@@ -63,6 +64,53 @@ pub fn fetch_file_content_and_renames_from_blobstore(
         .boxify()
 }
 
+/// As `fetch_file_content_and_renames_from_blobstore`, but streams the file content instead of
+/// buffering it. Copy information is not returned.
+pub fn fetch_file_content_stream_from_blobstore(
+    blobstore: &Arc<Blobstore>,
+    nodeid: NodeHash,
+) -> BoxStream<Bytes, Error> {
+    fetch_sized_file_content_stream_from_blobstore(blobstore, nodeid)
+        .map(|(_, chunks)| chunks)
+        .flatten_stream()
+        .boxify()
+}
+
+/// As `fetch_file_content_stream_from_blobstore`, along with the size of the file content. The
+/// first chunk is fetched before this resolves, as the size of the metadata is only known from
+/// it.
+pub fn fetch_sized_file_content_stream_from_blobstore(
+    blobstore: &Arc<Blobstore>,
+    nodeid: NodeHash,
+) -> BoxFuture<(u64, BoxStream<Bytes, Error>), Error> {
+    get_node(blobstore, nodeid)
+        .and_then({
+            let blobstore = blobstore.clone();
+            move |node| {
+                fetch_sized_content_stream(&blobstore, node.blob).and_then(move |sized| {
+                    sized.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
+                })
+            }
+        })
+        .and_then(|(size, chunks)| {
+            chunks
+                .into_future()
+                .map_err(|(err, _)| err)
+                .map(move |(first, rest)| match first {
+                    Some(first) => {
+                        // Metadata is at the very start of the content, and is much smaller
+                        // than a chunk, so only the first chunk needs to have it stripped.
+                        let (_, off) = file::File::extract_meta(&first);
+                        let first = first.slice_from(off);
+                        let chunks = stream::once(Ok(first)).chain(rest).boxify();
+                        (size - off as u64, chunks)
+                    }
+                    None => (size, rest.boxify()),
+                })
+        })
+        .boxify()
+}
+
 impl BlobEntry {
     pub fn new(
         blobstore: Arc<Blobstore>,
@@ -99,9 +147,7 @@ impl BlobEntry {
             .and_then({
                 let blobstore = blobstore.clone();
                 move |node| {
-                    let key = format!("sha1-{}", node.blob.sha1());
-
-                    blobstore.get(key).and_then(move |blob| {
+                    fetch_content(&blobstore, node.blob).and_then(move |blob| {
                         blob.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
                     })
                 }
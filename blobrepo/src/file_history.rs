@@ -0,0 +1,83 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::cmp;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+
+use failure::Error;
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures_ext::{BoxStream, StreamExt};
+
+use mercurial_types::{MPath, NodeHash, Parents, RepoPath, NULL_HASH};
+
+use repo::BlobRepo;
+
+/// How many filenodes to look up at once while walking the history. The linknodes of a batch are
+/// fetched with a single query.
+const HISTORY_BATCH_SIZE: usize = 100;
+
+/// Walk the history of the file `path` from the filenode `startnode`, following filenode
+/// parents breadth-first. Each filenode is returned with its parents, its linknode and the
+/// path and filenode it was copied from, if any. Copies are not followed.
+pub fn get_file_history(
+    repo: Arc<BlobRepo>,
+    startnode: NodeHash,
+    path: MPath,
+) -> BoxStream<(NodeHash, Parents, NodeHash, Option<(MPath, NodeHash)>), Error> {
+    if startnode == NULL_HASH {
+        return stream::empty().boxify();
+    }
+    let mut startstate = VecDeque::new();
+    startstate.push_back(startnode);
+    let seen_nodes: HashSet<_> = [startnode].iter().cloned().collect();
+
+    stream::unfold(
+        (startstate, seen_nodes),
+        move |cur_data: (VecDeque<NodeHash>, HashSet<NodeHash>)| {
+            let (mut nodes, mut seen_nodes) = cur_data;
+            if nodes.is_empty() {
+                return None;
+            }
+            // Taking the front of the queue and queueing the parents in order visits the
+            // filenodes in the same order as taking them one at a time would.
+            let batch_len = cmp::min(nodes.len(), HISTORY_BATCH_SIZE);
+            let batch: Vec<_> = nodes.drain(..batch_len).collect();
+
+            let parents_and_copies = future::join_all(
+                batch
+                    .iter()
+                    .map(|node| repo.get_parents(node).join(repo.get_file_copy(node)))
+                    .collect::<Vec<_>>(),
+            );
+
+            let linknodes = RepoPath::file(path.clone()).into_future().and_then({
+                let repo = repo.clone();
+                let batch = batch.clone();
+                move |path| {
+                    let keys = batch.into_iter().map(|node| (path.clone(), node)).collect();
+                    repo.get_linknodes(keys)
+                }
+            });
+
+            let joined = parents_and_copies.join(linknodes);
+
+            Some(joined.map(move |(parents_and_copies, linknodes)| {
+                let mut history = Vec::with_capacity(batch.len());
+                for ((node, (parents, copy)), linknode) in batch
+                    .into_iter()
+                    .zip(parents_and_copies)
+                    .zip(linknodes)
+                {
+                    nodes.extend(parents.into_iter().filter(|p| seen_nodes.insert(*p)));
+                    history.push((node, parents, linknode, copy));
+                }
+                (stream::iter_ok(history), (nodes, seen_nodes))
+            }))
+        },
+    ).flatten()
+        .boxify()
+}
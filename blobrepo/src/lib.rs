@@ -30,8 +30,8 @@ extern crate futures_stats;
 extern crate blobstore;
 extern crate bookmarks;
 extern crate changesets;
+extern crate compressedblob;
 extern crate fileblob;
-extern crate filebookmarks;
 extern crate fileheads;
 extern crate filelinknodes;
 #[macro_use]
@@ -46,12 +46,17 @@ extern crate memlinknodes;
 extern crate mercurial;
 extern crate mercurial_types;
 extern crate rocksblob;
+extern crate sqlblob;
+extern crate sqlbookmarks;
+extern crate sqllinknodes;
 extern crate storage_types;
 
 mod repo;
 mod changeset;
+mod chunked;
 mod manifest;
 mod file;
+mod file_history;
 mod errors;
 mod utils;
 mod repo_commit;
@@ -60,6 +65,7 @@ pub use errors::*;
 
 pub use changeset::BlobChangeset;
 pub use file::BlobEntry;
+pub use file_history::get_file_history;
 pub use manifest::BlobManifest;
 pub use repo::BlobRepo;
 pub use repo_commit::ChangesetHandle;
@@ -68,4 +74,5 @@ pub use repo_commit::compute_changed_files;
 //
 // TODO: (jsgf) T21597565 This is exposed here for blobimport -- don't use it for anything else.
 
+pub use chunked::{content_to_blobs, ChunkedContent, CHUNK_SIZE, CHUNK_THRESHOLD};
 pub use utils::RawNodeBlob;
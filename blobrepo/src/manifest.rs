@@ -18,6 +18,7 @@ use mercurial_types::nodehash::{ManifestId, NULL_HASH};
 
 use blobstore::Blobstore;
 
+use chunked::fetch_content;
 use errors::*;
 use file::BlobEntry;
 use utils::get_node;
@@ -43,10 +44,7 @@ impl BlobManifest {
             get_node(blobstore, nodehash)
                 .and_then({
                     let blobstore = blobstore.clone();
-                    move |nodeblob| {
-                        let blobkey = format!("sha1-{}", nodeblob.blob.sha1());
-                        blobstore.get(blobkey)
-                    }
+                    move |nodeblob| fetch_content(&blobstore, nodeblob.blob)
                 })
                 .and_then({
                     let blobstore = blobstore.clone();
@@ -23,8 +23,8 @@ use slog::{Discard, Drain, Logger};
 use blobstore::Blobstore;
 use bookmarks::Bookmarks;
 use changesets::{ChangesetInsert, Changesets, SqliteChangesets};
+use compressedblob::CompressedBlobstore;
 use fileblob::Fileblob;
-use filebookmarks::FileBookmarks;
 use fileheads::FileHeads;
 use filelinknodes::FileLinknodes;
 use heads::Heads;
@@ -34,21 +34,39 @@ use memblob::{EagerMemblob, LazyMemblob};
 use membookmarks::MemBookmarks;
 use memheads::MemHeads;
 use memlinknodes::MemLinknodes;
-use mercurial_types::{Blob, BlobNode, Changeset, ChangesetId, Entry, MPath, Manifest, NodeHash,
-                      Parents, RepoPath, RepositoryId, Time};
+use mercurial_types::{Blob, BlobHash, BlobNode, Changeset, ChangesetId, Entry, MPath, Manifest,
+                      NodeHash, Parents, RepoPath, RepositoryId, Time};
+use mercurial_types::hash::Sha1;
 use mercurial_types::manifest;
 use mercurial_types::nodehash::ManifestId;
 use rocksblob::Rocksblob;
+use sqlblob::Sqlblob;
+use sqlbookmarks::SqliteBookmarks;
+use sqllinknodes::SqliteLinknodes;
 use storage_types::Version;
 use tokio_core::reactor::Remote;
 
 use BlobChangeset;
 use BlobManifest;
+use chunked::{fetch_content, put_content};
 use errors::*;
-use file::{fetch_file_content_and_renames_from_blobstore, BlobEntry};
+use file::{fetch_file_content_and_renames_from_blobstore,
+           fetch_file_content_stream_from_blobstore,
+           fetch_sized_file_content_stream_from_blobstore, BlobEntry};
 use repo_commit::*;
 use utils::{get_node, get_node_key, RawNodeBlob};
 
+/// Open the bookmarks of a repo on local disk, which are kept in SQLite. Repos from before that
+/// keep them as files in `books/` instead, and have to be migrated with `blobimport
+/// --migrate-file-bookmarks` first.
+fn open_local_bookmarks(path: &Path, repoid: RepositoryId) -> Result<SqliteBookmarks> {
+    let bookmarks_path = path.join("bookmarks");
+    if !bookmarks_path.exists() && path.join("books").is_dir() {
+        return Err(ErrorKind::FileBookmarksNotMigrated(path.display().to_string()).into());
+    }
+    SqliteBookmarks::open(bookmarks_path.to_string_lossy(), repoid)
+}
+
 pub struct BlobRepo {
     logger: Logger,
     blobstore: Arc<Blobstore>,
@@ -83,10 +101,11 @@ impl BlobRepo {
     pub fn new_files(logger: Logger, path: &Path, repoid: RepositoryId) -> Result<Self> {
         let heads = FileHeads::open(path.join("heads"))
             .context(ErrorKind::StateOpen(StateOpenError::Heads))?;
-        let bookmarks = FileBookmarks::open(path.join("books"))
+        let bookmarks = open_local_bookmarks(path, repoid)
             .context(ErrorKind::StateOpen(StateOpenError::Bookmarks))?;
         let blobstore = Fileblob::open(path.join("blobs"))
             .context(ErrorKind::StateOpen(StateOpenError::Blobstore))?;
+        let blobstore = CompressedBlobstore::new(blobstore);
         let linknodes = FileLinknodes::open(path.join("linknodes"))
             .context(ErrorKind::StateOpen(StateOpenError::Linknodes))?;
         let changesets = SqliteChangesets::open(path.join("changesets").to_string_lossy())
@@ -106,8 +125,9 @@ impl BlobRepo {
     pub fn new_rocksdb(logger: Logger, path: &Path, repoid: RepositoryId) -> Result<Self> {
         let heads = FileHeads::open(path.join("heads"))
             .context(ErrorKind::StateOpen(StateOpenError::Heads))?;
-        let bookmarks = FileBookmarks::open(path.join("books"))
+        let bookmarks = open_local_bookmarks(path, repoid)
             .context(ErrorKind::StateOpen(StateOpenError::Bookmarks))?;
+        // Rocksblob already zstd-compresses its tables, so don't compress again
         let blobstore = Rocksblob::open(path.join("blobs"))
             .context(ErrorKind::StateOpen(StateOpenError::Blobstore))?;
         let linknodes = FileLinknodes::open(path.join("linknodes"))
@@ -126,6 +146,30 @@ impl BlobRepo {
         ))
     }
 
+    pub fn new_sqlite(logger: Logger, path: &Path, repoid: RepositoryId) -> Result<Self> {
+        let heads = FileHeads::open(path.join("heads"))
+            .context(ErrorKind::StateOpen(StateOpenError::Heads))?;
+        let bookmarks = open_local_bookmarks(path, repoid)
+            .context(ErrorKind::StateOpen(StateOpenError::Bookmarks))?;
+        let blobstore = Sqlblob::open(path.join("blobs").to_string_lossy())
+            .context(ErrorKind::StateOpen(StateOpenError::Blobstore))?;
+        let blobstore = CompressedBlobstore::new(blobstore);
+        let linknodes = SqliteLinknodes::open(path.join("linknodes").to_string_lossy(), repoid)
+            .context(ErrorKind::StateOpen(StateOpenError::Linknodes))?;
+        let changesets = SqliteChangesets::open(path.join("changesets").to_string_lossy())
+            .context(ErrorKind::StateOpen(StateOpenError::Changesets))?;
+
+        Ok(Self::new(
+            logger,
+            Arc::new(heads),
+            Arc::new(bookmarks),
+            Arc::new(blobstore),
+            Arc::new(linknodes),
+            Arc::new(changesets),
+            repoid,
+        ))
+    }
+
     // Memblob repos are test repos, and do not have to have a logger. If we're given None,
     // we won't log.
     pub fn new_memblob(
@@ -211,6 +255,35 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// The hash of the content of a file, as opposed to its filenode, which also covers its
+    /// parents and copy information.
+    pub fn get_file_content_hash(&self, key: &NodeHash) -> BoxFuture<BlobHash, Error> {
+        get_node(&self.blobstore, *key)
+            .map(|rawnode| rawnode.blob)
+            .boxify()
+    }
+
+    /// Stream the content of a file, without buffering all of it in memory. Very large files are
+    /// returned in several chunks.
+    pub fn get_file_content_stream(&self, key: &NodeHash) -> BoxStream<Bytes, Error> {
+        fetch_file_content_stream_from_blobstore(&self.blobstore, *key)
+    }
+
+    /// As `get_file_content_stream`, along with the size of the content, which is known before
+    /// it has all been streamed.
+    pub fn get_sized_file_content_stream(
+        &self,
+        key: &NodeHash,
+    ) -> BoxFuture<(u64, BoxStream<Bytes, Error>), Error> {
+        fetch_sized_file_content_stream_from_blobstore(&self.blobstore, *key)
+    }
+
+    /// Fetch the content of a largefile that was stored out of line, given the hash from its
+    /// standin. Resolves to `None` if the repo doesn't have it.
+    pub fn get_largefile(&self, hash: &Sha1) -> BoxFuture<Option<Bytes>, Error> {
+        fetch_content(&self.blobstore, BlobHash::new(*hash))
+    }
+
     pub fn get_parents(&self, key: &NodeHash) -> BoxFuture<Parents, Error> {
         get_node(&self.blobstore, *key)
             .map(|rawnode| rawnode.parents)
@@ -284,6 +357,14 @@ impl BlobRepo {
         self.linknodes.get(path, node)
     }
 
+    /// Look up several linknodes at once. The result is in the same order as `entries`.
+    pub fn get_linknodes(
+        &self,
+        entries: Vec<(RepoPath, NodeHash)>,
+    ) -> BoxFuture<Vec<NodeHash>, Error> {
+        self.linknodes.get_many(entries)
+    }
+
     pub fn get_generation_number(&self, cs: &ChangesetId) -> BoxFuture<Option<u64>, Error> {
         self.changesets
             .get(self.repoid, *cs)
@@ -305,6 +386,41 @@ impl BlobRepo {
         p1: Option<NodeHash>,
         p2: Option<NodeHash>,
         path: RepoPath,
+    ) -> Result<(NodeHash, BoxFuture<(BlobEntry, RepoPath), Error>)> {
+        self.upload_entry_impl(raw_content, content_type, p1, p2, path, None)
+    }
+
+    // Store the content of a largefile out of line, keyed by the hash that its standin refers to.
+    // Standins themselves are uploaded with upload_entry like any other file.
+    pub fn upload_largefile(&self, content: Bytes) -> (Sha1, BoxFuture<(), Error>) {
+        let hash = BlobHash::from(content.as_ref());
+        (*hash.sha1(), put_content(&self.blobstore, hash, content))
+    }
+
+    // Like upload_entry, but the entry is stored under the given node rather than one computed
+    // from its content and parents. This is for tree manifests converted from flat manifests:
+    // the root tree keeps the flat manifest's node, so that changeset hashes are preserved.
+    pub fn upload_entry_with_nodeid(
+        &self,
+        nodeid: NodeHash,
+        raw_content: Blob,
+        content_type: manifest::Type,
+        p1: Option<NodeHash>,
+        p2: Option<NodeHash>,
+        path: RepoPath,
+    ) -> Result<BoxFuture<(BlobEntry, RepoPath), Error>> {
+        self.upload_entry_impl(raw_content, content_type, p1, p2, path, Some(nodeid))
+            .map(|(_, upload)| upload)
+    }
+
+    fn upload_entry_impl(
+        &self,
+        raw_content: Blob,
+        content_type: manifest::Type,
+        p1: Option<NodeHash>,
+        p2: Option<NodeHash>,
+        path: RepoPath,
+        nodeid: Option<NodeHash>,
     ) -> Result<(NodeHash, BoxFuture<(BlobEntry, RepoPath), Error>)> {
         let p1 = p1.as_ref();
         let p2 = p2.as_ref();
@@ -320,9 +436,12 @@ impl BlobRepo {
             blob: blob_hash,
         };
 
-        let nodeid = BlobNode::new(raw_content.clone(), p1, p2)
-            .nodeid()
-            .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?;
+        let nodeid = match nodeid {
+            Some(nodeid) => nodeid,
+            None => BlobNode::new(raw_content.clone(), p1, p2)
+                .nodeid()
+                .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?,
+        };
 
         let blob_entry = BlobEntry::new(
             self.blobstore.clone(),
@@ -353,24 +472,23 @@ impl BlobRepo {
         }
 
         // Ensure that content is in the blobstore
-        let content_upload = self.blobstore
-            .put(
-                format!("sha1-{}", blob_hash.sha1()),
-                raw_content
-                    .clone()
-                    .into_inner()
-                    .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?,
-            )
-            .timed({
-                let logger = self.logger.clone();
-                let path = path.clone();
-                let nodeid = nodeid.clone();
-                move |stats, result| {
-                    if result.is_ok() {
-                        log_upload_stats(logger, path, nodeid, "content_uploaded", stats)
-                    }
+        let content_upload = put_content(
+            &self.blobstore,
+            blob_hash,
+            raw_content
+                .clone()
+                .into_inner()
+                .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?,
+        ).timed({
+            let logger = self.logger.clone();
+            let path = path.clone();
+            let nodeid = nodeid.clone();
+            move |stats, result| {
+                if result.is_ok() {
+                    log_upload_stats(logger, path, nodeid, "content_uploaded", stats)
                 }
-            });
+            }
+        });
         // Upload the new node
         let node_upload = self.blobstore.put(
             get_node_key(nodeid),
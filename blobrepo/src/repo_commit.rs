@@ -15,7 +15,7 @@ use futures::sync::oneshot;
 use futures_ext::{BoxFuture, BoxStream, FutureExt};
 
 use blobstore::Blobstore;
-use linknodes::{ErrorKind as LinknodeErrorKind, Linknodes};
+use linknodes::{LinknodeData, Linknodes};
 use mercurial::changeset::RevlogChangeset;
 use mercurial_types::{Changeset, Entry, EntryId, MPath, Manifest, NodeHash, Parents, RepoPath,
                       Time};
@@ -226,15 +226,17 @@ impl UploadEntries {
         let linknodes = {
             let mut inner = self.inner.lock().expect("Lock poisoned");
             let uploaded_entries = mem::replace(&mut inner.uploaded_entries, HashMap::new());
-            let futures = uploaded_entries.into_iter().map(move |(path, entryid)| {
-                linknodes
-                    .add(path, &entryid.into_nodehash(), &cs_id)
-                    .or_else(|err| match err.downcast_ref::<LinknodeErrorKind>() {
-                        Some(&LinknodeErrorKind::AlreadyExists { .. }) => future::ok(()),
-                        _ => future::err(err),
-                    })
-            });
-            future::join_all(futures).boxify()
+            // Entries that already have a linknode (e.g. because they were uploaded as part of
+            // another changeset) keep it.
+            let data = uploaded_entries
+                .into_iter()
+                .map(|(path, entryid)| LinknodeData {
+                    path,
+                    node: entryid.into_nodehash(),
+                    linknode: cs_id,
+                })
+                .collect();
+            linknodes.add_many(data)
         };
 
         parent_checks
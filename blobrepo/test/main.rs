@@ -26,11 +26,12 @@ extern crate memlinknodes;
 extern crate mercurial_types;
 
 use bytes::Bytes;
-use futures::Future;
+use futures::{Future, Stream};
 
-use blobrepo::{compute_changed_files, BlobRepo};
+use blobrepo::{compute_changed_files, BlobRepo, CHUNK_SIZE, CHUNK_THRESHOLD};
 use mercurial_types::{manifest, Blob, Changeset, ChangesetId, Entry, EntryId, MPath, MPathElement,
                       ManifestId, RepoPath};
+use mercurial_types::hash::Sha1;
 
 mod stats_units;
 #[macro_use]
@@ -112,6 +113,54 @@ test_both_repotypes!(
     upload_blob_one_parent_eager
 );
 
+fn upload_large_blob_chunked(repo: BlobRepo) {
+    let fake_path = RepoPath::file("fake/large").expect("Can't generate fake RepoPath");
+    let content = "a".repeat(CHUNK_THRESHOLD + CHUNK_SIZE / 2);
+
+    let (hash, future) = upload_file_no_parents(&repo, content.clone(), &fake_path);
+    run_future(future).unwrap();
+
+    // Large content is reassembled from its chunks...
+    let bytes = run_future(repo.get_file_content(&hash)).unwrap();
+    assert!(&bytes == content.as_bytes());
+
+    // ...or streamed one chunk at a time
+    let chunks = run_future(repo.get_file_content_stream(&hash).collect()).unwrap();
+    assert!(chunks.len() > 1);
+    let streamed: Vec<u8> = chunks.iter().flat_map(|c| c.iter().cloned()).collect();
+    assert!(&streamed[..] == content.as_bytes());
+
+    // ...with its size known up front
+    let (size, sized) = run_future(repo.get_sized_file_content_stream(&hash)).unwrap();
+    assert_eq!(size, content.len() as u64);
+    assert!(run_future(sized.collect()).unwrap() == chunks);
+}
+
+test_both_repotypes!(
+    upload_large_blob_chunked,
+    upload_large_blob_chunked_lazy,
+    upload_large_blob_chunked_eager
+);
+
+fn upload_largefile(repo: BlobRepo) {
+    let content = Bytes::from("a".repeat(CHUNK_THRESHOLD + CHUNK_SIZE / 2));
+
+    let (hash, future) = repo.upload_largefile(content.clone());
+    assert!(run_future(repo.get_largefile(&hash)).unwrap().is_none());
+    run_future(future).unwrap();
+
+    // The largefile is keyed by the hash a standin would refer to
+    assert!(hash == Sha1::from(content.as_ref()));
+    let bytes = run_future(repo.get_largefile(&hash)).unwrap();
+    assert!(bytes == Some(content));
+}
+
+test_both_repotypes!(
+    upload_largefile,
+    upload_largefile_lazy,
+    upload_largefile_eager
+);
+
 fn create_one_changeset(repo: BlobRepo) {
     let fake_file_path = RepoPath::file("file").expect("Can't generate fake RepoPath");
     let fake_dir_path = RepoPath::dir("dir").expect("Can't generate fake RepoPath");
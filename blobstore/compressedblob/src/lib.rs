@@ -0,0 +1,186 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate async_compression;
+extern crate bytes;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate zstd;
+
+extern crate blobstore;
+extern crate futures_ext;
+
+use std::io::{Cursor, Write};
+
+use async_compression::{Compressor, CompressorType};
+use bytes::Bytes;
+use failure::{Error, Result};
+use futures::future::{self, Future};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::Blobstore;
+
+/// Values smaller than this are not worth compressing - the zstd frame overhead eats most of the
+/// savings on small manifests and file nodes.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Every value written by `CompressedBlobstore` starts with this magic, followed by a format
+/// version byte and a byte describing the encoding of the rest of the value.
+const MAGIC: &[u8] = b"\0MNNKBLB";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 10;
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "Blob {} failed to decompress", _0)] DecompressionFailed(String),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Encoding {
+    Raw,
+    Zstd,
+}
+
+impl Encoding {
+    fn to_byte(self) -> u8 {
+        match self {
+            Encoding::Raw => 0,
+            Encoding::Zstd => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Encoding::Raw),
+            1 => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Blobstore wrapper that transparently zstd-compresses values on `put` and decompresses them on
+/// `get`.
+///
+/// Values shorter than `threshold`, or values that don't get smaller when compressed, are stored
+/// uncompressed, but still carry the encoding header. Blobs that were written to the underlying
+/// store without this wrapper are still readable: anything that doesn't start with a complete
+/// header (magic, a known format version and a known encoding) is returned as-is. The only
+/// legacy blobs that can be misread are ones whose first 10 bytes happen to be exactly such a
+/// header, which stores that have only ever held Mercurial data are not expected to contain.
+///
+/// Don't wrap stores that already compress their values (such as `Rocksblob`, which uses zstd
+/// for its tables), as that only costs CPU.
+#[derive(Clone, Debug)]
+pub struct CompressedBlobstore<T: Blobstore + Clone> {
+    blobstore: T,
+    threshold: usize,
+}
+
+impl<T: Blobstore + Clone> CompressedBlobstore<T> {
+    pub fn new(blobstore: T) -> Self {
+        Self::with_threshold(blobstore, DEFAULT_COMPRESSION_THRESHOLD)
+    }
+
+    pub fn with_threshold(blobstore: T, threshold: usize) -> Self {
+        CompressedBlobstore {
+            blobstore,
+            threshold,
+        }
+    }
+
+    pub fn as_inner(&self) -> &T {
+        &self.blobstore
+    }
+}
+
+fn compress(value: &[u8]) -> Result<Vec<u8>> {
+    let mut compressor = Compressor::new(
+        Cursor::new(Vec::with_capacity(value.len())),
+        CompressorType::Zstd { level: ZSTD_LEVEL },
+    );
+    compressor.write_all(value)?;
+    let buf = compressor.try_finish().map_err(|(_, err)| err)?;
+    Ok(buf.into_inner())
+}
+
+fn decompress(value: &[u8]) -> Result<Vec<u8>> {
+    // The zstd Decompressor in async_compression is not usable yet because it overconsumes its
+    // input; that doesn't matter here as the whole frame is already in memory.
+    Ok(zstd::decode_all(Cursor::new(value))?)
+}
+
+fn encode(value: Bytes, threshold: usize) -> Result<Bytes> {
+    let (encoding, body) = if value.len() >= threshold {
+        let compressed = compress(value.as_ref())?;
+        if compressed.len() < value.len() {
+            (Encoding::Zstd, Bytes::from(compressed))
+        } else {
+            (Encoding::Raw, value)
+        }
+    } else {
+        (Encoding::Raw, value)
+    };
+
+    let mut encoded = Vec::with_capacity(HEADER_LEN + body.len());
+    encoded.extend_from_slice(MAGIC);
+    encoded.push(FORMAT_VERSION);
+    encoded.push(encoding.to_byte());
+    encoded.extend_from_slice(body.as_ref());
+    Ok(Bytes::from(encoded))
+}
+
+fn decode(key: String, value: Bytes) -> Result<Bytes> {
+    let encoding = if value.len() >= HEADER_LEN && value.starts_with(MAGIC)
+        && value[MAGIC.len()] == FORMAT_VERSION
+    {
+        Encoding::from_byte(value[MAGIC.len() + 1])
+    } else {
+        None
+    };
+
+    match encoding {
+        // Written before compression was enabled for this store
+        None => Ok(value),
+        Some(Encoding::Raw) => Ok(value.slice_from(HEADER_LEN)),
+        Some(Encoding::Zstd) => decompress(&value[HEADER_LEN..])
+            .map(Bytes::from)
+            .map_err(|err| err.context(ErrorKind::DecompressionFailed(key)).into()),
+    }
+}
+
+impl<T: Blobstore + Clone> Blobstore for CompressedBlobstore<T> {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        self.blobstore
+            .get(key.clone())
+            .and_then(move |value| match value {
+                Some(value) => decode(key, value).map(Some),
+                None => Ok(None),
+            })
+            .boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        let blobstore = self.blobstore.clone();
+        let threshold = self.threshold;
+
+        future::lazy(move || encode(value, threshold))
+            .and_then(move |encoded| blobstore.put(key, encoded))
+            .boxify()
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.is_present(key)
+    }
+
+    fn assert_present(&self, key: String) -> BoxFuture<(), Error> {
+        self.blobstore.assert_present(key)
+    }
+}
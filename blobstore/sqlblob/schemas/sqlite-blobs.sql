@@ -0,0 +1,11 @@
+CREATE TABLE data (
+  id VARCHAR(255) PRIMARY KEY NOT NULL,
+  chunk_count INTEGER NOT NULL
+);
+
+CREATE TABLE chunk (
+  id VARCHAR(255) NOT NULL,
+  chunk_id INTEGER NOT NULL,
+  value BLOB NOT NULL,
+  PRIMARY KEY (id, chunk_id)
+);
@@ -0,0 +1,189 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate blobstore;
+extern crate futures_ext;
+
+use std::sync::{Arc, Mutex};
+
+use bytes::{Bytes, BytesMut};
+use diesel::{delete, insert_into, replace_into, Connection, SqliteConnection};
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use failure::{Error, Result};
+use futures::future;
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::Blobstore;
+
+mod schema;
+mod models;
+
+use models::{ChunkRow, DataRow};
+use schema::{chunk, data};
+
+/// Values are split into chunks of at most this many bytes, so that a single large blob doesn't
+/// end up as one huge row.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "Blob {} has {} chunks stored, expected {}", _0, _1, _2)]
+    ChunkCountMismatch(String, usize, usize),
+    #[fail(display = "Blob {} is too large to be stored in {} chunks", _0, _1)]
+    TooManyChunks(String, usize),
+}
+
+/// Blobstore backed by a single SQLite database.
+///
+/// Intended for single-node deployments where one file per blob (`Fileblob`) runs out of inodes
+/// and RocksDB (`Rocksblob`) isn't available.
+#[derive(Clone)]
+pub struct Sqlblob {
+    connection: Arc<Mutex<SqliteConnection>>,
+    chunk_size: usize,
+}
+
+impl Sqlblob {
+    /// Open a SQLite database. This is synchronous because the SQLite backend hits local
+    /// disk or memory.
+    pub fn open<P: AsRef<str>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let conn = SqliteConnection::establish(path)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(conn)),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        })
+    }
+
+    /// Create a new SQLite database.
+    pub fn create<P: AsRef<str>>(path: P) -> Result<Self> {
+        let blobs = Self::open(path)?;
+
+        let up_query = include_str!("../schemas/sqlite-blobs.sql");
+        blobs
+            .connection
+            .lock()
+            .expect("lock poisoned")
+            .batch_execute(&up_query)?;
+
+        Ok(blobs)
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory() -> Result<Self> {
+        Self::create(":memory:")
+    }
+
+    /// Change the size of the chunks that new values are split into. Values that are already
+    /// stored are unaffected.
+    pub fn with_chunk_size(self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk size must be positive");
+        Self { chunk_size, ..self }
+    }
+}
+
+impl Blobstore for Sqlblob {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        // TODO: don't block -- send this to another thread
+        let connection = self.connection.lock().expect("lock poisoned");
+
+        let chunk_count = data::table
+            .filter(data::id.eq(&key))
+            .select(data::chunk_count)
+            .first::<i32>(&*connection)
+            .optional();
+        // This code is written in this style to allow easy porting to futures.
+        let result = chunk_count
+            .map_err(Error::from)
+            .and_then(|chunk_count| match chunk_count {
+                None => Ok(None),
+                Some(chunk_count) => {
+                    let chunks = chunk::table
+                        .filter(chunk::id.eq(&key))
+                        .order(chunk::chunk_id.asc())
+                        .select(chunk::value)
+                        .load::<Vec<u8>>(&*connection)?;
+
+                    if chunks.len() != chunk_count as usize {
+                        bail_err!(ErrorKind::ChunkCountMismatch(
+                            key.clone(),
+                            chunks.len(),
+                            chunk_count as usize,
+                        ));
+                    }
+
+                    let len = chunks.iter().map(|chunk| chunk.len()).sum();
+                    let mut value = BytesMut::with_capacity(len);
+                    for chunk in chunks {
+                        value.extend_from_slice(&chunk);
+                    }
+                    Ok(Some(value.freeze()))
+                }
+            });
+
+        future::result(result).boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        let chunks: Vec<_> = value
+            .chunks(self.chunk_size)
+            .enumerate()
+            .map(|(chunk_id, chunk)| ChunkRow {
+                id: key.clone(),
+                chunk_id: chunk_id as i32,
+                value: chunk.to_vec(),
+            })
+            .collect();
+        if chunks.len() > i32::max_value() as usize {
+            return future::err(ErrorKind::TooManyChunks(key, chunks.len()).into()).boxify();
+        }
+        let data_row = DataRow {
+            id: key.clone(),
+            chunk_count: chunks.len() as i32,
+        };
+
+        let connection = self.connection.lock().expect("lock poisoned");
+        let txn_result = connection.transaction::<_, Error, _>(|| {
+            // Last put wins, so drop any chunks left over from a previous value for this key.
+            delete(chunk::table.filter(chunk::id.eq(&key))).execute(&*connection)?;
+            if !chunks.is_empty() {
+                insert_into(chunk::table)
+                    .values(&chunks)
+                    .execute(&*connection)?;
+            }
+            replace_into(data::table)
+                .values(&data_row)
+                .execute(&*connection)?;
+            Ok(())
+        });
+
+        future::result(txn_result).boxify()
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        let connection = self.connection.lock().expect("lock poisoned");
+
+        let result = data::table
+            .filter(data::id.eq(&key))
+            .select(data::chunk_count)
+            .first::<i32>(&*connection)
+            .optional()
+            .map(|chunk_count| chunk_count.is_some())
+            .map_err(Error::from);
+
+        future::result(result).boxify()
+    }
+}
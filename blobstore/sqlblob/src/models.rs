@@ -0,0 +1,24 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use schema::{chunk, data};
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable, Insertable)]
+#[table_name = "data"]
+pub(crate) struct DataRow {
+    pub id: String,
+    pub chunk_count: i32,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable, Insertable)]
+#[table_name = "chunk"]
+pub(crate) struct ChunkRow {
+    pub id: String,
+    pub chunk_id: i32,
+    pub value: Vec<u8>,
+}
@@ -0,0 +1,26 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The `table!` macros in this module describe the schemas for these tables in SQL storage.
+//! These descriptions are *not* the source of truth, so if the schema ever changes it will need
+//! to be updated here as well.
+
+table! {
+    data (id) {
+        id -> Text,
+        chunk_count -> Integer,
+    }
+}
+
+table! {
+    chunk (id, chunk_id) {
+        id -> Text,
+        chunk_id -> Integer,
+        value -> Binary,
+    }
+}
+
+allow_tables_to_appear_in_same_query!(data, chunk);
@@ -17,18 +17,22 @@ extern crate tempdir;
 extern crate tokio_core;
 
 extern crate blobstore;
+extern crate compressedblob;
 extern crate fileblob;
 extern crate memblob;
 extern crate rocksblob;
+extern crate sqlblob;
 
 use bytes::Bytes;
 use futures::Future;
 use tempdir::TempDir;
 
 use blobstore::Blobstore;
+use compressedblob::CompressedBlobstore;
 use fileblob::Fileblob;
 use memblob::EagerMemblob;
 use rocksblob::Rocksblob;
+use sqlblob::Sqlblob;
 
 fn simple<B>(blobstore: B)
 where
@@ -68,6 +72,50 @@ where
     assert_eq!(out, Bytes::from_static(b"bar"));
 }
 
+fn compressed_roundtrip<B>(blobstore: B)
+where
+    B: Blobstore + Clone,
+{
+    let compressed = CompressedBlobstore::with_threshold(blobstore.clone(), 16);
+    let value = Bytes::from(vec![b'a'; 1024]);
+
+    let foo = "foo".to_string();
+    let res = compressed
+        .put(foo.clone(), value.clone())
+        .and_then(|_| compressed.get(foo.clone()));
+    let out = res.wait().expect("pub/get failed").expect("missing");
+    assert_eq!(out, value);
+
+    // The underlying store should hold the compressed form
+    let raw = blobstore.get(foo).wait().expect("get failed").expect("missing");
+    assert!(raw.len() < value.len());
+}
+
+fn uncompressed_compat<B>(blobstore: B)
+where
+    B: Blobstore + Clone,
+{
+    let foo = "foo".to_string();
+    blobstore
+        .put(foo.clone(), Bytes::from_static(b"bar"))
+        .wait()
+        .expect("put failed");
+
+    // Starts with the magic, but not with a complete header
+    let magic = "magic".to_string();
+    blobstore
+        .put(magic.clone(), Bytes::from_static(b"\0MNNKBLB\x07bar"))
+        .wait()
+        .expect("put failed");
+
+    // Blobs written before the store was wrapped are returned unchanged
+    let compressed = CompressedBlobstore::new(blobstore);
+    let out = compressed.get(foo).wait().expect("get failed").expect("missing");
+    assert_eq!(out, Bytes::from_static(b"bar"));
+    let out = compressed.get(magic).wait().expect("get failed").expect("missing");
+    assert_eq!(out, Bytes::from_static(b"\0MNNKBLB\x07bar"));
+}
+
 macro_rules! blobstore_test_impl {
     ($mod_name: ident => {
         state: $state: expr,
@@ -106,6 +154,24 @@ blobstore_test_impl! {
     }
 }
 
+blobstore_test_impl! {
+    compressedblob_test => {
+        state: (),
+        new: |_| CompressedBlobstore::with_threshold(EagerMemblob::new(), 0),
+        persistent: false,
+    }
+}
+
+#[test]
+fn test_compressed_roundtrip() {
+    compressed_roundtrip(EagerMemblob::new());
+}
+
+#[test]
+fn test_uncompressed_compat() {
+    uncompressed_compat(EagerMemblob::new());
+}
+
 blobstore_test_impl! {
     fileblob_test => {
         state: TempDir::new("fileblob_test").unwrap(),
@@ -122,3 +188,33 @@ blobstore_test_impl! {
         persistent: true,
     }
 }
+
+blobstore_test_impl! {
+    sqlblob_test => {
+        state: TempDir::new("sqlblob_test").unwrap(),
+        new: |dir: &TempDir| {
+            Sqlblob::create(dir.path().join("blobs").to_string_lossy()).unwrap()
+        },
+        persistent: true,
+    }
+}
+
+#[test]
+fn test_sqlblob_chunked() {
+    let blobstore = Sqlblob::in_memory().unwrap().with_chunk_size(3);
+    let value = Bytes::from_static(b"0123456789");
+
+    let foo = "foo".to_string();
+    let res = blobstore
+        .put(foo.clone(), value.clone())
+        .and_then(|_| blobstore.get(foo.clone()));
+    let out = res.wait().expect("pub/get failed").expect("missing");
+    assert_eq!(out, value);
+
+    // Overwriting with a shorter value must not leave stale chunks behind
+    let res = blobstore
+        .put(foo.clone(), Bytes::from_static(b"bar"))
+        .and_then(|_| blobstore.get(foo));
+    let out = res.wait().expect("pub/get failed").expect("missing");
+    assert_eq!(out, Bytes::from_static(b"bar"));
+}
@@ -19,6 +19,7 @@ extern crate filebookmarks;
 extern crate membookmarks;
 extern crate mercurial_types;
 extern crate mercurial_types_mocks;
+extern crate sqlbookmarks;
 extern crate storage_types;
 
 use std::cell::RefCell;
@@ -34,6 +35,8 @@ use filebookmarks::FileBookmarks;
 use membookmarks::MemBookmarks;
 use mercurial_types::nodehash::ChangesetId;
 use mercurial_types_mocks::nodehash;
+use mercurial_types_mocks::repo::REPO_ZERO;
+use sqlbookmarks::{MysqlBookmarks, SqliteBookmarks};
 use storage_types::Version;
 
 fn basic<B>(bookmarks: B, core: &mut Core)
@@ -196,3 +199,27 @@ bookmarks_test_impl! {
         persistent: true,
     }
 }
+
+bookmarks_test_impl! {
+    sqlite_bookmarks_test => {
+        state: {
+            let dir = TempDir::new("sqlite_bookmarks_test").unwrap();
+            let path = dir.as_ref().join("bookmarks");
+            SqliteBookmarks::create(path.to_string_lossy(), REPO_ZERO).unwrap();
+            dir
+        },
+        new: |dir: &TempDir, _| {
+            let path = dir.as_ref().join("bookmarks");
+            SqliteBookmarks::open(path.to_string_lossy(), REPO_ZERO).unwrap()
+        },
+        persistent: true,
+    }
+}
+
+bookmarks_test_impl! {
+    mysql_bookmarks_test => {
+        state: (),
+        new: |_, _| MysqlBookmarks::create_test_db("bookmarks_test", REPO_ZERO).unwrap(),
+        persistent: false,
+    }
+}
@@ -0,0 +1,422 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Flat manifests in changegroups.
+//!
+//! Changegroups from repos without treemanifest carry flat manifests rather than a b2xtreegroup2
+//! part. Mononoke only stores tree manifests, so these are converted the same way blobimport
+//! converts a flat revlog repo, and the root tree keeps the flat manifest's node.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use failure::Compat;
+use futures::{future, Future, Stream};
+use futures::future::Shared;
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::{BlobEntry, BlobRepo};
+use mercurial::manifest::convert::{convert_flat, generate_flat, ConvertedTree};
+use mercurial::manifest::revlog::ManifestContent;
+use mercurial_bundles::changegroup::CgDeltaChunk;
+use mercurial_types::{delta, manifest, Blob, MPath, ManifestId, NodeHash, Parents, RepoPath};
+
+use errors::*;
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ManifestDeltaed {
+    pub chunk: CgDeltaChunk,
+}
+
+/// Tree manifests scheduled for upload, keyed and valued like the ones from a b2xtreegroup2 part.
+pub type TreeManifests = HashMap<
+    (NodeHash, RepoPath),
+    (
+        ManifestContent,
+        Shared<BoxFuture<(BlobEntry, RepoPath), Compat<Error>>>,
+    ),
+>;
+
+/// Number of flat manifests kept for later manifests in the changegroup to use as their delta
+/// base or parents. Older ones are rebuilt from their trees if they are needed again.
+const CONVERTED_CACHE_SIZE: usize = 100;
+
+/// A directory of a tree manifest: its path, node, text, and content with paths relative to the
+/// root.
+type Dir = (MPath, NodeHash, Bytes, ManifestContent);
+
+/// Reconstruct the flat manifests of a changegroup, then convert them to tree manifests and
+/// schedule those for upload. Delta bases and parents that aren't in the changegroup are
+/// loaded from the repo.
+pub fn upload_flat_manifests<S>(repo: Arc<BlobRepo>, deltaed: S) -> BoxFuture<TreeManifests, Error>
+where
+    S: Stream<Item = ManifestDeltaed, Error = Error> + Send + 'static,
+{
+    let converter = FlatManifestConverter {
+        repo,
+        converted: HashMap::new(),
+        order: VecDeque::new(),
+        trees: HashMap::new(),
+    };
+
+    deltaed
+        .fold(converter, |converter, ManifestDeltaed { chunk }| {
+            converter.add(chunk)
+        })
+        .map(|converter| converter.trees)
+        .map_err(|err| err.context("While converting flat Manifests").into())
+        .boxify()
+}
+
+struct FlatManifestConverter {
+    repo: Arc<BlobRepo>,
+    // The text and tree of the most recent flat manifests, which later manifests may use as
+    // their delta base or parents, oldest first in `order`.
+    converted: HashMap<NodeHash, (Bytes, Arc<ConvertedTree>)>,
+    order: VecDeque<NodeHash>,
+    trees: TreeManifests,
+}
+
+impl FlatManifestConverter {
+    fn add(mut self, chunk: CgDeltaChunk) -> BoxFuture<Self, Error> {
+        let mut missing: Vec<_> = vec![chunk.base, chunk.p1, chunk.p2]
+            .into_iter()
+            .filter_map(NodeHash::into_option)
+            .filter(|node| !self.converted.contains_key(node))
+            .collect();
+        missing.sort();
+        missing.dedup();
+
+        let loads: Vec<_> = missing
+            .into_iter()
+            .map(|node| self.load_flat_manifest(node).map(move |m| (node, m)))
+            .collect();
+        future::join_all(loads)
+            .and_then(move |loaded| {
+                // Nothing is evicted until the chunk is converted, so that loading one of its
+                // parents can't evict another.
+                for (node, manifest) in loaded {
+                    self.insert(node, manifest);
+                }
+                self.convert(chunk)?;
+                self.evict();
+                Ok(self)
+            })
+            .boxify()
+    }
+
+    fn get_converted(&self, node: &NodeHash) -> Result<&(Bytes, Arc<ConvertedTree>)> {
+        self.converted
+            .get(node)
+            .ok_or_else(|| format_err!("flat Manifest {} was not loaded", node))
+    }
+
+    fn convert(&mut self, chunk: CgDeltaChunk) -> Result<()> {
+        let CgDeltaChunk {
+            node,
+            p1,
+            p2,
+            base,
+            delta,
+            ..
+        } = chunk;
+
+        let text = match base.into_option() {
+            None => delta::apply(b"", &delta),
+            Some(base) => delta::apply(&self.get_converted(&base)?.0, &delta),
+        };
+        let content = ManifestContent::parse(&text)
+            .with_context(|_| format!("While parsing flat Manifest {}", node))?;
+
+        let p1 = p1.into_option();
+        let p2 = p2.into_option();
+        let (tree, tree_nodes) = {
+            let p1_tree = match p1 {
+                Some(ref p1) => Some(&*self.get_converted(p1)?.1),
+                None => None,
+            };
+            let p2_tree = match p2 {
+                Some(ref p2) => Some(&*self.get_converted(p2)?.1),
+                None => None,
+            };
+            convert_flat(
+                &node,
+                &Parents::new(p1.as_ref(), p2.as_ref()),
+                &content,
+                p1_tree,
+                p2_tree,
+            )?
+        };
+
+        for tree_node in tree_nodes {
+            let path = if tree_node.path.is_empty() {
+                RepoPath::root()
+            } else {
+                RepoPath::dir(tree_node.path)?
+            };
+            let key = (tree_node.node, path.clone());
+            if self.trees.contains_key(&key) {
+                continue;
+            }
+
+            let (p1, p2) = tree_node.parents.get_nodes();
+            let upload = self.repo.upload_entry_with_nodeid(
+                tree_node.node,
+                Blob::from(tree_node.text.clone()),
+                manifest::Type::Tree,
+                p1.cloned(),
+                p2.cloned(),
+                path,
+            )?;
+            let tree_content = ManifestContent::parse(&tree_node.text)?;
+            self.trees.insert(
+                key,
+                (tree_content, upload.map_err(Error::compat).boxify().shared()),
+            );
+        }
+
+        self.insert(node, (Bytes::from(text), Arc::new(tree)));
+        Ok(())
+    }
+
+    fn insert(&mut self, node: NodeHash, manifest: (Bytes, Arc<ConvertedTree>)) {
+        if self.converted.insert(node, manifest).is_none() {
+            self.order.push_back(node);
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.order.len() > CONVERTED_CACHE_SIZE {
+            let oldest = self.order.pop_front().expect("cache order is empty");
+            self.converted.remove(&oldest);
+        }
+    }
+
+    /// Load a manifest that was converted earlier, either from this changegroup or from the
+    /// repo, returning its flat text along with its tree.
+    fn load_flat_manifest(
+        &self,
+        node: NodeHash,
+    ) -> BoxFuture<(Bytes, Arc<ConvertedTree>), Error> {
+        let mut dirs = Vec::new();
+        let mut remote = Vec::new();
+        if let Err(err) = self.load_local_dirs(MPath::empty(), node, &mut dirs, &mut remote) {
+            return future::err(err).boxify();
+        }
+
+        future::join_all(remote)
+            .and_then(move |remote| {
+                for mut remote_dirs in remote {
+                    dirs.append(&mut remote_dirs);
+                }
+
+                let mut files = BTreeMap::new();
+                let mut tree = ConvertedTree::new();
+                for (path, node, text, content) in dirs {
+                    files.extend(
+                        content
+                            .files
+                            .into_iter()
+                            .filter(|&(_, ref details)| !details.is_tree()),
+                    );
+                    tree.add_dir(path, node, &text);
+                }
+
+                let mut flat = Vec::new();
+                generate_flat(&ManifestContent { files }, &mut flat)?;
+                Ok((Bytes::from(flat), Arc::new(tree)))
+            })
+            .map_err(move |err| {
+                err.context(format!("While loading Manifest {}", node))
+                    .into()
+            })
+            .boxify()
+    }
+
+    /// Collect the directories of the tree manifest `node` of `path` that were converted from
+    /// this changegroup, as they aren't in the repo until they are uploaded. Subdirectories that
+    /// are already in the repo are loaded from there instead, along with theirs.
+    fn load_local_dirs(
+        &self,
+        path: MPath,
+        node: NodeHash,
+        dirs: &mut Vec<Dir>,
+        remote: &mut Vec<BoxFuture<Vec<Dir>, Error>>,
+    ) -> Result<()> {
+        let repo_path = if path.is_empty() {
+            RepoPath::root()
+        } else {
+            RepoPath::dir(path.clone())?
+        };
+        let local = match self.trees.get(&(node, repo_path)) {
+            Some(&(ref content, _)) => content,
+            None => {
+                remote.push(load_dirs(self.repo.clone(), path, node));
+                return Ok(());
+            }
+        };
+
+        // The parsed content regenerates the text that was uploaded.
+        let mut text = Vec::new();
+        local.generate(&mut text)?;
+        let content = ManifestContent::parse_with_prefix(&text, &path)?;
+        for (subdir, details) in &content.files {
+            if details.is_tree() {
+                self.load_local_dirs(
+                    subdir.clone(),
+                    details.entryid().into_nodehash(),
+                    dirs,
+                    remote,
+                )?;
+            }
+        }
+        dirs.push((path, node, Bytes::from(text), content));
+        Ok(())
+    }
+}
+
+/// Load the tree manifest `node` of the directory `path`, and all its subdirectories, from the
+/// repo. Paths in the parsed contents are relative to the root.
+fn load_dirs(repo: Arc<BlobRepo>, path: MPath, node: NodeHash) -> BoxFuture<Vec<Dir>, Error> {
+    repo.get_root_entry(&ManifestId::new(node))
+        .get_raw_content()
+        .and_then(move |blob| {
+            let text = blob.into_inner()
+                .ok_or_else(|| format_err!("Manifest {} has no content", node))?;
+            let content = ManifestContent::parse_with_prefix(&text, &path)?;
+            let subdirs: Vec<_> = content
+                .files
+                .iter()
+                .filter(|&(_, details)| details.is_tree())
+                .map(|(subdir, details)| {
+                    load_dirs(
+                        repo.clone(),
+                        subdir.clone(),
+                        details.entryid().into_nodehash(),
+                    )
+                })
+                .collect();
+
+            Ok(future::join_all(subdirs).map(move |subdirs| {
+                let mut dirs = vec![(path, node, text, content)];
+                for mut subdir in subdirs {
+                    dirs.append(&mut subdir);
+                }
+                dirs
+            }))
+        })
+        .flatten()
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::stream;
+
+    use mercurial_types::delta::{Delta, Fragment};
+    use mercurial_types::nodehash::NULL_HASH;
+
+    fn hash(i: usize) -> NodeHash {
+        format!("{:040x}", i).parse().unwrap()
+    }
+
+    fn flat_text(files: &[(&str, usize)]) -> Vec<u8> {
+        let mut text = Vec::new();
+        for &(path, filenode) in files {
+            text.extend_from_slice(format!("{}\0{}\n", path, hash(filenode)).as_bytes());
+        }
+        text
+    }
+
+    /// A chunk that replaces the whole text of its first parent, which is its delta base.
+    fn chunk(
+        node: NodeHash,
+        p1: NodeHash,
+        p2: NodeHash,
+        base: &[u8],
+        text: Vec<u8>,
+    ) -> ManifestDeltaed {
+        let delta = Delta::new(vec![
+            Fragment {
+                start: 0,
+                end: base.len(),
+                content: text,
+            },
+        ]).expect("bad delta");
+        ManifestDeltaed {
+            chunk: CgDeltaChunk {
+                node,
+                p1,
+                p2,
+                base: p1,
+                linknode: node,
+                delta,
+            },
+        }
+    }
+
+    #[test]
+    fn merge_with_evicted_parent() {
+        let repo = Arc::new(BlobRepo::new_memblob_empty(None).expect("repo failed"));
+
+        // A linear history long enough for its first manifests to be evicted.
+        let count = CONVERTED_CACHE_SIZE + 10;
+        let mut chunks = Vec::new();
+        let mut texts: Vec<Vec<u8>> = Vec::new();
+        for i in 0..count {
+            let text = flat_text(&[("dir/changing", 1000 + i), ("same", 1)]);
+            let (p1, base) = if i == 0 {
+                (NULL_HASH, vec![])
+            } else {
+                (hash(i - 1), texts[i - 1].clone())
+            };
+            chunks.push(chunk(hash(i), p1, NULL_HASH, &base, text.clone()));
+            texts.push(text);
+        }
+
+        // Merge the oldest manifest that is still cached with the first one, which isn't.
+        // Loading the second parent mustn't evict the first.
+        let oldest_cached = count - CONVERTED_CACHE_SIZE;
+        let merge = hash(count);
+        chunks.push(chunk(
+            merge,
+            hash(oldest_cached),
+            hash(0),
+            &texts[oldest_cached],
+            flat_text(&[("dir/changing", 1000), ("other/new", 2), ("same", 1)]),
+        ));
+
+        let trees = upload_flat_manifests(repo, stream::iter_ok(chunks))
+            .wait()
+            .expect("conversion failed");
+
+        // `dir` is the same as in the first manifest, so the merge reuses its node, which can
+        // only be found if the first manifest was rebuilt correctly.
+        let dir_path = RepoPath::dir(MPath::new("dir").unwrap()).unwrap();
+        let first_dir = trees
+            .iter()
+            .filter(|&(&(_, ref path), &(ref content, _))| {
+                *path == dir_path
+                    && content
+                        .files
+                        .values()
+                        .any(|details| details.entryid().into_nodehash() == hash(1000))
+            })
+            .map(|(&(ref node, _), _)| *node)
+            .next()
+            .expect("dir of the first manifest is missing");
+        let (ref root, _) = trees[&(merge, RepoPath::root())];
+        let merge_dir = root.files
+            .get(&MPath::new("dir").unwrap())
+            .expect("dir is missing from the merge")
+            .entryid()
+            .into_nodehash();
+        assert_eq!(merge_dir, first_dir);
+    }
+}
@@ -11,6 +11,7 @@ use mercurial_bundles::changegroup::{Part, Section};
 
 use changegroup::changeset::ChangesetDeltaed;
 use changegroup::filelog::FilelogDeltaed;
+use changegroup::manifest::ManifestDeltaed;
 use errors::*;
 
 pub fn split_changegroup<S>(
@@ -19,6 +20,80 @@ pub fn split_changegroup<S>(
     BoxStream<ChangesetDeltaed, Error>,
     BoxStream<FilelogDeltaed, Error>,
 )
+where
+    S: Stream<Item = Part, Error = Error> + Send + 'static,
+{
+    let (changesets, remainder) = split_changesets(cg2s);
+
+    let filelogs = remainder
+        .skip_while({
+            let mut seen_manifest_end = false;
+            move |part| match part {
+                &Part::SectionEnd(Section::Manifest) if !seen_manifest_end => {
+                    seen_manifest_end = true;
+                    Ok(true)
+                }
+                _ if seen_manifest_end => Ok(false),
+                bad => bail_msg!("Expected Manifest end, found: {:?}", bad),
+            }
+        })
+        .map_err(|err| {
+            err.context("While skipping Manifests in Changegroup")
+                .into()
+        });
+
+    (changesets, extract_filelogs(filelogs))
+}
+
+/// Like split_changegroup, but for changegroups that carry flat manifests, which are returned
+/// as a third stream.
+pub fn split_changegroup_with_manifests<S>(
+    cg2s: S,
+) -> (
+    BoxStream<ChangesetDeltaed, Error>,
+    BoxStream<ManifestDeltaed, Error>,
+    BoxStream<FilelogDeltaed, Error>,
+)
+where
+    S: Stream<Item = Part, Error = Error> + Send + 'static,
+{
+    let (changesets, remainder) = split_changesets(cg2s);
+
+    let (manifests, remainder) = remainder
+        .take_while(|part| match part {
+            &Part::CgChunk(Section::Manifest, _) => Ok(true),
+            &Part::SectionEnd(Section::Manifest) => Ok(false),
+            bad => bail_msg!("Expected Manifest chunk or end, found: {:?}", bad),
+        })
+        .return_remainder();
+
+    let manifests = manifests
+        .and_then(|part| match part {
+            Part::CgChunk(Section::Manifest, chunk) => Ok(ManifestDeltaed { chunk }),
+            bad => bail_msg!("Expected Manifest chunk, found: {:?}", bad),
+        })
+        .map_err(|err| {
+            err.context("While extracting Manifests from Changegroup")
+                .into()
+        })
+        .boxify();
+
+    let filelogs = remainder
+        .from_err()
+        .map(|take_while_stream| take_while_stream.into_inner())
+        .flatten_stream();
+
+    (changesets, manifests, extract_filelogs(filelogs))
+}
+
+/// Split the changesets off the start of a changegroup, returning them along with the rest of
+/// the changegroup.
+fn split_changesets<S>(
+    cg2s: S,
+) -> (
+    BoxStream<ChangesetDeltaed, Error>,
+    BoxStream<Part, Error>,
+)
 where
     S: Stream<Item = Part, Error = Error> + Send + 'static,
 {
@@ -41,25 +116,21 @@ where
         })
         .boxify();
 
-    let filelogs = remainder
+    let remainder = remainder
         .from_err()
         .map(|take_while_stream| take_while_stream.into_inner())
         .flatten_stream()
-        .skip_while({
-            let mut seen_manifest_end = false;
-            move |part| match part {
-                &Part::SectionEnd(Section::Manifest) if !seen_manifest_end => {
-                    seen_manifest_end = true;
-                    Ok(true)
-                }
-                _ if seen_manifest_end => Ok(false),
-                bad => bail_msg!("Expected Manifest end, found: {:?}", bad),
-            }
-        })
-        .map_err(|err| {
-            err.context("While skipping Manifests in Changegroup")
-                .into()
-        })
+        .boxify();
+
+    (changesets, remainder)
+}
+
+/// Extract the filelogs from the end of a changegroup, starting just after the manifests.
+fn extract_filelogs<S>(parts: S) -> BoxStream<FilelogDeltaed, Error>
+where
+    S: Stream<Item = Part, Error = Error> + Send + 'static,
+{
+    parts
         .and_then({
             let mut seen_path = None;
             move |part| {
@@ -111,9 +182,7 @@ where
                 .into()
         })
         .filter_map(|x| x)
-        .boxify();
-
-    (changesets, filelogs)
+        .boxify()
 }
 
 /// Wrapper for Stream of Part that is supposed to ensure that there is exactly one Part::End in
@@ -367,6 +436,39 @@ mod tests {
         }
     }
 
+    quickcheck! {
+        fn splitting_with_manifests(
+            c: CgDeltaChunk,
+            m1: CgDeltaChunk,
+            m2: CgDeltaChunk,
+            f: CgDeltaChunk,
+            f_p: MPath
+        ) -> bool {
+            let (cs, ms, fs) = split_changegroup_with_manifests(iter_ok(
+                vec![
+                    Part::CgChunk(Section::Changeset, c.clone()),
+                    Part::SectionEnd(Section::Changeset),
+                    Part::CgChunk(Section::Manifest, m1.clone()),
+                    Part::CgChunk(Section::Manifest, m2.clone()),
+                    Part::SectionEnd(Section::Manifest),
+                    Part::CgChunk(Section::Filelog(f_p.clone()), f.clone()),
+                    Part::SectionEnd(Section::Filelog(f_p.clone())),
+                    Part::End,
+                ].into_iter(),
+            ));
+
+            equal(cs.collect().wait().unwrap(), vec![ChangesetDeltaed { chunk: c }])
+                && equal(
+                    ms.collect().wait().unwrap(),
+                    vec![ManifestDeltaed { chunk: m1 }, ManifestDeltaed { chunk: m2 }],
+                )
+                && equal(
+                    fs.collect().wait().unwrap(),
+                    vec![FilelogDeltaed { path: f_p, chunk: f }],
+                )
+        }
+    }
+
     #[test]
     fn splitting_error_two_ends() {
         {
@@ -47,4 +47,4 @@ mod stats;
 mod wirepackparser;
 mod upload_blobs;
 
-pub use resolver::resolve;
+pub use resolver::{resolve, resolve_bundle};
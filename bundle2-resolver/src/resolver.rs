@@ -18,11 +18,11 @@ use slog::Logger;
 use blobrepo::{BlobEntry, BlobRepo, ChangesetHandle};
 use mercurial::changeset::RevlogChangeset;
 use mercurial::manifest::revlog::ManifestContent;
-use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item};
+use mercurial_bundles::{parts, wirepack, Bundle2EncodeBuilder, Bundle2Item};
 use mercurial_types::{Changeset, ChangesetId, MPath, ManifestId, NodeHash, RepoPath};
 
 use changegroup::{convert_to_revlog_changesets, convert_to_revlog_filelog, split_changegroup,
-                  Filelog};
+                  split_changegroup_with_manifests, upload_flat_manifests, Filelog};
 use errors::*;
 use upload_blobs::{upload_blobs, UploadBlobsType, UploadableBlob};
 use wirepackparser::{TreemanifestBundle2Parser, TreemanifestEntry};
@@ -86,6 +86,54 @@ pub fn resolve(
         .boxify()
 }
 
+/// Like resolve, but for a bundle read from disk rather than pushed by a client. The bundle may
+/// lack a Replycaps part, and its changegroup may carry flat manifests, which are converted to
+/// tree manifests. There's no response, as there's nobody to send it to.
+pub fn resolve_bundle(
+    repo: Arc<BlobRepo>,
+    logger: Logger,
+    bundle2: BoxStream<Bundle2Item, Error>,
+) -> BoxFuture<(), Error> {
+    let resolver = Bundle2Resolver::new(repo, logger);
+
+    let bundle2 = resolver.resolve_start_and_maybe_replycaps(bundle2);
+
+    resolver
+        .resolve_changegroup_with_manifests(bundle2)
+        .and_then(move |(cg_push, flat_manifests, bundle2)| {
+            let changesets = cg_push.changesets;
+            let filelogs = cg_push.filelogs;
+
+            let bundle2 = resolver
+                .maybe_resolve_b2xtreegroup2(bundle2)
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    move |(mut manifests, bundle2)| {
+                        manifests.extend(flat_manifests);
+                        resolver
+                            .maybe_resolve_infinitepush_bookmarks(bundle2)
+                            .map(|(_, bundle2)| (manifests, bundle2))
+                    }
+                })
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    move |(manifests, bundle2)| {
+                        resolver
+                            .upload_changesets(changesets, filelogs, manifests)
+                            .map(|()| bundle2)
+                    }
+                })
+                .flatten_stream()
+                .boxify();
+
+            resolver.ensure_stream_finished(bundle2)
+        })
+        .map_err(|err| err.context("bundle2-resolver error").into())
+        .boxify()
+}
+
 fn next_item(
     bundle2: BoxStream<Bundle2Item, Error>,
 ) -> BoxFuture<(Option<Bundle2Item>, BoxStream<Bundle2Item, Error>), Error> {
@@ -128,6 +176,24 @@ impl Bundle2Resolver {
             .boxify()
     }
 
+    /// Parse Start and ignore its content, along with Replycaps if it's there
+    fn resolve_start_and_maybe_replycaps(
+        &self,
+        bundle2: BoxStream<Bundle2Item, Error>,
+    ) -> BoxStream<Bundle2Item, Error> {
+        next_item(bundle2)
+            .and_then(|(start, bundle2)| match start {
+                Some(Bundle2Item::Start(_)) => next_item(bundle2),
+                _ => err(format_err!("Expected Bundle2 Start")).boxify(),
+            })
+            .and_then(|(replycaps, bundle2)| match replycaps {
+                Some(Bundle2Item::Replycaps(_, part)) => part.map(|_| bundle2).boxify(),
+                other => ok(stream::iter_ok(other).chain(bundle2).boxify()).boxify(),
+            })
+            .flatten_stream()
+            .boxify()
+    }
+
     /// Parse changegroup.
     /// The ChangegroupId will be used in the last step for preparing response
     /// The Changesets should be parsed as RevlogChangesets and used for uploading changesets
@@ -170,6 +236,47 @@ impl Bundle2Resolver {
             .boxify()
     }
 
+    /// Parse changegroup that may contain flat manifests.
+    /// Like resolve_changegroup, but the flat Manifests are converted to tree manifests and
+    /// scheduled for uploading as well.
+    fn resolve_changegroup_with_manifests(
+        &self,
+        bundle2: BoxStream<Bundle2Item, Error>,
+    ) -> BoxFuture<(ChangegroupPush, Manifests, BoxStream<Bundle2Item, Error>), Error> {
+        let repo = self.repo.clone();
+
+        next_item(bundle2)
+            .and_then(move |(changegroup, bundle2)| match changegroup {
+                Some(Bundle2Item::Changegroup(header, parts))
+                | Some(Bundle2Item::B2xInfinitepush(header, parts)) => {
+                    let part_id = header.part_id();
+                    let (c, m, f) = split_changegroup_with_manifests(parts);
+                    convert_to_revlog_changesets(c)
+                        .collect()
+                        .join3(
+                            upload_flat_manifests(repo.clone(), m),
+                            upload_blobs(
+                                repo.clone(),
+                                convert_to_revlog_filelog(repo, f),
+                                UploadBlobsType::EnsureNoDuplicates,
+                            ).map_err(|err| err.context("While uploading File Blobs").into()),
+                        )
+                        .map(move |(changesets, manifests, filelogs)| {
+                            let cg_push = ChangegroupPush {
+                                part_id,
+                                changesets,
+                                filelogs,
+                            };
+                            (cg_push, manifests, bundle2)
+                        })
+                        .boxify()
+                }
+                _ => err(format_err!("Expected Bundle2 Changegroup")).boxify(),
+            })
+            .map_err(|err| err.context("While resolving Changegroup").into())
+            .boxify()
+    }
+
     /// Parse b2xtreegroup2.
     /// The Manifests should be scheduled for uploading to BlobRepo and the Future resolving in
     /// their upload as well as their parsed content should be used for uploading changesets.
@@ -181,21 +288,35 @@ impl Bundle2Resolver {
 
         next_item(bundle2)
             .and_then(move |(b2xtreegroup2, bundle2)| match b2xtreegroup2 {
-                Some(Bundle2Item::B2xTreegroup2(_, parts)) => {
-                    upload_blobs(
-                        repo,
-                        TreemanifestBundle2Parser::new(parts),
-                        UploadBlobsType::IgnoreDuplicates,
-                    ).map_err(|err| err.context("While uploading Manifest Blobs").into())
-                        .map(move |manifests| (manifests, bundle2))
-                        .boxify()
-                }
+                Some(Bundle2Item::B2xTreegroup2(_, parts)) => upload_b2xtreegroup2(repo, parts)
+                    .map(move |manifests| (manifests, bundle2))
+                    .boxify(),
                 _ => err(format_err!("Expected Bundle2 B2xTreegroup2")).boxify(),
             })
             .map_err(|err| err.context("While resolving B2xTreegroup2").into())
             .boxify()
     }
 
+    /// Parse b2xtreegroup2 if it's there.
+    /// Bundles whose manifests are all flat have no b2xtreegroup2.
+    fn maybe_resolve_b2xtreegroup2(
+        &self,
+        bundle2: BoxStream<Bundle2Item, Error>,
+    ) -> BoxFuture<(Manifests, BoxStream<Bundle2Item, Error>), Error> {
+        let repo = self.repo.clone();
+
+        next_item(bundle2)
+            .and_then(move |(b2xtreegroup2, bundle2)| match b2xtreegroup2 {
+                Some(Bundle2Item::B2xTreegroup2(_, parts)) => upload_b2xtreegroup2(repo, parts)
+                    .map(move |manifests| (manifests, bundle2))
+                    .boxify(),
+                other => ok((HashMap::new(), stream::iter_ok(other).chain(bundle2).boxify()))
+                    .boxify(),
+            })
+            .map_err(|err| err.context("While resolving B2xTreegroup2").into())
+            .boxify()
+    }
+
     /// Parse b2xinfinitepushscratchbookmarks.
     /// This part is ignored, so just parse it and forget it
     fn maybe_resolve_infinitepush_bookmarks(
@@ -343,6 +464,19 @@ impl Bundle2Resolver {
     }
 }
 
+/// Schedules the tree Manifests of a b2xtreegroup2 part for uploading
+fn upload_b2xtreegroup2(
+    repo: Arc<BlobRepo>,
+    parts: BoxStream<wirepack::Part, Error>,
+) -> BoxFuture<Manifests, Error> {
+    upload_blobs(
+        repo,
+        TreemanifestBundle2Parser::new(parts),
+        UploadBlobsType::IgnoreDuplicates,
+    ).map_err(|err| err.context("While uploading Manifest Blobs").into())
+        .boxify()
+}
+
 /// Retrieves the parent from uploaded changesets, if it is missing then fetches it from BlobRepo
 fn get_parent(
     repo: &BlobRepo,
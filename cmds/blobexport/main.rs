@@ -0,0 +1,547 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Export a blob repo as a Mercurial revlog repo.
+//!
+//! Changesets are written in generation number order, so that every revision lands after its
+//! parents. The output uses a fncache store, and is meant to pass `hg verify`. Each revision is
+//! appended to its revlog as soon as it is exported, so only what's needed to delta the next
+//! revision of each revlog is kept in memory.
+//!
+//! The output uses tree manifests, unless the repo was imported from flat manifests. Those
+//! are stored as trees whose root keeps the flat manifest's node, so they are exported as flat
+//! manifests again.
+
+#![deny(warnings)]
+
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+extern crate tokio_core;
+
+extern crate blobrepo;
+extern crate mercurial;
+extern crate mercurial_types;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches};
+use failure::{Result, ResultExt, SlogKVError};
+use futures::{Future, Stream};
+use slog::{Drain, Level, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+use tokio_core::reactor::Core;
+
+use blobrepo::BlobRepo;
+use mercurial::changeset::serialize_cs;
+use mercurial::manifest::Details;
+use mercurial::manifest::convert::generate_flat;
+use mercurial::manifest::revlog::ManifestContent;
+use mercurial::revlog::{Compression, RevIdx, RevlogWriter};
+use mercurial::revlogrepo::Required;
+use mercurial_types::{fncache_fsencode, BlobNode, Changeset, ChangesetId, Entry, MPath,
+                      MPathElement, NodeHash, RepositoryId, Type, NULL_HASH};
+use mercurial_types::manifest::Content;
+use mercurial_types::nodehash::ManifestId;
+
+#[derive(Debug, Eq, PartialEq)]
+enum BlobstoreType {
+    Files,
+    Rocksdb,
+    Sqlite,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ManifestFormat {
+    Tree,
+    Flat,
+}
+
+struct Exporter {
+    repo: BlobRepo,
+    core: Core,
+    logger: Logger,
+    compression: Compression,
+    store: PathBuf,
+    changelog: RevlogWriter,
+    // Decided by the first manifest that is exported
+    format: Option<ManifestFormat>,
+    // Manifest revlogs, keyed by directory. The root manifest is under the empty path, and is
+    // the only one for flat manifests.
+    trees: HashMap<MPath, RevlogWriter>,
+    files: HashMap<MPath, RevlogWriter>,
+}
+
+impl Exporter {
+    fn new(
+        repo: BlobRepo,
+        logger: Logger,
+        compression: Compression,
+        output: &Path,
+    ) -> Result<Self> {
+        let store = output.join(".hg").join("store");
+        fs::create_dir_all(&store)?;
+        Ok(Exporter {
+            repo,
+            core: Core::new()?,
+            logger,
+            compression,
+            store,
+            changelog: RevlogWriter::new(compression),
+            format: None,
+            trees: HashMap::new(),
+            files: HashMap::new(),
+        })
+    }
+
+    /// Return every changeset in the repo, sorted so that parents come before their children.
+    fn sorted_changesets(&mut self) -> Result<Vec<NodeHash>> {
+        let repo = self.repo.clone();
+        let changesets = self.repo
+            .get_changesets()
+            .and_then(move |node| {
+                repo.get_generation_number(&ChangesetId::new(node))
+                    .and_then(move |gen| match gen {
+                        Some(gen) => Ok((gen, node)),
+                        None => Err(format_err!("no generation number for changeset {}", node)),
+                    })
+            })
+            .collect();
+        let mut changesets = self.core.run(changesets)?;
+        changesets.sort();
+        Ok(changesets.into_iter().map(|(_, node)| node).collect())
+    }
+
+    fn export_changeset(&mut self, csid: NodeHash) -> Result<()> {
+        let cs = self.core
+            .run(self.repo.get_changeset_by_changesetid(&ChangesetId::new(csid)))?;
+        let linkrev = RevIdx::from(self.changelog.len());
+
+        let manifestid = cs.manifestid().into_nodehash();
+        if manifestid != NULL_HASH {
+            let root = self.repo.get_root_entry(&ManifestId::new(manifestid));
+            let format = match self.format {
+                Some(format) => format,
+                None => {
+                    let format = self.manifest_format(&*root)?;
+                    self.format = Some(format);
+                    format
+                }
+            };
+            let exported = match format {
+                ManifestFormat::Tree => self.export_tree(MPath::empty(), root, linkrev),
+                ManifestFormat::Flat => self.export_flat(root, linkrev),
+            };
+            exported.with_context(|_| format!("while exporting manifest of {}", csid))?;
+        }
+
+        let mut text = Vec::new();
+        serialize_cs(&cs, &mut text)?;
+        let (p1, p2) = cs.parents().get_nodes();
+        let (_, node) = self.changelog.add_revision(&text, p1, p2, linkrev)?;
+        if node != csid {
+            bail_msg!("changeset {} hashes to {} when serialized", csid, node);
+        }
+        append_revlog(&self.store.join("00changelog.i"), &mut self.changelog)
+    }
+
+    /// Tell whether a root manifest is a tree manifest, or was converted from a flat manifest, in
+    /// which case its text doesn't hash to its node.
+    fn manifest_format(&mut self, root: &(Entry + Sync)) -> Result<ManifestFormat> {
+        let text = self.core.run(root.get_raw_content())?;
+        let parents = self.core.run(root.get_parents())?;
+        let (p1, p2) = parents.get_nodes();
+        let node = BlobNode::new(text, p1, p2).nodeid();
+        if node == Some(root.get_hash().into_nodehash()) {
+            Ok(ManifestFormat::Tree)
+        } else {
+            info!(self.logger, "The repo was imported from flat manifests");
+            Ok(ManifestFormat::Flat)
+        }
+    }
+
+    fn export_tree(
+        &mut self,
+        path: MPath,
+        entry: Box<Entry + Sync>,
+        linkrev: RevIdx,
+    ) -> Result<()> {
+        let node = entry.get_hash().into_nodehash();
+        if has_node(&self.trees, &path, &node) {
+            // Everything below this tree must have been exported already.
+            return Ok(());
+        }
+
+        let children = match self.core.run(entry.get_content())? {
+            Content::Tree(manifest) => self.core.run(manifest.list().collect())?,
+            _ => bail_msg!("manifest {} at '{}' is not a tree", node, path),
+        };
+        for child in children {
+            let child_path = path.join_element(child.get_name());
+            match child.get_type() {
+                Type::Tree => self.export_tree(child_path, child, linkrev)?,
+                Type::File | Type::Executable | Type::Symlink => {
+                    self.export_file(child_path, child, linkrev)?
+                }
+            }
+        }
+
+        let compression = self.compression;
+        let text = self.core.run(entry.get_raw_content())?;
+        let parents = self.core.run(entry.get_parents())?;
+        let writer = self.trees
+            .entry(path.clone())
+            .or_insert_with(|| RevlogWriter::new(compression));
+        add_checked(writer, &path, node, text.as_slice(), parents, linkrev)?;
+        let revlog_path = if path.is_empty() {
+            self.store.join("00manifest.i")
+        } else {
+            store_path(&self.store, tree_elements(&path))
+        };
+        append_revlog(&revlog_path, writer)
+    }
+
+    /// Export the flat manifest that `root` was converted from. Its text lists every file, so the
+    /// whole tree is walked for each manifest.
+    fn export_flat(&mut self, root: Box<Entry + Sync>, linkrev: RevIdx) -> Result<()> {
+        let path = MPath::empty();
+        let node = root.get_hash().into_nodehash();
+        if has_node(&self.trees, &path, &node) {
+            return Ok(());
+        }
+
+        let mut files = BTreeMap::new();
+        self.export_flat_files(path.clone(), &*root, linkrev, &mut files)?;
+        let mut text = Vec::new();
+        generate_flat(&ManifestContent { files }, &mut text)?;
+
+        let compression = self.compression;
+        let parents = self.core.run(root.get_parents())?;
+        let writer = self.trees
+            .entry(path.clone())
+            .or_insert_with(|| RevlogWriter::new(compression));
+        add_checked(writer, &path, node, Some(&text[..]), parents, linkrev)?;
+        append_revlog(&self.store.join("00manifest.i"), writer)
+    }
+
+    /// Export the files below the tree `entry`, and add them to `files` for a flat manifest.
+    fn export_flat_files(
+        &mut self,
+        path: MPath,
+        entry: &(Entry + Sync),
+        linkrev: RevIdx,
+        files: &mut BTreeMap<MPath, Details>,
+    ) -> Result<()> {
+        let children = match self.core.run(entry.get_content())? {
+            Content::Tree(manifest) => self.core.run(manifest.list().collect())?,
+            _ => bail_msg!("manifest at '{}' is not a tree", path),
+        };
+        for child in children {
+            let child_path = path.join_element(child.get_name());
+            match child.get_type() {
+                Type::Tree => self.export_flat_files(child_path, &*child, linkrev, files)?,
+                Type::File | Type::Executable | Type::Symlink => {
+                    let details = Details::new(*child.get_hash(), child.get_type());
+                    files.insert(child_path.clone(), details);
+                    self.export_file(child_path, child, linkrev)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn export_file(
+        &mut self,
+        path: MPath,
+        entry: Box<Entry + Sync>,
+        linkrev: RevIdx,
+    ) -> Result<()> {
+        let node = entry.get_hash().into_nodehash();
+        if has_node(&self.files, &path, &node) {
+            return Ok(());
+        }
+
+        // The raw content includes the copy metadata, which is hashed as part of the node.
+        let compression = self.compression;
+        let text = self.core.run(entry.get_raw_content())?;
+        let parents = self.core.run(entry.get_parents())?;
+        let writer = self.files
+            .entry(path.clone())
+            .or_insert_with(|| RevlogWriter::new(compression));
+        add_checked(writer, &path, node, text.as_slice(), parents, linkrev)?;
+        append_revlog(&store_path(&self.store, file_elements(&path)), writer)
+    }
+
+    /// Finish the repo by writing `requires` and the fncache. The revlogs have been written
+    /// already.
+    fn finish(mut self) -> Result<()> {
+        // The changelog of a repo without changesets is an empty file, which has to exist.
+        append_revlog(&self.store.join("00changelog.i"), &mut self.changelog)?;
+
+        info!(
+            self.logger,
+            "Wrote {} changesets, {} manifest revlogs and {} filelogs",
+            self.changelog.len(),
+            self.trees.len(),
+            self.files.len()
+        );
+
+        let mut fncache: Vec<_> = self.trees
+            .keys()
+            .filter(|path| !path.is_empty())
+            .map(|path| join_elements(&tree_elements(path)))
+            .chain(
+                self.files
+                    .keys()
+                    .map(|path| join_elements(&file_elements(path))),
+            )
+            .collect();
+        fncache.sort();
+        let mut fncache_file = fs::File::create(self.store.join("fncache"))?;
+        for entry in fncache {
+            fncache_file.write_all(&entry)?;
+            fncache_file.write_all(b"\n")?;
+        }
+
+        // requires is written last, so that an export that failed part way isn't a repo.
+        let mut requires = vec![
+            Required::Dotencode,
+            Required::Fncache,
+            Required::Revlogv1,
+            Required::Store,
+        ];
+        if self.format != Some(ManifestFormat::Flat) {
+            requires.push(Required::Treemanifest);
+        }
+        match self.compression {
+            Compression::Lz4 => requires.push(Required::Lz4revlog),
+            Compression::Zstd => requires.push(Required::RevlogCompressionZstd),
+            Compression::None | Compression::Zlib => {}
+        }
+        let dothg = self.store.parent().expect("store is in .hg");
+        let mut requires_file = fs::File::create(dothg.join("requires"))?;
+        for req in requires {
+            write!(requires_file, "{}\n", req)?;
+        }
+        Ok(())
+    }
+}
+
+fn has_node(writers: &HashMap<MPath, RevlogWriter>, path: &MPath, node: &NodeHash) -> bool {
+    writers
+        .get(path)
+        .map(|writer| writer.get_idx_by_nodeid(node).is_some())
+        .unwrap_or(false)
+}
+
+/// Add a revision, making sure that it gets the same hash in the revlog as in the blob repo.
+fn add_checked(
+    writer: &mut RevlogWriter,
+    path: &MPath,
+    node: NodeHash,
+    text: Option<&[u8]>,
+    parents: mercurial_types::Parents,
+    linkrev: RevIdx,
+) -> Result<()> {
+    let text = match text {
+        Some(text) => text,
+        None => bail_msg!("no content for {} at '{}'", node, path),
+    };
+    let (p1, p2) = parents.get_nodes();
+    let (_, written) = writer
+        .add_revision(text, p1, p2, linkrev)
+        .with_context(|_| format!("while exporting {} at '{}'", node, path))?;
+    if written != node {
+        bail_msg!("{} at '{}' hashes to {} when exported", node, path, written);
+    }
+    Ok(())
+}
+
+/// The path of a directory's tree manifest revlog, relative to the store.
+fn tree_elements(path: &MPath) -> Vec<MPathElement> {
+    let mut elements = vec![MPathElement::new(b"meta".to_vec())];
+    elements.extend(path.into_iter().cloned());
+    elements.push(MPathElement::new(b"00manifest.i".to_vec()));
+    elements
+}
+
+/// The path of a file's filelog, relative to the store.
+fn file_elements(path: &MPath) -> Vec<MPathElement> {
+    let mut elements = vec![MPathElement::new(b"data".to_vec())];
+    elements.extend(path.into_iter().cloned());
+    if let Some(last) = elements.last_mut() {
+        last.extend(b".i");
+    }
+    elements
+}
+
+fn store_path(store: &Path, elements: Vec<MPathElement>) -> PathBuf {
+    store.join(fncache_fsencode(&elements, true))
+}
+
+/// Append the revisions added to `writer` since it was last written out to the revlog at
+/// `path`.
+fn append_revlog(path: &Path, writer: &mut RevlogWriter) -> Result<()> {
+    fn append(path: &Path, bytes: &[u8]) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|_| format!("can't open {:?}", path))?;
+        file.write_all(bytes)
+            .with_context(|_| format!("can't write {:?}", path))?;
+        Ok(())
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let (index, data) = writer.take_parts();
+    append(path, &index)?;
+    if let Some(data) = data {
+        append(&path.with_extension("d"), &data)?;
+    }
+    Ok(())
+}
+
+fn join_elements(elements: &[MPathElement]) -> Vec<u8> {
+    let elements: Vec<_> = elements.iter().map(MPathElement::as_bytes).collect();
+    elements.join(&b'/')
+}
+
+fn open_repo(
+    logger: &Logger,
+    input: &Path,
+    blobtype: BlobstoreType,
+    repo_id: RepositoryId,
+) -> Result<BlobRepo> {
+    let logger = logger.new(o!["BlobRepo" => format!("{:?}", input)]);
+    match blobtype {
+        BlobstoreType::Files => BlobRepo::new_files(logger, input, repo_id),
+        BlobstoreType::Rocksdb => BlobRepo::new_rocksdb(logger, input, repo_id),
+        BlobstoreType::Sqlite => BlobRepo::new_sqlite(logger, input, repo_id),
+    }
+}
+
+fn run_blobexport(
+    logger: &Logger,
+    input: PathBuf,
+    output: PathBuf,
+    blobtype: BlobstoreType,
+    repo_id: RepositoryId,
+    compression: Compression,
+) -> Result<()> {
+    if output.join(".hg").exists() {
+        bail_msg!("{:?} already contains a Mercurial repo", output);
+    }
+
+    let repo = open_repo(logger, &input, blobtype, repo_id)?;
+    let mut exporter = Exporter::new(repo, logger.clone(), compression, &output)?;
+
+    let changesets = exporter.sorted_changesets()?;
+    info!(logger, "Exporting {} changesets", changesets.len());
+    for (count, csid) in changesets.into_iter().enumerate() {
+        exporter.export_changeset(csid)?;
+        if (count + 1) % 1000 == 0 {
+            debug!(logger, "exported {} changesets", count + 1);
+        }
+    }
+
+    exporter.finish()
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("blob to revlog exporter")
+        .version("0.0.0")
+        .about("make revlogs")
+        .args_from_usage(
+            r#"
+            <INPUT>                  'input blob repo'
+            <OUTPUT>                 'output Mercurial repo'
+
+            -d, --debug              'print debug level output'
+            --repo-id [REPO_ID]      'id of the repo in the SQL stores. Default: 0'
+        "#,
+        )
+        .arg(
+            Arg::with_name("blobstore")
+                .long("blobstore")
+                .short("B")
+                .takes_value(true)
+                .possible_values(&["files", "rocksdb", "sqlite"])
+                .required(true)
+                .help("blobstore type"),
+        )
+        .arg(
+            Arg::with_name("compression")
+                .long("compression")
+                .takes_value(true)
+                .possible_values(&["zlib", "zstd", "lz4", "none"])
+                .default_value("zlib")
+                .help("how to compress revlog chunks; lz4 needs the lz4revlog extension"),
+        )
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let root_log = {
+        let level = if matches.is_present("debug") {
+            Level::Debug
+        } else {
+            Level::Info
+        };
+
+        let drain = glog_drain().filter_level(level).fuse();
+        slog::Logger::root(drain, o![])
+    };
+
+    fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<()> {
+        let input = matches.value_of("INPUT").unwrap();
+        let output = matches.value_of("OUTPUT").unwrap();
+
+        let blobtype = match matches.value_of("blobstore").unwrap() {
+            "files" => BlobstoreType::Files,
+            "rocksdb" => BlobstoreType::Rocksdb,
+            "sqlite" => BlobstoreType::Sqlite,
+            bad => panic!("unexpected blobstore type {}", bad),
+        };
+
+        let compression = match matches.value_of("compression").unwrap() {
+            "zlib" => Compression::Zlib,
+            "zstd" => Compression::Zstd,
+            "lz4" => Compression::Lz4,
+            "none" => Compression::None,
+            bad => panic!("unexpected compression {}", bad),
+        };
+
+        let repo_id = matches
+            .value_of("repo-id")
+            .map(|id| id.parse().expect("repo-id must be an integer"))
+            .unwrap_or(0);
+
+        run_blobexport(
+            root_log,
+            input.into(),
+            output.into(),
+            blobtype,
+            RepositoryId::new(repo_id),
+            compression,
+        )
+    }
+
+    if let Err(e) = run(&root_log, matches) {
+        error!(root_log, "Blobexport failed"; SlogKVError(e));
+        std::process::exit(1);
+    }
+}
@@ -4,38 +4,52 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::mpsc::SyncSender;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 
-use futures::{Future, IntoFuture, Stream};
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures::future::Shared;
 use futures_cpupool::CpuPool;
 use slog::Logger;
 use tokio_core::reactor::Core;
 
 use blobrepo::BlobChangeset;
-use failure::{Error, Result};
-use futures_ext::{BoxStream, FutureExt, StreamExt};
+use failure::{Compat, Error, Result, ResultExt};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use heads::Heads;
-use linknodes::Linknodes;
+use linknodes::{ErrorKind as LinknodeErrorKind, Linknodes};
 use mercurial::{self, RevlogManifest, RevlogRepo};
+use mercurial::manifest::convert::FlatConverter;
+use mercurial::manifest::revlog::ManifestContent;
 use mercurial::revlog::RevIdx;
-use mercurial_types::{Changeset, MPath, Manifest, NodeHash, RepoPath};
+use mercurial_types::{Blob, Changeset, MPath, Manifest, NodeHash, RepoPath};
 use mercurial_types::nodehash::{ChangesetId, EntryId};
 use stats::Timeseries;
 
-use BlobstoreEntry;
+use {BlobstoreEntry, BlobstoreSender, LargefilesMode};
 use STATS;
 use manifest;
 
+// Throughput is reported every time this many changesets have been written.
+const REPORT_INTERVAL: usize = 1000;
+
 pub(crate) struct ConvertContext<H> {
     pub repo: RevlogRepo,
-    pub sender: SyncSender<BlobstoreEntry>,
+    pub sender: BlobstoreSender,
     pub headstore: H,
     pub core: Core,
     pub cpupool: Arc<CpuPool>,
     pub logger: Logger,
     pub skip: Option<u64>,
     pub commits_limit: Option<u64>,
+    /// Import only these changesets, in this order, instead of the whole repo. Heads are not
+    /// touched, as they can only be updated once the changesets are known to be imported.
+    pub changesets: Option<Vec<NodeHash>>,
+    /// How many changesets have their files and manifests copied at once.
+    pub parallelism: usize,
+    pub largefiles: LargefilesMode,
 }
 
 impl<H> ConvertContext<H>
@@ -50,39 +64,110 @@ where
         let headstore = self.headstore;
         let skip = self.skip;
         let commits_limit = self.commits_limit;
+        let parallelism = self.parallelism;
+        let largefiles = self.largefiles;
 
-        let changesets: BoxStream<NodeHash, mercurial::Error> = if let Some(skip) = skip {
-            self.repo.changesets().skip(skip).boxify()
-        } else {
-            self.repo.changesets().boxify()
-        };
+        let import_heads = self.changesets.is_none();
+        let changesets: BoxStream<NodeHash, mercurial::Error> = match self.changesets {
+            Some(changesets) => stream::iter_ok(changesets).boxify(),
+            None => {
+                let changesets = if let Some(skip) = skip {
+                    self.repo.changesets().skip(skip).boxify()
+                } else {
+                    self.repo.changesets().boxify()
+                };
 
-        let changesets: BoxStream<NodeHash, mercurial::Error> = if let Some(limit) = commits_limit {
-            changesets.take(limit).boxify()
-        } else {
-            changesets.boxify()
+                if let Some(limit) = commits_limit {
+                    changesets.take(limit).boxify()
+                } else {
+                    changesets.boxify()
+                }
+            }
         };
         let linknodes_store = Arc::new(linknodes_store);
+        let flat_converter = Arc::new(FlatConverter::new(self.repo.clone()));
+        let progress = Arc::new(Progress::new());
 
-        // Generate stream of changesets. For each changeset, save the cs blob, and the manifest
-        // blob, and the files.
-        let changesets = changesets
+        // The import has two stages. First the files and manifests of up to `parallelism`
+        // changesets are copied at once. They come out of this stage in revlog order, so when a
+        // changeset leaves it, everything introduced by earlier changesets has been copied.
+        let copied = changesets
             .map_err(Error::from)
             .enumerate()
             .map({
                 let repo = self.repo.clone();
                 let sender = self.sender.clone();
+                let progress = progress.clone();
                 move |(seq, csid)| {
                     debug!(logger, "{}: changeset {}", seq, csid);
                     STATS::changesets.add_value(1);
-                    copy_changeset(repo.clone(), sender.clone(), linknodes_store.clone(), ChangesetId::new(csid))
+                    let copy = copy_changeset_content(
+                        repo.clone(),
+                        sender.clone(),
+                        linknodes_store.clone(),
+                        flat_converter.clone(),
+                        progress.clone(),
+                        largefiles,
+                        ChangesetId::new(csid),
+                    );
+                    cpupool.spawn(copy)
                 }
-            }) // Stream<Future<()>>
-            .map(|copy| cpupool.spawn(copy))
-            .buffer_unordered(100);
+            }) // Stream<Future<BlobChangeset>>
+            .buffered(parallelism);
+
+        // Then each changeset is written once its parents have been, so that the blobstore
+        // never has a changeset without its ancestors. Only the writes that may still be in
+        // progress are kept for children to wait on.
+        let mut saves: HashMap<NodeHash, Shared<BoxFuture<(), Compat<Error>>>> = HashMap::new();
+        let saved = copied
+            .map({
+                let sender = self.sender.clone();
+                let progress = progress.clone();
+                move |bcs| {
+                    progress.copied.fetch_add(1, Ordering::Relaxed);
+                    if saves.len() > 2 * parallelism {
+                        saves.retain(|_, save| save.peek().is_none());
+                    }
 
-        let heads = self.repo
-            .get_heads()
+                    let parents: Vec<_> = bcs.parents()
+                        .into_iter()
+                        .filter_map(|parent| saves.get(&parent).cloned())
+                        .collect();
+                    let node = bcs.get_changeset_id().into_nodehash();
+                    let sender = sender.clone();
+                    let save = future::join_all(parents)
+                        .from_err()
+                        .and_then(move |_| sender.send(BlobstoreEntry::Changeset(bcs)))
+                        .map_err(move |err| {
+                            Error::from(err.context(format_err!("Can't save changeset {}", node)))
+                                .compat()
+                        })
+                        .boxify()
+                        .shared();
+                    saves.insert(node, save.clone());
+                    save.from_err()
+                }
+            })
+            .buffer_unordered(parallelism)
+            .for_each(|_| {
+                STATS::changesets_saved.add_value(1);
+                let saved = progress.saved.fetch_add(1, Ordering::Relaxed) + 1;
+                if saved % REPORT_INTERVAL == 0 {
+                    progress.report(logger);
+                }
+                Ok(())
+            });
+
+        core.run(saved)?;
+        progress.report(logger);
+
+        // Heads are only added once all the changesets are there.
+        let heads: BoxStream<NodeHash, mercurial::Error> = if import_heads {
+            self.repo.get_heads().boxify()
+        } else {
+            stream::empty().boxify()
+        };
+        let heads = heads
             .map_err(Error::from)
             .map_err(|err| err.context("Failed get heads").into())
             .map(|h| {
@@ -95,90 +180,166 @@ where
                     }
                 })
             })
-            .buffer_unordered(100);
-
-        let convert = changesets.select(heads).for_each(|_| Ok(()));
+            .buffer_unordered(100)
+            .for_each(|_| Ok(()));
 
-        core.run(convert)?;
+        core.run(heads)?;
 
         info!(logger, "parsed everything, waiting for io");
         Ok(())
     }
 }
 
-/// Copy a changeset and its manifest into the blobstore
+/// How far each stage of the import has got.
+struct Progress {
+    start: Instant,
+    /// Changesets whose files and manifests have all been copied.
+    copied: AtomicUsize,
+    files: AtomicUsize,
+    manifests: AtomicUsize,
+    /// Changesets that have been written to the blobstore.
+    saved: AtomicUsize,
+}
+
+impl Progress {
+    fn new() -> Self {
+        Progress {
+            start: Instant::now(),
+            copied: AtomicUsize::new(0),
+            files: AtomicUsize::new(0),
+            manifests: AtomicUsize::new(0),
+            saved: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count a file or tree manifest that has been written.
+    fn add_entry(&self, path: &RepoPath) {
+        match *path {
+            RepoPath::FilePath(_) => {
+                STATS::files.add_value(1);
+                self.files.fetch_add(1, Ordering::Relaxed);
+            }
+            RepoPath::RootPath | RepoPath::DirectoryPath(_) => {
+                STATS::manifests.add_value(1);
+                self.manifests.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn report(&self, logger: &Logger) {
+        let elapsed = self.start.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let rate = |count: &AtomicUsize| {
+            let count = count.load(Ordering::Relaxed);
+            (count, count as f64 / secs.max(1e-3))
+        };
+        let (copied, copied_rate) = rate(&self.copied);
+        let (files, files_rate) = rate(&self.files);
+        let (manifests, manifests_rate) = rate(&self.manifests);
+        let (saved, saved_rate) = rate(&self.saved);
+        info!(
+            logger,
+            "copied {} changesets ({:.1}/s) with {} files ({:.1}/s) and {} manifests ({:.1}/s); \
+             saved {} changesets ({:.1}/s)",
+            copied,
+            copied_rate,
+            files,
+            files_rate,
+            manifests,
+            manifests_rate,
+            saved,
+            saved_rate
+        );
+    }
+}
+
+/// Copy the manifests and files introduced by a changeset into the blobstore, and return the
+/// changeset, ready to be written once its parents are.
 ///
-/// The changeset and the manifest are straightforward - we just make literal copies of the
-/// blobs into the blobstore.
+/// The manifest is straightforward - we just make literal copies of the blobs into the
+/// blobstore.
 ///
 /// The files are more complex. For each manifest, we generate a stream of entries, then flatten
 /// the entry streams from all changesets into a single stream. Then each entry is filtered
 /// against a set of entries that have already been copied, and any remaining are actually copied.
-fn copy_changeset<L>(
+fn copy_changeset_content<L>(
     revlog_repo: RevlogRepo,
-    sender: SyncSender<BlobstoreEntry>,
+    sender: BlobstoreSender,
     linknodes_store: L,
+    flat_converter: Arc<FlatConverter>,
+    progress: Arc<Progress>,
+    largefiles: LargefilesMode,
     csid: ChangesetId,
-) -> impl Future<Item = (), Error = Error> + Send + 'static
+) -> impl Future<Item = BlobChangeset, Error = Error> + Send + 'static
 where
     Error: Send + 'static,
     L: Linknodes,
 {
-    let put = {
-        let sender = sender.clone();
-        let csid = csid;
-
-        revlog_repo
-            .get_changeset_by_changesetid(&csid)
-            .from_err()
-            .and_then(move |cs| {
-                let bcs = BlobChangeset::new_with_id(&csid, cs);
-                sender
-                    .send(BlobstoreEntry::Changeset(bcs))
-                    .map_err(Error::from)
-            })
-    };
-
     let nodeid = csid.clone().into_nodehash();
     let entryid = EntryId::new(nodeid);
-    let manifest = revlog_repo
+    let copy = revlog_repo
         .get_changeset_by_changesetid(&csid)
         .join(revlog_repo.get_changelog_revlog_entry_by_id(&entryid))
         .from_err()
-        .and_then(move |(cs, entry)| {
-            let mfid = *cs.manifestid();
-            let linkrev = entry.linkrev;
-            put_blobs(
-                revlog_repo,
-                sender,
-                linknodes_store,
-                mfid.clone().into_nodehash(),
-                linkrev,
-            )
+        .and_then({
+            let csid = csid.clone();
+            move |(cs, entry)| {
+                let mfid = *cs.manifestid();
+                let linkrev = entry.linkrev;
+                put_blobs(
+                    revlog_repo,
+                    sender,
+                    linknodes_store,
+                    flat_converter,
+                    progress,
+                    largefiles,
+                    mfid.clone().into_nodehash(),
+                    cs.files().to_vec(),
+                    linkrev,
+                ).map(move |()| BlobChangeset::new_with_id(&csid, cs))
+            }
         })
         .map_err(move |err| {
             err.context(format_err!("Can't copy manifest for cs {}", csid))
                 .into()
         });
-    _assert_sized(&put);
-    _assert_sized(&manifest);
+    _assert_sized(&copy);
 
-    put.join(manifest).map(|_| ())
+    copy
 }
 
 /// Copy manifest and filelog entries into the blob store.
 ///
-/// See the help for copy_changeset for a full description.
+/// See the help for copy_changeset_content for a full description. Repos with only flat manifests
+/// have them converted to tree manifests on the way.
 fn put_blobs<L>(
     revlog_repo: RevlogRepo,
-    sender: SyncSender<BlobstoreEntry>,
+    sender: BlobstoreSender,
     linknodes_store: L,
+    flat_converter: Arc<FlatConverter>,
+    progress: Arc<Progress>,
+    largefiles: LargefilesMode,
     mfid: NodeHash,
+    changed_files: Vec<MPath>,
     linkrev: RevIdx,
-) -> impl Future<Item = (), Error = Error> + Send + 'static
+) -> BoxFuture<(), Error>
 where
     L: Linknodes,
 {
+    if !revlog_repo.has_tree_manifests() {
+        return put_flat_blobs(
+            revlog_repo,
+            sender,
+            linknodes_store,
+            flat_converter,
+            progress,
+            largefiles,
+            mfid,
+            changed_files,
+            linkrev,
+        ).boxify();
+    }
+
     let cs_entry_fut = revlog_repo.get_changelog().get_entry(linkrev).into_future();
 
     revlog_repo
@@ -191,10 +352,14 @@ where
                 mfid,
                 blob.as_blob().clone(),
                 blob.parents().clone(),
-            );
+            ).map({
+                let progress = progress.clone();
+                move |()| progress.add_entry(&RepoPath::root())
+            });
 
             let linknode = cs_entry.nodeid;
-            let put_root_linknode = linknodes_store.add(RepoPath::root(), &mfid, &linknode);
+            let put_root_linknode =
+                add_linknode(&linknodes_store, RepoPath::root(), &mfid, &linknode);
 
             // Get the listing of entries and fetch each of those
             let files = RevlogManifest::new(revlog_repo.clone(), blob)
@@ -216,12 +381,20 @@ where
                         .flatten()
                         .for_each(move |(entry, repopath)| {
                             // All entries share the same linknode to the changelog.
-                            let linknode_future = linknodes_store.add(
-                                repopath,
+                            let linknode_future = add_linknode(
+                                &linknodes_store,
+                                repopath.clone(),
                                 &entry.get_hash().into_nodehash(),
                                 &linknode,
                             );
-                            let copy_future = manifest::copy_entry(entry, sender.clone());
+                            let progress = progress.clone();
+                            let copy_future = manifest::copy_entry(
+                                entry,
+                                &revlog_repo,
+                                largefiles,
+                                repopath.clone(),
+                                sender.clone(),
+                            ).map(move |()| progress.add_entry(&repopath));
                             copy_future.join(linknode_future).map(|_| ())
                         })
                 })
@@ -234,6 +407,295 @@ where
 
             putmf.join3(put_root_linknode, files).map(|_| ())
         })
+        .boxify()
+}
+
+/// Convert a flat manifest to tree manifests, and copy the new trees and the files introduced
+/// by the changeset at `linkrev`, which changed `changed_files`, into the blob store.
+fn put_flat_blobs<L>(
+    revlog_repo: RevlogRepo,
+    sender: BlobstoreSender,
+    linknodes_store: L,
+    flat_converter: Arc<FlatConverter>,
+    progress: Arc<Progress>,
+    largefiles: LargefilesMode,
+    mfid: NodeHash,
+    changed_files: Vec<MPath>,
+    linkrev: RevIdx,
+) -> impl Future<Item = (), Error = Error> + Send + 'static
+where
+    L: Linknodes,
+{
+    let cs_entry_fut = revlog_repo.get_changelog().get_entry(linkrev).into_future();
+
+    revlog_repo
+        .get_manifest_blob_by_nodeid(&mfid)
+        .join(cs_entry_fut)
+        .from_err()
+        .and_then(move |(blob, cs_entry)| -> Result<_> {
+            let content = blob.as_blob()
+                .as_slice()
+                .ok_or_else(|| format_err!("missing data for manifest {}", mfid))
+                .and_then(ManifestContent::parse)
+                .with_context(|_| format_err!("while parsing flat manifest {}", mfid))?;
+            let (_, trees) = flat_converter
+                .convert(&mfid, blob.parents(), &content)
+                .with_context(|_| format_err!("while converting flat manifest {}", mfid))?;
+            Ok((content, trees, cs_entry.nodeid))
+        })
+        .and_then(move |(content, trees, linknode)| {
+            let put_trees: Vec<_> = trees
+                .into_iter()
+                .map(|tree| {
+                    let path = if tree.path.is_empty() {
+                        RepoPath::root()
+                    } else {
+                        RepoPath::DirectoryPath(tree.path)
+                    };
+                    let linknode_future =
+                        add_linknode(&linknodes_store, path.clone(), &tree.node, &linknode);
+                    let progress = progress.clone();
+                    let put_future = manifest::put_entry(
+                        sender.clone(),
+                        tree.node,
+                        Blob::from(tree.text),
+                        tree.parents,
+                    ).map(move |()| progress.add_entry(&path));
+                    put_future.join(linknode_future).map(|_| ())
+                })
+                .collect();
+
+            let files = manifest::get_flat_file_stream(
+                revlog_repo.clone(),
+                content,
+                changed_files,
+                linkrev,
+            )
+                .for_each(move |(repopath, hash, node)| {
+                    let linknode_future =
+                        add_linknode(&linknodes_store, repopath.clone(), &hash, &linknode);
+                    let progress = progress.clone();
+                    let put_future = manifest::put_file(
+                        sender.clone(),
+                        &revlog_repo,
+                        largefiles,
+                        repopath.clone(),
+                        hash,
+                        node.as_blob().clone(),
+                        *node.parents(),
+                    ).map(move |()| progress.add_entry(&repopath));
+                    put_future.join(linknode_future).map(|_| ())
+                });
+
+            future::join_all(put_trees).join(files).map(|_| ())
+        })
+}
+
+/// Add a linknode, tolerating it being there already from an interrupted import.
+fn add_linknode<L: Linknodes>(
+    linknodes_store: &L,
+    path: RepoPath,
+    node: &NodeHash,
+    linknode: &NodeHash,
+) -> impl Future<Item = (), Error = Error> + Send + 'static {
+    linknodes_store
+        .add(path, node, linknode)
+        .or_else(|err| match err.downcast_ref::<LinknodeErrorKind>() {
+            Some(&LinknodeErrorKind::AlreadyExists { .. }) => future::ok(()),
+            _ => future::err(err),
+        })
 }
 
 fn _assert_sized<T: Sized>(_: &T) {}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashSet};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::thread;
+
+    use bytes::Bytes;
+    use futures::sync::mpsc;
+    use slog::Discard;
+    use tempdir::TempDir;
+
+    use linknodes::NoopLinknodes;
+    use memheads::MemHeads;
+    use mercurial::revlog::{Compression, RevlogWriter};
+
+    use super::*;
+    use BlobstoreWrite;
+
+    /// Builds a revlog repo with flat manifests, one commit at a time.
+    struct TestRepo {
+        dir: TempDir,
+        changelog: RevlogWriter,
+        manifests: RevlogWriter,
+        filelogs: BTreeMap<String, RevlogWriter>,
+        // The changeset node, the files and the manifest node of each commit
+        commits: Vec<(NodeHash, BTreeMap<String, NodeHash>, NodeHash)>,
+    }
+
+    impl TestRepo {
+        fn new() -> Self {
+            TestRepo {
+                dir: TempDir::new("convert").expect("tempdir failed"),
+                changelog: RevlogWriter::new(Compression::None),
+                manifests: RevlogWriter::new(Compression::None),
+                filelogs: BTreeMap::new(),
+                commits: Vec::new(),
+            }
+        }
+
+        /// Commit `changes` on top of the commits at `parents`. A merge has the files of its
+        /// first parent, plus those that are only in the second.
+        fn commit(&mut self, parents: &[usize], changes: &[(&str, &str)]) -> usize {
+            let linkrev = RevIdx::from(self.commits.len());
+            let mut files = BTreeMap::new();
+            for parent in parents.iter().rev() {
+                files.extend(self.commits[*parent].1.clone());
+            }
+            for &(path, content) in changes {
+                let p1 = files.get(path).cloned();
+                let (_, node) = self.filelogs
+                    .entry(path.to_string())
+                    .or_insert_with(|| RevlogWriter::new(Compression::None))
+                    .add_revision(content.as_bytes(), p1.as_ref(), None, linkrev)
+                    .expect("add file failed");
+                files.insert(path.to_string(), node);
+            }
+
+            let cs_parents: Vec<_> = parents.iter().map(|p| self.commits[*p].0).collect();
+            let mf_parents: Vec<_> = parents.iter().map(|p| self.commits[*p].2).collect();
+            let (cs_p1, cs_p2) = (cs_parents.get(0), cs_parents.get(1));
+            let (mf_p1, mf_p2) = (mf_parents.get(0), mf_parents.get(1));
+
+            let manifest: String = files
+                .iter()
+                .map(|(path, node)| format!("{}\0{}\n", path, node))
+                .collect();
+            let (_, mfid) = self.manifests
+                .add_revision(manifest.as_bytes(), mf_p1, mf_p2, linkrev)
+                .expect("add manifest failed");
+
+            let mut changed: Vec<_> = changes.iter().map(|&(path, _)| path).collect();
+            changed.sort();
+            let mut text = format!("{}\ntest\n0 0\n", mfid);
+            for path in changed {
+                text.push_str(path);
+                text.push('\n');
+            }
+            text.push_str(&format!("\ncommit {}", linkrev.as_u32()));
+            let (_, csid) = self.changelog
+                .add_revision(text.as_bytes(), cs_p1, cs_p2, linkrev)
+                .expect("add changeset failed");
+
+            self.commits.push((csid, files, mfid));
+            self.commits.len() - 1
+        }
+
+        fn save(self) -> (TempDir, Vec<NodeHash>) {
+            let dothg = self.dir.path().join(".hg");
+            let store = dothg.join("store");
+            fs::create_dir_all(&store).expect("mkdir failed");
+            File::create(dothg.join("requires"))
+                .and_then(|mut requires| requires.write_all(b"revlogv1\nstore\n"))
+                .expect("writing requires failed");
+            self.changelog
+                .save(store.join("00changelog.i"))
+                .expect("saving changelog failed");
+            self.manifests
+                .save(store.join("00manifest.i"))
+                .expect("saving manifests failed");
+            for (path, filelog) in self.filelogs {
+                let path = store.join("data").join(format!("{}.i", path));
+                fs::create_dir_all(path.parent().unwrap()).expect("mkdir failed");
+                filelog.save(path).expect("saving filelog failed");
+            }
+            let changesets = self.commits.into_iter().map(|(csid, _, _)| csid).collect();
+            (self.dir, changesets)
+        }
+    }
+
+    /// Import the repo with the given parallelism. Returns the changesets in the order they
+    /// were written, and all the other blobs.
+    fn import(
+        repo: &RevlogRepo,
+        parallelism: usize,
+    ) -> (Vec<BlobChangeset>, BTreeMap<String, Bytes>) {
+        let (sender, recv) = mpsc::channel::<BlobstoreWrite>(10);
+        // Writes are acknowledged in the order they arrive, like the real IO thread does when
+        // the blobstore is quick.
+        let iothread = thread::spawn(move || {
+            let mut changesets = Vec::new();
+            let mut blobs = BTreeMap::new();
+            for write in recv.wait() {
+                let (entry, done) = write.expect("channel failed");
+                match entry {
+                    BlobstoreEntry::Changeset(bcs) => changesets.push(bcs),
+                    BlobstoreEntry::ManifestEntry((key, value)) => {
+                        blobs.insert(key, value);
+                    }
+                }
+                let _ = done.send(Ok(()));
+            }
+            (changesets, blobs)
+        });
+
+        let context = ConvertContext {
+            repo: repo.clone(),
+            sender: BlobstoreSender(sender),
+            headstore: MemHeads::new(),
+            core: Core::new().expect("core failed"),
+            cpupool: Arc::new(CpuPool::new(4)),
+            logger: Logger::root(Discard, o!()),
+            skip: None,
+            commits_limit: None,
+            changesets: None,
+            parallelism,
+            largefiles: LargefilesMode::Standin,
+        };
+        context
+            .convert(NoopLinknodes::new())
+            .expect("convert failed");
+        iothread.join().expect("IO thread panicked")
+    }
+
+    #[test]
+    fn convert_parallel() {
+        let mut repo = TestRepo::new();
+        let root = repo.commit(&[], &[("a", "a1\n")]);
+        let left = repo.commit(&[root], &[("b", "b1\n")]);
+        let right = repo.commit(&[root], &[("d/c", "c1\n")]);
+        let merge = repo.commit(&[left, right], &[]);
+        let after_merge = repo.commit(&[merge], &[("a", "a2\n"), ("d/e", "e1\n")]);
+        let branch = repo.commit(&[left], &[("f", "f1\n"), ("b", "b2\n")]);
+        repo.commit(&[after_merge, branch], &[("d/c", "c2\n")]);
+        let (dir, expected) = repo.save();
+        let repo = RevlogRepo::open(dir.path().join(".hg")).expect("open failed");
+
+        // Importing one changeset at a time is the sequential import.
+        let (sequential, sequential_blobs) = import(&repo, 1);
+        let sequential: Vec<_> = sequential
+            .iter()
+            .map(|bcs| bcs.get_changeset_id().into_nodehash())
+            .collect();
+        assert_eq!(sequential, expected);
+
+        for &parallelism in &[2, 3, 8] {
+            let (changesets, blobs) = import(&repo, parallelism);
+            assert_eq!(blobs, sequential_blobs);
+
+            // Every changeset is written once, after its parents.
+            let mut written = HashSet::new();
+            for bcs in &changesets {
+                for parent in bcs.parents() {
+                    assert!(written.contains(&parent), "parent {} written late", parent);
+                }
+                assert!(written.insert(bcs.get_changeset_id().into_nodehash()));
+            }
+            assert_eq!(written, expected.iter().cloned().collect());
+        }
+    }
+}
@@ -24,8 +24,11 @@ extern crate tokio_core;
 
 extern crate blobrepo;
 extern crate blobstore;
+extern crate bookmarks;
 extern crate changesets;
+extern crate compressedblob;
 extern crate fileblob;
+extern crate filebookmarks;
 extern crate fileheads;
 extern crate filekv;
 extern crate filelinknodes;
@@ -39,22 +42,35 @@ extern crate mercurial_types;
 extern crate rocksblob;
 extern crate rocksdb;
 extern crate services;
+extern crate sqlblob;
+extern crate sqlbookmarks;
+extern crate sqllinknodes;
 #[macro_use]
 extern crate stats;
+extern crate storage_types;
+#[cfg(test)]
+extern crate tempdir;
 
 mod convert;
 mod manifest;
 
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::Arc;
-use std::sync::mpsc::sync_channel;
 use std::thread;
 
 use bytes::Bytes;
 use changesets::{ChangesetInsert, Changesets, SqliteChangesets};
 use clap::{App, Arg, ArgMatches};
-use failure::{Error, Result, ResultExt, SlogKVError};
-use futures::{stream, Future, IntoFuture, Stream};
+use compressedblob::CompressedBlobstore;
+use failure::{Compat, Error, Result, ResultExt, SlogKVError};
+use futures::{future, stream, Future, IntoFuture, Sink, Stream};
+use futures::future::Shared;
+use futures::sync::{mpsc, oneshot};
 use futures_cpupool::CpuPool;
 use slog::{Drain, Level, Logger};
 use slog_glog_fmt::default_drain as glog_drain;
@@ -63,20 +79,29 @@ use tokio_core::reactor::{Core, Remote};
 
 use blobrepo::BlobChangeset;
 use blobstore::Blobstore;
+use bookmarks::Bookmarks;
 use fileblob::Fileblob;
+use filebookmarks::FileBookmarks;
 use filelinknodes::FileLinknodes;
-use futures_ext::{BoxFuture, FutureExt};
-use linknodes::NoopLinknodes;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use linknodes::{Linknodes, NoopLinknodes};
 use manifoldblob::ManifoldBlob;
 use mercurial::{RevlogRepo, RevlogRepoOptions};
-use mercurial_types::{Changeset, ChangesetId, RepositoryId};
+use mercurial_types::{Changeset, ChangesetId, NodeHash, RepositoryId};
 use rocksblob::Rocksblob;
+use sqlblob::Sqlblob;
+use sqlbookmarks::{BookmarkUpdate, SqliteBookmarks};
+use sqllinknodes::SqliteLinknodes;
+use storage_types::Version;
 
 const DEFAULT_MANIFOLD_BUCKET: &str = "mononoke_prod";
 
 define_stats! {
     prefix = "blobimport";
     changesets: timeseries(RATE, SUM),
+    changesets_saved: timeseries(RATE, SUM),
+    files: timeseries(RATE, SUM),
+    manifests: timeseries(RATE, SUM),
     heads: timeseries(RATE, SUM),
     duplicates: timeseries(RATE, SUM),
     failures: timeseries(RATE, SUM),
@@ -87,6 +112,7 @@ define_stats! {
 enum BlobstoreType {
     Files,
     Rocksdb,
+    Sqlite,
     Manifold(String),
 }
 
@@ -102,18 +128,61 @@ pub(crate) enum BlobstoreEntry {
     Changeset(BlobChangeset),
 }
 
+/// What to do with the content of largefiles, which is kept outside of the revlogs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum LargefilesMode {
+    /// Import only the standins.
+    Standin,
+    /// Store each largefile's content as the content of its standin's filenode, so that clients
+    /// without the largefiles extension get the real file. The filenode then no longer hashes
+    /// to its content.
+    Inline,
+    /// Store each largefile's content as a separate blob, keyed by the hash in its standin.
+    External,
+}
+
+type BlobstoreWrite = (BlobstoreEntry, oneshot::Sender<Result<()>>);
+
+/// Sends entries to the IO thread. Each send resolves once the entry has been written, so that
+/// a changeset is only written after everything it refers to.
+///
+/// The channel is asynchronous on both ends, so that waiting for a write never blocks the core
+/// that the IO thread (or Manifold, which runs on the IO thread's core) writes it on.
+#[derive(Clone)]
+pub(crate) struct BlobstoreSender(mpsc::Sender<BlobstoreWrite>);
+
+impl BlobstoreSender {
+    pub fn send(&self, entry: BlobstoreEntry) -> BoxFuture<(), Error> {
+        let (done, written) = oneshot::channel();
+        self.0
+            .clone()
+            .send((entry, done))
+            .map_err(|_| failure::err_msg("IO thread has stopped"))
+            .and_then(move |_| {
+                written
+                    .map_err(|_| failure::err_msg("IO thread dropped a write"))
+                    .and_then(|res| res)
+            })
+            .boxify()
+    }
+}
+
 fn run_blobimport<In, Out>(
     input: In,
     output: Out,
     blobtype: BlobstoreType,
+    repo_id: RepositoryId,
     write_linknodes: bool,
     logger: &Logger,
     postpone_compaction: bool,
     channel_size: usize,
+    parallelism: usize,
     skip: Option<u64>,
     commits_limit: Option<u64>,
+    incremental: bool,
     max_blob_size: Option<usize>,
     inmemory_logs_capacity: Option<usize>,
+    largefiles: LargefilesMode,
 ) -> Result<()>
 where
     In: Into<PathBuf>,
@@ -123,24 +192,37 @@ where
     let core = Core::new()?;
     let cpupool = Arc::new(CpuPool::new_num_cpus());
 
+    if incremental && skip.is_some() {
+        bail_msg!("--skip cannot be used with --incremental");
+    }
+
     info!(logger, "Opening headstore: {:?}", output);
     let headstore = open_headstore(output.clone(), &cpupool)?;
 
+    // Bookmarks are imported at the end, once the changesets they point to have been.
+    info!(logger, "Opening bookmarks store: {:?}", output);
+    let bookmarks_store = open_bookmarks_store(output.clone().into(), repo_id)?;
+
+    info!(logger, "Opening changesets store: {:?}", output);
+    let changesets_store = open_changesets_store(output.clone().into())?;
+
     if let BlobstoreType::Manifold(ref bucket) = blobtype {
         info!(logger, "Using ManifoldBlob with bucket: {:?}", bucket);
     } else {
         info!(logger, "Opening blobstore: {:?}", output);
     }
 
-    let (sender, recv) = sync_channel::<BlobstoreEntry>(channel_size);
+    // SQLite repos keep their linknodes in SQLite too.
+    let sql_linknodes = blobtype == BlobstoreType::Sqlite;
+
+    let (sender, recv) = mpsc::channel::<BlobstoreWrite>(channel_size);
     // Separate thread that does all blobstore operations. Other worker threads send parsed revlog
-    // data to this thread.
+    // data to this thread, and are told when it has been written.
     let iothread = thread::Builder::new()
         .name("iothread".to_owned())
         .spawn({
             let output = output.clone();
             move || {
-                let receiverstream = stream::iter_ok::<_, ()>(recv);
                 let mut core = Core::new().expect("cannot create core in iothread");
                 let blobstore = open_blobstore(
                     output,
@@ -149,54 +231,82 @@ where
                     postpone_compaction,
                     max_blob_size,
                 )?;
-                // Filter only manifest entries, because changeset entries should be unique
-                let mut inserted_manifest_entries = std::collections::HashSet::new();
-                let stream = receiverstream
-                    .map(move |sender_helper| match sender_helper {
-                        BlobstoreEntry::Changeset(bcs) => {
-                            bcs.save(blobstore.clone()).from_err().boxify()
-                        }
-                        BlobstoreEntry::ManifestEntry((key, value)) => {
-                            if inserted_manifest_entries.insert(key.clone()) {
-                                blobstore.put(key.clone(), value).from_err().boxify()
+                // Filter only manifest entries, because changeset entries should be unique. A
+                // duplicate is only reported as written once the first copy is, and the write
+                // is forgotten after that to save memory.
+                let manifest_writes = Rc::new(RefCell::new(HashMap::new()));
+                let stream = recv
+                    .map(move |(entry, done)| {
+                        let write: Box<Future<Item = (), Error = Error>> = match entry {
+                            BlobstoreEntry::Changeset(bcs) => {
+                                Box::new(bcs.save(blobstore.clone()).from_err())
+                            }
+                            BlobstoreEntry::ManifestEntry((key, value)) => {
+                                put_manifest_entry(&blobstore, &manifest_writes, key, value)
+                            }
+                        };
+                        write.then(move |res| {
+                            if res.is_err() {
+                                STATS::failures.add_value(1);
                             } else {
-                                STATS::duplicates.add_value(1);
-                                Ok(()).into_future().boxify()
+                                STATS::successes.add_value(1);
                             }
-                        }
+                            // The sender has gone away if its import already failed.
+                            let _ = done.send(res);
+                            Ok::<_, ()>(())
+                        })
                     })
-                    .map_err(|_| failure::err_msg("failure happened").into())
-                    .buffer_unordered(channel_size)
-                    .then(move |res: Result<()>| {
-                        if res.is_err() {
-                            STATS::failures.add_value(1);
-                        } else {
-                            STATS::successes.add_value(1);
-                        }
-                        res
-                    });
-                core.run(stream.for_each(|_| Ok(())))
+                    .buffer_unordered(channel_size);
+                core.run(stream.for_each(|()| Ok(())))
+                    .map_err(|()| failure::err_msg("failure happened"))
             }
         })
         .expect("cannot start iothread");
 
     let repo = open_repo(&input, inmemory_logs_capacity)?;
+    if repo.has_largefiles() && largefiles == LargefilesMode::Standin {
+        warn!(
+            logger,
+            "importing only the standins of largefiles; see --largefiles to import content"
+        );
+    }
+
+    // Changesets are only recorded in the changesets store once all their blobs are written,
+    // so anything missing from it still needs to be imported (possibly again, after a crash).
+    let missing = if incremental {
+        info!(logger, "Finding changesets missing from the changesets store");
+        let mut missing = find_missing_changesets(&repo, repo_id, changesets_store.clone())?;
+        let complete = match commits_limit {
+            Some(limit) if (limit as usize) < missing.len() => {
+                missing.truncate(limit as usize);
+                false
+            }
+            _ => true,
+        };
+        info!(logger, "{} changesets to import", missing.len());
+        Some((missing, complete))
+    } else {
+        None
+    };
 
     info!(logger, "Converting: {}", input.display());
     let convert_context = convert::ConvertContext {
         repo: repo.clone(),
-        sender,
+        sender: BlobstoreSender(sender),
         headstore,
         core,
         cpupool: cpupool.clone(),
         logger: logger.clone(),
         skip: skip,
         commits_limit: commits_limit,
+        changesets: missing.as_ref().map(|&(ref missing, _)| missing.clone()),
+        parallelism,
+        largefiles,
     };
     let res = if write_linknodes {
         info!(logger, "Opening linknodes store: {:?}", output);
         let output = output.clone().into();
-        let linknodes_store = open_linknodes_store(&output, &cpupool)?;
+        let linknodes_store = open_linknodes_store(&output, &cpupool, repo_id, sql_linknodes)?;
         convert_context.convert(linknodes_store)
     } else {
         info!(logger, "--linknodes not specified, not writing linknodes");
@@ -205,43 +315,217 @@ where
     iothread.join().expect("failed to join io thread")?;
     res?;
 
-    if !skip.is_none() && !commits_limit.is_none() {
+    // Heads and bookmarks may only point to changesets that have been fully imported.
+    let imported_all = if let Some((missing, complete)) = missing {
+        info!(logger, "recording {} imported changesets", missing.len());
+        let missing = stream::iter_ok(missing).boxify();
+        record_changesets(&repo, repo_id, changesets_store, missing)?;
+        if complete {
+            info!(logger, "updating heads");
+            sync_heads(&repo, open_headstore(output, &cpupool)?)?;
+        } else {
+            // Some heads may not have been imported yet.
+            warn!(logger, "not updating heads because --commits-limit is set");
+        }
+        complete
+    } else if skip.is_some() || commits_limit.is_some() {
         warn!(
             logger,
             "skipping filling up changesets store because --skip or --commits-limit is set"
         );
+        false
     } else {
         warn!(logger, "filling up changesets changesets store");
-        let changesets = open_changesets_store(output.into())?;
-        let mut core = Core::new()?;
-        let fut = repo.changesets()
-            .and_then(|node| {
-                let node = ChangesetId::new(node);
-                repo.get_changeset_by_changesetid(&node)
-                    .map(move |cs| (cs, node))
-            })
-            .for_each(|(cs, node)| {
-                let parents = cs.parents()
-                    .into_iter()
-                    .map(|p| ChangesetId::new(p))
-                    .collect();
-                let insert = ChangesetInsert {
-                    repo_id: RepositoryId::new(0), // TODO(stash): real repo id
-                    cs_id: node,
-                    parents,
-                };
-                changesets.add(&insert)
-            });
-        core.run(fut)?;
+        let changesets = repo.changesets().map_err(Error::from).boxify();
+        record_changesets(&repo, repo_id, changesets_store, changesets)?;
+        true
+    };
+
+    if imported_all {
+        let count = import_bookmarks(&repo, &bookmarks_store)?;
+        info!(logger, "imported {} bookmark changes", count);
+    } else {
+        warn!(logger, "not importing bookmarks because not all changesets were imported");
+    }
+    Ok(())
+}
+
+/// Write a manifest entry, unless the same key has been written or is being written already.
+/// `writes` maps every key seen so far to its write, or to `None` once it has been written.
+fn put_manifest_entry(
+    blobstore: &BBlobstore,
+    writes: &Rc<RefCell<HashMap<String, Option<Shared<BoxFuture<(), Compat<Error>>>>>>>,
+    key: String,
+    value: Bytes,
+) -> Box<Future<Item = (), Error = Error>> {
+    let write = match writes.borrow_mut().entry(key.clone()) {
+        Entry::Occupied(entry) => {
+            STATS::duplicates.add_value(1);
+            match *entry.get() {
+                Some(ref write) => write.clone(),
+                None => return Box::new(future::ok(())),
+            }
+        }
+        Entry::Vacant(entry) => {
+            let write = blobstore
+                .put(key.clone(), value)
+                .map_err(Error::compat)
+                .boxify()
+                .shared();
+            entry.insert(Some(write.clone()));
+            write
+        }
+    };
+
+    let writes = writes.clone();
+    Box::new(write.map(|_| ()).from_err().map(move |()| {
+        writes.borrow_mut().insert(key, None);
+    }))
+}
+
+/// Find the changesets in the revlog repo that are not in the changesets store. They are
+/// returned in revlog order, so parents always come before their children.
+fn find_missing_changesets(
+    repo: &RevlogRepo,
+    repo_id: RepositoryId,
+    changesets: Arc<Changesets>,
+) -> Result<Vec<NodeHash>> {
+    let mut core = Core::new()?;
+    let fut = repo.changesets()
+        .map_err(Error::from)
+        .map(move |node| {
+            changesets
+                .get(repo_id, ChangesetId::new(node))
+                .map(move |entry| (node, entry.is_none()))
+        })
+        .buffered(100)
+        .filter_map(|(node, missing)| if missing { Some(node) } else { None })
+        .collect();
+    core.run(fut)
+}
+
+/// Add changesets to the changesets store. They are added one at a time and in order, so the
+/// store never contains a changeset without its parents.
+fn record_changesets(
+    repo: &RevlogRepo,
+    repo_id: RepositoryId,
+    changesets_store: Arc<Changesets>,
+    changesets: BoxStream<NodeHash, Error>,
+) -> Result<()> {
+    let mut core = Core::new()?;
+    let fut = changesets
+        .and_then(|node| {
+            let node = ChangesetId::new(node);
+            repo.get_changeset_by_changesetid(&node)
+                .map(move |cs| (cs, node))
+                .from_err()
+        })
+        .for_each(|(cs, node)| {
+            let parents = cs.parents()
+                .into_iter()
+                .map(|p| ChangesetId::new(p))
+                .collect();
+            let insert = ChangesetInsert {
+                repo_id,
+                cs_id: node,
+                parents,
+            };
+            changesets_store.add(&insert)
+        });
+    core.run(fut)
+}
+
+/// Make the headstore match the heads of the revlog repo. New heads are added before stale ones
+/// are removed, so the headstore is never empty.
+fn sync_heads(repo: &RevlogRepo, headstore: Box<heads::Heads>) -> Result<()> {
+    let mut core = Core::new()?;
+    let revlog_heads: HashSet<_> = core.run(repo.get_heads().map_err(Error::from).collect())?
+        .into_iter()
+        .collect();
+    let stored_heads: HashSet<_> = core.run(headstore.heads().collect())?
+        .into_iter()
+        .collect();
+
+    for head in revlog_heads.difference(&stored_heads) {
+        STATS::heads.add_value(1);
+        core.run(headstore.add(head))?;
+    }
+    for head in stored_heads.difference(&revlog_heads) {
+        core.run(headstore.remove(head))?;
     }
     Ok(())
 }
 
+/// Make the bookmarks store match the revlog repo's bookmarks, moving all of them in a single
+/// atomic update. Returns the number of bookmarks that were created, moved or deleted.
+fn import_bookmarks(repo: &RevlogRepo, bookmarks: &SqliteBookmarks) -> Result<usize> {
+    let mut core = Core::new()?;
+    let stock = repo.bookmarks()?;
+    let stock_values = stock
+        .keys()
+        .and_then(|name| stock.get(&name).map(move |value| (name, value)))
+        .filter_map(|(name, value)| value.map(|(cs_id, _)| (name, cs_id)))
+        .collect();
+    let stock_values: HashMap<_, _> = core.run(stock_values)?.into_iter().collect();
+
+    let mut updates = vec![];
+    let current_names = core.run(bookmarks.keys().collect())?;
+    for name in current_names {
+        if !stock_values.contains_key(&name) {
+            if let Some((_, version)) = core.run(bookmarks.get(&name))? {
+                updates.push(BookmarkUpdate::delete(name, version));
+            }
+        }
+    }
+    for (name, cs_id) in stock_values {
+        match core.run(bookmarks.get(&name))? {
+            Some((ref current, _)) if *current == cs_id => {}
+            Some((_, version)) => updates.push(BookmarkUpdate::set(name, cs_id, version)),
+            None => updates.push(BookmarkUpdate::set(name, cs_id, Version::absent())),
+        }
+    }
+
+    if updates.is_empty() {
+        return Ok(0);
+    }
+    match core.run(bookmarks.set_many(&updates))? {
+        Some(_) => Ok(updates.len()),
+        None => bail_msg!("bookmarks were modified during the import, please rerun it"),
+    }
+}
+
+// SQLite stores are created by the first import, and reopened by incremental ones.
+
 fn open_changesets_store(mut output: PathBuf) -> Result<Arc<Changesets>> {
     output.push("changesets");
-    Ok(Arc::new(SqliteChangesets::create(
-        output.to_string_lossy(),
-    )?))
+    let changesets = if output.exists() {
+        SqliteChangesets::open(output.to_string_lossy())?
+    } else {
+        SqliteChangesets::create(output.to_string_lossy())?
+    };
+    Ok(Arc::new(changesets))
+}
+
+fn open_bookmarks_store(mut output: PathBuf, repo_id: RepositoryId) -> Result<SqliteBookmarks> {
+    output.push("bookmarks");
+    SqliteBookmarks::open_or_create(output.to_string_lossy(), repo_id)
+}
+
+/// Copy the bookmarks of a repo from before SQLite bookmarks out of `books/` and into its
+/// SQLite bookmarks, then rename the directory so that they aren't copied again. Nothing else
+/// may use the repo while this runs.
+fn migrate_file_bookmarks(output: PathBuf, repo_id: RepositoryId, logger: &Logger) -> Result<()> {
+    let file_bookmarks_path = output.join("books");
+    if !file_bookmarks_path.is_dir() {
+        bail_msg!("{} has no bookmarks to migrate", file_bookmarks_path.display());
+    }
+
+    let bookmarks_store = open_bookmarks_store(output.clone(), repo_id)?;
+    let file_bookmarks = FileBookmarks::open(&file_bookmarks_path)?;
+    let count = bookmarks_store.import_from(&file_bookmarks)?;
+    fs::rename(&file_bookmarks_path, output.join("books.migrated"))?;
+    info!(logger, "migrated {} bookmarks", count);
+    Ok(())
 }
 
 fn open_repo<P: Into<PathBuf>>(
@@ -275,10 +559,27 @@ fn open_headstore<P: Into<PathBuf>>(path: P, pool: &Arc<CpuPool>) -> Result<Box<
     Ok(Box::new(headstore))
 }
 
-fn open_linknodes_store<P: Into<PathBuf>>(path: P, pool: &Arc<CpuPool>) -> Result<FileLinknodes> {
+fn open_linknodes_store<P: Into<PathBuf>>(
+    path: P,
+    pool: &Arc<CpuPool>,
+    repo_id: RepositoryId,
+    sql_linknodes: bool,
+) -> Result<Arc<Linknodes>> {
     let mut linknodes_path = path.into();
     linknodes_path.push("linknodes");
-    let linknodes_store = FileLinknodes::create_with_pool(linknodes_path, pool.clone())?;
+    let linknodes_store: Arc<Linknodes> = if sql_linknodes {
+        let path = linknodes_path.to_string_lossy();
+        if linknodes_path.exists() {
+            Arc::new(SqliteLinknodes::open(path, repo_id)?)
+        } else {
+            Arc::new(SqliteLinknodes::create(path, repo_id)?)
+        }
+    } else {
+        Arc::new(FileLinknodes::create_with_pool(
+            linknodes_path,
+            pool.clone(),
+        )?)
+    };
     Ok(linknodes_store)
 }
 
@@ -293,9 +594,10 @@ fn open_blobstore<P: Into<PathBuf>>(
         BlobstoreType::Files => {
             let mut output = output.into();
             output.push("blobs");
-            Arc::new(Fileblob::create(output)
+            let fileblob = Fileblob::create(output)
                 .map_err(Error::from)
-                .context("Failed to open file blob store")?)
+                .context("Failed to open file blob store")?;
+            Arc::new(CompressedBlobstore::new(fileblob))
         }
         BlobstoreType::Rocksdb => {
             let mut output = output.into();
@@ -303,9 +605,24 @@ fn open_blobstore<P: Into<PathBuf>>(
             let options = rocksdb::Options::new()
                 .create_if_missing(true)
                 .disable_auto_compaction(postpone_compaction);
-            Arc::new(Rocksblob::open_with_options(output, options)
+            // Rocksblob already zstd-compresses its tables, so don't compress again
+            let rocksblob = Rocksblob::open_with_options(output, options)
+                .map_err(Error::from)
+                .context("Failed to open rocksdb blob store")?;
+            Arc::new(rocksblob)
+        }
+        BlobstoreType::Sqlite => {
+            let mut output = output.into();
+            output.push("blobs");
+            let sqlblob = if output.exists() {
+                Sqlblob::open(output.to_string_lossy())
+            } else {
+                Sqlblob::create(output.to_string_lossy())
+            };
+            let sqlblob = sqlblob
                 .map_err(Error::from)
-                .context("Failed to open rocksdb blob store")?)
+                .context("Failed to open sqlite blob store")?;
+            Arc::new(CompressedBlobstore::new(sqlblob))
         }
         BlobstoreType::Manifold(bucket) => {
             let mb: ManifoldBlob = ManifoldBlob::new_may_panic(bucket, remote);
@@ -356,7 +673,7 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
         .about("make blobs")
         .args_from_usage(
             r#"
-            <INPUT>                  'input revlog repo'
+            [INPUT]                  'input revlog repo'
             [OUTPUT]                 'output blobstore RepoCtx'
 
             -p, --port [PORT]        'if provided the thrift server will start on this port'
@@ -365,11 +682,15 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
 
             -d, --debug              'print debug level output'
             --linknodes              'also generate linknodes'
-            --channel-size [SIZE]    'channel size between worker and io threads. Default: 1000'
+            --channel-size [SIZE]    'max blobstore writes in flight. Default: 1000'
+            --parallelism [COUNT]    'number of changesets to copy at once. Default: 100'
             --skip [SKIP]            'skips commits from the beginning'
             --commits-limit [LIMIT]  'import only LIMIT first commits from revlog repo'
+            --incremental            'only import commits missing from the changesets store'
+            --repo-id [REPO_ID]      'id of the repo in the SQL stores. Default: 0'
             --max-blob-size [LIMIT]  'max size of the blob to be inserted'
             --inmemory-logs-capacity [CAPACITY]  'max number of filelogs and treelogs in memory'
+            --migrate-file-bookmarks 'only move the bookmarks in OUTPUT/books into SQLite'
         "#,
         )
         .arg(
@@ -377,7 +698,7 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
                 .long("blobstore")
                 .short("B")
                 .takes_value(true)
-                .possible_values(&["files", "rocksdb", "manifold"])
+                .possible_values(&["files", "rocksdb", "sqlite", "manifold"])
                 .required(true)
                 .help("blobstore type"),
         )
@@ -387,6 +708,18 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
                 .takes_value(true)
                 .help("bucket to use for manifold blobstore"),
         )
+        .arg(
+            Arg::with_name("largefiles")
+                .long("largefiles")
+                .takes_value(true)
+                .possible_values(&["standin", "inline", "external"])
+                .default_value("standin")
+                .help(
+                    "how to import largefiles from the repo's .hg/largefiles: only their \
+                     standins, their content in place of the standins, or also their content as \
+                     separate blobs",
+                ),
+        )
         .arg(
             Arg::with_name("in-memory-logs-capacity")
                 .long("in-memory-logs-capacity")
@@ -451,7 +784,25 @@ fn main() {
         start_thrift_service(&root_log, &matches)?;
         start_stats()?;
 
-        let input = matches.value_of("INPUT").unwrap();
+        let repo_id = matches
+            .value_of("repo-id")
+            .map(|id| id.parse().expect("repo-id must be an integer"))
+            .unwrap_or(0);
+
+        if matches.is_present("migrate-file-bookmarks") {
+            // The output is the only argument then, so it is the first one.
+            let output = matches
+                .value_of("OUTPUT")
+                .or(matches.value_of("INPUT"))
+                .expect("output must be specified");
+            return migrate_file_bookmarks(
+                PathBuf::from(output),
+                RepositoryId::new(repo_id),
+                root_log,
+            );
+        }
+
+        let input = matches.value_of("INPUT").expect("input must be specified");
         let output = matches.value_of("OUTPUT");
         let bucket = matches
             .value_of("bucket")
@@ -460,6 +811,7 @@ fn main() {
         let blobtype = match matches.value_of("blobstore").unwrap() {
             "files" => BlobstoreType::Files,
             "rocksdb" => BlobstoreType::Rocksdb,
+            "sqlite" => BlobstoreType::Sqlite,
             "manifold" => BlobstoreType::Manifold(bucket.to_string()),
             bad => panic!("unexpected blobstore type {}", bad),
         };
@@ -471,16 +823,33 @@ fn main() {
             .map(|size| size.parse().expect("channel-size must be positive integer"))
             .unwrap_or(1000);
 
+        let parallelism: usize = matches
+            .value_of("parallelism")
+            .map(|count| count.parse().expect("parallelism must be positive integer"))
+            .unwrap_or(100);
+        if parallelism == 0 {
+            bail_msg!("--parallelism must be positive");
+        }
+
         let write_linknodes = matches.is_present("linknodes");
 
+        let largefiles = match matches.value_of("largefiles").unwrap() {
+            "standin" => LargefilesMode::Standin,
+            "inline" => LargefilesMode::Inline,
+            "external" => LargefilesMode::External,
+            bad => panic!("unexpected largefiles mode {}", bad),
+        };
+
         run_blobimport(
             input,
             output.expect("output must be specified").to_string(),
             blobtype,
+            RepositoryId::new(repo_id),
             write_linknodes,
             &root_log,
             postpone_compaction,
             channel_size,
+            parallelism,
             matches
                 .value_of("skip")
                 .map(|size| size.parse().expect("skip must be positive integer")),
@@ -488,6 +857,7 @@ fn main() {
                 size.parse()
                     .expect("commits-limit must be positive integer")
             }),
+            matches.is_present("incremental"),
             matches.value_of("max-blob-size").map(|size| {
                 size.parse()
                     .expect("max-blob-size must be positive integer")
@@ -497,6 +867,7 @@ fn main() {
                     .parse()
                     .expect("inmemory_logs_capacity must be positive integer")
             }),
+            largefiles,
         )?;
 
         if matches.value_of("blobstore").unwrap() == "rocksdb" && postpone_compaction {
@@ -4,23 +4,24 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::sync::mpsc::SyncSender;
-
 use bincode;
 use bytes::Bytes;
 use failure::{self, Error};
-use futures::{self, Future, IntoFuture, Stream};
+use futures::{self, future, Future, IntoFuture, Stream};
 
-use blobrepo::RawNodeBlob;
-use futures_ext::StreamExt;
+use blobrepo::{content_to_blobs, RawNodeBlob};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use mercurial::RevlogRepo;
+use mercurial::largefiles::{is_standin, parse_standin};
+use mercurial::manifest::revlog::ManifestContent;
 use mercurial::revlog::RevIdx;
-use mercurial_types::{self, Blob, BlobHash, Entry, MPath, NodeHash, Parents, RepoPath, Type};
+use mercurial_types::{self, Blob, BlobHash, BlobNode, Entry, MPath, NodeHash, Parents, RepoPath,
+                      Type};
 
-use BlobstoreEntry;
+use {BlobstoreEntry, BlobstoreSender, LargefilesMode};
 
 pub(crate) fn put_entry(
-    sender: SyncSender<BlobstoreEntry>,
+    sender: BlobstoreSender,
     entry_hash: NodeHash,
     blob: Blob,
     parents: Parents,
@@ -31,41 +32,107 @@ where
     let bytes = blob.into_inner()
         .ok_or(failure::err_msg("missing blob data"))
         .into_future();
-    bytes.and_then(move |bytes| {
-        let nodeblob = RawNodeBlob {
-            parents: parents,
-            blob: BlobHash::from(bytes.as_ref()),
-        };
-        // TODO: (jsgf) T21597565 Convert blobimport to use blobrepo methods to name and create
-        // blobs.
-        let nodekey = format!("node-{}.bincode", entry_hash);
-        let blobkey = format!("sha1-{}", nodeblob.blob.sha1());
-        let nodeblob = bincode::serialize(&nodeblob)
-            .expect("bincode serialize failed");
-
-        let res1 = sender.send(BlobstoreEntry::ManifestEntry((
-            nodekey,
-            Bytes::from(nodeblob),
-        )));
-        let res2 = sender.send(BlobstoreEntry::ManifestEntry((blobkey, bytes)));
-
-        res1.and(res2).map_err(Error::from)
-    })
+    bytes
+        .and_then(move |bytes| {
+            let nodeblob = RawNodeBlob {
+                parents: parents,
+                blob: BlobHash::from(bytes.as_ref()),
+            };
+            // TODO: (jsgf) T21597565 Convert blobimport to use blobrepo methods to name and
+            // create blobs.
+            let nodekey = format!("node-{}.bincode", entry_hash);
+            let content_blobs = content_to_blobs(nodeblob.blob, bytes)?;
+            let nodeblob = bincode::serialize(&nodeblob)
+                .expect("bincode serialize failed");
+
+            // Very large files are split into several chunk blobs
+            let mut writes: Vec<_> = content_blobs
+                .into_iter()
+                .map(|(key, value)| sender.send(BlobstoreEntry::ManifestEntry((key, value))))
+                .collect();
+            writes.push(sender.send(BlobstoreEntry::ManifestEntry((
+                nodekey,
+                Bytes::from(nodeblob),
+            ))));
+            Ok(future::join_all(writes).map(|_| ()))
+        })
+        .flatten()
+}
+
+/// Put a file's entry into the blobstore, handling its largefile content as `largefiles` says
+/// if it's a standin.
+pub(crate) fn put_file(
+    sender: BlobstoreSender,
+    revlog_repo: &RevlogRepo,
+    largefiles: LargefilesMode,
+    path: RepoPath,
+    entry_hash: NodeHash,
+    blob: Blob,
+    parents: Parents,
+) -> BoxFuture<(), Error> {
+    let is_standin = match path {
+        RepoPath::FilePath(ref path) => is_standin(path),
+        _ => false,
+    };
+    if !is_standin || largefiles == LargefilesMode::Standin {
+        return put_entry(sender, entry_hash, blob, parents).boxify();
+    }
+
+    let hash = blob.as_slice()
+        .ok_or(failure::err_msg("missing blob data"))
+        .and_then(parse_standin);
+    let hash = match hash {
+        Ok(hash) => hash,
+        Err(err) => {
+            let err = err.context(format_err!("cannot parse standin {:?}", path));
+            return future::err(err.into()).boxify();
+        }
+    };
+
+    revlog_repo
+        .get_largefile(&hash)
+        .and_then(move |content| match largefiles {
+            // The content hashes to the standin's hash, so it's stored under the same key as
+            // it is with External, just without the standin's own text.
+            LargefilesMode::Inline => {
+                Ok(put_entry(sender, entry_hash, Blob::from(content), parents).boxify())
+            }
+            _ => {
+                let content_blobs = content_to_blobs(BlobHash::new(hash), content)?;
+                let mut writes: Vec<_> = content_blobs
+                    .into_iter()
+                    .map(|(key, value)| sender.send(BlobstoreEntry::ManifestEntry((key, value))))
+                    .collect();
+                writes.push(put_entry(sender, entry_hash, blob, parents).boxify());
+                Ok(future::join_all(writes).map(|_| ()).boxify())
+            }
+        })
+        .flatten()
+        .map_err(move |err| {
+            Error::from(err.context(format_err!("cannot copy largefile for {:?}", path)))
+        })
+        .boxify()
 }
 
 // Copy a single manifest entry into the blobstore
 // TODO: #[async]
 pub(crate) fn copy_entry(
     entry: Box<Entry>,
-    sender: SyncSender<BlobstoreEntry>,
+    revlog_repo: &RevlogRepo,
+    largefiles: LargefilesMode,
+    path: RepoPath,
+    sender: BlobstoreSender,
 ) -> impl Future<Item = (), Error = Error> + Send + 'static {
     let hash = (*entry).get_hash().into_nodehash();
+    let revlog_repo = revlog_repo.clone();
 
     let blobfuture = entry.get_raw_content().map_err(Error::from);
 
     blobfuture
         .join(entry.get_parents().map_err(Error::from))
-        .and_then(move |(blob, parents)| put_entry(sender, hash, blob, parents))
+        .and_then(move |(blob, parents)| {
+            put_file(sender, &revlog_repo, largefiles, path, hash, blob, parents)
+        })
 }
 
 pub(crate) fn get_entry_stream(
@@ -121,3 +188,41 @@ pub(crate) fn get_entry_stream(
             .boxify(),
     }
 }
+
+/// Stream the file entries of a flat manifest that were introduced by the changeset at
+/// `cs_rev`, along with their filelog revisions. Only the files that the changeset lists as
+/// changed can have been introduced by it, so the others aren't looked at.
+pub(crate) fn get_flat_file_stream(
+    revlog_repo: RevlogRepo,
+    content: ManifestContent,
+    changed_files: Vec<MPath>,
+    cs_rev: RevIdx,
+) -> BoxStream<(RepoPath, NodeHash, BlobNode), Error> {
+    // Removed files are listed as changed too, but aren't in the manifest.
+    let candidates: Vec<_> = changed_files
+        .into_iter()
+        .filter_map(|path| {
+            let details = content.files.get(&path).cloned();
+            details.map(|details| (path, details))
+        })
+        .collect();
+
+    futures::stream::iter_ok(candidates.into_iter())
+        .and_then(move |(path, details)| {
+            let node = details.entryid().into_nodehash();
+            let result = revlog_repo.get_file_revlog(&path).and_then(|revlog| {
+                // A file can be changed back to an earlier revision, which is then reused.
+                if revlog.get_entry_by_id(details.entryid())?.linkrev == cs_rev {
+                    let repopath = RepoPath::file(path.clone())?;
+                    Ok(Some((repopath, node, revlog.get_rev_by_nodeid(&node)?)))
+                } else {
+                    Ok(None)
+                }
+            });
+            result.map_err(|err| {
+                Error::from(err.context(format_err!("cannot copy {} node {}", path, node)))
+            })
+        })
+        .filter_map(|entry| entry)
+        .boxify()
+}
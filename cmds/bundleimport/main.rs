@@ -0,0 +1,209 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Import a Mercurial bundle file into a blob repo.
+//!
+//! The bundle goes through the same bundle2-resolver code that handles pushes, without a server
+//! in the way. HG10 bundles (uncompressed, gzip or bzip2) and HG20 bundles are supported, and
+//! flat manifests are converted to tree manifests as they're imported.
+
+#![deny(warnings)]
+
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+extern crate tokio_core;
+extern crate tokio_io;
+
+extern crate blobrepo;
+extern crate bundle2_resolver;
+extern crate futures_ext;
+extern crate mercurial_bundles;
+extern crate mercurial_types;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::{App, Arg, ArgMatches};
+use failure::{Error, Result, ResultExt, SlogKVError};
+use futures::Stream;
+use slog::{Drain, Level, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+use tokio_core::reactor::Core;
+use tokio_io::AsyncRead;
+
+use blobrepo::BlobRepo;
+use futures_ext::{BoxStream, StreamExt};
+use mercurial_bundles::Bundle2Item;
+use mercurial_bundles::bundle10::bundle10_stream;
+use mercurial_bundles::bundle2::Bundle2Stream;
+use mercurial_types::RepositoryId;
+
+#[derive(Debug, Eq, PartialEq)]
+enum BlobstoreType {
+    Files,
+    Rocksdb,
+    Sqlite,
+}
+
+fn open_repo(
+    logger: &Logger,
+    output: &Path,
+    blobtype: BlobstoreType,
+    repo_id: RepositoryId,
+) -> Result<BlobRepo> {
+    let logger = logger.new(o!["BlobRepo" => format!("{:?}", output)]);
+    match blobtype {
+        BlobstoreType::Files => BlobRepo::new_files(logger, output, repo_id),
+        BlobstoreType::Rocksdb => BlobRepo::new_rocksdb(logger, output, repo_id),
+        BlobstoreType::Sqlite => BlobRepo::new_sqlite(logger, output, repo_id),
+    }
+}
+
+/// A bundle file, read as the bundle is parsed. Reads block, which is fine as parsing the
+/// bundle is the only thing that runs on the core.
+struct BundleReader(BufReader<File>);
+
+impl Read for BundleReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl BufRead for BundleReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.0.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.0.consume(amt)
+    }
+}
+
+impl AsyncRead for BundleReader {}
+
+/// Parse a bundle of either version into bundle2 items.
+fn bundle_stream(logger: &Logger, input: &Path) -> Result<BoxStream<Bundle2Item, Error>> {
+    let mut reader = File::open(input)
+        .map(|file| BundleReader(BufReader::new(file)))
+        .with_context(|_| format!("can't open {:?}", input))?;
+    let mut magic = [0; 4];
+    {
+        let start = reader
+            .fill_buf()
+            .with_context(|_| format!("can't read {:?}", input))?;
+        if start.len() < magic.len() {
+            bail_msg!("not a Mercurial bundle");
+        }
+        magic.copy_from_slice(&start[..magic.len()]);
+    }
+
+    let logger = logger.new(o!["bundle" => "parser"]);
+    let stream = match &magic {
+        b"HG10" => bundle10_stream(reader, logger),
+        b"HG20" => Bundle2Stream::new(reader, logger)
+            .filter_map(|event| event.into_next().ok())
+            .boxify(),
+        _ => bail_msg!("not a Mercurial bundle"),
+    };
+    Ok(stream)
+}
+
+fn run_bundleimport(
+    logger: &Logger,
+    input: PathBuf,
+    output: PathBuf,
+    blobtype: BlobstoreType,
+    repo_id: RepositoryId,
+) -> Result<()> {
+    let repo = Arc::new(open_repo(logger, &output, blobtype, repo_id)?);
+    let bundle2 = bundle_stream(logger, &input)?;
+
+    info!(logger, "Importing {:?}", input);
+    let mut core = Core::new()?;
+    core.run(bundle2_resolver::resolve_bundle(
+        repo,
+        logger.clone(),
+        bundle2,
+    ))?;
+    info!(logger, "Imported {:?}", input);
+    Ok(())
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("bundle importer")
+        .version("0.0.0")
+        .about("import a Mercurial bundle into a blob repo")
+        .args_from_usage(
+            r#"
+            <INPUT>                  'input bundle file'
+            <OUTPUT>                 'output blob repo'
+
+            -d, --debug              'print debug level output'
+            --repo-id [REPO_ID]      'id of the repo in the SQL stores. Default: 0'
+        "#,
+        )
+        .arg(
+            Arg::with_name("blobstore")
+                .long("blobstore")
+                .short("B")
+                .takes_value(true)
+                .possible_values(&["files", "rocksdb", "sqlite"])
+                .required(true)
+                .help("blobstore type"),
+        )
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let root_log = {
+        let level = if matches.is_present("debug") {
+            Level::Debug
+        } else {
+            Level::Info
+        };
+
+        let drain = glog_drain().filter_level(level).fuse();
+        slog::Logger::root(drain, o![])
+    };
+
+    fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<()> {
+        let input = matches.value_of("INPUT").unwrap();
+        let output = matches.value_of("OUTPUT").unwrap();
+
+        let blobtype = match matches.value_of("blobstore").unwrap() {
+            "files" => BlobstoreType::Files,
+            "rocksdb" => BlobstoreType::Rocksdb,
+            "sqlite" => BlobstoreType::Sqlite,
+            bad => panic!("unexpected blobstore type {}", bad),
+        };
+
+        let repo_id = matches
+            .value_of("repo-id")
+            .map(|id| id.parse().expect("repo-id must be an integer"))
+            .unwrap_or(0);
+
+        run_bundleimport(
+            root_log,
+            input.into(),
+            output.into(),
+            blobtype,
+            RepositoryId::new(repo_id),
+        )
+    }
+
+    if let Err(e) = run(&root_log, matches) {
+        error!(root_log, "Bundleimport failed"; SlogKVError(e));
+        std::process::exit(1);
+    }
+}
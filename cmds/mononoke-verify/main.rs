@@ -0,0 +1,811 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Check that a blob repo faithfully mirrors the revlog repo it was imported from.
+//!
+//! Every changeset in the revlog is compared with its counterpart in the blob repo: changeset
+//! blobs, parents, generation numbers, and the whole tree of manifests and files under it,
+//! including copy information and linknodes. Flat manifests are converted to tree manifests
+//! the way blobimport converts them before they are compared. Each mismatch is printed to
+//! stdout as one line of JSON, so that the output can be processed by other tools.
+
+#![deny(warnings)]
+
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+extern crate tokio_core;
+
+extern crate blobrepo;
+extern crate mercurial;
+extern crate mercurial_types;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::Arc;
+
+use clap::{App, Arg, ArgMatches};
+use failure::{Result, SlogKVError};
+use futures::{Future, Stream};
+use slog::{Drain, Level, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+use tokio_core::reactor::Core;
+
+use blobrepo::BlobRepo;
+use mercurial::RevlogRepo;
+use mercurial::changeset::serialize_cs;
+use mercurial::file::File;
+use mercurial::manifest::Details;
+use mercurial::manifest::convert::{ConvertedTree, FlatConverter, TreeNode};
+use mercurial::manifest::revlog::ManifestContent;
+use mercurial::revlog::Revlog;
+use mercurial_types::{Blob, BlobNode, Changeset, ChangesetId, Entry, EntryId, MPath,
+                      MPathElement, Manifest, NodeHash, Parents, RepoPath, RepositoryId, Type,
+                      NULL_HASH};
+use mercurial_types::hash::Sha1;
+use mercurial_types::manifest::Content;
+use mercurial_types::nodehash::ManifestId;
+
+#[derive(Debug, Eq, PartialEq)]
+enum BlobstoreType {
+    Files,
+    Rocksdb,
+    Sqlite,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum MismatchKind {
+    /// The changeset isn't in the blob repo at all.
+    MissingChangeset,
+    /// The changeset serializes to something other than the revlog's changeset blob.
+    ChangesetContent,
+    Parents,
+    GenerationNumber,
+    /// A manifest entry, or its node, is missing from the blob repo.
+    MissingEntry,
+    /// The blob repo's manifest has an entry that the revlog's doesn't.
+    ExtraEntry,
+    EntryType,
+    EntryHash,
+    /// A node's content differs, but any copy information matches.
+    Content,
+    CopyInfo,
+    EntryParents,
+    Linknode,
+}
+
+/// A single difference between the revlog repo and the blob repo, reported as JSON.
+#[derive(Debug, Serialize)]
+struct Mismatch {
+    /// The changeset being verified when the mismatch was found.
+    changeset: NodeHash,
+    kind: MismatchKind,
+    /// Path of the manifest entry, if any. The root manifest has an empty path.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node: Option<NodeHash>,
+    revlog: String,
+    blobrepo: String,
+}
+
+/// The entries of every directory of a flat manifest: files have their details, and
+/// subdirectories have None.
+type FlatDirs = HashMap<MPath, BTreeMap<MPathElement, Option<Details>>>;
+
+/// A flat manifest, along with the tree manifests that blobimport converts it to.
+struct FlatTree {
+    tree: Arc<ConvertedTree>,
+    /// The tree nodes that the manifest introduces, by directory.
+    new_nodes: HashMap<MPath, TreeNode>,
+    dirs: FlatDirs,
+    /// The linknode of the tree nodes that the manifest introduces.
+    linknode: NodeHash,
+}
+
+struct Verifier {
+    revlog: RevlogRepo,
+    blobrepo: BlobRepo,
+    core: Core,
+    check_linknodes: bool,
+    // Only set for revlogs with flat manifests.
+    flat_converter: Option<FlatConverter>,
+    // Entries that were already verified as part of an earlier changeset.
+    checked: HashSet<(RepoPath, NodeHash)>,
+    // The changeset being verified.
+    changeset: NodeHash,
+    mismatches: usize,
+}
+
+impl Verifier {
+    fn report<R: Display, B: Display>(
+        &mut self,
+        kind: MismatchKind,
+        path: Option<&RepoPath>,
+        node: Option<NodeHash>,
+        revlog: R,
+        blobrepo: B,
+    ) -> Result<()> {
+        let mismatch = Mismatch {
+            changeset: self.changeset,
+            kind,
+            path: path.map(|path| match path.mpath() {
+                Some(path) => path.to_string(),
+                None => String::new(),
+            }),
+            node,
+            revlog: revlog.to_string(),
+            blobrepo: blobrepo.to_string(),
+        };
+        println!("{}", serde_json::to_string(&mismatch)?);
+        self.mismatches += 1;
+        Ok(())
+    }
+
+    fn verify_changeset(&mut self, csid: NodeHash, generation: u64) -> Result<()> {
+        self.changeset = csid;
+        let changesetid = ChangesetId::new(csid);
+
+        if !self.core.run(self.blobrepo.changeset_exists(&changesetid))? {
+            return self.report(MismatchKind::MissingChangeset, None, None, "present", "missing");
+        }
+
+        let revlog_node = self.core
+            .run(self.revlog.get_changeset_blob_by_nodeid(&csid))?;
+        let revlog_cs = self.core.run(self.revlog.get_changeset_by_changesetid(&changesetid))?;
+        let blob_cs = self.core
+            .run(self.blobrepo.get_changeset_by_changesetid(&changesetid))?;
+
+        let mut blob_text = Vec::new();
+        serialize_cs(&blob_cs, &mut blob_text)?;
+        let revlog_text = revlog_node.as_blob().as_slice().unwrap_or(&[]);
+        if revlog_text != &blob_text[..] {
+            self.report(
+                MismatchKind::ChangesetContent,
+                None,
+                None,
+                describe(revlog_text),
+                describe(&blob_text),
+            )?;
+        }
+
+        if revlog_cs.parents() != blob_cs.parents() {
+            self.report(
+                MismatchKind::Parents,
+                None,
+                None,
+                format!("{:?}", revlog_cs.parents()),
+                format!("{:?}", blob_cs.parents()),
+            )?;
+        }
+
+        match self.core
+            .run(self.blobrepo.get_generation_number(&changesetid))?
+        {
+            Some(gen) if gen == generation => (),
+            gen => self.report(
+                MismatchKind::GenerationNumber,
+                None,
+                None,
+                generation,
+                gen.map(|gen| gen.to_string())
+                    .unwrap_or_else(|| "missing".into()),
+            )?,
+        }
+
+        let manifestid = revlog_cs.manifestid().into_nodehash();
+        if manifestid != NULL_HASH && self.flat_converter.is_some() {
+            let revlog_root = self.core
+                .run(self.revlog.get_manifest_blob_by_nodeid(&manifestid))?;
+            let blob_root = self.blobrepo
+                .get_root_entry(&ManifestId::new(manifestid));
+            self.verify_flat_manifest(manifestid, revlog_root, blob_root)?;
+        } else if manifestid != NULL_HASH {
+            let revlog_root = self.core
+                .run(self.revlog.get_manifest_blob_by_nodeid(&manifestid))?;
+            let revlog_children = self.core
+                .run(self.revlog.get_manifest_by_nodeid(&manifestid))
+                .and_then(|manifest| self.core.run(manifest.list().collect()))?;
+            let blob_root = self.blobrepo
+                .get_root_entry(&ManifestId::new(manifestid));
+            self.verify_tree(
+                RepoPath::root(),
+                manifestid,
+                (revlog_root.as_blob().clone(), *revlog_root.parents()),
+                revlog_children,
+                blob_root,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn verify_tree(
+        &mut self,
+        path: RepoPath,
+        node: NodeHash,
+        revlog_node: (Blob, Parents),
+        revlog_children: Vec<Box<Entry + Sync>>,
+        blob_entry: Box<Entry + Sync>,
+    ) -> Result<()> {
+        if !self.checked.insert((path.clone(), node)) {
+            return Ok(());
+        }
+        if !self.verify_node(&path, node, revlog_node, None, &*blob_entry)? {
+            return Ok(());
+        }
+
+        let blob_children = match self.core.run(blob_entry.get_content()) {
+            Ok(Content::Tree(manifest)) => self.core.run(manifest.list().collect())?,
+            Ok(_) => {
+                return self.report(MismatchKind::EntryType, Some(&path), Some(node), "t", "?");
+            }
+            Err(err) => {
+                return self.report(MismatchKind::MissingEntry, Some(&path), Some(node), "", err);
+            }
+        };
+        let mut blob_children: HashMap<_, _> = blob_children
+            .into_iter()
+            .map(|entry| (entry.get_name().clone(), entry))
+            .collect();
+
+        for revlog_child in revlog_children {
+            let name = revlog_child.get_name().clone();
+            let child_path = child_path(&path, &name, revlog_child.get_type())?;
+            let child_node = revlog_child.get_hash().into_nodehash();
+
+            let blob_child = match blob_children.remove(&name) {
+                Some(blob_child) => blob_child,
+                None => {
+                    self.report(
+                        MismatchKind::MissingEntry,
+                        Some(&child_path),
+                        Some(child_node),
+                        "present",
+                        "missing",
+                    )?;
+                    continue;
+                }
+            };
+            if revlog_child.get_type() != blob_child.get_type() {
+                self.report(
+                    MismatchKind::EntryType,
+                    Some(&child_path),
+                    Some(child_node),
+                    revlog_child.get_type(),
+                    blob_child.get_type(),
+                )?;
+                continue;
+            }
+            if blob_child.get_hash().into_nodehash() != child_node {
+                self.report(
+                    MismatchKind::EntryHash,
+                    Some(&child_path),
+                    Some(child_node),
+                    child_node,
+                    blob_child.get_hash().into_nodehash(),
+                )?;
+                continue;
+            }
+
+            let revlog_node = self.core
+                .run(revlog_child.get_raw_content().join(revlog_child.get_parents()))?;
+            if revlog_child.get_type() == Type::Tree {
+                let grandchildren = match self.core.run(revlog_child.get_content())? {
+                    Content::Tree(manifest) => self.core.run(manifest.list().collect())?,
+                    _ => bail_msg!("revlog tree {} is not a tree", child_path),
+                };
+                self.verify_tree(child_path, child_node, revlog_node, grandchildren, blob_child)?;
+            } else if self.checked.insert((child_path.clone(), child_node)) {
+                self.verify_node(&child_path, child_node, revlog_node, None, &*blob_child)?;
+            }
+        }
+
+        for (name, blob_child) in blob_children {
+            let child_path = child_path(&path, &name, blob_child.get_type())?;
+            self.report(
+                MismatchKind::ExtraEntry,
+                Some(&child_path),
+                Some(blob_child.get_hash().into_nodehash()),
+                "missing",
+                "present",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// blobimport converts flat manifests to tree manifests, so the revlog side is converted
+    /// the same way before it is compared with the trees of the blob repo.
+    fn verify_flat_manifest(
+        &mut self,
+        mfid: NodeHash,
+        revlog_root: BlobNode,
+        blob_root: Box<Entry + Sync>,
+    ) -> Result<()> {
+        let content = revlog_root
+            .as_blob()
+            .as_slice()
+            .ok_or_else(|| format_err!("missing data for manifest {}", mfid))
+            .and_then(ManifestContent::parse)?;
+        let (tree, new_nodes) = self.flat_converter
+            .as_ref()
+            .expect("flat manifests need a converter")
+            .convert(&mfid, revlog_root.parents(), &content)?;
+        let flat = FlatTree {
+            tree,
+            new_nodes: new_nodes
+                .into_iter()
+                .map(|node| (node.path.clone(), node))
+                .collect(),
+            dirs: flat_dirs(&content),
+            linknode: self.revlog_linknode(&RepoPath::root(), mfid)?,
+        };
+        self.verify_flat_tree(RepoPath::root(), mfid, blob_root, &flat)
+    }
+
+    /// Compare a tree of the blob repo with the converted flat manifest. Trees that the
+    /// manifest introduces are compared in full. The others are the same as in a parent
+    /// manifest, which may not have been verified, so only their text is compared.
+    fn verify_flat_tree(
+        &mut self,
+        path: RepoPath,
+        node: NodeHash,
+        blob_entry: Box<Entry + Sync>,
+        flat: &FlatTree,
+    ) -> Result<()> {
+        if !self.checked.insert((path.clone(), node)) {
+            return Ok(());
+        }
+        let mpath = path.mpath().cloned().unwrap_or_else(MPath::empty);
+
+        match flat.new_nodes.get(&mpath) {
+            Some(tree_node) => {
+                let revlog_node = (Blob::from(tree_node.text.clone()), tree_node.parents);
+                let linknode = Some(flat.linknode);
+                if !self.verify_node(&path, node, revlog_node, linknode, &*blob_entry)? {
+                    return Ok(());
+                }
+            }
+            None => {
+                let text_hash = *flat.tree
+                    .get_dir_text_hash(&mpath)
+                    .ok_or_else(|| format_err!("converted tree has no {}", path))?;
+                match self.core.run(blob_entry.get_raw_content()) {
+                    Ok(blob) => {
+                        let data = blob.as_slice().unwrap_or(&[]);
+                        if Sha1::from(data) != text_hash {
+                            self.report(
+                                MismatchKind::Content,
+                                Some(&path),
+                                Some(node),
+                                format!("sha1 {}", text_hash),
+                                describe(data),
+                            )?;
+                        }
+                    }
+                    Err(err) => {
+                        return self.report(
+                            MismatchKind::MissingEntry,
+                            Some(&path),
+                            Some(node),
+                            "present",
+                            err,
+                        );
+                    }
+                }
+            }
+        }
+
+        let blob_children = match self.core.run(blob_entry.get_content()) {
+            Ok(Content::Tree(manifest)) => self.core.run(manifest.list().collect())?,
+            Ok(_) => {
+                return self.report(MismatchKind::EntryType, Some(&path), Some(node), "t", "?");
+            }
+            Err(err) => {
+                return self.report(MismatchKind::MissingEntry, Some(&path), Some(node), "", err);
+            }
+        };
+        let mut expected_children = flat.dirs.get(&mpath).cloned().unwrap_or_default();
+
+        for blob_child in blob_children {
+            let name = blob_child.get_name().clone();
+            let child_path = child_path(&path, &name, blob_child.get_type())?;
+            let child_node = blob_child.get_hash().into_nodehash();
+
+            let expected = match name.as_ref().and_then(|name| expected_children.remove(name)) {
+                Some(expected) => expected,
+                None => {
+                    self.report(
+                        MismatchKind::ExtraEntry,
+                        Some(&child_path),
+                        Some(child_node),
+                        "missing",
+                        "present",
+                    )?;
+                    continue;
+                }
+            };
+            let (expected_type, expected_node) = match expected {
+                Some(details) => (details.flag(), details.entryid().into_nodehash()),
+                None => {
+                    let child_mpath = mpath.join_element(&name);
+                    let dir_node = flat.tree
+                        .get_dir(&child_mpath)
+                        .ok_or_else(|| format_err!("converted tree has no {}", child_mpath))?;
+                    (Type::Tree, *dir_node)
+                }
+            };
+            if blob_child.get_type() != expected_type {
+                self.report(
+                    MismatchKind::EntryType,
+                    Some(&child_path),
+                    Some(child_node),
+                    expected_type,
+                    blob_child.get_type(),
+                )?;
+                continue;
+            }
+            if child_node != expected_node {
+                self.report(
+                    MismatchKind::EntryHash,
+                    Some(&child_path),
+                    Some(child_node),
+                    expected_node,
+                    child_node,
+                )?;
+                continue;
+            }
+
+            if expected_type == Type::Tree {
+                self.verify_flat_tree(child_path, child_node, blob_child, flat)?;
+            } else if self.checked.insert((child_path.clone(), child_node)) {
+                let file_mpath = mpath.join_element(&name);
+                let revlog_node = self.revlog
+                    .get_file_revlog(&file_mpath)?
+                    .get_rev_by_nodeid(&child_node)?;
+                let revlog_node = (revlog_node.as_blob().clone(), *revlog_node.parents());
+                self.verify_node(&child_path, child_node, revlog_node, None, &*blob_child)?;
+            }
+        }
+
+        for (name, expected) in expected_children {
+            let name = Some(name);
+            let (ty, child_node) = match expected {
+                Some(details) => (details.flag(), details.entryid().into_nodehash()),
+                None => {
+                    let child_mpath = mpath.join_element(&name);
+                    let dir_node = flat.tree.get_dir(&child_mpath).cloned();
+                    (Type::Tree, dir_node.unwrap_or(NULL_HASH))
+                }
+            };
+            let child_path = child_path(&path, &name, ty)?;
+            self.report(
+                MismatchKind::MissingEntry,
+                Some(&child_path),
+                Some(child_node),
+                "present",
+                "missing",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Compare the content, parents and linknode of a single node. Returns false if the node
+    /// couldn't be read from the blob repo at all. The expected linknode is read from the
+    /// revlogs unless `linknode` is given.
+    fn verify_node(
+        &mut self,
+        path: &RepoPath,
+        node: NodeHash,
+        revlog_node: (Blob, Parents),
+        linknode: Option<NodeHash>,
+        blob_entry: &Entry,
+    ) -> Result<bool> {
+        let (revlog_blob, revlog_parents) = revlog_node;
+        let fetched = self.core
+            .run(blob_entry.get_raw_content().join(blob_entry.get_parents()));
+        let (blob_blob, blob_parents) = match fetched {
+            Ok(fetched) => fetched,
+            Err(err) => {
+                self.report(MismatchKind::MissingEntry, Some(path), Some(node), "present", err)?;
+                return Ok(false);
+            }
+        };
+
+        let revlog_data = revlog_blob.as_slice().unwrap_or(&[]);
+        let blob_data = blob_blob.as_slice().unwrap_or(&[]);
+        if revlog_data != blob_data {
+            let revlog_copy = copy_info(&revlog_blob, &revlog_parents);
+            let blob_copy = copy_info(&blob_blob, &blob_parents);
+            if blob_entry.get_type() != Type::Tree && revlog_copy != blob_copy {
+                self.report(
+                    MismatchKind::CopyInfo,
+                    Some(path),
+                    Some(node),
+                    revlog_copy,
+                    blob_copy,
+                )?;
+            } else {
+                self.report(
+                    MismatchKind::Content,
+                    Some(path),
+                    Some(node),
+                    describe(revlog_data),
+                    describe(blob_data),
+                )?;
+            }
+        }
+
+        if revlog_parents != blob_parents {
+            self.report(
+                MismatchKind::EntryParents,
+                Some(path),
+                Some(node),
+                format!("{:?}", revlog_parents),
+                format!("{:?}", blob_parents),
+            )?;
+        }
+
+        if self.check_linknodes {
+            let revlog_linknode = match linknode {
+                Some(linknode) => linknode,
+                None => self.revlog_linknode(path, node)?,
+            };
+            match self.core.run(self.blobrepo.get_linknode(path.clone(), &node)) {
+                Ok(ref linknode) if *linknode == revlog_linknode => (),
+                Ok(linknode) => self.report(
+                    MismatchKind::Linknode,
+                    Some(path),
+                    Some(node),
+                    revlog_linknode,
+                    linknode,
+                )?,
+                Err(_) => self.report(
+                    MismatchKind::Linknode,
+                    Some(path),
+                    Some(node),
+                    revlog_linknode,
+                    "missing",
+                )?,
+            }
+        }
+        Ok(true)
+    }
+
+    fn revlog_linknode(&self, path: &RepoPath, node: NodeHash) -> Result<NodeHash> {
+        let revlog = match *path {
+            RepoPath::RootPath => self.revlog.get_manifest_revlog().clone(),
+            _ => self.revlog.get_path_revlog(path)?,
+        };
+        let linkrev = revlog.get_entry_by_id(&EntryId::new(node))?.linkrev;
+        Ok(self.revlog.get_changelog().get_entry(linkrev)?.nodeid)
+    }
+}
+
+fn child_path(parent: &RepoPath, name: &Option<MPathElement>, ty: Type) -> Result<RepoPath> {
+    let path = match parent.mpath() {
+        Some(parent) => parent.join_element(name),
+        None => MPath::empty().join_element(name),
+    };
+    match ty {
+        Type::Tree => Ok(RepoPath::dir(path)?),
+        Type::File | Type::Executable | Type::Symlink => Ok(RepoPath::file(path)?),
+    }
+}
+
+fn flat_dirs(content: &ManifestContent) -> FlatDirs {
+    let mut dirs = HashMap::new();
+    dirs.insert(MPath::empty(), BTreeMap::new());
+    for (path, details) in &content.files {
+        let elements: Vec<_> = path.into_iter().collect();
+        let mut dir = MPath::empty();
+        for (idx, element) in elements.iter().enumerate() {
+            let entry = if idx + 1 == elements.len() {
+                Some(*details)
+            } else {
+                None
+            };
+            dirs.entry(dir.clone())
+                .or_insert_with(BTreeMap::new)
+                .insert((*element).clone(), entry);
+            dir = dir.join(Some(*element));
+        }
+    }
+    dirs
+}
+
+fn copy_info(blob: &Blob, parents: &Parents) -> String {
+    let (p1, p2) = parents.get_nodes();
+    match File::new(BlobNode::new(blob.clone(), p1, p2)).copied_from() {
+        Ok(Some((path, node))) => format!("{}@{}", path, node),
+        Ok(None) => "none".into(),
+        Err(err) => format!("invalid: {}", err),
+    }
+}
+
+fn describe(data: &[u8]) -> String {
+    format!("{} bytes, sha1 {}", data.len(), Sha1::from(data))
+}
+
+/// Generation numbers for every changeset in the changelog, in revlog order. They're computed
+/// the same way as in the changesets store: roots are 1, and everything else is one more than
+/// its highest parent.
+fn generation_numbers(changelog: &Revlog) -> Vec<(NodeHash, u64)> {
+    let mut gens: Vec<(NodeHash, u64)> = Vec::new();
+    for (_, entry) in changelog {
+        let gen = [entry.p1, entry.p2]
+            .iter()
+            .filter_map(|parent| *parent)
+            .map(|parent| gens[parent.as_u32() as usize].1)
+            .max()
+            .unwrap_or(0) + 1;
+        gens.push((entry.nodeid, gen));
+    }
+    gens
+}
+
+/// Pick roughly one in `rate` changesets, based on the changeset hash so that the same
+/// changesets are picked every time.
+fn sampled(node: &NodeHash, rate: u32) -> bool {
+    let bytes = node.sha1().as_ref();
+    let value = (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8
+        | bytes[3] as u32;
+    value % rate == 0
+}
+
+fn open_blobrepo(
+    logger: &Logger,
+    path: &Path,
+    blobtype: BlobstoreType,
+    repo_id: RepositoryId,
+) -> Result<BlobRepo> {
+    let logger = logger.new(o!["BlobRepo" => format!("{:?}", path)]);
+    match blobtype {
+        BlobstoreType::Files => BlobRepo::new_files(logger, path, repo_id),
+        BlobstoreType::Rocksdb => BlobRepo::new_rocksdb(logger, path, repo_id),
+        BlobstoreType::Sqlite => BlobRepo::new_sqlite(logger, path, repo_id),
+    }
+}
+
+fn run_verify(
+    logger: &Logger,
+    revlog: RevlogRepo,
+    blobrepo: BlobRepo,
+    sample_rate: u32,
+    check_linknodes: bool,
+) -> Result<usize> {
+    let generations = generation_numbers(revlog.get_changelog());
+    let flat_converter = if revlog.has_tree_manifests() {
+        None
+    } else {
+        Some(FlatConverter::new(revlog.clone()))
+    };
+    let mut verifier = Verifier {
+        revlog,
+        blobrepo,
+        core: Core::new()?,
+        check_linknodes,
+        flat_converter,
+        checked: HashSet::new(),
+        changeset: NULL_HASH,
+        mismatches: 0,
+    };
+
+    let mut verified = 0;
+    for (csid, generation) in generations {
+        if !sampled(&csid, sample_rate) {
+            continue;
+        }
+        verifier.verify_changeset(csid, generation)?;
+        verified += 1;
+        if verified % 1000 == 0 {
+            debug!(logger, "verified {} changesets", verified);
+        }
+    }
+
+    info!(
+        logger,
+        "Verified {} changesets, found {} mismatches", verified, verifier.mismatches
+    );
+    Ok(verifier.mismatches)
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("blob repo verifier")
+        .version("0.0.0")
+        .about("check that a blob repo matches the revlog repo it was imported from")
+        .args_from_usage(
+            r#"
+            <REVLOG>                 'revlog repo (path to its .hg directory)'
+            <BLOBREPO>               'blob repo'
+
+            -d, --debug              'print debug level output'
+            --repo-id [REPO_ID]      'id of the repo in the SQL stores. Default: 0'
+            --sample-rate [RATE]     'only verify about one in every RATE changesets'
+            --skip-linknodes         'do not verify linknodes'
+        "#,
+        )
+        .arg(
+            Arg::with_name("blobstore")
+                .long("blobstore")
+                .short("B")
+                .takes_value(true)
+                .possible_values(&["files", "rocksdb", "sqlite"])
+                .required(true)
+                .help("blobstore type"),
+        )
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let root_log = {
+        let level = if matches.is_present("debug") {
+            Level::Debug
+        } else {
+            Level::Info
+        };
+
+        let drain = glog_drain().filter_level(level).fuse();
+        slog::Logger::root(drain, o![])
+    };
+
+    fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<usize> {
+        let blobtype = match matches.value_of("blobstore").unwrap() {
+            "files" => BlobstoreType::Files,
+            "rocksdb" => BlobstoreType::Rocksdb,
+            "sqlite" => BlobstoreType::Sqlite,
+            bad => panic!("unexpected blobstore type {}", bad),
+        };
+
+        let repo_id = matches
+            .value_of("repo-id")
+            .map(|id| id.parse().expect("repo-id must be an integer"))
+            .unwrap_or(0);
+
+        let sample_rate = matches
+            .value_of("sample-rate")
+            .map(|rate| rate.parse().expect("sample-rate must be a positive integer"))
+            .unwrap_or(1);
+        if sample_rate == 0 {
+            bail_msg!("sample-rate must be a positive integer");
+        }
+
+        let revlog = RevlogRepo::open(matches.value_of("REVLOG").unwrap())?;
+        let blobrepo = open_blobrepo(
+            root_log,
+            Path::new(matches.value_of("BLOBREPO").unwrap()),
+            blobtype,
+            RepositoryId::new(repo_id),
+        )?;
+
+        run_verify(
+            root_log,
+            revlog,
+            blobrepo,
+            sample_rate,
+            !matches.is_present("skip-linknodes"),
+        )
+    }
+
+    match run(&root_log, matches) {
+        Ok(0) => (),
+        Ok(_) => std::process::exit(1),
+        Err(e) => {
+            error!(root_log, "Verification failed"; SlogKVError(e));
+            std::process::exit(2);
+        }
+    }
+}
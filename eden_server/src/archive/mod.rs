@@ -0,0 +1,241 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Archives of a directory of a changeset, written while they are sent to the client.
+//!
+//! The files of the directory are listed from its vfs, and a few of them are opened at a time.
+//! Their contents are streamed into the archive a chunk at a time, so large files are never held
+//! in memory whole, let alone the whole archive.
+
+mod tar;
+mod zip;
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{stream, Async, Future, Poll, Stream};
+
+use blobrepo::BlobRepo;
+use failure::{Error, Result};
+use futures_ext::{BoxStream, FutureExt, StreamExt};
+use mercurial_types::{MPath, Type};
+use vfs::{ManifestVfsDir, ManifestVfsFile, VfsDir, VfsNode};
+
+use encoding::{CompressedStream, ContentCoding};
+
+use self::tar::TarWriter;
+use self::zip::ZipWriter;
+
+// Number of file contents that are fetched at once
+const FETCH_CONCURRENCY: usize = 10;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// The extension of archives in the format, as used in urls.
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ArchiveFormat> {
+        match s {
+            "tar.gz" => Ok(ArchiveFormat::TarGz),
+            "zip" => Ok(ArchiveFormat::Zip),
+            _ => bail_msg!("unknown archive format: {}", s),
+        }
+    }
+}
+
+/// A part of the files to add to an archive. A regular file is added as a `Start`, the chunks of
+/// its content and an `End`. A symlink is added whole, as its target is short.
+pub enum ArchivePart {
+    /// Start of a regular file. `ty` is `File` or `Executable`, and `size` is the size of its
+    /// content, which the archive formats need before the content itself.
+    Start { path: MPath, ty: Type, size: u64 },
+    /// A chunk of the content of the file that was started last
+    Data(Bytes),
+    /// End of the file that was started last
+    End,
+    /// A symlink, and its target
+    Symlink { path: MPath, target: Bytes },
+}
+
+/// Writers of the archive formats, which return the bytes of the archive as parts are added.
+enum ArchiveWriter {
+    Tar(TarWriter),
+    Zip(ZipWriter),
+}
+
+impl ArchiveWriter {
+    fn new(format: ArchiveFormat, mtime: u64) -> ArchiveWriter {
+        match format {
+            ArchiveFormat::TarGz => ArchiveWriter::Tar(TarWriter::new(mtime)),
+            ArchiveFormat::Zip => ArchiveWriter::Zip(ZipWriter::new(mtime)),
+        }
+    }
+
+    fn add(&mut self, part: ArchivePart) -> Result<Bytes> {
+        match *self {
+            ArchiveWriter::Tar(ref mut writer) => writer.add(part),
+            ArchiveWriter::Zip(ref mut writer) => writer.add(part),
+        }
+    }
+
+    fn finish(self) -> Result<Bytes> {
+        match self {
+            ArchiveWriter::Tar(writer) => writer.finish(),
+            ArchiveWriter::Zip(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Writes a stream of file parts into an archive, yielding the archive a part at a time.
+struct ArchiveStream<S> {
+    inner: S,
+    writer: Option<ArchiveWriter>,
+}
+
+impl<S> Stream for ArchiveStream<S>
+where
+    S: Stream<Item = ArchivePart, Error = Error>,
+{
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        if self.writer.is_none() {
+            return Ok(Async::Ready(None));
+        }
+        let chunk = match try_ready!(self.inner.poll()) {
+            Some(part) => self.writer
+                .as_mut()
+                .expect("archive is already finished")
+                .add(part)?,
+            None => self.writer
+                .take()
+                .expect("archive writer is missing")
+                .finish()?,
+        };
+        Ok(Async::Ready(Some(chunk)))
+    }
+}
+
+/// List the files under `dir` in order, with their paths relative to `dir`.
+fn list_files(dir: &ManifestVfsDir, prefix: &MPath, files: &mut Vec<(MPath, ManifestVfsFile)>) {
+    let mut names: Vec<_> = dir.read().into_iter().cloned().collect();
+    names.sort();
+    for name in names {
+        let path = prefix.join(&name);
+        match dir.step(&name).expect("listed name is missing from vfs dir") {
+            VfsNode::Dir(subdir) => list_files(&subdir, &path, files),
+            VfsNode::File(file) => files.push((path, file)),
+        }
+    }
+}
+
+/// Archive the files under `dir`. `mtime` is the modification time given to every file, as the
+/// content of a changeset doesn't have one of its own.
+pub fn archive(
+    repo: Arc<BlobRepo>,
+    dir: ManifestVfsDir,
+    mtime: u64,
+    format: ArchiveFormat,
+) -> BoxStream<Bytes, Error> {
+    let mut files = vec![];
+    list_files(&dir, &MPath::empty(), &mut files);
+
+    let parts = stream::iter_ok(files)
+        .map(move |(path, file)| {
+            let entry = file.entry();
+            let ty = entry.get_type();
+            let node = entry.get_hash().into_nodehash();
+            if ty == Type::Symlink {
+                repo.get_file_content(&node)
+                    .map(move |target| {
+                        stream::once(Ok(ArchivePart::Symlink { path, target })).boxify()
+                    })
+                    .boxify()
+            } else {
+                repo.get_sized_file_content_stream(&node)
+                    .map(move |(size, chunks)| {
+                        stream::once(Ok(ArchivePart::Start { path, ty, size }))
+                            .chain(chunks.map(ArchivePart::Data))
+                            .chain(stream::once(Ok(ArchivePart::End)))
+                            .boxify()
+                    })
+                    .boxify()
+            }
+        })
+        .buffered(FETCH_CONCURRENCY)
+        .flatten();
+    let archive = ArchiveStream {
+        inner: parts,
+        writer: Some(ArchiveWriter::new(format, mtime)),
+    };
+
+    match format {
+        ArchiveFormat::TarGz => CompressedStream::new(archive, ContentCoding::Gzip).boxify(),
+        ArchiveFormat::Zip => archive.boxify(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file(path: &str, ty: Type, content: &'static str) -> Vec<ArchivePart> {
+        vec![
+            ArchivePart::Start {
+                path: MPath::new(path).unwrap(),
+                ty,
+                size: content.len() as u64,
+            },
+            ArchivePart::Data(Bytes::from(content)),
+            ArchivePart::End,
+        ]
+    }
+
+    #[test]
+    fn test_format() {
+        for format in &[ArchiveFormat::TarGz, ArchiveFormat::Zip] {
+            assert_eq!(format.extension().parse::<ArchiveFormat>().unwrap(), *format);
+        }
+        assert!("tar".parse::<ArchiveFormat>().is_err());
+    }
+
+    #[test]
+    fn test_archive_stream() {
+        let mut parts = file("a", Type::File, "hello");
+        parts.extend(file("b", Type::Executable, "world"));
+        let archive = ArchiveStream {
+            inner: stream::iter_ok(parts),
+            writer: Some(ArchiveWriter::new(ArchiveFormat::TarGz, 0)),
+        };
+        let chunks: Vec<_> = archive.collect().wait().unwrap();
+        let sizes: Vec<_> = chunks.iter().map(|chunk| chunk.len()).collect();
+        // The header, content and padding of each file, then the end of the archive
+        assert_eq!(sizes, vec![512, 5, 507, 512, 5, 507, 1024]);
+    }
+}
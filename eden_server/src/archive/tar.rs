@@ -0,0 +1,360 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Writer of ustar archives, see POSIX.1-2001 `pax`. Names that don't fit in a ustar header are
+//! written as GNU long name entries, which all the common tar implementations understand.
+
+use bytes::Bytes;
+
+use failure::Result;
+use mercurial_types::{MPath, Type};
+
+use super::ArchivePart;
+
+const BLOCK_SIZE: usize = 512;
+const NAME_SIZE: usize = 100;
+const PREFIX_SIZE: usize = 155;
+
+const REGULAR: u8 = b'0';
+const SYMLINK: u8 = b'2';
+const GNU_LONGNAME: u8 = b'L';
+const GNU_LONGLINK: u8 = b'K';
+
+/// Name of the entries that hold a long name for the entry after them
+const LONGLINK_NAME: &'static [u8] = b"././@LongLink";
+
+/// Write `value` as a NUL-terminated octal number that fills `field`.
+fn write_octal(field: &mut [u8], value: u64) -> Result<()> {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    if digits.len() >= field.len() {
+        bail_msg!("{} is too large for a tar header", value);
+    }
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+    Ok(())
+}
+
+struct Header<'a> {
+    name: &'a [u8],
+    prefix: &'a [u8],
+    mode: u64,
+    size: u64,
+    mtime: u64,
+    typeflag: u8,
+    linkname: &'a [u8],
+}
+
+impl<'a> Header<'a> {
+    fn to_block(&self) -> Result<[u8; BLOCK_SIZE]> {
+        let mut block = [0; BLOCK_SIZE];
+        block[..self.name.len()].copy_from_slice(self.name);
+        write_octal(&mut block[100..108], self.mode)?;
+        // uid and gid
+        write_octal(&mut block[108..116], 0)?;
+        write_octal(&mut block[116..124], 0)?;
+        write_octal(&mut block[124..136], self.size)?;
+        write_octal(&mut block[136..148], self.mtime)?;
+        block[156] = self.typeflag;
+        block[157..157 + self.linkname.len()].copy_from_slice(self.linkname);
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+        block[345..345 + self.prefix.len()].copy_from_slice(self.prefix);
+
+        // The checksum is computed as if its own field were spaces
+        block[148..156].copy_from_slice(b"        ");
+        let checksum: u64 = block.iter().map(|byte| *byte as u64).sum();
+        write_octal(&mut block[148..155], checksum)?;
+        Ok(block)
+    }
+}
+
+/// Split `path` into the prefix and name fields of a ustar header, if it fits in them.
+fn split_path(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    if path.len() <= NAME_SIZE {
+        return Some((&[][..], path));
+    }
+    path.iter()
+        .enumerate()
+        .filter(|&(_, byte)| *byte == b'/')
+        .map(|(pos, _)| (&path[..pos], &path[pos + 1..]))
+        .find(|&(prefix, name)| prefix.len() <= PREFIX_SIZE && name.len() <= NAME_SIZE)
+}
+
+/// Size of the padding after `len` bytes, up to a whole number of blocks.
+fn padding(len: u64) -> usize {
+    ((BLOCK_SIZE as u64 - len % BLOCK_SIZE as u64) % BLOCK_SIZE as u64) as usize
+}
+
+/// Pad `out` to a whole number of blocks.
+fn pad(out: &mut Vec<u8>) {
+    let len = out.len();
+    out.resize(len + padding(len as u64), 0);
+}
+
+/// A regular file whose content is being written.
+struct OpenFile {
+    path: MPath,
+    size: u64,
+    written: u64,
+}
+
+pub struct TarWriter {
+    mtime: u64,
+    file: Option<OpenFile>,
+}
+
+impl TarWriter {
+    pub fn new(mtime: u64) -> TarWriter {
+        TarWriter { mtime, file: None }
+    }
+
+    /// Write a GNU entry that holds a name which is too long for the header after it.
+    fn write_long_name(&self, out: &mut Vec<u8>, typeflag: u8, name: &[u8]) -> Result<()> {
+        let header = Header {
+            name: LONGLINK_NAME,
+            prefix: &[],
+            mode: 0,
+            size: name.len() as u64 + 1,
+            mtime: 0,
+            typeflag,
+            linkname: &[],
+        };
+        out.extend_from_slice(&header.to_block()?);
+        out.extend_from_slice(name);
+        out.push(0);
+        pad(out);
+        Ok(())
+    }
+
+    pub fn add(&mut self, part: ArchivePart) -> Result<Bytes> {
+        if let Some(ref file) = self.file {
+            match part {
+                ArchivePart::Data(_) | ArchivePart::End => (),
+                _ => bail_msg!("'{}' was not ended before the next file", file.path),
+            }
+        }
+        match part {
+            ArchivePart::Start { path, ty, size } => {
+                let mode = match ty {
+                    Type::File => 0o644,
+                    Type::Executable => 0o755,
+                    Type::Symlink => bail_msg!("'{}' is a symlink, which is added whole", path),
+                    Type::Tree => bail_msg!("'{}' is a directory", path),
+                };
+                let out = self.header(&path, mode, size, REGULAR, &[])?;
+                self.file = Some(OpenFile {
+                    path,
+                    size,
+                    written: 0,
+                });
+                Ok(Bytes::from(out))
+            }
+            ArchivePart::Data(data) => {
+                let file = match self.file {
+                    Some(ref mut file) => file,
+                    None => bail_msg!("file content outside of a file"),
+                };
+                file.written += data.len() as u64;
+                if file.written > file.size {
+                    bail_msg!("'{}' is larger than its size of {}", file.path, file.size);
+                }
+                Ok(data)
+            }
+            ArchivePart::End => {
+                let file = match self.file.take() {
+                    Some(file) => file,
+                    None => bail_msg!("end of a file that was not started"),
+                };
+                if file.written != file.size {
+                    bail_msg!("'{}' is smaller than its size of {}", file.path, file.size);
+                }
+                Ok(Bytes::from(vec![0; padding(file.size)]))
+            }
+            ArchivePart::Symlink { path, target } => {
+                let out = self.header(&path, 0o777, 0, SYMLINK, &target)?;
+                Ok(Bytes::from(out))
+            }
+        }
+    }
+
+    /// The header of a file, after the long name entries it needs, if any.
+    fn header(
+        &self,
+        path: &MPath,
+        mode: u64,
+        size: u64,
+        typeflag: u8,
+        linkname: &[u8],
+    ) -> Result<Vec<u8>> {
+        let path = path.to_vec();
+        let mut out = Vec::with_capacity(BLOCK_SIZE);
+        let linkname = if linkname.len() > NAME_SIZE {
+            self.write_long_name(&mut out, GNU_LONGLINK, linkname)?;
+            &linkname[..NAME_SIZE]
+        } else {
+            linkname
+        };
+        let (prefix, name) = match split_path(&path) {
+            Some(split) => split,
+            None => {
+                self.write_long_name(&mut out, GNU_LONGNAME, &path)?;
+                (&[][..], &path[..NAME_SIZE])
+            }
+        };
+
+        let header = Header {
+            name,
+            prefix,
+            mode,
+            size,
+            mtime: self.mtime,
+            typeflag,
+            linkname,
+        };
+        out.extend_from_slice(&header.to_block()?);
+        Ok(out)
+    }
+
+    /// The end of the archive, which is two empty blocks.
+    pub fn finish(self) -> Result<Bytes> {
+        if let Some(file) = self.file {
+            bail_msg!("'{}' was not ended before the end of the archive", file.path);
+        }
+        Ok(Bytes::from(vec![0; 2 * BLOCK_SIZE]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str;
+
+    use super::*;
+
+    fn add(ty: Type, path: &str, content: &str) -> Vec<u8> {
+        let path = MPath::new(path).unwrap();
+        let parts = if ty == Type::Symlink {
+            vec![
+                ArchivePart::Symlink {
+                    path,
+                    target: Bytes::from(content),
+                },
+            ]
+        } else {
+            vec![
+                ArchivePart::Start {
+                    path,
+                    ty,
+                    size: content.len() as u64,
+                },
+                ArchivePart::Data(Bytes::from(content)),
+                ArchivePart::End,
+            ]
+        };
+        let mut writer = TarWriter::new(1_500_000_000);
+        let mut out = vec![];
+        for part in parts {
+            out.extend_from_slice(&writer.add(part).unwrap());
+        }
+        out
+    }
+
+    /// The value of a NUL-terminated field of a header.
+    fn field(block: &[u8], start: usize, len: usize) -> &str {
+        let field = &block[start..start + len];
+        let end = field.iter().position(|byte| *byte == 0).unwrap_or(len);
+        str::from_utf8(&field[..end]).unwrap()
+    }
+
+    fn check_checksum(block: &[u8]) {
+        let sum: u64 = block
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| (if i >= 148 && i < 156 { b' ' } else { *byte }) as u64)
+            .sum();
+        assert_eq!(u64::from_str_radix(field(block, 148, 8), 8).unwrap(), sum);
+    }
+
+    #[test]
+    fn test_file() {
+        let out = add(Type::Executable, "dir/file", "hello");
+        assert_eq!(out.len(), 2 * BLOCK_SIZE);
+        check_checksum(&out[..BLOCK_SIZE]);
+        assert_eq!(field(&out, 0, 100), "dir/file");
+        assert_eq!(field(&out, 100, 8), "0000755");
+        assert_eq!(field(&out, 124, 12), "00000000005");
+        assert_eq!(field(&out, 136, 12), "13132027400");
+        assert_eq!(out[156], REGULAR);
+        assert_eq!(field(&out, 257, 8), "ustar");
+        assert_eq!(&out[BLOCK_SIZE..BLOCK_SIZE + 6], b"hello\0");
+
+        let out = add(Type::File, "empty", "");
+        assert_eq!(out.len(), BLOCK_SIZE);
+        assert_eq!(field(&out, 100, 8), "0000644");
+    }
+
+    #[test]
+    fn test_symlink() {
+        let out = add(Type::Symlink, "link", "dir/file");
+        assert_eq!(out.len(), BLOCK_SIZE);
+        check_checksum(&out);
+        assert_eq!(out[156], SYMLINK);
+        assert_eq!(field(&out, 124, 12), "00000000000");
+        assert_eq!(field(&out, 157, 100), "dir/file");
+    }
+
+    #[test]
+    fn test_prefix() {
+        let dir = "d".repeat(120);
+        let out = add(Type::File, &format!("{}/file", dir), "");
+        assert_eq!(out.len(), BLOCK_SIZE);
+        check_checksum(&out);
+        assert_eq!(field(&out, 0, 100), "file");
+        assert_eq!(field(&out, 345, 155), dir);
+    }
+
+    #[test]
+    fn test_long_name() {
+        let path = "f".repeat(200);
+        let out = add(Type::File, &path, "x");
+        // The long name entry and its data, then the header and data of the file
+        assert_eq!(out.len(), 4 * BLOCK_SIZE);
+        check_checksum(&out[..BLOCK_SIZE]);
+        assert_eq!(out[156], GNU_LONGNAME);
+        assert_eq!(field(&out, 124, 12), "00000000311");
+        assert_eq!(field(&out[BLOCK_SIZE..], 0, BLOCK_SIZE), path);
+        check_checksum(&out[2 * BLOCK_SIZE..3 * BLOCK_SIZE]);
+        assert_eq!(field(&out[2 * BLOCK_SIZE..], 0, 100), &path[..100]);
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let start = || ArchivePart::Start {
+            path: MPath::new("file").unwrap(),
+            ty: Type::File,
+            size: 3,
+        };
+
+        let mut writer = TarWriter::new(0);
+        writer.add(start()).unwrap();
+        assert!(writer.add(ArchivePart::Data(Bytes::from("abcd"))).is_err());
+
+        let mut writer = TarWriter::new(0);
+        writer.add(start()).unwrap();
+        writer.add(ArchivePart::Data(Bytes::from("ab"))).unwrap();
+        assert!(writer.add(ArchivePart::End).is_err());
+
+        let mut writer = TarWriter::new(0);
+        writer.add(start()).unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn test_finish() {
+        let out = TarWriter::new(0).finish().unwrap();
+        assert_eq!(out.len(), 2 * BLOCK_SIZE);
+        assert!(out.iter().all(|byte| *byte == 0));
+    }
+}
@@ -0,0 +1,718 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Writer of zip archives, see PKWARE's APPNOTE.TXT. The content of a file is deflated as it is
+//! streamed, so its CRC and compressed size are written in a data descriptor after it rather than
+//! in its header. Files, offsets and archives that are too large for the original format, and
+//! archives of more than 65535 files, use the zip64 extensions.
+
+use std::cmp;
+use std::io::Write;
+use std::mem;
+use std::str;
+
+use bytes::{BufMut, Bytes};
+use flate2::{Compression, Crc};
+use flate2::write::DeflateEncoder;
+
+use failure::Result;
+use mercurial_types::{MPath, Type};
+
+use super::ArchivePart;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+
+const LOCAL_HEADER_SIZE: usize = 30;
+const DATA_DESCRIPTOR_SIZE: usize = 16;
+const ZIP64_DATA_DESCRIPTOR_SIZE: usize = 24;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE: usize = 56;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIZE: usize = 20;
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+
+/// Version 2.0 of the format, which has deflate
+const VERSION: u16 = 20;
+/// Version 4.5 of the format, which has zip64
+const VERSION_ZIP64: u16 = 45;
+/// Version made by a Unix host, so that the high half of the external attributes is a mode
+const VERSION_MADE_BY: u16 = 3 << 8 | VERSION_ZIP64;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// General purpose flag of files whose CRC and sizes are in a data descriptor after their data
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+/// General purpose flag of names that are encoded as UTF-8
+const FLAG_UTF8: u16 = 1 << 11;
+
+/// Zip64 extra field, which has the sizes and offset that don't fit in a header
+const ZIP64_ID: u16 = 0x0001;
+/// Size of the zip64 extra field of a local header, which always has both sizes
+const ZIP64_LOCAL_SIZE: usize = 20;
+/// Value of the fields that are too large for a header or end record, and are in the zip64 extra
+/// field or end record instead
+const ZIP64_MARKER: u32 = 0xffff_ffff;
+const ZIP64_COUNT_MARKER: u16 = 0xffff;
+/// Files of this size and larger have zip64 sizes. It leaves room for deflate to make the content
+/// slightly larger, which it does to content that doesn't compress.
+const ZIP64_THRESHOLD: u64 = 0xfff0_0000;
+
+/// Extended timestamp extra field, which has the modification time as a Unix timestamp
+const EXTENDED_TIMESTAMP_ID: u16 = 0x5455;
+const EXTENDED_TIMESTAMP_SIZE: usize = 9;
+
+const MODE_FILE: u32 = 0o100644;
+const MODE_EXECUTABLE: u32 = 0o100755;
+const MODE_SYMLINK: u32 = 0o120777;
+
+/// 1980-01-01, the earliest time that MS-DOS timestamps can represent
+const DOS_EPOCH: u64 = 315_532_800;
+const DOS_MAX_YEAR: i64 = 2107;
+
+/// The MS-DOS date and time of a Unix timestamp, in UTC. Times outside of the range of the
+/// format are clamped to it.
+fn dos_datetime(time: u64) -> (u16, u16) {
+    let time = cmp::max(time, DOS_EPOCH);
+    let days = (time / 86400) as i64;
+    let seconds = time % 86400;
+
+    // Civil date of a number of days since 1970-01-01, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    if year > DOS_MAX_YEAR {
+        return (0xff9f, 0xbf7d);
+    }
+    let date = ((year - 1980) << 9 | month << 5 | day) as u16;
+    let time = ((seconds / 3600) << 11 | (seconds / 60 % 60) << 5 | (seconds % 60 / 2)) as u16;
+    (date, time)
+}
+
+/// What the central directory needs to know about a file.
+struct CentralEntry {
+    name: Vec<u8>,
+    flags: u16,
+    method: u16,
+    crc: u32,
+    compressed_size: u64,
+    size: u64,
+    mode: u32,
+    offset: u64,
+    /// Whether the local header has a zip64 extra field, and the data descriptor 64-bit sizes
+    zip64: bool,
+}
+
+/// A regular file whose content is being deflated.
+struct OpenFile {
+    path: MPath,
+    entry: CentralEntry,
+    encoder: DeflateEncoder<Vec<u8>>,
+    crc: Crc,
+    written: u64,
+}
+
+/// The value of a 32-bit field, or the marker that it is in a zip64 extra field or end record.
+fn to_field(value: u64) -> u32 {
+    cmp::min(value, ZIP64_MARKER as u64) as u32
+}
+
+pub struct ZipWriter {
+    mtime: u64,
+    date: u16,
+    time: u16,
+    /// Number of bytes written so far
+    offset: u64,
+    entries: Vec<CentralEntry>,
+    file: Option<OpenFile>,
+}
+
+impl ZipWriter {
+    pub fn new(mtime: u64) -> ZipWriter {
+        let (date, time) = dos_datetime(mtime);
+        ZipWriter {
+            mtime,
+            date,
+            time,
+            offset: 0,
+            entries: vec![],
+            file: None,
+        }
+    }
+
+    fn put_extended_timestamp(&self, out: &mut Vec<u8>) {
+        out.put_u16_le(EXTENDED_TIMESTAMP_ID);
+        out.put_u16_le(EXTENDED_TIMESTAMP_SIZE as u16 - 4);
+        // Only the modification time is present
+        out.put_u8(1);
+        out.put_u32_le(cmp::min(self.mtime, u32::max_value() as u64) as u32);
+    }
+
+    /// The entry of a file that starts at the current offset.
+    fn entry(&self, path: &MPath, mode: u32, flags: u16, method: u16, size: u64) -> CentralEntry {
+        let name = path.to_vec();
+        let flags = if str::from_utf8(&name).is_ok() {
+            flags | FLAG_UTF8
+        } else {
+            flags
+        };
+        CentralEntry {
+            name,
+            flags,
+            method,
+            crc: 0,
+            compressed_size: 0,
+            size,
+            mode,
+            offset: self.offset,
+            zip64: size >= ZIP64_THRESHOLD,
+        }
+    }
+
+    fn local_header(&self, entry: &CentralEntry) -> Vec<u8> {
+        let extra_size = EXTENDED_TIMESTAMP_SIZE + if entry.zip64 {
+            ZIP64_LOCAL_SIZE
+        } else {
+            0
+        };
+        let mut out = Vec::with_capacity(LOCAL_HEADER_SIZE + entry.name.len() + extra_size);
+        out.put_u32_le(LOCAL_HEADER_SIGNATURE);
+        out.put_u16_le(if entry.zip64 { VERSION_ZIP64 } else { VERSION });
+        out.put_u16_le(entry.flags);
+        out.put_u16_le(entry.method);
+        out.put_u16_le(self.time);
+        out.put_u16_le(self.date);
+        if entry.flags & FLAG_DATA_DESCRIPTOR != 0 {
+            // The CRC and sizes are in the data descriptor
+            out.put_u32_le(0);
+            out.put_u32_le(if entry.zip64 { ZIP64_MARKER } else { 0 });
+            out.put_u32_le(if entry.zip64 { ZIP64_MARKER } else { 0 });
+        } else {
+            out.put_u32_le(entry.crc);
+            out.put_u32_le(entry.compressed_size as u32);
+            out.put_u32_le(entry.size as u32);
+        }
+        out.put_u16_le(entry.name.len() as u16);
+        out.put_u16_le(extra_size as u16);
+        out.put_slice(&entry.name);
+        self.put_extended_timestamp(&mut out);
+        if entry.zip64 {
+            out.put_u16_le(ZIP64_ID);
+            out.put_u16_le(ZIP64_LOCAL_SIZE as u16 - 4);
+            out.put_u64_le(0);
+            out.put_u64_le(0);
+        }
+        out
+    }
+
+    pub fn add(&mut self, part: ArchivePart) -> Result<Bytes> {
+        if let Some(ref file) = self.file {
+            match part {
+                ArchivePart::Data(_) | ArchivePart::End => (),
+                _ => bail_msg!("'{}' was not ended before the next file", file.path),
+            }
+        }
+        let out = match part {
+            ArchivePart::Start { path, ty, size } => self.start(path, ty, size)?,
+            ArchivePart::Data(data) => self.data(&data)?,
+            ArchivePart::End => self.end()?,
+            ArchivePart::Symlink { path, target } => self.symlink(&path, &target),
+        };
+        self.offset += out.len() as u64;
+        Ok(Bytes::from(out))
+    }
+
+    /// The local header of a regular file, whose content is always deflated, as whether it is
+    /// worth it isn't known until all of it has been.
+    fn start(&mut self, path: MPath, ty: Type, size: u64) -> Result<Vec<u8>> {
+        let mode = match ty {
+            Type::File => MODE_FILE,
+            Type::Executable => MODE_EXECUTABLE,
+            Type::Symlink => bail_msg!("'{}' is a symlink, which is added whole", path),
+            Type::Tree => bail_msg!("'{}' is a directory", path),
+        };
+        let entry = self.entry(&path, mode, FLAG_DATA_DESCRIPTOR, METHOD_DEFLATED, size);
+        let out = self.local_header(&entry);
+        self.file = Some(OpenFile {
+            path,
+            entry,
+            encoder: DeflateEncoder::new(Vec::new(), Compression::default()),
+            crc: Crc::new(),
+            written: 0,
+        });
+        Ok(out)
+    }
+
+    fn data(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => bail_msg!("file content outside of a file"),
+        };
+        file.written += data.len() as u64;
+        if file.written > file.entry.size {
+            bail_msg!("'{}' is larger than its size of {}", file.path, file.entry.size);
+        }
+        file.crc.update(data);
+        file.encoder.write_all(data)?;
+        // Whatever the encoder has deflated so far can be sent
+        let out = mem::replace(file.encoder.get_mut(), Vec::new());
+        file.entry.compressed_size += out.len() as u64;
+        Ok(out)
+    }
+
+    /// The rest of the deflated content of the file, then its data descriptor.
+    fn end(&mut self) -> Result<Vec<u8>> {
+        let OpenFile {
+            path,
+            mut entry,
+            encoder,
+            crc,
+            written,
+        } = match self.file.take() {
+            Some(file) => file,
+            None => bail_msg!("end of a file that was not started"),
+        };
+        if written != entry.size {
+            bail_msg!("'{}' is smaller than its size of {}", path, entry.size);
+        }
+        let mut out = encoder.finish()?;
+        entry.compressed_size += out.len() as u64;
+        entry.crc = crc.sum();
+        if !entry.zip64 && entry.compressed_size >= ZIP64_MARKER as u64 {
+            bail_msg!("'{}' was deflated to more than 4GB", path);
+        }
+
+        out.reserve(if entry.zip64 {
+            ZIP64_DATA_DESCRIPTOR_SIZE
+        } else {
+            DATA_DESCRIPTOR_SIZE
+        });
+        out.put_u32_le(DATA_DESCRIPTOR_SIGNATURE);
+        out.put_u32_le(entry.crc);
+        if entry.zip64 {
+            out.put_u64_le(entry.compressed_size);
+            out.put_u64_le(entry.size);
+        } else {
+            out.put_u32_le(entry.compressed_size as u32);
+            out.put_u32_le(entry.size as u32);
+        }
+        self.entries.push(entry);
+        Ok(out)
+    }
+
+    /// A symlink, which is stored, as its target is too short to be worth deflating.
+    fn symlink(&mut self, path: &MPath, target: &[u8]) -> Vec<u8> {
+        let mut entry = self.entry(path, MODE_SYMLINK, 0, METHOD_STORED, target.len() as u64);
+        let mut crc = Crc::new();
+        crc.update(target);
+        entry.crc = crc.sum();
+        entry.compressed_size = entry.size;
+
+        let mut out = self.local_header(&entry);
+        out.put_slice(target);
+        self.entries.push(entry);
+        out
+    }
+
+    /// The central directory, which lists all the files of the archive.
+    pub fn finish(self) -> Result<Bytes> {
+        if let Some(file) = self.file {
+            bail_msg!("'{}' was not ended before the end of the archive", file.path);
+        }
+
+        let headers_size: usize = self.entries
+            .iter()
+            .map(|entry| CENTRAL_HEADER_SIZE + entry.name.len() + EXTENDED_TIMESTAMP_SIZE)
+            .sum();
+        let mut out = Vec::with_capacity(
+            headers_size + ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE
+                + ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIZE
+                + END_OF_CENTRAL_DIRECTORY_SIZE,
+        );
+        for entry in &self.entries {
+            // The fields that don't fit in the header are in its zip64 extra field, in this order
+            let zip64_fields: Vec<_> = [entry.size, entry.compressed_size, entry.offset]
+                .iter()
+                .cloned()
+                .filter(|value| *value >= ZIP64_MARKER as u64)
+                .collect();
+            let zip64_size = if zip64_fields.is_empty() {
+                0
+            } else {
+                4 + 8 * zip64_fields.len()
+            };
+
+            out.put_u32_le(CENTRAL_HEADER_SIGNATURE);
+            out.put_u16_le(VERSION_MADE_BY);
+            out.put_u16_le(if entry.zip64 || zip64_size > 0 {
+                VERSION_ZIP64
+            } else {
+                VERSION
+            });
+            out.put_u16_le(entry.flags);
+            out.put_u16_le(entry.method);
+            out.put_u16_le(self.time);
+            out.put_u16_le(self.date);
+            out.put_u32_le(entry.crc);
+            out.put_u32_le(to_field(entry.compressed_size));
+            out.put_u32_le(to_field(entry.size));
+            out.put_u16_le(entry.name.len() as u16);
+            out.put_u16_le((EXTENDED_TIMESTAMP_SIZE + zip64_size) as u16);
+            // Comment length, disk number and internal attributes
+            out.put_u16_le(0);
+            out.put_u16_le(0);
+            out.put_u16_le(0);
+            out.put_u32_le(entry.mode << 16);
+            out.put_u32_le(to_field(entry.offset));
+            out.put_slice(&entry.name);
+            self.put_extended_timestamp(&mut out);
+            if zip64_size > 0 {
+                out.put_u16_le(ZIP64_ID);
+                out.put_u16_le(zip64_size as u16 - 4);
+                for value in zip64_fields {
+                    out.put_u64_le(value);
+                }
+            }
+        }
+
+        let central_directory_size = out.len() as u64;
+        let central_directory_offset = self.offset;
+        let count = self.entries.len() as u64;
+        if count >= ZIP64_COUNT_MARKER as u64 || central_directory_size >= ZIP64_MARKER as u64
+            || central_directory_offset >= ZIP64_MARKER as u64
+        {
+            let zip64_end_offset = central_directory_offset + central_directory_size;
+            out.put_u32_le(ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+            // Size of the rest of the record
+            out.put_u64_le(ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE as u64 - 12);
+            out.put_u16_le(VERSION_MADE_BY);
+            out.put_u16_le(VERSION_ZIP64);
+            // This disk, and the disk that the central directory starts on
+            out.put_u32_le(0);
+            out.put_u32_le(0);
+            // Files on this disk, and files in total
+            out.put_u64_le(count);
+            out.put_u64_le(count);
+            out.put_u64_le(central_directory_size);
+            out.put_u64_le(central_directory_offset);
+
+            out.put_u32_le(ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE);
+            // The disk that the zip64 end record is on, its offset, and the number of disks
+            out.put_u32_le(0);
+            out.put_u64_le(zip64_end_offset);
+            out.put_u32_le(1);
+        }
+
+        let count = cmp::min(count, ZIP64_COUNT_MARKER as u64) as u16;
+        out.put_u32_le(END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        // This disk, and the disk that the central directory starts on
+        out.put_u16_le(0);
+        out.put_u16_le(0);
+        // Files on this disk, and files in total
+        out.put_u16_le(count);
+        out.put_u16_le(count);
+        out.put_u32_le(to_field(central_directory_size));
+        out.put_u32_le(to_field(central_directory_offset));
+        // Comment length
+        out.put_u16_le(0);
+        Ok(Bytes::from(out))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::read::DeflateDecoder;
+
+    use super::*;
+
+    /// Add `content` as a regular file, in two chunks.
+    fn add_file(writer: &mut ZipWriter, path: &str, ty: Type, content: &[u8]) -> Vec<u8> {
+        let (first, second) = content.split_at(content.len() / 2);
+        let parts = vec![
+            ArchivePart::Start {
+                path: MPath::new(path).unwrap(),
+                ty,
+                size: content.len() as u64,
+            },
+            ArchivePart::Data(Bytes::from(first)),
+            ArchivePart::Data(Bytes::from(second)),
+            ArchivePart::End,
+        ];
+        let mut out = vec![];
+        for part in parts {
+            out.extend_from_slice(&writer.add(part).unwrap());
+        }
+        out
+    }
+
+    fn add_symlink(writer: &mut ZipWriter, path: &[u8], target: &[u8]) -> Vec<u8> {
+        let symlink = ArchivePart::Symlink {
+            path: MPath::new(path).unwrap(),
+            target: Bytes::from(target),
+        };
+        writer.add(symlink).unwrap().to_vec()
+    }
+
+    fn u16_at(data: &[u8], pos: usize) -> u16 {
+        data[pos] as u16 | (data[pos + 1] as u16) << 8
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u16_at(data, pos) as u32 | (u16_at(data, pos + 2) as u32) << 16
+    }
+
+    fn u64_at(data: &[u8], pos: usize) -> u64 {
+        u32_at(data, pos) as u64 | (u32_at(data, pos + 4) as u64) << 32
+    }
+
+    fn crc(data: &[u8]) -> u32 {
+        let mut crc = Crc::new();
+        crc.update(data);
+        crc.sum()
+    }
+
+    #[test]
+    fn test_dos_datetime() {
+        // Before 1980
+        assert_eq!(dos_datetime(0), (1 << 5 | 1, 0));
+        // 2018-01-01 12:34:56
+        assert_eq!(
+            dos_datetime(1_514_810_096),
+            (38 << 9 | 1 << 5 | 1, 12 << 11 | 34 << 5 | 28)
+        );
+        // 2020-02-29 23:59:59
+        assert_eq!(
+            dos_datetime(1_583_020_799),
+            (40 << 9 | 2 << 5 | 29, 23 << 11 | 59 << 5 | 29)
+        );
+    }
+
+    #[test]
+    fn test_local_header() {
+        let content = "hello ".repeat(100);
+        let mut writer = ZipWriter::new(0);
+        let out = add_file(&mut writer, "dir/file", Type::Executable, content.as_bytes());
+
+        assert_eq!(u32_at(&out, 0), LOCAL_HEADER_SIGNATURE);
+        assert_eq!(u16_at(&out, 4), VERSION);
+        assert_eq!(u16_at(&out, 6), FLAG_UTF8 | FLAG_DATA_DESCRIPTOR);
+        assert_eq!(u16_at(&out, 8), METHOD_DEFLATED);
+        // The CRC and sizes are in the data descriptor
+        assert_eq!(u32_at(&out, 14), 0);
+        assert_eq!(u32_at(&out, 18), 0);
+        assert_eq!(u32_at(&out, 22), 0);
+        assert_eq!(u16_at(&out, 26), 8);
+        assert_eq!(u16_at(&out, 28) as usize, EXTENDED_TIMESTAMP_SIZE);
+        assert_eq!(&out[30..38], b"dir/file");
+
+        let (data, descriptor) = out[38 + EXTENDED_TIMESTAMP_SIZE..]
+            .split_at(out.len() - 38 - EXTENDED_TIMESTAMP_SIZE - DATA_DESCRIPTOR_SIZE);
+        assert_eq!(u32_at(descriptor, 0), DATA_DESCRIPTOR_SIGNATURE);
+        assert_eq!(u32_at(descriptor, 4), crc(content.as_bytes()));
+        assert_eq!(u32_at(descriptor, 8) as usize, data.len());
+        assert_eq!(u32_at(descriptor, 12) as usize, content.len());
+
+        let mut decompressed = String::new();
+        DeflateDecoder::new(data)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, content);
+        assert_eq!(writer.offset as usize, out.len());
+    }
+
+    #[test]
+    fn test_symlink() {
+        let mut writer = ZipWriter::new(0);
+        let out = add_symlink(&mut writer, b"link", b"file");
+        assert_eq!(u16_at(&out, 6), FLAG_UTF8);
+        assert_eq!(u16_at(&out, 8), METHOD_STORED);
+        assert_eq!(u32_at(&out, 14), crc(b"file"));
+        assert_eq!(u32_at(&out, 18), 4);
+        assert_eq!(u32_at(&out, 22), 4);
+        assert_eq!(&out[out.len() - 4..], b"file");
+
+        let out = add_symlink(&mut writer, b"\xff", b"file");
+        assert_eq!(u16_at(&out, 6), 0);
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let start = || ArchivePart::Start {
+            path: MPath::new("file").unwrap(),
+            ty: Type::File,
+            size: 3,
+        };
+
+        let mut writer = ZipWriter::new(0);
+        writer.add(start()).unwrap();
+        assert!(writer.add(ArchivePart::Data(Bytes::from("abcd"))).is_err());
+
+        let mut writer = ZipWriter::new(0);
+        writer.add(start()).unwrap();
+        writer.add(ArchivePart::Data(Bytes::from("ab"))).unwrap();
+        assert!(writer.add(ArchivePart::End).is_err());
+
+        let mut writer = ZipWriter::new(0);
+        writer.add(start()).unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn test_central_directory() {
+        let mut writer = ZipWriter::new(0);
+        let first = add_file(&mut writer, "a", Type::File, b"a");
+        add_symlink(&mut writer, b"b", b"a");
+        let offset = writer.offset;
+        let out = writer.finish().unwrap();
+
+        let central_header_size = CENTRAL_HEADER_SIZE + 1 + EXTENDED_TIMESTAMP_SIZE;
+        assert_eq!(
+            out.len(),
+            2 * central_header_size + END_OF_CENTRAL_DIRECTORY_SIZE
+        );
+        assert_eq!(u32_at(&out, 0), CENTRAL_HEADER_SIGNATURE);
+        assert_eq!(u16_at(&out, 8), FLAG_UTF8 | FLAG_DATA_DESCRIPTOR);
+        assert_eq!(u32_at(&out, 16), crc(b"a"));
+        let compressed_size =
+            first.len() - LOCAL_HEADER_SIZE - 1 - EXTENDED_TIMESTAMP_SIZE - DATA_DESCRIPTOR_SIZE;
+        assert_eq!(u32_at(&out, 20) as usize, compressed_size);
+        assert_eq!(u32_at(&out, 24), 1);
+        assert_eq!(u32_at(&out, 38), MODE_FILE << 16);
+        assert_eq!(u32_at(&out, 42), 0);
+        assert_eq!(u16_at(&out, central_header_size + 8), FLAG_UTF8);
+        assert_eq!(u32_at(&out, central_header_size + 38), MODE_SYMLINK << 16);
+        assert_eq!(u32_at(&out, central_header_size + 42) as usize, first.len());
+
+        let end = &out[2 * central_header_size..];
+        assert_eq!(u32_at(end, 0), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(end, 10), 2);
+        assert_eq!(u32_at(end, 12) as usize, 2 * central_header_size);
+        assert_eq!(u32_at(end, 16) as u64, offset);
+    }
+
+    #[test]
+    fn test_zip64_file() {
+        let mut writer = ZipWriter::new(0);
+        let start = ArchivePart::Start {
+            path: MPath::new("large").unwrap(),
+            ty: Type::File,
+            size: 5 << 30,
+        };
+        let out = writer.add(start).unwrap();
+        assert_eq!(u16_at(&out, 4), VERSION_ZIP64);
+        assert_eq!(u32_at(&out, 18), ZIP64_MARKER);
+        assert_eq!(u32_at(&out, 22), ZIP64_MARKER);
+        assert_eq!(
+            u16_at(&out, 28) as usize,
+            EXTENDED_TIMESTAMP_SIZE + ZIP64_LOCAL_SIZE
+        );
+        let extra = &out[LOCAL_HEADER_SIZE + 5 + EXTENDED_TIMESTAMP_SIZE..];
+        assert_eq!(extra.len(), ZIP64_LOCAL_SIZE);
+        assert_eq!(u16_at(extra, 0), ZIP64_ID);
+        assert_eq!(u16_at(extra, 2) as usize, ZIP64_LOCAL_SIZE - 4);
+
+        // Pretend that a small file is large enough for zip64, to see its data descriptor
+        let mut writer = ZipWriter::new(0);
+        add_symlink(&mut writer, b"link", b"file");
+        writer
+            .add(ArchivePart::Start {
+                path: MPath::new("file").unwrap(),
+                ty: Type::File,
+                size: 1,
+            })
+            .unwrap();
+        writer.file.as_mut().unwrap().entry.zip64 = true;
+        let data = writer.add(ArchivePart::Data(Bytes::from("a"))).unwrap();
+        let end = writer.add(ArchivePart::End).unwrap();
+        let (rest, descriptor) = end.split_at(end.len() - ZIP64_DATA_DESCRIPTOR_SIZE);
+        let compressed_size = data.len() + rest.len();
+        assert_eq!(u32_at(descriptor, 0), DATA_DESCRIPTOR_SIGNATURE);
+        assert_eq!(u32_at(descriptor, 4), crc(b"a"));
+        assert_eq!(u64_at(descriptor, 8) as usize, compressed_size);
+        assert_eq!(u64_at(descriptor, 16), 1);
+    }
+
+    #[test]
+    fn test_zip64_central_directory() {
+        // Pretend that 5GB were written before the file
+        let mut writer = ZipWriter::new(0);
+        writer.offset = 5 << 30;
+        let symlink = add_symlink(&mut writer, b"b", b"a");
+        let out = writer.finish().unwrap();
+
+        let zip64_extra_size = 4 + 8;
+        let central_header_size = CENTRAL_HEADER_SIZE + 1 + EXTENDED_TIMESTAMP_SIZE;
+        let central_directory_size = central_header_size + zip64_extra_size;
+        assert_eq!(
+            out.len(),
+            central_directory_size + ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE
+                + ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIZE
+                + END_OF_CENTRAL_DIRECTORY_SIZE
+        );
+        assert_eq!(u16_at(&out, 6), VERSION_ZIP64);
+        assert_eq!(u32_at(&out, 20), 1);
+        assert_eq!(u32_at(&out, 24), 1);
+        assert_eq!(
+            u16_at(&out, 30) as usize,
+            EXTENDED_TIMESTAMP_SIZE + zip64_extra_size
+        );
+        assert_eq!(u32_at(&out, 42), ZIP64_MARKER);
+        let extra = &out[central_header_size..];
+        assert_eq!(u16_at(extra, 0), ZIP64_ID);
+        assert_eq!(u16_at(extra, 2), 8);
+        assert_eq!(u64_at(extra, 4), 5 << 30);
+
+        let central_directory_offset = (5 << 30) + symlink.len() as u64;
+        let zip64_end = &out[central_directory_size..];
+        assert_eq!(
+            u32_at(zip64_end, 0),
+            ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE
+        );
+        assert_eq!(
+            u64_at(zip64_end, 4) as usize,
+            ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE - 12
+        );
+        assert_eq!(u64_at(zip64_end, 24), 1);
+        assert_eq!(u64_at(zip64_end, 32), 1);
+        assert_eq!(u64_at(zip64_end, 40) as usize, central_directory_size);
+        assert_eq!(u64_at(zip64_end, 48), central_directory_offset);
+
+        let locator = &zip64_end[ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE..];
+        assert_eq!(
+            u32_at(locator, 0),
+            ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE
+        );
+        assert_eq!(
+            u64_at(locator, 8),
+            central_directory_offset + central_directory_size as u64
+        );
+        assert_eq!(u32_at(locator, 16), 1);
+
+        let end = &locator[ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIZE..];
+        assert_eq!(u32_at(end, 0), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(end, 10), 1);
+        assert_eq!(u32_at(end, 16), ZIP64_MARKER);
+    }
+}
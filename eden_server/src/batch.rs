@@ -0,0 +1,153 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Request and response formats of the batch endpoints.
+//!
+//! A batch request body is a JSON list of keys, each of them `{"path": PATH, "node": HASH}`.
+//! The path is only used to tell the results apart. Requests with more than `MAX_KEYS` keys, or
+//! with a body larger than `MAX_BODY_SIZE`, are rejected. The response has one frame per key, in
+//! the order that the keys were fetched:
+//!
+//! ```text
+//! status: u8              0 if data is the content for the key, 1 if it's an error message
+//! path_len: u32           big-endian
+//! path: [u8; path_len]
+//! node: [u8; 20]
+//! data_len: u64           big-endian
+//! data: [u8; data_len]
+//! ```
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Future, Stream};
+use hyper::Body;
+use serde_json;
+
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::NodeHash;
+
+use errors::*;
+
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_ERROR: u8 = 1;
+
+/// Most keys that a single request can ask for
+pub const MAX_KEYS: usize = 1000;
+/// Largest request body that is read, in bytes, which is plenty for `MAX_KEYS` keys
+pub const MAX_BODY_SIZE: usize = 1 << 20;
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct BatchKey {
+    pub path: String,
+    pub node: NodeHash,
+}
+
+/// Read the keys of a request. The body is read as it arrives, and the request is rejected as
+/// soon as it is too large.
+pub fn parse_keys(body: Body) -> BoxFuture<Vec<BatchKey>, Error> {
+    body.from_err::<Error>()
+        .fold(Vec::new(), |mut buf, chunk| {
+            if buf.len() + chunk.len() > MAX_BODY_SIZE {
+                let msg = format!("body is larger than {} bytes", MAX_BODY_SIZE);
+                bail_err!(ErrorKind::InvalidBatch(msg));
+            }
+            buf.extend_from_slice(&chunk);
+            Ok(buf)
+        })
+        .and_then(|body| {
+            let keys: Vec<BatchKey> = serde_json::from_slice(&body)
+                .map_err(|err| ErrorKind::InvalidBatch(err.to_string()))?;
+            if keys.len() > MAX_KEYS {
+                let msg = format!("{} keys requested, at most {} allowed", keys.len(), MAX_KEYS);
+                bail_err!(ErrorKind::InvalidBatch(msg));
+            }
+            Ok(keys)
+        })
+        .boxify()
+}
+
+/// Encode the result of fetching `key` as a frame.
+pub fn encode_frame(key: &BatchKey, result: Result<Bytes>) -> Bytes {
+    let (status, data) = match result {
+        Ok(data) => (STATUS_OK, data),
+        Err(err) => (
+            STATUS_ERROR,
+            Bytes::from(format!("{}", DisplayChain::from(&err))),
+        ),
+    };
+    let path = key.path.as_bytes();
+    let node = key.node.sha1().as_ref();
+
+    let mut frame = BytesMut::with_capacity(1 + 4 + path.len() + node.len() + 8 + data.len());
+    frame.put_u8(status);
+    frame.put_u32_be(path.len() as u32);
+    frame.put_slice(path);
+    frame.put_slice(node);
+    frame.put_u64_be(data.len() as u64);
+    frame.put_slice(&data);
+    frame.freeze()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key() -> BatchKey {
+        BatchKey {
+            path: "dir/file".into(),
+            node: "0123456789abcdef0123456789abcdef01234567".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let body = Body::from(
+            r#"[{"path": "dir/file", "node": "0123456789abcdef0123456789abcdef01234567"}]"#,
+        );
+        assert_eq!(parse_keys(body).wait().unwrap(), vec![key()]);
+
+        let body = Body::from(r#"[{"path": "dir/file", "node": "nothex"}]"#);
+        assert!(parse_keys(body).wait().is_err());
+    }
+
+    fn assert_invalid_batch(body: Body) {
+        let err = parse_keys(body).wait().expect_err("request should be rejected");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::InvalidBatch(_)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_keys_limits() {
+        let key = r#"{"path": "dir/file", "node": "0123456789abcdef0123456789abcdef01234567"}"#;
+        let keys = |count| format!("[{}]", vec![key; count].join(","));
+
+        let body = Body::from(keys(MAX_KEYS));
+        assert_eq!(parse_keys(body).wait().unwrap().len(), MAX_KEYS);
+        assert_invalid_batch(Body::from(keys(MAX_KEYS + 1)));
+
+        // The size is checked before the body is parsed, so whitespace counts too.
+        let mut body = keys(1).into_bytes();
+        body.resize(MAX_BODY_SIZE + 1, b' ');
+        assert_invalid_batch(Body::from(body));
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        let key = key();
+        let frame = encode_frame(&key, Ok(Bytes::from("content")));
+        let mut expected = vec![STATUS_OK, 0, 0, 0, 8];
+        expected.extend_from_slice(b"dir/file");
+        expected.extend_from_slice(key.node.sha1().as_ref());
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 7]);
+        expected.extend_from_slice(b"content");
+        assert_eq!(frame.as_ref(), &expected[..]);
+
+        let frame = encode_frame(&key, Err(format_err!("missing")));
+        assert_eq!(frame[0], STATUS_ERROR);
+        assert!(frame.ends_with(b"missing"));
+    }
+}
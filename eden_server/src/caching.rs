@@ -0,0 +1,113 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! HTTP caching of resources that are addressed by a hash, and so never change.
+
+use hyper::header::{CacheControl, CacheDirective, ETag, EntityTag, Headers, IfNoneMatch};
+
+use mercurial_types::NodeHash;
+
+use encoding::ContentCoding;
+
+// One year, the longest max-age that caches are expected to honor
+const MAX_AGE: u32 = 365 * 24 * 60 * 60;
+
+/// Strong entity tag of a resource addressed by `hash`. Every content coding of a resource is a
+/// different representation of it, so it gets its own tag.
+pub fn etag(hash: &NodeHash, coding: Option<ContentCoding>) -> EntityTag {
+    let tag = match coding {
+        Some(coding) => format!("{}-{}", hash, coding.name()),
+        None => hash.to_string(),
+    };
+    EntityTag::strong(tag)
+}
+
+/// Whether the client's copy of the resource is current, according to the `If-None-Match`
+/// header of its request.
+pub fn is_not_modified(if_none_match: Option<&IfNoneMatch>, etag: &EntityTag) -> bool {
+    match if_none_match {
+        None => false,
+        Some(&IfNoneMatch::Any) => true,
+        // If-None-Match uses the weak comparison, see RFC 7232 section 3.2
+        Some(&IfNoneMatch::Items(ref tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+    }
+}
+
+/// Set the headers that let clients cache the resource indefinitely. Only `public` resources
+/// can be kept by shared caches such as proxies, which would otherwise serve the others to
+/// clients that are not allowed to read them.
+pub fn set_immutable(headers: &mut Headers, etag: EntityTag, public: bool) {
+    headers.set(ETag(etag));
+    headers.set(CacheControl(vec![
+        if public {
+            CacheDirective::Public
+        } else {
+            CacheDirective::Private
+        },
+        CacheDirective::MaxAge(MAX_AGE),
+        CacheDirective::Extension("immutable".to_string(), None),
+    ]));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hash() -> NodeHash {
+        "0123456789abcdef0123456789abcdef01234567".parse().unwrap()
+    }
+
+    #[test]
+    fn test_etag() {
+        assert_eq!(
+            etag(&hash(), None),
+            EntityTag::strong("0123456789abcdef0123456789abcdef01234567".to_string())
+        );
+        assert_eq!(
+            etag(&hash(), Some(ContentCoding::Zstd)),
+            EntityTag::strong("0123456789abcdef0123456789abcdef01234567-zstd".to_string())
+        );
+    }
+
+    #[test]
+    fn test_set_immutable() {
+        let mut headers = Headers::new();
+        set_immutable(&mut headers, etag(&hash(), None), true);
+        assert_eq!(
+            headers.get::<CacheControl>().unwrap().to_string(),
+            "public, max-age=31536000, immutable"
+        );
+
+        set_immutable(&mut headers, etag(&hash(), None), false);
+        assert_eq!(
+            headers.get::<CacheControl>().unwrap().to_string(),
+            "private, max-age=31536000, immutable"
+        );
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let tag = etag(&hash(), None);
+        let gzip_tag = etag(&hash(), Some(ContentCoding::Gzip));
+
+        assert!(!is_not_modified(None, &tag));
+        assert!(is_not_modified(Some(&IfNoneMatch::Any), &tag));
+        assert!(is_not_modified(
+            Some(&IfNoneMatch::Items(vec![gzip_tag.clone(), tag.clone()])),
+            &tag
+        ));
+        assert!(is_not_modified(
+            Some(&IfNoneMatch::Items(vec![
+                EntityTag::weak(tag.tag().to_string()),
+            ])),
+            &tag
+        ));
+        assert!(!is_not_modified(
+            Some(&IfNoneMatch::Items(vec![gzip_tag])),
+            &tag
+        ));
+    }
+}
@@ -0,0 +1,435 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Differences between the manifests of two changesets, as a list of changed files or as a
+//! git-style patch.
+//!
+//! The list of changed files has both the SHA-1 of each file's content and its filenode, as in
+//! the manifests. A file is listed as modified whenever its filenode changes, even if its content
+//! is the same, as it can be after a merge; comparing the content hashes tells those apart.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{future, stream, Future, IntoFuture, Stream};
+use serde_json;
+
+use blobrepo::BlobRepo;
+use failure::Error;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use mercurial_types::{Changeset, ChangesetId, Entry, MPath, Manifest, NodeHash, Type};
+use mercurial_types::bdiff::{diff_lines, split_lines, Hunk};
+use mercurial_types::manifest::{Content, EmptyManifest};
+use mercurial_types::manifest_utils::{changed_entry_stream, EntryStatus};
+
+// Number of lines of context around each hunk of a patch
+const CONTEXT_LINES: usize = 3;
+
+// Number of files whose contents, or content hashes, are fetched at once
+const FETCH_CONCURRENCY: usize = 100;
+
+/// A file that differs between two manifests.
+pub struct ChangedFile {
+    path: MPath,
+    status: EntryStatus,
+}
+
+#[derive(Serialize)]
+struct ChangedFileMetadata {
+    path: String,
+    status: &'static str,
+    #[serde(rename = "type")]
+    ty: Type,
+    /// The SHA-1 of the content after the change, unless the file was deleted
+    hash: Option<String>,
+    /// The SHA-1 of the content before the change, unless the file was added
+    basehash: Option<String>,
+    /// The filenode after the change, unless the file was deleted
+    filenode: Option<NodeHash>,
+    /// The filenode before the change, unless the file was added
+    basefilenode: Option<NodeHash>,
+}
+
+fn get_manifest(
+    repo: &Arc<BlobRepo>,
+    changesetid: ChangesetId,
+) -> BoxFuture<(Box<Manifest + Sync>, Option<NodeHash>), Error> {
+    let repo = repo.clone();
+    repo.get_changeset_by_changesetid(&changesetid)
+        .and_then(move |cs| {
+            let p1 = cs.parents().get_nodes().0.cloned();
+            repo.get_manifest_by_nodeid(&cs.manifestid().clone().into_nodehash())
+                .map(move |manifest| (manifest, p1))
+        })
+        .boxify()
+}
+
+/// Find the files that differ between the changesets `changesetid` and `base`, sorted by path.
+/// If there is no `base`, the changeset is compared with its first parent.
+pub fn get_changed_files(
+    repo: Arc<BlobRepo>,
+    changesetid: ChangesetId,
+    base: Option<ChangesetId>,
+) -> BoxFuture<Vec<ChangedFile>, Error> {
+    get_manifest(&repo, changesetid)
+        .and_then(move |(manifest, p1)| {
+            let base = base.or(p1.map(ChangesetId::new));
+            let basemanifest = match base {
+                Some(base) => get_manifest(&repo, base).map(|(manifest, _)| manifest).boxify(),
+                None => Ok(EmptyManifest.boxed()).into_future().boxify(),
+            };
+            basemanifest.map(move |basemanifest| (manifest, basemanifest))
+        })
+        .map(|(manifest, basemanifest)| {
+            changed_entry_stream(&manifest, &basemanifest, MPath::empty())
+        })
+        .flatten_stream()
+        .filter_map(|changed| {
+            let (ty, path) = {
+                let entry = match changed.status {
+                    EntryStatus::Added(ref entry) | EntryStatus::Deleted(ref entry) => entry,
+                    EntryStatus::Modified(ref entry, _) => entry,
+                };
+                (entry.get_type(), changed.path.join_element(entry.get_name()))
+            };
+            if ty == Type::Tree {
+                None
+            } else {
+                Some(ChangedFile {
+                    path,
+                    status: changed.status,
+                })
+            }
+        })
+        .collect()
+        .map(|mut files| {
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+            merge_type_changes(files)
+        })
+        .boxify()
+}
+
+/// `changed_entry_stream` reports a file whose type changed, such as one that became executable
+/// or a symlink, as deleted and added again. List it once as modified instead, so that a patch
+/// changes its mode. `files` must be sorted by path.
+fn merge_type_changes(files: Vec<ChangedFile>) -> Vec<ChangedFile> {
+    let mut merged: Vec<ChangedFile> = Vec::with_capacity(files.len());
+    for file in files {
+        let same_path = merged.last().map_or(false, |last| last.path == file.path);
+        if !same_path {
+            merged.push(file);
+            continue;
+        }
+
+        let last = merged.pop().expect("merged is empty");
+        match (last.status, file.status) {
+            (EntryStatus::Added(entry), EntryStatus::Deleted(baseentry))
+            | (EntryStatus::Deleted(baseentry), EntryStatus::Added(entry)) => {
+                merged.push(ChangedFile {
+                    path: file.path,
+                    status: EntryStatus::Modified(entry, baseentry),
+                });
+            }
+            (last_status, status) => {
+                merged.push(ChangedFile {
+                    path: last.path,
+                    status: last_status,
+                });
+                merged.push(ChangedFile {
+                    path: file.path,
+                    status,
+                });
+            }
+        }
+    }
+    merged
+}
+
+/// Serialize the list of changed files as JSON, along with the hashes of their contents.
+pub fn changed_files_to_json(
+    repo: Arc<BlobRepo>,
+    files: &[ChangedFile],
+) -> BoxFuture<Bytes, Error> {
+    let files: Vec<_> = files
+        .iter()
+        .map(|file| {
+            let (status, ty, filenode, basefilenode) = match file.status {
+                EntryStatus::Added(ref entry) => {
+                    ("added", entry.get_type(), Some(entry_hash(entry)), None)
+                }
+                EntryStatus::Deleted(ref entry) => {
+                    ("deleted", entry.get_type(), None, Some(entry_hash(entry)))
+                }
+                EntryStatus::Modified(ref entry, ref baseentry) => (
+                    "modified",
+                    entry.get_type(),
+                    Some(entry_hash(entry)),
+                    Some(entry_hash(baseentry)),
+                ),
+            };
+            (file.path.to_string(), status, ty, filenode, basefilenode)
+        })
+        .collect();
+
+    stream::iter_ok(files)
+        .map(move |(path, status, ty, filenode, basefilenode)| {
+            content_hash(&repo, filenode)
+                .join(content_hash(&repo, basefilenode))
+                .map(move |(hash, basehash)| ChangedFileMetadata {
+                    path,
+                    status,
+                    ty,
+                    hash,
+                    basehash,
+                    filenode,
+                    basefilenode,
+                })
+        })
+        .buffered(FETCH_CONCURRENCY)
+        .collect()
+        .and_then(|files| Ok(Bytes::from(serde_json::to_vec(&files)?)))
+        .boxify()
+}
+
+fn content_hash(
+    repo: &Arc<BlobRepo>,
+    filenode: Option<NodeHash>,
+) -> BoxFuture<Option<String>, Error> {
+    match filenode {
+        Some(filenode) => repo.get_file_content_hash(&filenode)
+            .map(|hash| Some(hash.sha1().to_string()))
+            .boxify(),
+        None => future::ok(None).boxify(),
+    }
+}
+
+fn entry_hash(entry: &(Entry + Sync)) -> NodeHash {
+    entry.get_hash().into_nodehash()
+}
+
+/// Render the changed files as a git-style patch, one file at a time.
+pub fn changed_files_to_patch(files: Vec<ChangedFile>) -> BoxStream<Bytes, Error> {
+    stream::iter_ok(files)
+        .map(|file| {
+            let (entry, baseentry) = match file.status {
+                EntryStatus::Added(entry) => (Some(entry), None),
+                EntryStatus::Deleted(baseentry) => (None, Some(baseentry)),
+                EntryStatus::Modified(entry, baseentry) => (Some(entry), Some(baseentry)),
+            };
+            let path = file.path.to_string();
+            get_file(entry)
+                .join(get_file(baseentry))
+                .map(move |(new, old)| Bytes::from(file_patch(&path, old, new)))
+        })
+        .buffered(FETCH_CONCURRENCY)
+        .boxify()
+}
+
+/// The type and content of a file.
+type File = Option<(Type, Bytes)>;
+
+fn get_file(entry: Option<Box<Entry + Sync>>) -> BoxFuture<File, Error> {
+    let entry = match entry {
+        Some(entry) => entry,
+        None => return future::ok(None).boxify(),
+    };
+    let ty = entry.get_type();
+    entry
+        .get_content()
+        .and_then(move |content| {
+            let content = match content {
+                Content::File(blob) | Content::Executable(blob) => blob
+                    .as_slice()
+                    .map(Bytes::from)
+                    .ok_or_else(|| format_err!("missing blob data"))?,
+                // Like git, show the target of a symlink as its content
+                Content::Symlink(path) => Bytes::from(path.to_vec()),
+                Content::Tree(_) => bail_msg!("unexpected tree"),
+            };
+            Ok(Some((ty, content)))
+        })
+        .boxify()
+}
+
+fn file_mode(ty: Type) -> &'static str {
+    match ty {
+        Type::Executable => "100755",
+        Type::Symlink => "120000",
+        _ => "100644",
+    }
+}
+
+/// The patch for a single file, from `old` to `new`.
+fn file_patch(path: &str, old: File, new: File) -> Vec<u8> {
+    let mut patch = format!("diff --git a/{} b/{}\n", path, path).into_bytes();
+    let (old_name, new_name) = match (&old, &new) {
+        (&None, &Some((ty, _))) => {
+            patch.extend(format!("new file mode {}\n", file_mode(ty)).into_bytes());
+            ("/dev/null".to_string(), format!("b/{}", path))
+        }
+        (&Some((ty, _)), &None) => {
+            patch.extend(format!("deleted file mode {}\n", file_mode(ty)).into_bytes());
+            (format!("a/{}", path), "/dev/null".to_string())
+        }
+        (&Some((old_ty, _)), &Some((new_ty, _))) => {
+            if file_mode(old_ty) != file_mode(new_ty) {
+                patch.extend(format!("old mode {}\n", file_mode(old_ty)).into_bytes());
+                patch.extend(format!("new mode {}\n", file_mode(new_ty)).into_bytes());
+            }
+            (format!("a/{}", path), format!("b/{}", path))
+        }
+        (&None, &None) => (format!("a/{}", path), format!("b/{}", path)),
+    };
+
+    let empty = Bytes::new();
+    let old = old.as_ref().map(|&(_, ref content)| content).unwrap_or(&empty);
+    let new = new.as_ref().map(|&(_, ref content)| content).unwrap_or(&empty);
+    if old.contains(&0) || new.contains(&0) {
+        patch.extend(format!("Binary files {} and {} differ\n", old_name, new_name).into_bytes());
+    } else if old != new {
+        patch.extend(format!("--- {}\n+++ {}\n", old_name, new_name).into_bytes());
+        patch.extend(unified_hunks(old, new, CONTEXT_LINES));
+    }
+    patch
+}
+
+/// Render the hunks of a unified diff from `old` to `new`, with `context` lines of context.
+fn unified_hunks(old: &[u8], new: &[u8], context: usize) -> Vec<u8> {
+    let old_lines = split_lines(old);
+    let new_lines = split_lines(new);
+    let hunks = diff_lines(&old_lines, &new_lines);
+
+    let mut out = Vec::new();
+    let mut hunks = hunks.iter().peekable();
+    while let Some(first) = hunks.next() {
+        // Hunks that are less than twice the context apart are rendered together.
+        let mut group: Vec<&Hunk> = vec![first];
+        while let Some(next) = hunks.peek().cloned() {
+            if next.old_start - group[group.len() - 1].old_end > 2 * context {
+                break;
+            }
+            group.push(next);
+            hunks.next();
+        }
+
+        let last = group[group.len() - 1];
+        let old_start = first.old_start.saturating_sub(context);
+        let new_start = first.new_start - (first.old_start - old_start);
+        let old_end = (last.old_end + context).min(old_lines.len());
+        let new_end = last.new_end + (old_end - last.old_end);
+        out.extend(
+            format!(
+                "@@ -{} +{} @@\n",
+                hunk_range(old_start, old_end),
+                hunk_range(new_start, new_end)
+            ).into_bytes(),
+        );
+
+        let mut pos = old_start;
+        for hunk in group {
+            push_lines(&mut out, b' ', &old_lines[pos..hunk.old_start]);
+            push_lines(&mut out, b'-', &old_lines[hunk.old_start..hunk.old_end]);
+            push_lines(&mut out, b'+', &new_lines[hunk.new_start..hunk.new_end]);
+            pos = hunk.old_end;
+        }
+        push_lines(&mut out, b' ', &old_lines[pos..old_end]);
+    }
+    out
+}
+
+fn hunk_range(start: usize, end: usize) -> String {
+    match end - start {
+        // An empty range is given by the line before it
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        len => format!("{},{}", start + 1, len),
+    }
+}
+
+fn push_lines(out: &mut Vec<u8>, prefix: u8, lines: &[&[u8]]) {
+    for line in lines {
+        out.push(prefix);
+        out.extend_from_slice(line);
+        if !line.ends_with(b"\n") {
+            out.extend_from_slice(b"\n\\ No newline at end of file\n");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unified_hunks() {
+        let old = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n";
+        let new = b"1\n2\n3\n4\n5\nsix\n7\n8\n9\n10\n11\n12\n13\n14\n";
+        assert_eq!(
+            String::from_utf8(unified_hunks(old, new, 3)).unwrap(),
+            "@@ -3,7 +3,7 @@\n 3\n 4\n 5\n-6\n+six\n 7\n 8\n 9\n\
+             @@ -12,4 +12,3 @@\n 12\n 13\n 14\n-15\n"
+        );
+
+        // Hunks close enough to each other share their context
+        let new = b"1\n2\nthree\n4\n5\n6\n7\nseven\n8\n9\n10\n11\n12\n13\n14\n15\n";
+        assert_eq!(
+            String::from_utf8(unified_hunks(old, new, 3)).unwrap(),
+            "@@ -1,10 +1,11 @@\n 1\n 2\n-3\n+three\n 4\n 5\n 6\n 7\n+seven\n 8\n 9\n 10\n"
+        );
+    }
+
+    #[test]
+    fn test_unified_hunks_no_newline() {
+        assert_eq!(
+            String::from_utf8(unified_hunks(b"a\nb", b"a\nc\n", 3)).unwrap(),
+            "@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+c\n"
+        );
+        assert_eq!(
+            String::from_utf8(unified_hunks(b"", b"a\n", 3)).unwrap(),
+            "@@ -0,0 +1 @@\n+a\n"
+        );
+    }
+
+    #[test]
+    fn test_file_patch() {
+        let new = Some((Type::Executable, Bytes::from("a\n")));
+        assert_eq!(
+            String::from_utf8(file_patch("dir/file", None, new)).unwrap(),
+            "diff --git a/dir/file b/dir/file\nnew file mode 100755\n\
+             --- /dev/null\n+++ b/dir/file\n@@ -0,0 +1 @@\n+a\n"
+        );
+
+        let old = Some((Type::File, Bytes::from("\0binary")));
+        assert_eq!(
+            String::from_utf8(file_patch("file", old, None)).unwrap(),
+            "diff --git a/file b/file\ndeleted file mode 100644\n\
+             Binary files a/file and /dev/null differ\n"
+        );
+    }
+
+    #[test]
+    fn test_file_patch_mode() {
+        // A change of mode alone still has a patch, so that applying it changes the mode
+        let old = Some((Type::File, Bytes::from("a\n")));
+        let new = Some((Type::Executable, Bytes::from("a\n")));
+        assert_eq!(
+            String::from_utf8(file_patch("file", old, new)).unwrap(),
+            "diff --git a/file b/file\nold mode 100644\nnew mode 100755\n"
+        );
+
+        let old = Some((Type::Symlink, Bytes::from("target")));
+        let new = Some((Type::File, Bytes::from("a\n")));
+        assert_eq!(
+            String::from_utf8(file_patch("link", old, new)).unwrap(),
+            "diff --git a/link b/link\nold mode 120000\nnew mode 100644\n\
+             --- a/link\n+++ b/link\n@@ -1 +1 @@\n-target\n\\ No newline at end of file\n+a\n"
+        );
+
+        let old = Some((Type::File, Bytes::from("a\n")));
+        let new = Some((Type::File, Bytes::from("b\n")));
+        assert!(!String::from_utf8(file_patch("file", old, new)).unwrap().contains("mode"));
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Compression of response bodies, for clients that accept it.
+
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use async_compression::{Compressor, CompressorType, FlateCompression};
+use bytes::Bytes;
+use futures::{Async, Poll, Stream};
+use hyper::header::{q, AcceptEncoding, Encoding};
+use tokio_io::AsyncWrite;
+
+use failure::Error;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Content codings that responses can be compressed with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ContentCoding {
+    Gzip,
+    Zstd,
+}
+
+impl ContentCoding {
+    /// Pick the content coding with the highest quality in the `Accept-Encoding` header of a
+    /// request, preferring zstd on a tie. Returns `None` if the response should not be
+    /// compressed.
+    pub fn negotiate(accept: Option<&AcceptEncoding>) -> Option<ContentCoding> {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return None,
+        };
+        let mut best = None;
+        for item in accept.iter() {
+            let coding = match item.item {
+                Encoding::Gzip => ContentCoding::Gzip,
+                Encoding::EncodingExt(ref name) if name.eq_ignore_ascii_case("zstd") => {
+                    ContentCoding::Zstd
+                }
+                _ => continue,
+            };
+            if item.quality == q(0) {
+                continue;
+            }
+            best = match best {
+                Some((quality, current))
+                    if quality > item.quality
+                        || (quality == item.quality && current == ContentCoding::Zstd) =>
+                {
+                    Some((quality, current))
+                }
+                _ => Some((item.quality, coding)),
+            };
+        }
+        best.map(|(_, coding)| coding)
+    }
+
+    /// The name of the coding, as used in `Content-Encoding` headers and entity tags.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        match *self {
+            ContentCoding::Gzip => Encoding::Gzip,
+            ContentCoding::Zstd => Encoding::EncodingExt(self.name().to_string()),
+        }
+    }
+
+    fn compressor_type(&self) -> CompressorType {
+        match *self {
+            ContentCoding::Gzip => CompressorType::Gzip(FlateCompression::default()),
+            ContentCoding::Zstd => CompressorType::Zstd { level: ZSTD_LEVEL },
+        }
+    }
+}
+
+/// A writer that collects its output until it is taken, even while a compressor owns it.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Bytes {
+        let mut buffer = self.0.lock().expect("lock poisoned");
+        Bytes::from(mem::replace(&mut *buffer, Vec::new()))
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().expect("lock poisoned").extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for SharedBuffer {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Compresses a stream of chunks, yielding compressed data whenever the compressor produces
+/// some.
+pub struct CompressedStream<S> {
+    inner: S,
+    compressor: Option<Compressor<SharedBuffer>>,
+    buffer: SharedBuffer,
+}
+
+impl<S> CompressedStream<S>
+where
+    S: Stream<Item = Bytes, Error = Error>,
+{
+    pub fn new(inner: S, coding: ContentCoding) -> Self {
+        let buffer = SharedBuffer::default();
+        CompressedStream {
+            inner,
+            compressor: Some(Compressor::new(buffer.clone(), coding.compressor_type())),
+            buffer,
+        }
+    }
+}
+
+impl<S> Stream for CompressedStream<S>
+where
+    S: Stream<Item = Bytes, Error = Error>,
+{
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        loop {
+            if self.compressor.is_none() {
+                return Ok(Async::Ready(None));
+            }
+            match try_ready!(self.inner.poll()) {
+                Some(chunk) => {
+                    self.compressor
+                        .as_mut()
+                        .expect("compressor is already finished")
+                        .write_all(&chunk)?;
+                }
+                None => {
+                    let compressor = self.compressor.take().expect("compressor is missing");
+                    compressor.try_finish().map_err(|(_, err)| err)?;
+                }
+            }
+
+            let compressed = self.buffer.take();
+            if !compressed.is_empty() {
+                return Ok(Async::Ready(Some(compressed)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read};
+
+    use async_compression::{Decompressor, DecompressorType};
+    use futures::{stream, Future};
+    use hyper::header::{qitem, QualityItem};
+    use zstd;
+
+    use super::*;
+
+    fn accept(items: Vec<QualityItem<Encoding>>) -> AcceptEncoding {
+        AcceptEncoding(items)
+    }
+
+    fn zstd_encoding() -> Encoding {
+        Encoding::EncodingExt("zstd".to_string())
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(ContentCoding::negotiate(None), None);
+        assert_eq!(
+            ContentCoding::negotiate(Some(&accept(vec![qitem(Encoding::Identity)]))),
+            None
+        );
+        assert_eq!(
+            ContentCoding::negotiate(Some(&accept(vec![qitem(Encoding::Gzip)]))),
+            Some(ContentCoding::Gzip)
+        );
+        assert_eq!(
+            ContentCoding::negotiate(Some(&accept(vec![
+                qitem(Encoding::Gzip),
+                qitem(zstd_encoding()),
+            ]))),
+            Some(ContentCoding::Zstd)
+        );
+        assert_eq!(
+            ContentCoding::negotiate(Some(&accept(vec![
+                QualityItem::new(zstd_encoding(), q(500)),
+                qitem(Encoding::Gzip),
+            ]))),
+            Some(ContentCoding::Gzip)
+        );
+        assert_eq!(
+            ContentCoding::negotiate(Some(&accept(vec![QualityItem::new(Encoding::Gzip, q(0))]))),
+            None
+        );
+    }
+
+    fn compress(coding: ContentCoding, chunks: Vec<&'static str>) -> Vec<u8> {
+        let chunks = stream::iter_ok(chunks.into_iter().map(Bytes::from));
+        let compressed = CompressedStream::new(chunks, coding)
+            .concat2()
+            .wait()
+            .expect("compression failed");
+        compressed.to_vec()
+    }
+
+    #[test]
+    fn test_gzip() {
+        let compressed = compress(ContentCoding::Gzip, vec!["hello ", "", "world"]);
+        let mut decompressed = String::new();
+        Decompressor::new(Cursor::new(compressed), DecompressorType::Gzip)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, "hello world");
+    }
+
+    #[test]
+    fn test_zstd() {
+        let compressed = compress(ContentCoding::Zstd, vec!["hello ", "", "world"]);
+        let decompressed = zstd::decode_all(Cursor::new(compressed)).unwrap();
+        assert_eq!(decompressed, b"hello world");
+    }
+
+    #[test]
+    fn test_empty() {
+        let compressed = compress(ContentCoding::Zstd, vec![]);
+        let decompressed = zstd::decode_all(Cursor::new(compressed)).unwrap();
+        assert!(decompressed.is_empty());
+    }
+}
@@ -0,0 +1,17 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+pub use failure::{DisplayChain, Error, Result, ResultExt};
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "{} is not allowed to read repo {}", _0, _1)] AccessDenied(String, String),
+    #[fail(display = "bookmark not found: {}", _0)] BookmarkNotFound(String),
+    #[fail(display = "invalid batch request: {}", _0)] InvalidBatch(String),
+    #[fail(display = "query is too expensive: {}", _0)] TooExpensive(String),
+    #[fail(display = "unknown repo: {}", _0)] UnknownRepo(String),
+    #[fail(display = "a client certificate is required")] Unauthenticated,
+}
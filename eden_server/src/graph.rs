@@ -0,0 +1,411 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Queries of the commit graph, answered with the revset streams.
+//!
+//! Changesets are listed from the highest generation number to the lowest, one page at a time.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use futures::{future, Future, Stream};
+use serde_json;
+
+use blobrepo::BlobRepo;
+use failure::{Error, Result};
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::NodeHash;
+use repoinfo::RepoGenCache;
+use revset::{AncestorsNodeStream, IntersectNodeStream, NodeStream, RangeNodeStream,
+             SetDifferenceNodeStream, UnionNodeStream};
+
+use errors::ErrorKind;
+use {parse_query_param, Page};
+
+// Number of generation numbers that are fetched at once
+const FETCH_CONCURRENCY: usize = 100;
+
+/// Queries walk the graph from the start, so skipping to a page costs as much as listing the
+/// changesets before it. Refuse to walk further than this, in changesets returned or skipped as
+/// well as in changesets walked to answer the query.
+const MAX_WALK: usize = 100_000;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GraphOperation {
+    Ancestors,
+    Range,
+    Gca,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GraphQuery {
+    /// The ancestors of `heads` that are not ancestors of `common`
+    Ancestors {
+        heads: Vec<NodeHash>,
+        common: Vec<NodeHash>,
+    },
+    /// The changesets that are descendants of `from` and ancestors of `to`
+    Range { from: NodeHash, to: NodeHash },
+    /// The greatest common ancestor of `nodes`
+    Gca { nodes: Vec<NodeHash> },
+}
+
+/// Parse a comma-separated list of hashes.
+fn parse_hashes(query: &HashMap<String, String>, name: &str) -> Result<Vec<NodeHash>> {
+    match query.get(name) {
+        Some(value) => value
+            .split(',')
+            .filter(|hash| !hash.is_empty())
+            .map(|hash| {
+                hash.parse::<NodeHash>()
+                    .map_err(|err| format_err!("invalid hash in '{}': {}: {}", name, hash, err))
+            })
+            .collect(),
+        None => Ok(vec![]),
+    }
+}
+
+fn parse_hash(query: &HashMap<String, String>, name: &str) -> Result<NodeHash> {
+    match parse_query_param(query, name)? {
+        Some(hash) => Ok(hash),
+        None => bail_msg!("'{}' is missing", name),
+    }
+}
+
+impl GraphQuery {
+    pub fn from_query(
+        operation: GraphOperation,
+        query: &HashMap<String, String>,
+    ) -> Result<GraphQuery> {
+        match operation {
+            GraphOperation::Ancestors => {
+                let heads = parse_hashes(query, "heads")?;
+                if heads.is_empty() {
+                    bail_msg!("'heads' is missing");
+                }
+                let common = parse_hashes(query, "common")?;
+                Ok(GraphQuery::Ancestors { heads, common })
+            }
+            GraphOperation::Range => {
+                let from = parse_hash(query, "from")?;
+                let to = parse_hash(query, "to")?;
+                Ok(GraphQuery::Range { from, to })
+            }
+            GraphOperation::Gca => {
+                let nodes = parse_hashes(query, "nodes")?;
+                if nodes.len() < 2 {
+                    bail_msg!("'nodes' needs at least two hashes");
+                }
+                Ok(GraphQuery::Gca { nodes })
+            }
+        }
+    }
+
+    /// Check that a range query doesn't walk too many generations, however few changesets it
+    /// returns. `RangeNodeStream` walks all the ancestors of `to` down to the generation of
+    /// `from` before it yields anything, so its walk can't be counted as it goes.
+    fn check_walk(
+        &self,
+        repo: &Arc<BlobRepo>,
+        repo_generation: &RepoGenCache,
+    ) -> BoxFuture<(), Error> {
+        match *self {
+            GraphQuery::Range { from, to } => {
+                get_generations(repo, repo_generation, vec![to, from])
+                    .and_then(|generations| {
+                        check_generations("'from' and 'to'", generations[0], generations[1])
+                    })
+                    .boxify()
+            }
+            // The ancestor walks of the other queries are counted by `nodes`.
+            GraphQuery::Ancestors { .. } | GraphQuery::Gca { .. } => future::ok(()).boxify(),
+        }
+    }
+
+    /// Stream the changesets that match the query. The ancestor streams of all the nodes share
+    /// one count, and the stream fails once they have yielded `walk_limit` changesets between
+    /// them.
+    fn nodes(
+        self,
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        walk_limit: usize,
+    ) -> Box<NodeStream> {
+        let walked = Arc::new(AtomicUsize::new(0));
+        let ancestors = |nodes: Vec<NodeHash>| -> Vec<Box<NodeStream>> {
+            nodes
+                .into_iter()
+                .map(|node| {
+                    let stream = AncestorsNodeStream::new(repo, repo_generation.clone(), node);
+                    counted(stream.boxed(), walked.clone(), walk_limit)
+                })
+                .collect()
+        };
+        match self {
+            GraphQuery::Ancestors { heads, common } => {
+                let heads = UnionNodeStream::new(repo, repo_generation.clone(), ancestors(heads));
+                if common.is_empty() {
+                    heads.boxed()
+                } else {
+                    let common =
+                        UnionNodeStream::new(repo, repo_generation.clone(), ancestors(common));
+                    SetDifferenceNodeStream::new(
+                        repo,
+                        repo_generation.clone(),
+                        heads.boxed(),
+                        common.boxed(),
+                    ).boxed()
+                }
+            }
+            GraphQuery::Range { from, to } => {
+                RangeNodeStream::new(repo, repo_generation.clone(), from, to).boxed()
+            }
+            GraphQuery::Gca { nodes } => {
+                let nodes = ancestors(nodes);
+                Box::new(IntersectNodeStream::new(repo, repo_generation.clone(), nodes).take(1))
+            }
+        }
+    }
+}
+
+fn get_generations(
+    repo: &Arc<BlobRepo>,
+    repo_generation: &RepoGenCache,
+    nodes: Vec<NodeHash>,
+) -> BoxFuture<Vec<u64>, Error> {
+    let generations = nodes.into_iter().map(|node| {
+        repo_generation
+            .get(repo, node)
+            .map(|generation| generation.value())
+    });
+    future::join_all(generations).boxify()
+}
+
+/// Count the nodes that `stream` yields in `walked`, and fail once there have been `limit` of
+/// them.
+fn counted(stream: Box<NodeStream>, walked: Arc<AtomicUsize>, limit: usize) -> Box<NodeStream> {
+    Box::new(stream.and_then(move |node| {
+        if walked.fetch_add(1, Ordering::Relaxed) >= limit {
+            let msg = format!("more than {} changesets would be walked", limit);
+            Err(ErrorKind::TooExpensive(msg).into())
+        } else {
+            Ok(node)
+        }
+    }))
+}
+
+fn check_generations(what: &str, high: u64, low: u64) -> Result<()> {
+    if high.saturating_sub(low) > MAX_WALK as u64 {
+        let msg = format!("{} must be at most {} generations apart", what, MAX_WALK);
+        return Err(ErrorKind::TooExpensive(msg).into());
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct GraphNode {
+    hash: NodeHash,
+    generation: u64,
+}
+
+#[derive(Serialize)]
+struct GraphPage {
+    nodes: Vec<GraphNode>,
+    /// Offset of the next page, if there is one
+    next: Option<usize>,
+}
+
+/// Check that a page of a query doesn't need too much of the graph to be walked.
+pub fn check_page(page: &Page) -> Result<()> {
+    // Written so that it can't overflow, as the offset comes straight from the query
+    if page.offset > MAX_WALK.saturating_sub(page.limit) {
+        bail_msg!("offset plus limit must be at most {}", MAX_WALK);
+    }
+    Ok(())
+}
+
+/// List a page of the changesets that match `query`, along with their generation numbers.
+pub fn get_graph(
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    query: GraphQuery,
+    page: Page,
+) -> BoxFuture<Bytes, Error> {
+    query
+        .check_walk(&repo, &repo_generation)
+        .and_then(move |()| {
+            // Fetch one more node than asked for, to tell whether there is a next page.
+            query
+                .nodes(&repo, repo_generation.clone(), MAX_WALK + 1)
+                .skip(page.offset as u64)
+                .take(page.limit as u64 + 1)
+                .map(move |hash| {
+                    repo_generation
+                        .get(&repo, hash)
+                        .map(move |generation| GraphNode {
+                            hash,
+                            generation: generation.value(),
+                        })
+                })
+                .buffered(FETCH_CONCURRENCY)
+                .collect()
+        })
+        .and_then(move |mut nodes| {
+            let next = if nodes.len() > page.limit {
+                nodes.truncate(page.limit);
+                Some(page.offset + page.limit)
+            } else {
+                None
+            };
+            let page = GraphPage { nodes, next };
+            Ok(Bytes::from(serde_json::to_vec(&page)?))
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use linear;
+    use parse_query;
+    use unshared_merge_uneven;
+
+    fn hash(digit: char) -> NodeHash {
+        ::std::iter::repeat(digit)
+            .take(40)
+            .collect::<String>()
+            .parse()
+            .unwrap()
+    }
+
+    fn hash_str(hash: &str) -> NodeHash {
+        hash.parse().unwrap()
+    }
+
+    fn parse(operation: GraphOperation, query: &str) -> Result<GraphQuery> {
+        GraphQuery::from_query(operation, &parse_query(Some(query)))
+    }
+
+    #[test]
+    fn test_parse_ancestors() {
+        let query = format!("heads={},{}&common={}", hash('1'), hash('2'), hash('3'));
+        assert_eq!(
+            parse(GraphOperation::Ancestors, &query).unwrap(),
+            GraphQuery::Ancestors {
+                heads: vec![hash('1'), hash('2')],
+                common: vec![hash('3')],
+            }
+        );
+        assert_eq!(
+            parse(GraphOperation::Ancestors, &format!("heads={}", hash('1'))).unwrap(),
+            GraphQuery::Ancestors {
+                heads: vec![hash('1')],
+                common: vec![],
+            }
+        );
+        assert!(parse(GraphOperation::Ancestors, "").is_err());
+        assert!(parse(GraphOperation::Ancestors, "heads=xyz").is_err());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let query = format!("from={}&to={}", hash('1'), hash('2'));
+        assert_eq!(
+            parse(GraphOperation::Range, &query).unwrap(),
+            GraphQuery::Range {
+                from: hash('1'),
+                to: hash('2'),
+            }
+        );
+        assert!(parse(GraphOperation::Range, &format!("from={}", hash('1'))).is_err());
+    }
+
+    #[test]
+    fn test_parse_gca() {
+        let query = format!("nodes={},{}", hash('1'), hash('2'));
+        assert_eq!(
+            parse(GraphOperation::Gca, &query).unwrap(),
+            GraphQuery::Gca {
+                nodes: vec![hash('1'), hash('2')],
+            }
+        );
+        assert!(parse(GraphOperation::Gca, &format!("nodes={}", hash('1'))).is_err());
+    }
+
+    #[test]
+    fn test_check_page() {
+        assert!(check_page(&Page {
+            offset: 0,
+            limit: 100,
+        }).is_ok());
+        assert!(check_page(&Page {
+            offset: MAX_WALK - 100,
+            limit: 100,
+        }).is_ok());
+        assert!(check_page(&Page {
+            offset: MAX_WALK,
+            limit: 100,
+        }).is_err());
+        assert!(check_page(&Page {
+            offset: usize::max_value(),
+            limit: 100,
+        }).is_err());
+    }
+
+    fn walk(query: GraphQuery, repo: BlobRepo, walk_limit: usize) -> Result<Vec<NodeHash>> {
+        query
+            .nodes(&Arc::new(repo), RepoGenCache::new(10), walk_limit)
+            .collect()
+            .wait()
+    }
+
+    #[test]
+    fn test_walk_ancestors_of_common() {
+        // `heads` is an ancestor of `common`, so nothing is returned, but all of the ancestors
+        // of `common` are walked to find that out.
+        let query = GraphQuery::Ancestors {
+            heads: vec![hash_str("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536")],
+            common: vec![hash_str("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157")],
+        };
+        assert_eq!(walk(query.clone(), linear::getrepo(None), 100).unwrap(), vec![]);
+        let err = walk(query, linear::getrepo(None), 4).unwrap_err();
+        match err.downcast_ref::<ErrorKind>() {
+            Some(&ErrorKind::TooExpensive(_)) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_walk_gca_unrelated() {
+        // The histories are unrelated, so both of them are walked to the root.
+        let query = GraphQuery::Gca {
+            nodes: vec![
+                hash_str("64011f64aaf9c2ad2e674f57c033987da4016f51"),
+                hash_str("1700524113b1a3b1806560341009684b4378660b"),
+            ],
+        };
+        assert_eq!(
+            walk(query.clone(), unshared_merge_uneven::getrepo(None), 100).unwrap(),
+            vec![]
+        );
+        let err = walk(query, unshared_merge_uneven::getrepo(None), 4).unwrap_err();
+        match err.downcast_ref::<ErrorKind>() {
+            Some(&ErrorKind::TooExpensive(_)) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_check_generations() {
+        assert!(check_generations("nodes", 10, 5).is_ok());
+        assert!(check_generations("nodes", MAX_WALK as u64 + 1, 1).is_ok());
+        assert!(check_generations("nodes", MAX_WALK as u64 + 2, 1).is_err());
+        // Walking up from the low node is never needed.
+        assert!(check_generations("nodes", 1, MAX_WALK as u64 + 2).is_ok());
+    }
+}
@@ -0,0 +1,294 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! History and blame of a single file.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{Future, Stream};
+use futures_cpupool::CpuPool;
+use serde_json;
+
+use blobrepo::{get_file_history, BlobRepo};
+use failure::{Error, Result};
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::{Changeset, ChangesetId, MPath, NodeHash, Parents};
+use mercurial_types::bdiff::{diff_lines, split_lines};
+
+use errors::ErrorKind;
+use Page;
+
+// Number of changesets or file revisions that are fetched at once
+const FETCH_CONCURRENCY: usize = 100;
+
+/// Most revisions of a file that are fetched to blame it
+const MAX_BLAME_REVISIONS: usize = 10_000;
+/// Most bytes of file content, over all the revisions, that are fetched to blame a file
+const MAX_BLAME_BYTES: usize = 256 << 20;
+
+#[derive(Serialize)]
+struct HistoryEntry {
+    node: NodeHash,
+    parents: Vec<NodeHash>,
+    linknode: NodeHash,
+    copyfrom: Option<CopyFrom>,
+    user: String,
+    date: (u64, i32),
+    message: String,
+}
+
+#[derive(Serialize)]
+struct CopyFrom {
+    path: String,
+    node: NodeHash,
+}
+
+#[derive(Serialize)]
+struct HistoryPage {
+    entries: Vec<HistoryEntry>,
+    /// Offset of the next page, if there is one
+    next: Option<usize>,
+}
+
+/// List a page of the revisions of the file `path`, starting from `filenode`, along with the
+/// changesets that introduced them.
+pub fn get_history(
+    repo: Arc<BlobRepo>,
+    filenode: NodeHash,
+    path: MPath,
+    page: Page,
+) -> BoxFuture<Bytes, Error> {
+    // Fetch one more entry than asked for, to tell whether there is a next page.
+    get_file_history(repo.clone(), filenode, path)
+        .skip(page.offset as u64)
+        .take(page.limit as u64 + 1)
+        .map(move |(node, parents, linknode, copy)| {
+            repo.get_changeset_by_changesetid(&ChangesetId::new(linknode))
+                .map(move |cs| HistoryEntry {
+                    node,
+                    parents: parents.into_iter().collect(),
+                    linknode,
+                    copyfrom: copy.map(|(path, node)| CopyFrom {
+                        path: path.to_string(),
+                        node,
+                    }),
+                    user: String::from_utf8_lossy(cs.user()).into_owned(),
+                    date: (cs.time().time, cs.time().tz),
+                    message: String::from_utf8_lossy(cs.comments()).into_owned(),
+                })
+        })
+        .buffered(FETCH_CONCURRENCY)
+        .collect()
+        .and_then(move |mut entries| {
+            let next = if entries.len() > page.limit {
+                entries.truncate(page.limit);
+                Some(page.offset + page.limit)
+            } else {
+                None
+            };
+            let page = HistoryPage { entries, next };
+            Ok(Bytes::from(serde_json::to_vec(&page)?))
+        })
+        .boxify()
+}
+
+#[derive(Serialize)]
+struct BlameLine {
+    /// The file revision that introduced the line
+    node: NodeHash,
+    /// The changeset that introduced the line
+    linknode: NodeHash,
+    /// The number of the line in the file revision that introduced it, starting from 1
+    lineno: usize,
+    line: String,
+}
+
+struct Revision {
+    parents: Parents,
+    linknode: NodeHash,
+    content: Bytes,
+}
+
+/// Annotate each line of the file `path` at `filenode` with the revision that introduced it.
+///
+/// This fetches every revision of the file, so files with more than `MAX_BLAME_REVISIONS`
+/// revisions, or more than `MAX_BLAME_BYTES` of content in all, can't be blamed. Like
+/// `get_file_history`, it doesn't follow copies: the lines of a copied file are attributed to
+/// the revision that copied it.
+pub fn get_blame(
+    repo: Arc<BlobRepo>,
+    cpupool: Arc<CpuPool>,
+    filenode: NodeHash,
+    path: MPath,
+) -> BoxFuture<Bytes, Error> {
+    let (mut count, mut size) = (0, 0);
+    get_file_history(repo.clone(), filenode, path)
+        .map(move |(node, parents, linknode, _)| {
+            repo.get_file_content(&node).map(move |content| {
+                let revision = Revision {
+                    parents,
+                    linknode,
+                    content,
+                };
+                (node, revision)
+            })
+        })
+        .buffer_unordered(FETCH_CONCURRENCY)
+        .and_then(move |(node, revision)| {
+            count += 1;
+            size += revision.content.len();
+            check_blame_size(count, size).map(|()| (node, revision))
+        })
+        .collect()
+        .and_then(move |revisions| {
+            cpupool.spawn_fn(move || {
+                let revisions: HashMap<_, _> = revisions.into_iter().collect();
+                let annotation = annotate(filenode, &revisions);
+                let content = &revisions[&filenode].content;
+                let lines: Vec<_> = split_lines(content)
+                    .into_iter()
+                    .zip(annotation)
+                    .map(|(line, (node, lineno))| BlameLine {
+                        node,
+                        linknode: revisions[&node].linknode,
+                        lineno: lineno + 1,
+                        line: String::from_utf8_lossy(line).into_owned(),
+                    })
+                    .collect();
+                Ok(Bytes::from(serde_json::to_vec(&lines)?))
+            })
+        })
+        .boxify()
+}
+
+/// Check that blaming a file doesn't fetch too much of its history.
+fn check_blame_size(revisions: usize, bytes: usize) -> Result<()> {
+    let msg = if revisions > MAX_BLAME_REVISIONS {
+        format!("the file has more than {} revisions", MAX_BLAME_REVISIONS)
+    } else if bytes > MAX_BLAME_BYTES {
+        format!("the revisions of the file are larger than {} bytes", MAX_BLAME_BYTES)
+    } else {
+        return Ok(());
+    };
+    Err(ErrorKind::TooExpensive(msg).into())
+}
+
+/// For each line of the revision `node`, find the revision that introduced it and the index of
+/// the line in that revision. A line that is unchanged from a parent is attributed as in that
+/// parent, preferring p1 for merges.
+fn annotate(node: NodeHash, revisions: &HashMap<NodeHash, Revision>) -> Vec<(NodeHash, usize)> {
+    // Parents have to be annotated before their children, so visit the revisions in
+    // topological order.
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![(node, false)];
+    while let Some((node, parents_done)) = stack.pop() {
+        if parents_done {
+            order.push(node);
+        } else if visited.insert(node) {
+            stack.push((node, true));
+            for parent in &revisions[&node].parents {
+                if !visited.contains(&parent) {
+                    stack.push((parent, false));
+                }
+            }
+        }
+    }
+
+    let mut annotations: HashMap<NodeHash, Vec<(NodeHash, usize)>> = HashMap::new();
+    for node in order {
+        let revision = &revisions[&node];
+        let lines = split_lines(&revision.content);
+        let mut annotation: Vec<_> = (0..lines.len()).map(|idx| (node, idx)).collect();
+
+        let parents: Vec<_> = revision.parents.into_iter().collect();
+        for parent in parents.into_iter().rev() {
+            let parent_lines = split_lines(&revisions[&parent].content);
+            let parent_annotation = &annotations[&parent];
+            let mut copy = |parent_idx: usize, idx: usize, count: usize| {
+                annotation[idx..idx + count]
+                    .copy_from_slice(&parent_annotation[parent_idx..parent_idx + count]);
+            };
+
+            // Lines between hunks are the same in both texts.
+            let (mut parent_pos, mut pos) = (0, 0);
+            for hunk in diff_lines(&parent_lines, &lines) {
+                copy(parent_pos, pos, hunk.new_start - pos);
+                parent_pos = hunk.old_end;
+                pos = hunk.new_end;
+            }
+            copy(parent_pos, pos, lines.len() - pos);
+        }
+        annotations.insert(node, annotation);
+    }
+    annotations.remove(&node).expect("revision was not annotated")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hash(digit: char) -> NodeHash {
+        ::std::iter::repeat(digit)
+            .take(40)
+            .collect::<String>()
+            .parse()
+            .unwrap()
+    }
+
+    fn revision(parents: Parents, content: &'static str) -> Revision {
+        Revision {
+            parents,
+            linknode: hash('f'),
+            content: Bytes::from(content),
+        }
+    }
+
+    #[test]
+    fn test_annotate_linear() {
+        let mut revisions = HashMap::new();
+        revisions.insert(hash('1'), revision(Parents::None, "a\nb\n"));
+        revisions.insert(hash('2'), revision(Parents::One(hash('1')), "a\nc\nb\n"));
+        revisions.insert(hash('3'), revision(Parents::One(hash('2')), "c\nb\nd"));
+
+        assert_eq!(
+            annotate(hash('3'), &revisions),
+            vec![(hash('2'), 1), (hash('1'), 1), (hash('3'), 2)]
+        );
+    }
+
+    #[test]
+    fn test_check_blame_size() {
+        assert!(check_blame_size(1, 0).is_ok());
+        assert!(check_blame_size(MAX_BLAME_REVISIONS, MAX_BLAME_BYTES).is_ok());
+        assert!(check_blame_size(MAX_BLAME_REVISIONS + 1, 0).is_err());
+        assert!(check_blame_size(1, MAX_BLAME_BYTES + 1).is_err());
+    }
+
+    #[test]
+    fn test_annotate_merge() {
+        let mut revisions = HashMap::new();
+        revisions.insert(hash('1'), revision(Parents::None, "a\n"));
+        revisions.insert(hash('2'), revision(Parents::One(hash('1')), "a\nb\n"));
+        revisions.insert(hash('3'), revision(Parents::One(hash('1')), "c\na\n"));
+        revisions.insert(
+            hash('4'),
+            revision(Parents::Two(hash('2'), hash('3')), "c\na\nb\ne\n"),
+        );
+
+        assert_eq!(
+            annotate(hash('4'), &revisions),
+            vec![
+                (hash('3'), 0),
+                (hash('1'), 0),
+                (hash('2'), 1),
+                (hash('4'), 3),
+            ]
+        );
+    }
+}
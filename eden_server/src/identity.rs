@@ -0,0 +1,112 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Identities of clients, taken from their TLS certificates.
+
+use std::io::{Read, Write};
+
+use native_tls::TlsStream;
+use native_tls::backend::openssl::TlsStreamExt;
+use openssl::nid;
+use openssl::x509::{X509NameRef, X509Ref};
+
+use metaconfig::repoconfig::{Access, RepoAcl};
+
+/// Who a client is. The server has verified its certificate against the CA, so the subject of
+/// the certificate can be trusted.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Identity {
+    /// Common name of the subject
+    pub user: String,
+    /// Organizational units of the subject
+    pub groups: Vec<String>,
+}
+
+fn entries(name: &X509NameRef, nid: nid::Nid) -> Vec<String> {
+    name.entries_by_nid(nid)
+        .filter_map(|entry| entry.data().as_utf8().ok())
+        .map(|data| data.to_string())
+        .collect()
+}
+
+impl Identity {
+    /// The identity of the subject of `cert`, if it has a common name.
+    pub fn from_certificate(cert: &X509Ref) -> Option<Identity> {
+        let subject = cert.subject_name();
+        let user = entries(subject, nid::COMMONNAME).into_iter().next()?;
+        let groups = entries(subject, nid::ORGANIZATIONALUNITNAME);
+        Some(Identity { user, groups })
+    }
+
+    /// The identity of the peer of a TLS connection, if it sent a certificate.
+    pub fn from_tls_stream<S: Read + Write>(stream: &TlsStream<S>) -> Option<Identity> {
+        stream
+            .raw_stream()
+            .ssl()
+            .peer_certificate()
+            .and_then(|cert| Identity::from_certificate(&cert))
+    }
+
+    /// Whether the identity has `access` to a repo. A repo without an ACL is open to every
+    /// identity.
+    pub fn is_allowed(&self, acl: Option<&RepoAcl>, access: Access) -> bool {
+        match acl {
+            Some(acl) => acl.allows(access, &self.user, &self.groups),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use openssl::x509::X509;
+
+    use metaconfig::repoconfig::AclEntry;
+
+    use super::*;
+
+    fn test_identity() -> Identity {
+        let cert = X509::from_pem(include_bytes!("../../tests/integration/edenservertest.crt"))
+            .expect("failed to parse test certificate");
+        Identity::from_certificate(&cert).expect("test certificate has no identity")
+    }
+
+    #[test]
+    fn test_from_certificate() {
+        assert_eq!(
+            test_identity(),
+            Identity {
+                user: "localhost".to_string(),
+                groups: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let identity = test_identity();
+        assert!(identity.is_allowed(None, Access::Write));
+
+        let acl = RepoAcl {
+            readers: vec![AclEntry::User("localhost".to_string())],
+            writers: vec![],
+        };
+        assert!(identity.is_allowed(Some(&acl), Access::Read));
+        assert!(!identity.is_allowed(Some(&acl), Access::Write));
+
+        let acl = RepoAcl {
+            readers: vec![AclEntry::User("someone".to_string())],
+            writers: vec![AclEntry::Group("engineers".to_string())],
+        };
+        assert!(!identity.is_allowed(Some(&acl), Access::Read));
+
+        let engineer = Identity {
+            user: "someone-else".to_string(),
+            groups: vec!["engineers".to_string()],
+        };
+        assert!(engineer.is_allowed(Some(&acl), Access::Write));
+    }
+}
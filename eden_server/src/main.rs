@@ -12,25 +12,61 @@
 ///
 /// # Request examples
 /// ```
+/// /repos - returns the names of the repos that are served
+/// /REPO/bookmarks - returns all the bookmarks of the repo and the changesets they point to
+/// /REPO/bookmark/NAME - returns the changeset that the bookmark NAME points to
+/// /REPO/cs/HASH - returns the metadata of the changeset HASH
 /// /REPO/cs/HASH/roottreemanifestid - returns root tree manifest node for the HASH
+/// /REPO/cs/HASH/tree/PATH - returns the listing of the directory PATH in the changeset HASH
+/// /REPO/cs/HASH/file/PATH - returns the content of the file PATH in the changeset HASH
+/// /REPO/cs/HASH/history/PATH?offset=N&limit=N - returns a page of the history of the file PATH
+/// /REPO/cs/HASH/blame/PATH - returns the revision that introduced each line of the file PATH
+/// /REPO/cs/HASH/diff?base=BASE&patch=1 - returns the files changed since BASE (or the first
+///     parent) with their types, content hashes and filenodes, or the changes as a git-style
+///     patch with patch=1
+/// /REPO/cs/HASH/archive.tar.gz?path=PATH - returns the files in the directory PATH (or the
+///     whole changeset) as a gzipped tarball, or as a zip archive with archive.zip
+/// /REPO/graph/ancestors?heads=HASH,...&common=HASH,... - returns a page of the ancestors of
+///     the heads that are not ancestors of the common changesets
+/// /REPO/graph/range?from=HASH&to=HASH - returns a page of the descendants of `from` that are
+///     ancestors of `to`
+/// /REPO/graph/gca?nodes=HASH,... - returns the greatest common ancestor of the changesets
+/// POST /REPO/trees - returns the trees for a list of keys, see the `batch` module
+/// POST /REPO/blobs - returns the file contents for a list of keys, see the `batch` module
 /// ```
+///
+/// Clients are identified by the common name of their certificate. Repos that have an ACL in
+/// their config can only be read by the identities (or organizational units) in it.
+///
+/// All the requests for a hash (or a changeset) return the same content every time, so their
+/// responses have strong ETags and can be cached indefinitely, though only by the client if the
+/// repo has an ACL. Responses are compressed with gzip or zstd if the client accepts it.
 extern crate ascii;
+extern crate async_compression;
 extern crate blobrepo;
 extern crate bytes;
 extern crate clap;
 #[macro_use]
 extern crate failure_ext as failure;
+extern crate flate2;
+#[macro_use]
 extern crate futures;
 extern crate futures_cpupool;
+#[macro_use]
 extern crate futures_ext;
 extern crate futures_stats;
 extern crate hyper;
+#[cfg(test)]
+extern crate linear;
 #[macro_use]
 extern crate lazy_static;
 extern crate mercurial_types;
+extern crate metaconfig;
 extern crate native_tls;
 extern crate openssl;
 extern crate regex;
+extern crate repoinfo;
+extern crate revset;
 extern crate scuba;
 extern crate secure_utils;
 extern crate serde;
@@ -41,45 +77,75 @@ extern crate serde_json;
 extern crate slog;
 extern crate slog_glog_fmt;
 extern crate tokio_core;
-extern crate tokio_proto;
+extern crate tokio_io;
 extern crate tokio_tls;
 extern crate toml;
+#[cfg(test)]
+extern crate unshared_merge_uneven;
+extern crate url;
+extern crate vfs;
+#[cfg(test)]
+extern crate zstd;
+
+mod archive;
+mod batch;
+mod caching;
+mod diff;
+mod encoding;
+mod errors;
+mod graph;
+mod history;
+mod identity;
+mod metadata;
 
 use std::collections::HashMap;
 use std::ffi::OsString;
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
 use std::os::unix::ffi::OsStringExt;
 use std::path::PathBuf;
+use std::result;
 use std::str::FromStr;
 use std::string::ToString;
 use std::sync::Arc;
-use tokio_core::reactor::Core;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Remote};
 
+use archive::ArchiveFormat;
 use blobrepo::BlobRepo;
 use bytes::Bytes;
-use clap::App;
-use futures::{Future, IntoFuture, Stream};
+use encoding::{CompressedStream, ContentCoding};
+use clap::{App, ArgGroup, ArgMatches};
+use futures::{Future, IntoFuture, Sink, Stream};
 use futures::sync::oneshot;
 use futures_cpupool::CpuPool;
 use futures_ext::{BoxFuture, FutureExt};
 use futures_stats::{Stats, Timed};
-use hyper::StatusCode;
+use identity::Identity;
+use graph::{GraphOperation, GraphQuery};
+use hyper::{Body, Chunk, Method, StatusCode};
+use hyper::header::{AcceptEncoding, ContentEncoding, ContentType, Headers, IfNoneMatch};
 use hyper::server::{Http, Request, Response, Service};
-use mercurial_types::{Changeset, MPathElement, NodeHash, RepositoryId};
+use mercurial_types::{Changeset, MPath, MPathElement, NodeHash, RepositoryId};
 use mercurial_types::nodehash::ChangesetId;
+use metaconfig::{ConfigRepoCommit, RepoConfigs};
+use metaconfig::repoconfig::{Access, RepoAcl, RepoType};
 use native_tls::TlsAcceptor;
 use native_tls::backend::openssl::TlsAcceptorBuilderExt;
-use openssl::ssl::{SSL_VERIFY_FAIL_IF_NO_PEER_CERT, SSL_VERIFY_PEER};
+use openssl::ssl::SSL_VERIFY_PEER;
 use regex::{Captures, Regex};
+use repoinfo::RepoGenCache;
 use scuba::{ScubaClient, ScubaSample};
 use slog::{Drain, Level, Logger};
-use tokio_proto::TcpServer;
-use tokio_tls::proto;
+use tokio_tls::TlsAcceptorExt;
+use url::form_urlencoded;
+use url::percent_encoding::percent_decode;
+use vfs::{vfs_from_manifest, ManifestVfsDir, ManifestVfsFile, VfsDir, VfsNode, VfsWalker};
 
-pub use failure::{DisplayChain, Error, Result, ResultExt};
+use errors::*;
 
-type NameToRepo = HashMap<String, Arc<BlobRepo>>;
+type NameToRepo = HashMap<String, ServedRepo>;
 type UrlParseFunc = fn(Captures) -> Result<ParsedUrl>;
 
 struct Route(Regex, UrlParseFunc);
@@ -90,12 +156,32 @@ const SCUBA_COL_POLL_TIME: &'static str = "poll_time_ns";
 const SCUBA_COL_POLL_COUNT: &'static str = "poll_count";
 const SCUBA_COL_HASH: &'static str = "hash";
 const SCUBA_COL_HOSTNAME: &'static str = "hostname";
+const SCUBA_COL_IDENTITY: &'static str = "identity";
+const SCUBA_COL_PATH: &'static str = "path";
 const SCUBA_COL_OPERATION: &'static str = "operation";
 const SCUBA_COL_REPO: &'static str = "repo";
 const SCUBA_OPERATION_GET_TREE_CONTENT: &'static str = "get_tree_content";
 const SCUBA_OPERATION_GET_TREE_CONTENT_LIGHT: &'static str = "get_tree_content_light";
 const SCUBA_OPERATION_GET_MENIFEST: &'static str = "get_root_tree_manifest_id";
 const SCUBA_OPERATION_GET_BLOB_CONTENT: &'static str = "get_blob_content";
+const SCUBA_OPERATION_GET_TREE_BY_PATH: &'static str = "get_tree_by_path";
+const SCUBA_OPERATION_GET_FILE_BY_PATH: &'static str = "get_file_by_path";
+const SCUBA_OPERATION_GET_TREES: &'static str = "get_trees";
+const SCUBA_OPERATION_GET_BLOBS: &'static str = "get_blobs";
+const SCUBA_OPERATION_GET_HISTORY: &'static str = "get_history";
+const SCUBA_OPERATION_GET_BLAME: &'static str = "get_blame";
+const SCUBA_OPERATION_GET_DIFF: &'static str = "get_diff";
+const SCUBA_OPERATION_GET_BOOKMARKS: &'static str = "get_bookmarks";
+const SCUBA_OPERATION_GET_BOOKMARK: &'static str = "get_bookmark";
+const SCUBA_OPERATION_GET_CHANGESET: &'static str = "get_changeset";
+const SCUBA_OPERATION_GET_GRAPH: &'static str = "get_graph";
+const SCUBA_OPERATION_GET_ARCHIVE: &'static str = "get_archive";
+
+// Number of keys of a batch request that are fetched at once
+const BATCH_CONCURRENCY: usize = 100;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
 
 fn parse_capture<T>(caps: &Captures, index: usize) -> Result<T>
 where
@@ -109,6 +195,115 @@ where
     str::parse::<T>(s).map_err(Error::from)
 }
 
+/// Parse the query string of a url into its parameters.
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    match query {
+        Some(query) => form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect(),
+        None => HashMap::new(),
+    }
+}
+
+fn parse_query_param<T>(query: &HashMap<String, String>, name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    Error: std::convert::From<<T as std::str::FromStr>::Err>,
+{
+    match query.get(name) {
+        Some(value) => str::parse::<T>(value)
+            .map(Some)
+            .map_err(Error::from)
+            .with_context(|_| format_err!("invalid value for '{}': {}", name, value))
+            .map_err(Error::from),
+        None => Ok(None),
+    }
+}
+
+fn parse_bool_query_param(query: &HashMap<String, String>, name: &str) -> Result<bool> {
+    match query.get(name).map(String::as_str) {
+        None | Some("0") | Some("false") => Ok(false),
+        Some("1") | Some("true") => Ok(true),
+        Some(value) => bail_msg!("invalid value for '{}': {}", name, value),
+    }
+}
+
+/// Which part of a paginated listing to return.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Page {
+    offset: usize,
+    limit: usize,
+}
+
+impl Page {
+    fn from_query(query: &HashMap<String, String>) -> Result<Page> {
+        let offset = parse_query_param(query, "offset")?.unwrap_or(0);
+        let limit = parse_query_param(query, "limit")?.unwrap_or(DEFAULT_PAGE_SIZE);
+        if limit == 0 || limit > MAX_PAGE_SIZE {
+            bail_msg!("limit must be between 1 and {}", MAX_PAGE_SIZE);
+        }
+        Ok(Page { offset, limit })
+    }
+}
+
+struct DiffOptions {
+    base: Option<ChangesetId>,
+    patch: bool,
+}
+
+impl DiffOptions {
+    fn from_query(query: &HashMap<String, String>) -> Result<DiffOptions> {
+        let base = parse_query_param::<NodeHash>(query, "base")?.map(ChangesetId::new);
+        let patch = parse_bool_query_param(query, "patch")?;
+        Ok(DiffOptions { base, patch })
+    }
+}
+
+/// The directory to archive, which is the root of the changeset if the query doesn't have one.
+fn parse_archive_path(query: &HashMap<String, String>) -> Result<MPath> {
+    MPath::new(query.get("path").map(String::as_str).unwrap_or(""))
+}
+
+/// Parse a percent-encoded path. A missing capture is the root path.
+fn parse_path_capture(caps: &Captures, index: usize) -> Result<MPath> {
+    let path = caps.get(index).map(|m| m.as_str()).unwrap_or("");
+    let path: Vec<u8> = percent_decode(path.as_bytes()).collect();
+    MPath::new(path)
+}
+
+fn parse_repos_url(_caps: Captures) -> Result<ParsedUrl> {
+    Ok(ParsedUrl::Repos)
+}
+
+fn parse_bookmarks_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    Ok(ParsedUrl::Bookmarks(repo))
+}
+
+fn parse_bookmark_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let name = caps.get(2).expect("incorrect url parsing regex").as_str();
+    let name: Vec<u8> = percent_decode(name.as_bytes()).collect();
+    Ok(ParsedUrl::Bookmark(repo, name))
+}
+
+fn parse_changeset_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let hash = parse_capture::<NodeHash>(&caps, 2)?;
+    Ok(ParsedUrl::Changeset(repo, hash))
+}
+
+fn parse_graph_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let operation = match caps.get(2).expect("incorrect url parsing regex").as_str() {
+        "ancestors" => GraphOperation::Ancestors,
+        "range" => GraphOperation::Range,
+        "gca" => GraphOperation::Gca,
+        _ => unreachable!("incorrect url parsing regex"),
+    };
+    Ok(ParsedUrl::Graph(repo, operation))
+}
+
 fn parse_root_treemanifest_id_url(caps: Captures) -> Result<ParsedUrl> {
     let repo = parse_capture::<String>(&caps, 1)?;
     let hash = parse_capture::<NodeHash>(&caps, 2)?;
@@ -133,6 +328,60 @@ fn parse_blob_content_url(caps: Captures) -> Result<ParsedUrl> {
     Ok(ParsedUrl::BlobContent(repo, hash))
 }
 
+fn parse_tree_by_path_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let hash = parse_capture::<NodeHash>(&caps, 2)?;
+    let path = parse_path_capture(&caps, 3)?;
+    Ok(ParsedUrl::TreeByPath(repo, hash, path))
+}
+
+fn parse_file_by_path_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let hash = parse_capture::<NodeHash>(&caps, 2)?;
+    let path = parse_path_capture(&caps, 3)?;
+    if path.is_empty() {
+        bail_msg!("file path is empty");
+    }
+    Ok(ParsedUrl::FileByPath(repo, hash, path))
+}
+
+fn parse_trees_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    Ok(ParsedUrl::Trees(repo))
+}
+
+fn parse_blobs_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    Ok(ParsedUrl::Blobs(repo))
+}
+
+fn parse_history_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let hash = parse_capture::<NodeHash>(&caps, 2)?;
+    let path = parse_path_capture(&caps, 3)?;
+    Ok(ParsedUrl::History(repo, hash, path))
+}
+
+fn parse_blame_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let hash = parse_capture::<NodeHash>(&caps, 2)?;
+    let path = parse_path_capture(&caps, 3)?;
+    Ok(ParsedUrl::Blame(repo, hash, path))
+}
+
+fn parse_diff_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let hash = parse_capture::<NodeHash>(&caps, 2)?;
+    Ok(ParsedUrl::Diff(repo, hash))
+}
+
+fn parse_archive_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let hash = parse_capture::<NodeHash>(&caps, 2)?;
+    let format = parse_capture::<ArchiveFormat>(&caps, 3)?;
+    Ok(ParsedUrl::Archive(repo, hash, format))
+}
+
 /// Generic url-handling function
 /// Accepts vector of tuples (regex, url handling function)
 /// If url matches regex then url handling function is called
@@ -146,28 +395,117 @@ fn parse_url(url: &str, routes: &[Route]) -> Result<ParsedUrl> {
 }
 
 enum ParsedUrl {
+    Repos,
+    Bookmarks(String),
+    Bookmark(String, Vec<u8>),
+    Changeset(String, NodeHash),
+    Graph(String, GraphOperation),
     RootTreeManifestId(String, NodeHash),
     TreeContent(String, NodeHash),
     TreeContentLight(String, NodeHash),
     BlobContent(String, NodeHash),
+    TreeByPath(String, NodeHash, MPath),
+    FileByPath(String, NodeHash, MPath),
+    Trees(String),
+    Blobs(String),
+    History(String, NodeHash, MPath),
+    Blame(String, NodeHash, MPath),
+    Diff(String, NodeHash),
+    Archive(String, NodeHash, ArchiveFormat),
+}
+
+impl ParsedUrl {
+    /// The name of the repo that the request is for, if it is for one.
+    fn reponame(&self) -> Option<&str> {
+        match *self {
+            ParsedUrl::Repos => None,
+            ParsedUrl::Bookmarks(ref reponame)
+            | ParsedUrl::Bookmark(ref reponame, _)
+            | ParsedUrl::Changeset(ref reponame, _)
+            | ParsedUrl::Graph(ref reponame, _)
+            | ParsedUrl::RootTreeManifestId(ref reponame, _)
+            | ParsedUrl::TreeContent(ref reponame, _)
+            | ParsedUrl::TreeContentLight(ref reponame, _)
+            | ParsedUrl::BlobContent(ref reponame, _)
+            | ParsedUrl::TreeByPath(ref reponame, _, _)
+            | ParsedUrl::FileByPath(ref reponame, _, _)
+            | ParsedUrl::Trees(ref reponame)
+            | ParsedUrl::Blobs(ref reponame)
+            | ParsedUrl::History(ref reponame, _, _)
+            | ParsedUrl::Blame(ref reponame, _, _)
+            | ParsedUrl::Diff(ref reponame, _)
+            | ParsedUrl::Archive(ref reponame, _, _) => Some(reponame),
+        }
+    }
+
+    /// The hash that the requested resource is addressed by, if it is addressed by one.
+    fn hash(&self) -> Option<&NodeHash> {
+        match *self {
+            ParsedUrl::Repos
+            | ParsedUrl::Bookmarks(_)
+            | ParsedUrl::Bookmark(_, _)
+            | ParsedUrl::Graph(_, _)
+            | ParsedUrl::Trees(_)
+            | ParsedUrl::Blobs(_) => None,
+            ParsedUrl::Changeset(_, ref hash)
+            | ParsedUrl::RootTreeManifestId(_, ref hash)
+            | ParsedUrl::TreeContent(_, ref hash)
+            | ParsedUrl::TreeContentLight(_, ref hash)
+            | ParsedUrl::BlobContent(_, ref hash)
+            | ParsedUrl::TreeByPath(_, ref hash, _)
+            | ParsedUrl::FileByPath(_, ref hash, _)
+            | ParsedUrl::History(_, ref hash, _)
+            | ParsedUrl::Blame(_, ref hash, _)
+            | ParsedUrl::Diff(_, ref hash)
+            | ParsedUrl::Archive(_, ref hash, _) => Some(hash),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Respond with an error message as JSON.
+fn set_json_error<E: fmt::Display>(resp: &mut Response, status: StatusCode, err: E) {
+    let body = ErrorResponse {
+        error: err.to_string(),
+    };
+    resp.set_body(serde_json::to_vec(&body).expect("failed to serialize error"));
+    resp.headers_mut().set(ContentType::json());
+    resp.set_status(status);
 }
 
 lazy_static! {
     static ref ROUTES: Vec<Route> = {
         vec![
             // Workaround for https://github.com/rust-lang/rust/issues/20178
+            (r"^/repos/?$", parse_repos_url as UrlParseFunc),
+            (r"^/(\w+)/bookmarks/?$", parse_bookmarks_url as UrlParseFunc),
+            (r"^/(\w+)/bookmark/(.+)$", parse_bookmark_url as UrlParseFunc),
+            (r"^/(\w+)/cs/(\w+)/?$", parse_changeset_url as UrlParseFunc),
+            (r"^/(\w+)/graph/(ancestors|range|gca)/?$", parse_graph_url as UrlParseFunc),
             (r"^/(\w+)/cs/(\w+)/roottreemanifestid/?$",
             parse_root_treemanifest_id_url as UrlParseFunc),
             (r"^/(\w+)/treenode/(\w+)/?$", parse_tree_content_url as UrlParseFunc),
             (r"^/(\w+)/treenode_simple/(\w+)/?$", parse_tree_content_light_url as UrlParseFunc),
             (r"^/(\w+)/blob/(\w+)/?$", parse_blob_content_url as UrlParseFunc),
+            (r"^/(\w+)/cs/(\w+)/tree(?:/(.*?))?/?$", parse_tree_by_path_url as UrlParseFunc),
+            (r"^/(\w+)/cs/(\w+)/file/(.+)$", parse_file_by_path_url as UrlParseFunc),
+            (r"^/(\w+)/trees/?$", parse_trees_url as UrlParseFunc),
+            (r"^/(\w+)/blobs/?$", parse_blobs_url as UrlParseFunc),
+            (r"^/(\w+)/cs/(\w+)/history/(.+)$", parse_history_url as UrlParseFunc),
+            (r"^/(\w+)/cs/(\w+)/blame/(.+)$", parse_blame_url as UrlParseFunc),
+            (r"^/(\w+)/cs/(\w+)/diff/?$", parse_diff_url as UrlParseFunc),
+            (r"^/(\w+)/cs/(\w+)/archive\.(tar\.gz|zip)$", parse_archive_url as UrlParseFunc),
         ].into_iter().map(|(re, func)| Route(Regex::new(re).expect("bad regex"), func)).collect()
     };
 }
 
 #[derive(Serialize)]
 struct TreeMetadata {
-    hash: NodeHash,
+    hash: Option<NodeHash>,
     path: PathBuf,
     #[serde(rename = "type")]
     ty: mercurial_types::Type,
@@ -182,7 +520,7 @@ impl TreeMetadata {
             .unwrap_or(MPathElement::new(vec![]));
 
         TreeMetadata {
-            hash: entry.get_hash().into_nodehash().clone(),
+            hash: Some(entry.get_hash().into_nodehash().clone()),
             path: PathBuf::from(OsString::from_vec(Vec::from(name.as_bytes()))),
             ty: entry.get_type(),
             size,
@@ -203,43 +541,218 @@ impl TreeMetadata {
                 .boxify()
         }
     }
+
+    /// Metadata of the node `name` in a vfs directory. Directories in a vfs are made up from the
+    /// paths of the files in them, so they don't have a hash.
+    fn from_vfs_node(
+        name: MPathElement,
+        node: VfsNode<ManifestVfsDir, ManifestVfsFile>,
+    ) -> BoxFuture<TreeMetadata, Error> {
+        let path = PathBuf::from(OsString::from_vec(Vec::from(name.as_bytes())));
+        match node {
+            VfsNode::Dir(_) => {
+                let metadata = TreeMetadata {
+                    hash: None,
+                    path,
+                    ty: mercurial_types::Type::Tree,
+                    size: None,
+                };
+                Ok(metadata).into_future().boxify()
+            }
+            VfsNode::File(file) => {
+                let entry = file.entry();
+                let hash = entry.get_hash().into_nodehash();
+                let ty = entry.get_type();
+                entry
+                    .get_size()
+                    .map(move |size| TreeMetadata {
+                        hash: Some(hash),
+                        path,
+                        ty,
+                        size,
+                    })
+                    .boxify()
+            }
+        }
+    }
+}
+
+/// Serialize a tree listing as a JSON array.
+fn serialize_tree<S>(entries: S) -> BoxFuture<Bytes, Error>
+where
+    S: Stream<Item = TreeMetadata, Error = Error> + Send + 'static,
+{
+    entries
+        .map(|metadata| {
+            let err_msg = format!(
+                "failed to get metadata for {}",
+                metadata.path.to_string_lossy()
+            );
+            serde_json::to_value(&metadata).unwrap_or(err_msg.into())
+        })
+        .collect()
+        .map(|entries| {
+            let x: serde_json::Value = entries.into();
+            Bytes::from(x.to_string().into_bytes())
+        })
+        .boxify()
+}
+
+/// List the tree manifest `hash` as JSON.
+fn tree_content(
+    repo: &BlobRepo,
+    cpupool: Arc<CpuPool>,
+    hash: &NodeHash,
+    options: TreeMetadataOptions,
+) -> BoxFuture<Bytes, Error> {
+    let entries = repo.get_manifest_by_nodeid(&hash)
+        .map(|manifest| manifest.list())
+        .flatten_stream()
+        .map(move |entry| cpupool.spawn(TreeMetadata::from_entry(entry, &options)))
+        .buffer_unordered(100) // Schedules 100 futures on cpupool
+        .from_err();
+    serialize_tree(entries)
+}
+
+/// Send `chunks` to the client as they are produced.
+fn stream_body<S>(cpupool: &CpuPool, chunks: S) -> Body
+where
+    S: Stream<Item = Bytes, Error = Error> + Send + 'static,
+{
+    let (sender, body) = Body::pair();
+    let chunks = chunks.then(|res| {
+        let chunk = res.map(Chunk::from).map_err(|err| {
+            let err = format!("{}", DisplayChain::from(&err));
+            hyper::Error::Io(io::Error::new(io::ErrorKind::Other, err))
+        });
+        Ok(chunk)
+    });
+    cpupool
+        .spawn(sender.send_all(chunks).map(|_| ()).map_err(|_| ()))
+        .forget();
+    body
+}
+
+/// Compress `body` with `coding` as it is sent to the client.
+fn compress_body(cpupool: &CpuPool, body: Body, coding: ContentCoding) -> Body {
+    let chunks = body.map(|chunk| Bytes::from(&chunk[..])).from_err();
+    stream_body(cpupool, CompressedStream::new(chunks, coding))
+}
+
+/// Stream the content of a file to the client.
+fn stream_file_content(
+    repo: &BlobRepo,
+    cpupool: Arc<CpuPool>,
+    hash: &NodeHash,
+) -> BoxFuture<Body, Error> {
+    // Wait for the first chunk so that a missing blob is still reported as an error, then
+    // stream the rest of the content to the client as it is fetched.
+    repo.get_file_content_stream(hash)
+        .into_future()
+        .map_err(|(err, _)| err)
+        .map(move |(first, rest)| {
+            stream_body(&cpupool, futures::stream::iter_ok(first).chain(rest))
+        })
+        .boxify()
 }
 
 struct TreeMetadataOptions {
     fetch_size: bool,
 }
 
+/// A repo that is served, along with the caches that the requests for it share.
+#[derive(Clone)]
+struct ServedRepo {
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    acl: Option<RepoAcl>,
+}
+
 struct EdenServer {
     name_to_repo: NameToRepo,
     cpupool: Arc<CpuPool>,
     logger: Logger,
     scuba: Arc<ScubaClient>,
+    /// Identity of the client of the connection, if it has one
+    identity: Option<Identity>,
 }
 
 impl EdenServer
 where
     EdenServer: Service,
 {
-    fn new(name_to_repo: NameToRepo, cpupool: Arc<CpuPool>, logger: Logger) -> EdenServer {
+    fn new(
+        name_to_repo: NameToRepo,
+        cpupool: Arc<CpuPool>,
+        logger: Logger,
+        identity: Option<Identity>,
+    ) -> EdenServer {
         EdenServer {
             name_to_repo,
             cpupool,
             logger,
             scuba: Arc::new(ScubaClient::new(SCUBA_TABLE)),
+            identity,
+        }
+    }
+
+    fn get_served_repo(&self, reponame: &str) -> Result<&ServedRepo> {
+        match self.name_to_repo.get(reponame) {
+            Some(served_repo) => Ok(served_repo),
+            None => Err(ErrorKind::UnknownRepo(reponame.to_string()).into()),
         }
     }
 
+    fn get_repo(&self, reponame: &str) -> Result<Arc<BlobRepo>> {
+        self.get_served_repo(reponame).map(|served_repo| served_repo.repo.clone())
+    }
+
+    /// List the names of the repos that `identity` can read as a sorted JSON array.
+    fn list_repos(&self, identity: &Identity) -> Result<Bytes> {
+        let mut names: Vec<_> = self.name_to_repo
+            .iter()
+            .filter(|&(_, served_repo)| identity.is_allowed(served_repo.acl.as_ref(), Access::Read))
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        Ok(Bytes::from(serde_json::to_vec(&names)?))
+    }
+
+    fn get_bookmarks(&self, reponame: String) -> BoxFuture<Bytes, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+        metadata::get_bookmarks(repo)
+    }
+
+    fn get_bookmark(&self, reponame: String, name: Vec<u8>) -> BoxFuture<Bytes, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+        metadata::get_bookmark(repo, name)
+    }
+
+    fn get_changeset(
+        &self,
+        reponame: String,
+        changesetid: &ChangesetId,
+    ) -> BoxFuture<Bytes, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+        metadata::get_changeset(repo, *changesetid)
+    }
+
+    fn get_graph(
+        &self,
+        reponame: String,
+        query: GraphQuery,
+        page: Page,
+    ) -> BoxFuture<Bytes, Error> {
+        let served_repo = try_boxfuture!(self.get_served_repo(&reponame)).clone();
+        graph::get_graph(served_repo.repo, served_repo.repo_generation, query, page)
+    }
+
     fn get_root_tree_manifest_id(
         &self,
         reponame: String,
         changesetid: &ChangesetId,
     ) -> Box<futures::Future<Item = Bytes, Error = Error> + Send> {
-        let repo = match self.name_to_repo.get(&reponame) {
-            Some(repo) => repo,
-            None => {
-                return futures::future::err(failure::err_msg("unknown repo")).boxify();
-            }
-        };
+        let repo = try_boxfuture!(self.get_repo(&reponame));
         repo.get_changeset_by_changesetid(&changesetid)
             .map(|cs| {
                 Bytes::from(
@@ -260,50 +773,215 @@ where
         hash: &NodeHash,
         options: TreeMetadataOptions,
     ) -> Box<futures::Future<Item = Bytes, Error = Error> + Send> {
-        let repo = match self.name_to_repo.get(&reponame) {
-            Some(repo) => repo,
-            None => {
-                return futures::future::err(failure::err_msg("unknown repo")).boxify();
-            }
-        };
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+
+        tree_content(&repo, self.cpupool.clone(), hash, options)
+    }
+
+    fn get_blob_content(
+        &self,
+        reponame: String,
+        hash: &NodeHash,
+    ) -> Box<futures::Future<Item = Body, Error = Error> + Send> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+
+        stream_file_content(&repo, self.cpupool.clone(), hash)
+    }
 
+    /// Find the node at `path` in the changeset, by walking a vfs of its manifest.
+    fn get_node_by_path(
+        &self,
+        reponame: String,
+        changesetid: &ChangesetId,
+        path: MPath,
+    ) -> BoxFuture<VfsNode<ManifestVfsDir, ManifestVfsFile>, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+
+        let changesetid = *changesetid;
+        repo.get_changeset_by_changesetid(&changesetid)
+            .and_then(move |cs| {
+                repo.get_manifest_by_nodeid(&cs.manifestid().clone().into_nodehash())
+            })
+            .and_then(|manifest| vfs_from_manifest(&manifest))
+            .and_then({
+                let path = path.clone();
+                move |vfs| VfsWalker::new(vfs.into_node(), path).walk()
+            })
+            .map_err(move |err: Error| {
+                err.context(format_err!("while looking up '{}' in {}", path, changesetid))
+                    .into()
+            })
+            .boxify()
+    }
+
+    fn get_tree_by_path(
+        &self,
+        reponame: String,
+        changesetid: &ChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Bytes, Error> {
         let cpupool = self.cpupool.clone();
-        repo.get_manifest_by_nodeid(&hash)
-            .map(|manifest| manifest.list())
+        let entries = self.get_node_by_path(reponame, changesetid, path.clone())
+            .and_then(move |node| match node {
+                VfsNode::Dir(dir) => Ok(dir),
+                VfsNode::File(_) => bail_msg!("'{}' is not a directory", path),
+            })
+            .map(|dir| {
+                let names: Vec<_> = dir.read().into_iter().cloned().collect();
+                futures::stream::iter_ok(names.into_iter().map(move |name| {
+                    let node = dir.step(&name).expect("listed name is missing from vfs dir");
+                    (name, node)
+                }))
+            })
             .flatten_stream()
-            .map(move |entry| cpupool.spawn(TreeMetadata::from_entry(entry, &options)))
-            .buffer_unordered(100) // Schedules 100 futures on cpupool
-            .from_err()
-            .map(|metadata| {
-                let err_msg = format!(
-                    "failed to get metadata for {}",
-                    metadata.path.to_string_lossy()
-                );
-                serde_json::to_value(&metadata).unwrap_or(err_msg.into())
+            .map(move |(name, node)| cpupool.spawn(TreeMetadata::from_vfs_node(name, node)))
+            .buffer_unordered(100);
+        serialize_tree(entries)
+    }
+
+    /// Find the filenode of the file at `path` in the changeset.
+    fn get_filenode_by_path(
+        &self,
+        reponame: String,
+        changesetid: &ChangesetId,
+        path: MPath,
+    ) -> BoxFuture<NodeHash, Error> {
+        self.get_node_by_path(reponame, changesetid, path.clone())
+            .and_then(move |node| match node {
+                VfsNode::File(file) => Ok(file.entry().get_hash().into_nodehash()),
+                VfsNode::Dir(_) => bail_msg!("'{}' is a directory", path),
             })
-            .collect()
-            .map(|entries| {
-                let x: serde_json::Value = entries.into();
-                Bytes::from(x.to_string().into_bytes())
+            .boxify()
+    }
+
+    fn get_file_by_path(
+        &self,
+        reponame: String,
+        changesetid: &ChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Body, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+
+        let cpupool = self.cpupool.clone();
+        self.get_filenode_by_path(reponame, changesetid, path)
+            .and_then(move |hash| stream_file_content(&repo, cpupool, &hash))
+            .boxify()
+    }
+
+    fn get_history(
+        &self,
+        reponame: String,
+        changesetid: &ChangesetId,
+        path: MPath,
+        page: Page,
+    ) -> BoxFuture<Bytes, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+
+        self.get_filenode_by_path(reponame, changesetid, path.clone())
+            .and_then(move |filenode| history::get_history(repo, filenode, path, page))
+            .boxify()
+    }
+
+    fn get_blame(
+        &self,
+        reponame: String,
+        changesetid: &ChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Bytes, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+
+        let cpupool = self.cpupool.clone();
+        self.get_filenode_by_path(reponame, changesetid, path.clone())
+            .and_then(move |filenode| history::get_blame(repo, cpupool, filenode, path))
+            .boxify()
+    }
+
+    fn get_diff(
+        &self,
+        reponame: String,
+        changesetid: &ChangesetId,
+        options: DiffOptions,
+    ) -> BoxFuture<Body, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+
+        let cpupool = self.cpupool.clone();
+        diff::get_changed_files(repo.clone(), *changesetid, options.base)
+            .and_then(move |files| {
+                if options.patch {
+                    Ok(stream_body(&cpupool, diff::changed_files_to_patch(files)))
+                        .into_future()
+                        .boxify()
+                } else {
+                    diff::changed_files_to_json(repo, &files)
+                        .map(Body::from)
+                        .boxify()
+                }
             })
             .boxify()
     }
 
-    fn get_blob_content(
+    /// Archive the files in the directory at `path` in the changeset. Every file gets the time
+    /// of the changeset as its modification time.
+    fn get_archive(
         &self,
         reponame: String,
-        hash: &NodeHash,
-    ) -> Box<futures::Future<Item = Bytes, Error = Error> + Send> {
-        let repo = match self.name_to_repo.get(&reponame) {
-            Some(repo) => repo,
-            None => {
-                return futures::future::err(failure::err_msg("unknown repo")).boxify();
-            }
-        };
+        changesetid: &ChangesetId,
+        path: MPath,
+        format: ArchiveFormat,
+    ) -> BoxFuture<Body, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
 
-        repo.get_file_content(hash)
-            .from_err()
-            .and_then(|content| futures::future::ok(content))
+        let cpupool = self.cpupool.clone();
+        let mtime = repo.get_changeset_by_changesetid(changesetid)
+            .map(|cs| cs.time().time);
+        self.get_node_by_path(reponame, changesetid, path.clone())
+            .and_then(move |node| match node {
+                VfsNode::Dir(dir) => Ok(dir),
+                VfsNode::File(_) => bail_msg!("'{}' is not a directory", path),
+            })
+            .join(mtime)
+            .map(move |(dir, mtime)| {
+                stream_body(&cpupool, archive::archive(repo, dir, mtime, format))
+            })
+            .boxify()
+    }
+
+    /// Fetch the tree listing of each key in the request, as `/treenode_simple/` does.
+    fn get_trees(&self, reponame: String, body: Body) -> BoxFuture<Body, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+
+        let cpupool = self.cpupool.clone();
+        batch::parse_keys(body)
+            .map(move |keys| {
+                let frames = futures::stream::iter_ok(keys)
+                    .map({
+                        let cpupool = cpupool.clone();
+                        move |key| {
+                            let options = TreeMetadataOptions { fetch_size: false };
+                            tree_content(&repo, cpupool.clone(), &key.node, options)
+                                .then(move |res| Ok(batch::encode_frame(&key, res)))
+                        }
+                    })
+                    .buffer_unordered(BATCH_CONCURRENCY);
+                stream_body(&cpupool, frames)
+            })
+            .boxify()
+    }
+
+    fn get_blobs(&self, reponame: String, body: Body) -> BoxFuture<Body, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+
+        let cpupool = self.cpupool.clone();
+        batch::parse_keys(body)
+            .map(move |keys| {
+                let frames = futures::stream::iter_ok(keys)
+                    .map(move |key| {
+                        repo.get_file_content(&key.node)
+                            .then(move |res| Ok(batch::encode_frame(&key, res)))
+                    })
+                    .buffer_unordered(BATCH_CONCURRENCY);
+                stream_body(&cpupool, frames)
+            })
             .boxify()
     }
 }
@@ -334,6 +1012,16 @@ impl Service for EdenServer {
         sample.add(SCUBA_COL_HOSTNAME, req.uri().host().unwrap_or("unknown"));
 
         let mut resp = Response::new();
+        let identity = match self.identity {
+            Some(ref identity) => identity,
+            None => {
+                set_json_error(&mut resp, StatusCode::Unauthorized, ErrorKind::Unauthenticated);
+                return futures::future::ok(resp).boxify();
+            }
+        };
+        sample.add(SCUBA_COL_IDENTITY, identity.user.clone());
+
+        let query = parse_query(req.uri().query());
         let parsed_req = match parse_url(req.uri().path(), &ROUTES) {
             Ok(req) => req,
             Err(err) => {
@@ -343,12 +1031,95 @@ impl Service for EdenServer {
             }
         };
 
+        // Shared caches can only keep responses that anyone may read
+        let mut public = true;
+        if let Some(reponame) = parsed_req.reponame() {
+            let denied = match self.get_served_repo(reponame) {
+                Ok(served_repo) => {
+                    public = served_repo.acl.is_none();
+                    if identity.is_allowed(served_repo.acl.as_ref(), Access::Read) {
+                        None
+                    } else {
+                        let user = identity.user.clone();
+                        let err = ErrorKind::AccessDenied(user, reponame.to_string());
+                        Some((StatusCode::Forbidden, err.into()))
+                    }
+                }
+                Err(err) => Some((StatusCode::NotFound, err)),
+            };
+            if let Some((status, err)) = denied {
+                set_json_error(&mut resp, status, err);
+                return futures::future::ok(resp).boxify();
+            }
+        }
+
+        let coding = match parsed_req {
+            // Archives are compressed already, or are made up of compressed files
+            ParsedUrl::Archive(..) => None,
+            _ => ContentCoding::negotiate(req.headers().get::<AcceptEncoding>()),
+        };
+        let etag = parsed_req.hash().map(|hash| caching::etag(hash, coding));
+        if let Some(ref etag) = etag {
+            if caching::is_not_modified(req.headers().get::<IfNoneMatch>(), etag) {
+                caching::set_immutable(resp.headers_mut(), etag.clone(), public);
+                resp.headers_mut().set_raw("Vary", "Accept-Encoding");
+                resp.set_status(StatusCode::NotModified);
+                return futures::future::ok(resp).boxify();
+            }
+        }
+
+        // Headers that describe the content of a successful response
+        let mut content_headers = Headers::new();
         let result_future = match parsed_req {
+            ParsedUrl::Repos => self.list_repos(identity)
+                .map(Body::from)
+                .into_future()
+                .boxify(),
+            ParsedUrl::Bookmarks(reponame) => {
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_BOOKMARKS);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_bookmarks(reponame).map(Body::from).boxify()
+            }
+            ParsedUrl::Bookmark(reponame, name) => {
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_BOOKMARK);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_bookmark(reponame, name).map(Body::from).boxify()
+            }
+            ParsedUrl::Changeset(reponame, hash) => {
+                sample.add(SCUBA_COL_HASH, hash.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_CHANGESET);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_changeset(reponame, &ChangesetId::new(hash))
+                    .map(Body::from)
+                    .boxify()
+            }
+            ParsedUrl::Graph(reponame, operation) => {
+                let parsed = GraphQuery::from_query(operation, &query).and_then(|graph_query| {
+                    let page = Page::from_query(&query)?;
+                    graph::check_page(&page)?;
+                    Ok((graph_query, page))
+                });
+                let (graph_query, page) = match parsed {
+                    Ok(parsed) => parsed,
+                    Err(err) => {
+                        resp.set_body(err.to_string());
+                        resp.set_status(StatusCode::BadRequest);
+                        return futures::future::ok(resp).boxify();
+                    }
+                };
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_GRAPH);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_graph(reponame, graph_query, page)
+                    .map(Body::from)
+                    .boxify()
+            }
             ParsedUrl::RootTreeManifestId(reponame, hash) => {
                 sample.add(SCUBA_COL_HASH, hash.to_string());
                 sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_MENIFEST);
                 sample.add(SCUBA_COL_REPO, reponame.clone());
                 self.get_root_tree_manifest_id(reponame, &ChangesetId::new(hash))
+                    .map(Body::from)
+                    .boxify()
             }
             ParsedUrl::TreeContent(reponame, hash) => {
                 sample.add(SCUBA_COL_HASH, hash.to_string());
@@ -356,7 +1127,9 @@ impl Service for EdenServer {
                 sample.add(SCUBA_COL_REPO, reponame.clone());
 
                 let options = TreeMetadataOptions { fetch_size: true };
-                self.get_tree_content(reponame, &hash, options).boxify()
+                self.get_tree_content(reponame, &hash, options)
+                    .map(Body::from)
+                    .boxify()
             }
             ParsedUrl::TreeContentLight(reponame, hash) => {
                 sample.add(SCUBA_COL_HASH, hash.to_string());
@@ -364,7 +1137,9 @@ impl Service for EdenServer {
                 sample.add(SCUBA_COL_REPO, reponame.clone());
 
                 let options = TreeMetadataOptions { fetch_size: false };
-                self.get_tree_content(reponame, &hash, options).boxify()
+                self.get_tree_content(reponame, &hash, options)
+                    .map(Body::from)
+                    .boxify()
             }
             ParsedUrl::BlobContent(reponame, hash) => {
                 sample.add(SCUBA_COL_HASH, hash.to_string());
@@ -372,18 +1147,132 @@ impl Service for EdenServer {
                 sample.add(SCUBA_COL_REPO, reponame.clone());
                 self.get_blob_content(reponame, &hash)
             }
+            ParsedUrl::TreeByPath(reponame, hash, path) => {
+                sample.add(SCUBA_COL_HASH, hash.to_string());
+                sample.add(SCUBA_COL_PATH, path.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_TREE_BY_PATH);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_tree_by_path(reponame, &ChangesetId::new(hash), path)
+                    .map(Body::from)
+                    .boxify()
+            }
+            ParsedUrl::FileByPath(reponame, hash, path) => {
+                sample.add(SCUBA_COL_HASH, hash.to_string());
+                sample.add(SCUBA_COL_PATH, path.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_FILE_BY_PATH);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_file_by_path(reponame, &ChangesetId::new(hash), path)
+            }
+            ParsedUrl::Trees(_) | ParsedUrl::Blobs(_) if req.method() != &Method::Post => {
+                resp.set_body("batch requests must be POSTs");
+                resp.set_status(StatusCode::MethodNotAllowed);
+                return futures::future::ok(resp).boxify();
+            }
+            ParsedUrl::Trees(reponame) => {
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_TREES);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_trees(reponame, req.body())
+            }
+            ParsedUrl::Blobs(reponame) => {
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_BLOBS);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_blobs(reponame, req.body())
+            }
+            ParsedUrl::History(reponame, hash, path) => {
+                let page = match Page::from_query(&query) {
+                    Ok(page) => page,
+                    Err(err) => {
+                        resp.set_body(err.to_string());
+                        resp.set_status(StatusCode::BadRequest);
+                        return futures::future::ok(resp).boxify();
+                    }
+                };
+                sample.add(SCUBA_COL_HASH, hash.to_string());
+                sample.add(SCUBA_COL_PATH, path.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_HISTORY);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_history(reponame, &ChangesetId::new(hash), path, page)
+                    .map(Body::from)
+                    .boxify()
+            }
+            ParsedUrl::Blame(reponame, hash, path) => {
+                sample.add(SCUBA_COL_HASH, hash.to_string());
+                sample.add(SCUBA_COL_PATH, path.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_BLAME);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_blame(reponame, &ChangesetId::new(hash), path)
+                    .map(Body::from)
+                    .boxify()
+            }
+            ParsedUrl::Diff(reponame, hash) => {
+                let options = match DiffOptions::from_query(&query) {
+                    Ok(options) => options,
+                    Err(err) => {
+                        resp.set_body(err.to_string());
+                        resp.set_status(StatusCode::BadRequest);
+                        return futures::future::ok(resp).boxify();
+                    }
+                };
+                sample.add(SCUBA_COL_HASH, hash.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_DIFF);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.get_diff(reponame, &ChangesetId::new(hash), options)
+            }
+            ParsedUrl::Archive(reponame, hash, format) => {
+                let path = match parse_archive_path(&query) {
+                    Ok(path) => path,
+                    Err(err) => {
+                        resp.set_body(err.to_string());
+                        resp.set_status(StatusCode::BadRequest);
+                        return futures::future::ok(resp).boxify();
+                    }
+                };
+                sample.add(SCUBA_COL_HASH, hash.to_string());
+                sample.add(SCUBA_COL_PATH, path.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_GET_ARCHIVE);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                content_headers.set_raw("Content-Type", format.content_type());
+                content_headers.set_raw(
+                    "Content-Disposition",
+                    format!(
+                        "attachment; filename=\"{}-{}.{}\"",
+                        reponame,
+                        hash,
+                        format.extension()
+                    ),
+                );
+                self.get_archive(reponame, &ChangesetId::new(hash), path, format)
+            }
         };
 
+        let cpupool = self.cpupool.clone();
         result_future
-            .then(|res| {
+            .then(move |res| {
                 match res {
                     Ok(output) => {
-                        resp.set_body(output);
+                        resp.headers_mut().extend(content_headers.iter());
+                        resp.headers_mut().set_raw("Vary", "Accept-Encoding");
+                        if let Some(etag) = etag {
+                            caching::set_immutable(resp.headers_mut(), etag, public);
+                        }
+                        match coding {
+                            Some(coding) => {
+                                resp.headers_mut()
+                                    .set(ContentEncoding(vec![coding.encoding()]));
+                                resp.set_body(compress_body(&cpupool, output, coding));
+                            }
+                            None => resp.set_body(output),
+                        }
                     }
                     Err(e) => {
+                        let status = match e.downcast_ref::<ErrorKind>() {
+                            Some(&ErrorKind::InvalidBatch(_)) => StatusCode::BadRequest,
+                            Some(&ErrorKind::TooExpensive(_)) => StatusCode::BadRequest,
+                            _ => StatusCode::NotFound,
+                        };
                         let error_msg = format!("{}", DisplayChain::from(&e));
                         resp.set_body(error_msg);
-                        resp.set_status(StatusCode::NotFound);
+                        resp.set_status(status);
                     }
                 };
                 futures::future::ok(resp)
@@ -413,18 +1302,16 @@ fn build_tls_acceptor(ssl: Ssl) -> Result<TlsAcceptor> {
 
         // SSL_VERIFY_PEER checks client certificate if it was supplied.
         // Connection is terminated if certificate verification fails.
-        // SSL_VERIFY_FAIL_IF_NO_PEER_CERT terminates the connection if client did not return
-        // certificate.
+        // Clients without a certificate can still connect, so that they get a 401 response
+        // instead of a failed handshake.
         // More about it - https://wiki.openssl.org/index.php/Manual:SSL_CTX_set_verify(3)
-        sslcontextbuilder.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT);
+        sslcontextbuilder.set_verify(SSL_VERIFY_PEER);
     }
     tlsacceptor_builder.build().map_err(Error::from)
 }
 
-fn start_server(addr: &str, reponame: String, repo: BlobRepo, logger: Logger, ssl: Ssl) {
+fn start_server(addr: &str, name_to_repo: NameToRepo, logger: Logger, ssl: Ssl) {
     let addr = addr.parse().expect("Failed to parse address");
-    let mut map = HashMap::new();
-    map.insert(reponame, Arc::new(repo));
 
     let tlsacceptor = build_tls_acceptor(ssl);
     let tlsacceptor = match tlsacceptor {
@@ -436,25 +1323,40 @@ fn start_server(addr: &str, reponame: String, repo: BlobRepo, logger: Logger, ss
     };
 
     let cpupool = Arc::new(CpuPool::new_num_cpus());
-    let protoserver = proto::Server::new(Http::new(), tlsacceptor);
-    let tcpserver = TcpServer::new(protoserver, addr);
+    let http: Http = Http::new();
+    let mut core = Core::new().expect("cannot create core for the server");
+    let handle = core.handle();
+    let listener = TcpListener::bind(&addr, &handle).expect("cannot bind to address");
 
     info!(logger, "started eden server");
-    tcpserver.serve(move || {
-        Ok(EdenServer::new(
-            map.clone(),
-            cpupool.clone(),
-            logger.clone(),
-        ))
+    // Each connection gets its own service, so that requests know the identity from the
+    // client's certificate.
+    let server = listener.incoming().for_each(move |(socket, peer_addr)| {
+        let connection = tlsacceptor.accept_async(socket).then({
+            let name_to_repo = name_to_repo.clone();
+            let cpupool = cpupool.clone();
+            let logger = logger.clone();
+            let handle = handle.clone();
+            let http = http.clone();
+            move |res| -> result::Result<(), ()> {
+                match res {
+                    Ok(stream) => {
+                        let identity = Identity::from_tls_stream(stream.get_ref());
+                        debug!(logger, "connection from {}: {:?}", peer_addr, identity);
+                        let service = EdenServer::new(name_to_repo, cpupool, logger, identity);
+                        http.bind_connection(&handle, stream, peer_addr, service);
+                    }
+                    Err(err) => {
+                        warn!(logger, "tls handshake with {} failed: {}", peer_addr, err);
+                    }
+                }
+                Ok(())
+            }
+        });
+        handle.spawn(connection);
+        Ok(())
     });
-}
-
-/// Types of repositories supported
-#[derive(Clone, Debug, Deserialize)]
-enum RawRepoType {
-    #[serde(rename = "blob:files")] BlobFiles,
-    #[serde(rename = "blob:rocks")] BlobRocks,
-    #[serde(rename = "blob:manifold")] BlobManifold,
+    core.run(server).expect("eden server failed");
 }
 
 #[derive(Debug, Deserialize)]
@@ -464,15 +1366,79 @@ struct Ssl {
     ca_pem_file: String,
 }
 
+/// Settings of the server itself. The repos that are served come from the config repo.
 #[derive(Debug, Deserialize)]
-struct RawRepoConfig {
-    path: Option<PathBuf>,
-    manifold_bucket: Option<String>,
-    repotype: RawRepoType,
-    reponame: String,
+struct RawServerConfig {
     addr: String,
     ssl: Ssl,
-    repoid: i32,
+}
+
+fn get_config<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<RepoConfigs> {
+    let commit = match matches.value_of("crbookmark") {
+        Some(bookmark) => ConfigRepoCommit::Bookmark(bookmark.to_string()),
+        None => {
+            let hash = matches.value_of("crhash").unwrap();
+            ConfigRepoCommit::Changeset(ChangesetId::from_str(hash)?)
+        }
+    };
+    let crpath = matches.value_of("crpath").unwrap();
+    let (changesetid, config) = RepoConfigs::read_revlog_config_repo_at(crpath, commit).wait()?;
+    info!(
+        logger,
+        "Config repository was read from commit: {}", changesetid
+    );
+    Ok(config)
+}
+
+/// Manifold requires a separate detached thread to do the IO, so start one and return a handle
+/// to its reactor.
+fn start_manifold_thread() -> Remote {
+    let (sender, receiver) = oneshot::channel();
+    std::thread::spawn(move || {
+        let mut core = Core::new().expect("cannot create core for manifold");
+        sender
+            .send(core.remote())
+            .expect("cannot send remote handle for manifold");
+        loop {
+            // loop infinitely; it will be stopped when the whole server is stopped
+            core.turn(None);
+        }
+    });
+    receiver
+        .wait()
+        .expect("cannot get remote handle for manifold")
+}
+
+/// Open all the blob repos in `configs`. Revlog repos can't be served, so they are skipped.
+fn open_repos(logger: &Logger, configs: RepoConfigs) -> Result<NameToRepo> {
+    let mut remote = None;
+    let mut name_to_repo = HashMap::new();
+    for (reponame, config) in configs.repos {
+        let repo_logger = logger.new(o!("repo" => reponame.clone()));
+        let repoid = RepositoryId::new(config.repoid);
+        let repo = match config.repotype {
+            RepoType::Revlog(_) => {
+                warn!(logger, "skipping revlog repo {}", reponame);
+                continue;
+            }
+            RepoType::BlobFiles(ref path) => BlobRepo::new_files(repo_logger, path, repoid),
+            RepoType::BlobRocks(ref path) => BlobRepo::new_rocksdb(repo_logger, path, repoid),
+            RepoType::BlobSqlite(ref path) => BlobRepo::new_sqlite(repo_logger, path, repoid),
+            RepoType::TestBlobManifold(ref bucket, _) => {
+                let remote = remote.get_or_insert_with(start_manifold_thread);
+                BlobRepo::new_test_manifold(repo_logger, bucket, remote, repoid)
+            }
+        };
+        let repo = repo.with_context(|_| format_err!("couldn't open repo {}", reponame))?;
+        info!(logger, "opened repo {}", reponame);
+        let served_repo = ServedRepo {
+            repo: Arc::new(repo),
+            repo_generation: RepoGenCache::new(config.generation_cache_size),
+            acl: config.acl,
+        };
+        name_to_repo.insert(reponame, served_repo);
+    }
+    Ok(name_to_repo)
 }
 
 fn main() {
@@ -481,9 +1447,17 @@ fn main() {
         .about("Http server that can answers a few Eden requests")
         .args_from_usage(
             "--config-file=[FILE] 'Toml config file path'
+            <crpath>      -P, --configrepo_path [PATH]           'path to the config repo'
+            [crbookmark]  -B, --configrepo_bookmark [BOOKMARK]   'config repo bookmark'
+            [crhash]      -C, --configrepo_hash [HASH]           'config repo commit hash'
             -d, --debug              'print debug level output'
             ",
         )
+        .group(
+            ArgGroup::default()
+                .args(&["crbookmark", "crhash"])
+                .required(true),
+        )
         .get_matches();
     let config_file = matches
         .value_of("config-file")
@@ -494,7 +1468,7 @@ fn main() {
         .read_to_end(&mut config_bytes)
         .expect("reading config file failed");
     let config =
-        toml::from_slice::<RawRepoConfig>(&config_bytes).expect("reading config file failed");
+        toml::from_slice::<RawServerConfig>(&config_bytes).expect("reading config file failed");
 
     let root_logger = {
         let level = if matches.is_present("debug") {
@@ -507,66 +1481,17 @@ fn main() {
         Logger::root(drain, o![])
     };
 
-    match config.repotype {
-        RawRepoType::BlobFiles => {
-            let path = config.path.expect("Please specify a path to the blobrepo");
-            let repo_logger = root_logger.new(o!("repo" => format!("{}", path.display())));
-            start_server(
-                &config.addr,
-                config.reponame,
-                BlobRepo::new_files(repo_logger, &path, RepositoryId::new(config.repoid))
-                    .expect("couldn't open blob state"),
-                root_logger.clone(),
-                config.ssl,
-            )
-        }
-        RawRepoType::BlobRocks => {
-            let path = config.path.expect("Please specify a path to the blobrepo");
-            let repo_logger = root_logger.new(o!("repo" => format!("{}", path.display())));
-            start_server(
-                &config.addr,
-                config.reponame,
-                BlobRepo::new_rocksdb(repo_logger, &path, RepositoryId::new(config.repoid))
-                    .expect("couldn't open blob state"),
-                root_logger.clone(),
-                config.ssl,
-            )
-        }
-        RawRepoType::BlobManifold => {
-            let bucket = config
-                .manifold_bucket
-                .expect("manifold bucket is not specified");
-            let repo_logger = root_logger.new(o!("repo" => format!("{}", bucket)));
-            let (sender, receiver) = oneshot::channel();
-            // manifold requires a separate detached thread to do the IO, that's why we create a
-            // separate thread to handle it.
-            std::thread::spawn(move || {
-                let mut core = Core::new().expect("cannot create core for manifold");
-                sender
-                    .send(core.remote())
-                    .expect("cannot send remote handle for manifold");
-                loop {
-                    // loop infinitely; it will be stopped when the whole server is stopped
-                    core.turn(None);
-                }
-            });
-            let remote = receiver
-                .wait()
-                .expect("cannot get remote handle for manifold");
-            start_server(
-                &config.addr,
-                config.reponame,
-                BlobRepo::new_test_manifold(
-                    repo_logger,
-                    bucket,
-                    &remote,
-                    RepositoryId::new(config.repoid),
-                ).expect("couldn't open blob state"),
-                root_logger.clone(),
-                config.ssl,
-            )
+    let name_to_repo = match get_config(&root_logger, &matches)
+        .and_then(|configs| open_repos(&root_logger, configs))
+    {
+        Ok(name_to_repo) => name_to_repo,
+        Err(err) => {
+            error!(root_logger, "{}", DisplayChain::from(&err));
+            std::process::exit(1);
         }
     };
+
+    start_server(&config.addr, name_to_repo, root_logger, config.ssl);
 }
 
 #[cfg(test)]
@@ -586,4 +1511,165 @@ mod test {
         let incorrect_url = format!("/repo/cs/{}/roottreemanifestid", badhash);
         assert!(parse_url(&incorrect_url, &routes).is_err());
     }
+
+    #[test]
+    fn test_repos_url_parsing() {
+        let routes = &ROUTES;
+        match parse_url("/repos", &routes) {
+            Ok(parsed) => assert_eq!(parsed.reponame(), None),
+            _ => panic!("expected a repos url"),
+        }
+        assert!(parse_url("/repos/", &routes).is_ok());
+
+        let hash = std::iter::repeat("a").take(40).collect::<String>();
+        match parse_url(&format!("/repo/cs/{}/diff", hash), &routes) {
+            Ok(parsed) => assert_eq!(parsed.reponame(), Some("repo")),
+            _ => panic!("expected a diff url"),
+        }
+    }
+
+    #[test]
+    fn test_metadata_url_parsing() {
+        let routes = &ROUTES;
+        match parse_url("/repo/bookmarks", &routes) {
+            Ok(ParsedUrl::Bookmarks(repo)) => assert_eq!(repo, "repo"),
+            _ => panic!("expected a bookmarks url"),
+        }
+        match parse_url("/repo/bookmark/release%2F1.0", &routes) {
+            Ok(ParsedUrl::Bookmark(repo, name)) => {
+                assert_eq!(repo, "repo");
+                assert_eq!(name, b"release/1.0");
+            }
+            _ => panic!("expected a bookmark url"),
+        }
+        assert!(parse_url("/repo/bookmark/", &routes).is_err());
+
+        let hash = std::iter::repeat("a").take(40).collect::<String>();
+        match parse_url(&format!("/repo/cs/{}/", hash), &routes) {
+            Ok(ParsedUrl::Changeset(_, parsed)) => assert_eq!(parsed.to_string(), hash),
+            _ => panic!("expected a changeset url"),
+        }
+    }
+
+    #[test]
+    fn test_graph_url_parsing() {
+        let routes = &ROUTES;
+        match parse_url("/repo/graph/ancestors", &routes) {
+            Ok(ParsedUrl::Graph(repo, operation)) => {
+                assert_eq!(repo, "repo");
+                assert_eq!(operation, GraphOperation::Ancestors);
+            }
+            _ => panic!("expected an ancestors url"),
+        }
+        match parse_url("/repo/graph/range/", &routes) {
+            Ok(ParsedUrl::Graph(_, operation)) => assert_eq!(operation, GraphOperation::Range),
+            _ => panic!("expected a range url"),
+        }
+        match parse_url("/repo/graph/gca", &routes) {
+            Ok(ParsedUrl::Graph(_, operation)) => assert_eq!(operation, GraphOperation::Gca),
+            _ => panic!("expected a gca url"),
+        }
+        assert!(parse_url("/repo/graph/descendants", &routes).is_err());
+    }
+
+    #[test]
+    fn test_path_url_parsing() {
+        let routes = &ROUTES;
+        let hash = std::iter::repeat("a").take(40).collect::<String>();
+
+        match parse_url(&format!("/repo/cs/{}/tree", hash), &routes) {
+            Ok(ParsedUrl::TreeByPath(_, _, path)) => assert!(path.is_empty()),
+            _ => panic!("expected a root tree url"),
+        }
+        match parse_url(&format!("/repo/cs/{}/tree/dir/sub%20dir/", hash), &routes) {
+            Ok(ParsedUrl::TreeByPath(_, _, path)) => {
+                assert_eq!(path, MPath::new("dir/sub dir").unwrap())
+            }
+            _ => panic!("expected a tree url"),
+        }
+        match parse_url(&format!("/repo/cs/{}/file/dir/file.rs", hash), &routes) {
+            Ok(ParsedUrl::FileByPath(_, _, path)) => {
+                assert_eq!(path, MPath::new("dir/file.rs").unwrap())
+            }
+            _ => panic!("expected a file url"),
+        }
+        assert!(parse_url(&format!("/repo/cs/{}/file/", hash), &routes).is_err());
+        assert!(parse_url(&format!("/repo/cs/{}/treefoo", hash), &routes).is_err());
+    }
+
+    #[test]
+    fn test_page_parsing() {
+        let query = parse_query(Some("offset=200&limit=50"));
+        assert_eq!(
+            Page::from_query(&query).unwrap(),
+            Page {
+                offset: 200,
+                limit: 50,
+            }
+        );
+        assert_eq!(
+            Page::from_query(&parse_query(None)).unwrap(),
+            Page {
+                offset: 0,
+                limit: DEFAULT_PAGE_SIZE,
+            }
+        );
+        assert!(Page::from_query(&parse_query(Some("limit=0"))).is_err());
+        assert!(Page::from_query(&parse_query(Some("offset=x"))).is_err());
+    }
+
+    #[test]
+    fn test_diff_options_parsing() {
+        let hash = std::iter::repeat("a").take(40).collect::<String>();
+        let query = parse_query(Some(&format!("base={}&patch=1", hash)));
+        let options = DiffOptions::from_query(&query).unwrap();
+        assert_eq!(options.base, Some(ChangesetId::new(hash.parse().unwrap())));
+        assert!(options.patch);
+
+        let options = DiffOptions::from_query(&parse_query(None)).unwrap();
+        assert_eq!(options.base, None);
+        assert!(!options.patch);
+
+        assert!(DiffOptions::from_query(&parse_query(Some("base=xyz"))).is_err());
+        assert!(DiffOptions::from_query(&parse_query(Some("patch=maybe"))).is_err());
+    }
+
+    #[test]
+    fn test_archive_url_parsing() {
+        let routes = &ROUTES;
+        let hash = std::iter::repeat("a").take(40).collect::<String>();
+
+        match parse_url(&format!("/repo/cs/{}/archive.tar.gz", hash), &routes) {
+            Ok(ParsedUrl::Archive(repo, _, format)) => {
+                assert_eq!(repo, "repo");
+                assert_eq!(format, ArchiveFormat::TarGz);
+            }
+            _ => panic!("expected a tarball url"),
+        }
+        match parse_url(&format!("/repo/cs/{}/archive.zip", hash), &routes) {
+            Ok(ParsedUrl::Archive(_, _, format)) => assert_eq!(format, ArchiveFormat::Zip),
+            _ => panic!("expected a zip url"),
+        }
+        assert!(parse_url(&format!("/repo/cs/{}/archive.tar", hash), &routes).is_err());
+
+        let query = parse_query(Some("path=dir%2Fsub+dir"));
+        assert_eq!(
+            parse_archive_path(&query).unwrap(),
+            MPath::new("dir/sub dir").unwrap()
+        );
+        assert!(parse_archive_path(&parse_query(None)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_batch_url_parsing() {
+        let routes = &ROUTES;
+        match parse_url("/repo/trees", &routes) {
+            Ok(ParsedUrl::Trees(repo)) => assert_eq!(repo, "repo"),
+            _ => panic!("expected a trees url"),
+        }
+        match parse_url("/repo/blobs/", &routes) {
+            Ok(ParsedUrl::Blobs(repo)) => assert_eq!(repo, "repo"),
+            _ => panic!("expected a blobs url"),
+        }
+    }
 }
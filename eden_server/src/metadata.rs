@@ -0,0 +1,121 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Bookmarks and changeset metadata.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{Future, Stream};
+use serde_json;
+
+use blobrepo::BlobRepo;
+use failure::Error;
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::{Changeset, ChangesetId, NodeHash};
+
+use errors::ErrorKind;
+
+// Number of bookmarks that are resolved at once
+const FETCH_CONCURRENCY: usize = 100;
+
+#[derive(Serialize)]
+struct Bookmark {
+    name: String,
+    hash: NodeHash,
+}
+
+#[derive(Serialize)]
+struct ChangesetMetadata {
+    hash: NodeHash,
+    manifest: NodeHash,
+    user: String,
+    date: (u64, i32),
+    message: String,
+    parents: Vec<NodeHash>,
+    extras: BTreeMap<String, String>,
+    files: Vec<String>,
+}
+
+impl ChangesetMetadata {
+    fn new<C: Changeset>(changesetid: ChangesetId, cs: &C) -> ChangesetMetadata {
+        ChangesetMetadata {
+            hash: changesetid.into_nodehash(),
+            manifest: cs.manifestid().clone().into_nodehash(),
+            user: String::from_utf8_lossy(cs.user()).into_owned(),
+            date: (cs.time().time, cs.time().tz),
+            message: String::from_utf8_lossy(cs.comments()).into_owned(),
+            parents: cs.parents().into_iter().collect(),
+            extras: cs.extra()
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        String::from_utf8_lossy(key).into_owned(),
+                        String::from_utf8_lossy(value).into_owned(),
+                    )
+                })
+                .collect(),
+            files: cs.files().iter().map(|path| path.to_string()).collect(),
+        }
+    }
+}
+
+/// List all the bookmarks of the repo, as a JSON object from name to changeset hash.
+pub fn get_bookmarks(repo: Arc<BlobRepo>) -> BoxFuture<Bytes, Error> {
+    repo.get_bookmark_keys()
+        .map({
+            let repo = repo.clone();
+            move |name| {
+                repo.get_bookmark_value(&name)
+                    .map(move |value| value.map(|(cs, _)| (name, cs)))
+            }
+        })
+        .buffer_unordered(FETCH_CONCURRENCY)
+        // A bookmark can be deleted between listing and resolving it
+        .filter_map(|bookmark| bookmark)
+        .collect()
+        .and_then(|bookmarks| {
+            let bookmarks: BTreeMap<_, _> = bookmarks
+                .into_iter()
+                .map(|(name, cs)| {
+                    (
+                        String::from_utf8_lossy(&name).into_owned(),
+                        cs.into_nodehash(),
+                    )
+                })
+                .collect();
+            Ok(Bytes::from(serde_json::to_vec(&bookmarks)?))
+        })
+        .boxify()
+}
+
+/// Resolve the bookmark `name` to the changeset that it points to.
+pub fn get_bookmark(repo: Arc<BlobRepo>, name: Vec<u8>) -> BoxFuture<Bytes, Error> {
+    repo.get_bookmark_value(&name)
+        .and_then(move |value| {
+            let name = String::from_utf8_lossy(&name).into_owned();
+            let bookmark = match value {
+                Some((cs, _)) => Bookmark {
+                    name,
+                    hash: cs.into_nodehash(),
+                },
+                None => return Err(ErrorKind::BookmarkNotFound(name).into()),
+            };
+            Ok(Bytes::from(serde_json::to_vec(&bookmark)?))
+        })
+        .boxify()
+}
+
+/// Describe the changeset as JSON.
+pub fn get_changeset(repo: Arc<BlobRepo>, changesetid: ChangesetId) -> BoxFuture<Bytes, Error> {
+    repo.get_changeset_by_changesetid(&changesetid)
+        .and_then(move |cs| {
+            let metadata = ChangesetMetadata::new(changesetid, &cs);
+            Ok(Bytes::from(serde_json::to_vec(&metadata)?))
+        })
+        .boxify()
+}
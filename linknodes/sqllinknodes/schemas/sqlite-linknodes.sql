@@ -0,0 +1,10 @@
+CREATE TABLE linknodes (
+  repo_id INTEGER NOT NULL,
+  path_hash BINARY(20) NOT NULL,
+  path VARBINARY(4096) NOT NULL,
+  node BINARY(20) NOT NULL,
+  linknode BINARY(20) NOT NULL,
+  PRIMARY KEY (repo_id, path_hash, node)
+);
+
+CREATE INDEX linknodes_node ON linknodes (repo_id, node);
@@ -0,0 +1,292 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! SQL-backed linknodes store.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate diesel;
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate db;
+extern crate futures_ext;
+extern crate linknodes;
+extern crate mercurial_types;
+
+use std::collections::{HashMap, HashSet};
+use std::result;
+use std::sync::Mutex;
+
+use diesel::{insert_into, Connection, MysqlConnection, SqliteConnection};
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use failure::{Error, Result};
+use futures::future;
+
+use db::ConnectionParams;
+use futures_ext::{BoxFuture, FutureExt};
+use linknodes::{ErrorKind, LinknodeData, Linknodes, OptionNodeHash};
+use mercurial_types::{NodeHash, RepoPath, RepositoryId};
+use mercurial_types::hash::Sha1;
+
+mod schema;
+mod models;
+
+use models::LinknodeRow;
+use schema::linknodes as linknodes_table;
+
+/// Number of rows that a statement reads or writes at once. Each row of an insert has 5 bound
+/// variables, and SQLite only allows 999 in a statement.
+const BATCH_SIZE: usize = 100;
+
+pub struct SqliteLinknodes {
+    connection: Mutex<SqliteConnection>,
+    repo_id: RepositoryId,
+}
+
+impl SqliteLinknodes {
+    /// Open a SQLite database. This is synchronous because the SQLite backend hits local
+    /// disk or memory.
+    pub fn open<P: AsRef<str>>(path: P, repo_id: RepositoryId) -> Result<Self> {
+        let path = path.as_ref();
+        let conn = SqliteConnection::establish(path)?;
+        Ok(Self {
+            connection: Mutex::new(conn),
+            repo_id,
+        })
+    }
+
+    /// Create a new SQLite database.
+    pub fn create<P: AsRef<str>>(path: P, repo_id: RepositoryId) -> Result<Self> {
+        let linknodes = Self::open(path, repo_id)?;
+
+        let up_query = include_str!("../schemas/sqlite-linknodes.sql");
+        linknodes
+            .connection
+            .lock()
+            .expect("lock poisoned")
+            .batch_execute(&up_query)?;
+
+        Ok(linknodes)
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory(repo_id: RepositoryId) -> Result<Self> {
+        Self::create(":memory:", repo_id)
+    }
+}
+
+pub struct MysqlLinknodes {
+    connection: Mutex<MysqlConnection>,
+    repo_id: RepositoryId,
+}
+
+impl MysqlLinknodes {
+    pub fn open(params: ConnectionParams, repo_id: RepositoryId) -> Result<Self> {
+        let url = params.to_diesel_url()?;
+        let conn = MysqlConnection::establish(&url)?;
+        Ok(Self {
+            connection: Mutex::new(conn),
+            repo_id,
+        })
+    }
+
+    pub fn create_test_db<P: AsRef<str>>(prefix: P, repo_id: RepositoryId) -> Result<Self> {
+        let params = db::create_test_db(prefix)?;
+        Self::create(params, repo_id)
+    }
+
+    fn create(params: ConnectionParams, repo_id: RepositoryId) -> Result<Self> {
+        let linknodes = Self::open(params, repo_id)?;
+
+        let up_query = include_str!("../schemas/mysql-linknodes.sql");
+        linknodes
+            .connection
+            .lock()
+            .expect("lock poisoned")
+            .batch_execute(&up_query)?;
+
+        Ok(linknodes)
+    }
+}
+
+/// Using a macro here is unfortunate, but it appears to be the only way to share this code
+/// between SQLite and MySQL.
+macro_rules! impl_linknodes {
+    ($struct: ty, $conn: ty) => {
+        impl Linknodes for $struct {
+            fn add(
+                &self,
+                path: RepoPath,
+                node: &NodeHash,
+                linknode: &NodeHash,
+            ) -> BoxFuture<(), Error> {
+                let row = linknode_row(self.repo_id, &path, *node, *linknode);
+                let connection = self.connection.lock().expect("lock poisoned");
+                let result = insert_into(linknodes_table::table)
+                    .values(&row)
+                    .execute(&*connection);
+
+                // This code is written in this style to allow easy porting to futures.
+                let result = match result {
+                    Ok(_rows) => Ok(()),
+                    Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                        // Report the existing linknode if it can be found.
+                        let old_linknode = linknodes_table::table
+                            .filter(linknodes_table::repo_id.eq(self.repo_id))
+                            .filter(linknodes_table::path_hash.eq(&row.path_hash))
+                            .filter(linknodes_table::node.eq(row.node))
+                            .select(linknodes_table::linknode)
+                            .first::<NodeHash>(&*connection)
+                            .ok();
+                        Err(ErrorKind::AlreadyExists {
+                            path,
+                            node: *node,
+                            old_linknode: OptionNodeHash(old_linknode),
+                            new_linknode: *linknode,
+                        }.into())
+                    }
+                    Err(err) => Err(err.into()),
+                };
+                future::result(result).boxify()
+            }
+
+            fn get(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<NodeHash, Error> {
+                // TODO: don't block -- send this to another thread
+                let connection = self.connection.lock().expect("lock poisoned");
+                let linknode = linknodes_table::table
+                    .filter(linknodes_table::repo_id.eq(self.repo_id))
+                    .filter(linknodes_table::path_hash.eq(path_hash(&path)))
+                    .filter(linknodes_table::node.eq(*node))
+                    .select(linknodes_table::linknode)
+                    .first::<NodeHash>(&*connection)
+                    .optional();
+
+                let result = match linknode {
+                    Ok(Some(linknode)) => Ok(linknode),
+                    Ok(None) => Err(ErrorKind::NotFound(path, *node).into()),
+                    Err(err) => Err(err.into()),
+                };
+                future::result(result).boxify()
+            }
+
+            /// Insert all the linknodes in a single transaction, a batch of rows at a time.
+            /// Entries that already have a linknode are filtered out first.
+            fn add_many(&self, linknodes: Vec<LinknodeData>) -> BoxFuture<(), Error> {
+                let mut seen = HashSet::new();
+                let rows: Vec<_> = linknodes
+                    .into_iter()
+                    .map(|data| linknode_row(self.repo_id, &data.path, data.node, data.linknode))
+                    .filter(|row| seen.insert((row.path_hash.clone(), row.node)))
+                    .collect();
+                if rows.is_empty() {
+                    return future::ok(()).boxify();
+                }
+
+                let connection = self.connection.lock().expect("lock poisoned");
+                let txn_result = connection.transaction::<_, Error, _>(|| {
+                    for batch in rows.chunks(BATCH_SIZE) {
+                        let nodes: Vec<_> = batch.iter().map(|row| row.node).collect();
+                        let existing: HashSet<_> = linknodes_table::table
+                            .filter(linknodes_table::repo_id.eq(self.repo_id))
+                            .filter(linknodes_table::node.eq_any(nodes))
+                            .select((linknodes_table::path_hash, linknodes_table::node))
+                            .load::<(Vec<u8>, NodeHash)>(&*connection)?
+                            .into_iter()
+                            .collect();
+
+                        let new_rows: Vec<_> = batch
+                            .iter()
+                            .filter(|row| {
+                                !existing.contains(&(row.path_hash.clone(), row.node))
+                            })
+                            .cloned()
+                            .collect();
+                        if !new_rows.is_empty() {
+                            insert_into(linknodes_table::table)
+                                .values(&new_rows)
+                                .execute(&*connection)?;
+                        }
+                    }
+                    Ok(())
+                });
+                future::result(txn_result).boxify()
+            }
+
+            fn get_many(
+                &self,
+                entries: Vec<(RepoPath, NodeHash)>,
+            ) -> BoxFuture<Vec<NodeHash>, Error> {
+                if entries.is_empty() {
+                    return future::ok(vec![]).boxify();
+                }
+
+                // TODO: don't block -- send this to another thread
+                let connection = self.connection.lock().expect("lock poisoned");
+                // Nodes are very rarely shared between paths, so filtering on the nodes alone
+                // is selective enough. Paths are matched up afterwards.
+                let nodes: Vec<_> = entries.iter().map(|&(_, node)| node).collect();
+                let rows: result::Result<Vec<_>, _> = nodes
+                    .chunks(BATCH_SIZE)
+                    .map(|batch| {
+                        linknodes_table::table
+                            .filter(linknodes_table::repo_id.eq(self.repo_id))
+                            .filter(linknodes_table::node.eq_any(batch.to_vec()))
+                            .select((
+                                linknodes_table::path_hash,
+                                linknodes_table::node,
+                                linknodes_table::linknode,
+                            ))
+                            .load::<(Vec<u8>, NodeHash, NodeHash)>(&*connection)
+                    })
+                    .collect();
+
+                let result = rows.map_err(Error::from).and_then(|rows| {
+                    let found: HashMap<_, _> = rows.into_iter()
+                        .flat_map(|rows| rows)
+                        .map(|(path_hash, node, linknode)| ((path_hash, node), linknode))
+                        .collect();
+                    entries
+                        .into_iter()
+                        .map(|(path, node)| {
+                            found
+                                .get(&(path_hash(&path), node))
+                                .cloned()
+                                .ok_or_else(|| ErrorKind::NotFound(path, node).into())
+                        })
+                        .collect::<result::Result<Vec<_>, Error>>()
+                });
+                future::result(result).boxify()
+            }
+        }
+    }
+}
+
+impl_linknodes!(MysqlLinknodes, MysqlConnection);
+impl_linknodes!(SqliteLinknodes, SqliteConnection);
+
+fn path_hash(path: &RepoPath) -> Vec<u8> {
+    Sha1::from(&path.serialize()[..]).as_ref().to_vec()
+}
+
+fn linknode_row(
+    repo_id: RepositoryId,
+    path: &RepoPath,
+    node: NodeHash,
+    linknode: NodeHash,
+) -> LinknodeRow {
+    let path = path.serialize();
+    LinknodeRow {
+        repo_id,
+        path_hash: Sha1::from(&path[..]).as_ref().to_vec(),
+        path,
+        node,
+        linknode,
+    }
+}
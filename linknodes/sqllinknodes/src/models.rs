@@ -0,0 +1,22 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use mercurial_types::{NodeHash, RepositoryId};
+
+use schema::linknodes;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable, Insertable)]
+#[table_name = "linknodes"]
+pub(crate) struct LinknodeRow {
+    pub repo_id: RepositoryId,
+    /// Hash of `path`, so that the primary key stays short however long the path is.
+    pub path_hash: Vec<u8>,
+    /// The serialized `RepoPath`.
+    pub path: Vec<u8>,
+    pub node: NodeHash,
+    pub linknode: NodeHash,
+}
@@ -19,7 +19,8 @@ extern crate mercurial_types;
 use std::fmt;
 use std::sync::Arc;
 
-use futures::future::{err, ok};
+use futures::Future;
+use futures::future::{err, join_all, ok};
 use futures_ext::{BoxFuture, FutureExt};
 
 use mercurial_types::{NodeHash, RepoPath};
@@ -68,6 +69,37 @@ pub use errors::*;
 pub trait Linknodes: Send + Sync + 'static {
     fn add(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> BoxFuture<(), Error>;
     fn get(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<NodeHash, Error>;
+
+    /// Add linknodes for several entries at once. Unlike `add`, entries that already have a
+    /// linknode are not an error -- the existing linknode is kept.
+    ///
+    /// The default implementation calls `add` for every entry. Stores that can do better (e.g.
+    /// with a single bulk insert) should override this.
+    fn add_many(&self, linknodes: Vec<LinknodeData>) -> BoxFuture<(), Error> {
+        let futures: Vec<_> = linknodes
+            .into_iter()
+            .map(|data| {
+                self.add(data.path, &data.node, &data.linknode)
+                    .or_else(|err| match err.downcast_ref::<ErrorKind>() {
+                        Some(&ErrorKind::AlreadyExists { .. }) => Ok(()),
+                        _ => Err(err),
+                    })
+            })
+            .collect();
+        join_all(futures).map(|_| ()).boxify()
+    }
+
+    /// Look up the linknodes for several entries at once. The result is in the same order as
+    /// `entries`. Fails with `NotFound` if any of the entries is missing.
+    ///
+    /// The default implementation calls `get` for every entry.
+    fn get_many(&self, entries: Vec<(RepoPath, NodeHash)>) -> BoxFuture<Vec<NodeHash>, Error> {
+        let futures: Vec<_> = entries
+            .into_iter()
+            .map(|(path, node)| self.get(path, &node))
+            .collect();
+        join_all(futures).boxify()
+    }
 }
 
 /// A linknodes implementation that never stores anything.
@@ -102,6 +134,16 @@ impl Linknodes for Arc<Linknodes> {
     fn add(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> BoxFuture<(), Error> {
         (**self).add(path, node, linknode)
     }
+
+    #[inline]
+    fn add_many(&self, linknodes: Vec<LinknodeData>) -> BoxFuture<(), Error> {
+        (**self).add_many(linknodes)
+    }
+
+    #[inline]
+    fn get_many(&self, entries: Vec<(RepoPath, NodeHash)>) -> BoxFuture<Vec<NodeHash>, Error> {
+        (**self).get_many(entries)
+    }
 }
 
 impl<L> Linknodes for Arc<L>
@@ -117,6 +159,16 @@ where
     fn add(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> BoxFuture<(), Error> {
         (**self).add(path, node, linknode)
     }
+
+    #[inline]
+    fn add_many(&self, linknodes: Vec<LinknodeData>) -> BoxFuture<(), Error> {
+        (**self).add_many(linknodes)
+    }
+
+    #[inline]
+    fn get_many(&self, entries: Vec<(RepoPath, NodeHash)>) -> BoxFuture<Vec<NodeHash>, Error> {
+        (**self).get_many(entries)
+    }
 }
 
 /// A struct representing all the data associated with a linknode. This definition is here so that
@@ -18,15 +18,18 @@ extern crate linknodes;
 extern crate memlinknodes;
 extern crate mercurial_types;
 extern crate mercurial_types_mocks;
+extern crate sqllinknodes;
 
 use futures::Future;
 use tempdir::TempDir;
 
 use filelinknodes::FileLinknodes;
-use linknodes::{ErrorKind, Linknodes, OptionNodeHash};
+use linknodes::{ErrorKind, LinknodeData, Linknodes, OptionNodeHash};
 use memlinknodes::MemLinknodes;
-use mercurial_types::RepoPath;
+use mercurial_types::{NodeHash, RepoPath};
 use mercurial_types_mocks::nodehash::*;
+use mercurial_types_mocks::repo::REPO_ZERO;
+use sqllinknodes::{MysqlLinknodes, SqliteLinknodes};
 
 fn add_and_get<L: Linknodes>(linknodes: L) {
     let path = RepoPath::file("abc").unwrap();
@@ -75,6 +78,78 @@ fn not_found<L: Linknodes>(linknodes: L) {
     );
 }
 
+fn add_many_and_get_many<L: Linknodes>(linknodes: L) {
+    let file = RepoPath::file("abc").unwrap();
+    let dir = RepoPath::dir("abc").unwrap();
+    linknodes
+        .add(file.clone(), &NULL_HASH, &ONES_HASH)
+        .wait()
+        .unwrap();
+
+    // The existing linknode for (file, NULL_HASH) is kept.
+    let data = vec![
+        LinknodeData {
+            path: file.clone(),
+            node: NULL_HASH,
+            linknode: THREES_HASH,
+        },
+        LinknodeData {
+            path: dir.clone(),
+            node: NULL_HASH,
+            linknode: TWOS_HASH,
+        },
+        LinknodeData {
+            path: RepoPath::root(),
+            node: AS_HASH,
+            linknode: TWOS_HASH,
+        },
+    ];
+    linknodes.add_many(data).wait().unwrap();
+
+    let entries = vec![
+        (RepoPath::root(), AS_HASH),
+        (file.clone(), NULL_HASH),
+        (dir.clone(), NULL_HASH),
+    ];
+    assert_eq!(
+        linknodes.get_many(entries).wait().unwrap(),
+        vec![TWOS_HASH, ONES_HASH, TWOS_HASH]
+    );
+
+    assert_matches!(
+        linknodes
+            .get_many(vec![(file.clone(), NULL_HASH), (file.clone(), AS_HASH)])
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>().unwrap(),
+        ErrorKind::NotFound(ref p, ref h) if p == &file && *h == AS_HASH
+    );
+}
+
+fn add_many_and_get_many_large<L: Linknodes>(linknodes: L) {
+    // More entries than fit in a single SQL statement.
+    let entries: Vec<_> = (0..1200)
+        .map(|i| {
+            let path = RepoPath::file(format!("file{}", i).as_str()).unwrap();
+            let node: NodeHash = format!("{:040x}", i).parse().unwrap();
+            (path, node)
+        })
+        .collect();
+    let data = entries
+        .iter()
+        .map(|&(ref path, node)| LinknodeData {
+            path: path.clone(),
+            node,
+            linknode: ONES_HASH,
+        })
+        .collect();
+    linknodes.add_many(data).wait().unwrap();
+
+    let found = linknodes.get_many(entries).wait().unwrap();
+    assert_eq!(found.len(), 1200);
+    assert!(found.iter().all(|linknode| *linknode == ONES_HASH));
+}
+
 fn persistence<F, L>(mut new_linknodes: F)
 where
     F: FnMut() -> L,
@@ -114,6 +189,18 @@ macro_rules! linknodes_test_impl {
                 not_found($new_cb(&state));
             }
 
+            #[test]
+            fn test_add_many_and_get_many() {
+                let state = $state;
+                add_many_and_get_many($new_cb(&state));
+            }
+
+            #[test]
+            fn test_add_many_and_get_many_large() {
+                let state = $state;
+                add_many_and_get_many_large($new_cb(&state));
+            }
+
             #[test]
             fn test_persistence() {
                 // Not all linknode implementations support persistence. There doesn't seem to be
@@ -143,3 +230,26 @@ linknodes_test_impl! {
         persistent: true,
     }
 }
+
+linknodes_test_impl! {
+    sqlite_linknodes_test => {
+        state: TempDir::new("sqlite_linknodes_test").unwrap(),
+        new: |dir: &TempDir| {
+            let path = dir.as_ref().join("linknodes");
+            if path.exists() {
+                SqliteLinknodes::open(path.to_string_lossy(), REPO_ZERO).unwrap()
+            } else {
+                SqliteLinknodes::create(path.to_string_lossy(), REPO_ZERO).unwrap()
+            }
+        },
+        persistent: true,
+    }
+}
+
+linknodes_test_impl! {
+    mysql_linknodes_test => {
+        state: (),
+        new: |_| MysqlLinknodes::create_test_db("linknodes_test", REPO_ZERO).unwrap(),
+        persistent: false,
+    }
+}
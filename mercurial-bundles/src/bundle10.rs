@@ -0,0 +1,77 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Parsing for HG10 (changegroup1) bundles, as written by `hg bundle --type v1`.
+//!
+//! An HG10 bundle is the magic string "HG10", a two byte compression type, and a bare
+//! changegroup1 stream. To let the rest of Mononoke treat these the same way as bundle2, the
+//! stream is presented as a bundle2 with a single changegroup part.
+
+use std::collections::HashMap;
+use std::io::{BufRead, Cursor, Read};
+
+use futures::{stream, Future, Stream};
+use futures_ext::{BoxStream, StreamExt};
+use slog;
+use tokio_io::AsyncRead;
+use tokio_io::codec::FramedRead;
+use tokio_io::io::read_exact;
+
+use async_compression::{Decompressor, DecompressorType};
+
+use Bundle2Item;
+use changegroup::CgVersion;
+use changegroup::unpacker::CgUnpacker;
+use errors::*;
+use part_header::{PartHeaderBuilder, PartHeaderType};
+use types::StreamHeader;
+
+/// Parse an HG10 bundle into a stream of bundle2 items.
+pub fn bundle10_stream<R>(read: R, logger: slog::Logger) -> BoxStream<Bundle2Item, Error>
+where
+    R: AsyncRead + BufRead + 'static + Send,
+{
+    read_exact(read, [0u8; 6])
+        .map_err(Error::from)
+        .and_then(move |(read, magic)| {
+            if &magic[..4] != b"HG10" {
+                bail_err!(ErrorKind::Bundle2Decode(
+                    "invalid bundle10 magic string".into(),
+                ));
+            }
+            let read: Box<AsyncRead + Send> = match &magic[4..] {
+                b"UN" => Box::new(read),
+                b"GZ" => Box::new(Decompressor::new(read, DecompressorType::Zlib)),
+                // The "BZ" is both the compression type and the start of the bzip2 stream, so
+                // it has to be put back for the decoder.
+                b"BZ" => Box::new(Decompressor::new(
+                    Cursor::new(b"BZ".to_vec()).chain(read),
+                    DecompressorType::Bzip2,
+                )),
+                compression => bail_err!(ErrorKind::Bundle2Decode(format!(
+                    "unknown compression '{}'",
+                    String::from_utf8_lossy(compression)
+                ))),
+            };
+
+            let mut header = PartHeaderBuilder::new(PartHeaderType::Changegroup, true)?;
+            header.add_mparam("version", "01")?;
+            let changegroup = FramedRead::new(
+                read,
+                CgUnpacker::new(logger.new(o!("stream" => "cg1")), CgVersion::Cg1),
+            );
+
+            Ok(stream::iter_ok(vec![
+                Bundle2Item::Start(StreamHeader {
+                    m_stream_params: HashMap::new(),
+                    a_stream_params: HashMap::new(),
+                }),
+                Bundle2Item::Changegroup(header.build(0), changegroup.boxify()),
+            ]))
+        })
+        .flatten_stream()
+        .boxify()
+}
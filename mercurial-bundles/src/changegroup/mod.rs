@@ -6,9 +6,35 @@
 
 use mercurial_types::{Delta, MPath, NodeHash};
 
+use errors::*;
+
 pub mod packer;
 pub mod unpacker;
 
+/// The changegroup format version. Version 1 is found in HG10 bundles; version 2 adds the
+/// delta base to chunk headers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CgVersion {
+    Cg1,
+    Cg2,
+}
+
+impl CgVersion {
+    /// The version of a bundle2 changegroup part given its `version` parameter. Clients that
+    /// push to us always send changegroup2, so that's what a missing parameter means. Other
+    /// versions, such as changegroup3, are an error.
+    pub fn from_param(version: Option<&[u8]>) -> Result<Self> {
+        match version {
+            Some(b"01") => Ok(CgVersion::Cg1),
+            Some(b"02") | None => Ok(CgVersion::Cg2),
+            Some(version) => bail_err!(ErrorKind::Bundle2Decode(format!(
+                "unknown changegroup version {:?}",
+                String::from_utf8_lossy(version)
+            ))),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Section {
     Changeset,
@@ -122,7 +148,7 @@ mod test {
             .map(|chunk| chunk.into_bytes().expect("expected normal chunk"));
 
         let logger = make_root_logger();
-        let unpacker = unpacker::Cg2Unpacker::new(logger);
+        let unpacker = unpacker::CgUnpacker::new(logger, CgVersion::Cg2);
         let part_stream = chunks.decode(unpacker);
 
         let parts = Vec::new();
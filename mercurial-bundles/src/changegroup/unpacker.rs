@@ -14,18 +14,22 @@ use bytes::BytesMut;
 use slog;
 use tokio_io::codec::Decoder;
 
-use mercurial_types::MPath;
+use mercurial_types::{MPath, NodeHash};
 
 use delta;
 use errors::*;
 use utils::BytesExt;
 
-use super::{CgDeltaChunk, Part, Section};
+use super::{CgDeltaChunk, CgVersion, Part, Section};
 
 #[derive(Debug)]
-pub struct Cg2Unpacker {
+pub struct CgUnpacker {
     logger: slog::Logger,
+    version: CgVersion,
     state: State,
+    // The node of the last chunk in the current section. Changegroup version 1 has no base node
+    // in its chunk headers: each chunk is a delta against the one before it.
+    prev_node: Option<NodeHash>,
 }
 
 impl Part {
@@ -58,13 +62,16 @@ impl Part {
 // See the chunk header definition below for the first 100 bytes. The last 4 is
 // for the length field itself.
 const CHUNK_HEADER_LEN: usize = 20 + 20 + 20 + 20 + 20 + 4;
+// Changegroup version 1 chunk headers don't have the base node.
+const CG1_CHUNK_HEADER_LEN: usize = 20 + 20 + 20 + 20 + 4;
 
-impl Decoder for Cg2Unpacker {
+impl Decoder for CgUnpacker {
     type Item = Part;
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
-        match Self::decode_next(buf, self.state.take()) {
+        let state = self.state.take();
+        match Self::decode_next(buf, state, self.version, &mut self.prev_node) {
             Err(e) => {
                 self.state = State::Invalid;
                 Err(e)
@@ -106,17 +113,24 @@ impl Decoder for Cg2Unpacker {
     }
 }
 
-impl Cg2Unpacker {
-    pub fn new(logger: slog::Logger) -> Self {
-        Cg2Unpacker {
+impl CgUnpacker {
+    pub fn new(logger: slog::Logger, version: CgVersion) -> Self {
+        CgUnpacker {
             logger: logger,
+            version: version,
             state: State::Changeset,
+            prev_node: None,
         }
     }
 
-    fn decode_next(buf: &mut BytesMut, state: State) -> Result<(Option<Part>, State)> {
+    fn decode_next(
+        buf: &mut BytesMut,
+        state: State,
+        version: CgVersion,
+        prev_node: &mut Option<NodeHash>,
+    ) -> Result<(Option<Part>, State)> {
         match state {
-            State::Changeset => match Self::decode_chunk(buf)? {
+            State::Changeset => match Self::decode_chunk(buf, version, prev_node)? {
                 None => Ok((None, State::Changeset)),
                 Some(CgChunk::Empty) => {
                     Ok((Some(Part::SectionEnd(Section::Changeset)), State::Manifest))
@@ -126,7 +140,7 @@ impl Cg2Unpacker {
                     State::Changeset,
                 )),
             },
-            State::Manifest => match Self::decode_chunk(buf)? {
+            State::Manifest => match Self::decode_chunk(buf, version, prev_node)? {
                 None => Ok((None, State::Manifest)),
                 Some(CgChunk::Empty) => {
                     Ok((Some(Part::SectionEnd(Section::Manifest)), State::Filename))
@@ -140,18 +154,25 @@ impl Cg2Unpacker {
                 let filename = Self::decode_filename(buf)?;
                 match filename {
                     DecodeRes::None => Ok((None, State::Filename)),
-                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, f),
+                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, f, version, prev_node),
                     DecodeRes::End => Ok((Some(Part::End), State::End)),
                 }
             }
-            State::Filelog(filename) => Self::decode_filelog_chunk(buf, filename),
+            State::Filelog(filename) => {
+                Self::decode_filelog_chunk(buf, filename, version, prev_node)
+            }
             State::End => Ok((None, State::End)),
             State::Invalid => Err(ErrorKind::Cg2Decode("byte stream corrupt".into()).into()),
         }
     }
 
-    fn decode_filelog_chunk(buf: &mut BytesMut, f: MPath) -> Result<(Option<Part>, State)> {
-        match Self::decode_chunk(buf)? {
+    fn decode_filelog_chunk(
+        buf: &mut BytesMut,
+        f: MPath,
+        version: CgVersion,
+        prev_node: &mut Option<NodeHash>,
+    ) -> Result<(Option<Part>, State)> {
+        match Self::decode_chunk(buf, version, prev_node)? {
             None => Ok((None, State::Filelog(f))),
             Some(CgChunk::Empty) => {
                 Ok((Some(Part::SectionEnd(Section::Filelog(f))), State::Filename))
@@ -163,7 +184,11 @@ impl Cg2Unpacker {
         }
     }
 
-    fn decode_chunk(buf: &mut BytesMut) -> Result<Option<CgChunk>> {
+    fn decode_chunk(
+        buf: &mut BytesMut,
+        version: CgVersion,
+        prev_node: &mut Option<NodeHash>,
+    ) -> Result<Option<CgChunk>> {
         if buf.len() < 4 {
             return Ok(None);
         }
@@ -174,12 +199,17 @@ impl Cg2Unpacker {
         let chunk_len = chunk_len as usize;
         if chunk_len == 0 {
             let _ = buf.drain_i32();
+            *prev_node = None;
             return Ok(Some(CgChunk::Empty));
         }
-        if chunk_len < CHUNK_HEADER_LEN {
+        let header_len = match version {
+            CgVersion::Cg1 => CG1_CHUNK_HEADER_LEN,
+            CgVersion::Cg2 => CHUNK_HEADER_LEN,
+        };
+        if chunk_len < header_len {
             let msg = format!(
                 "invalid chunk: length >= {} required, found {}",
-                CHUNK_HEADER_LEN, chunk_len
+                header_len, chunk_len
             );
             bail_err!(ErrorKind::Cg2Decode(msg));
         }
@@ -197,14 +227,20 @@ impl Cg2Unpacker {
         // base node: NodeHash (20 bytes) (new in changegroup2)
         // link node: NodeHash (20 bytes)
         // ---
+        //
+        // In changegroup1 the base is the previous chunk in the section, or p1 for the first.
 
         let node = buf.drain_node();
         let p1 = buf.drain_node();
         let p2 = buf.drain_node();
-        let base = buf.drain_node();
+        let base = match version {
+            CgVersion::Cg1 => prev_node.unwrap_or(p1),
+            CgVersion::Cg2 => buf.drain_node(),
+        };
         let linknode = buf.drain_node();
+        *prev_node = Some(node);
 
-        let delta = delta::decode_delta(buf.split_to(chunk_len - CHUNK_HEADER_LEN))?;
+        let delta = delta::decode_delta(buf.split_to(chunk_len - header_len))?;
         return Ok(Some(CgChunk::Delta(CgDeltaChunk {
             node: node,
             p1: p1,
@@ -50,6 +50,7 @@ extern crate mercurial_types_mocks;
 #[cfg(test)]
 extern crate partial_io;
 
+pub mod bundle10;
 pub mod bundle2;
 pub mod bundle2_encode;
 pub mod changegroup;
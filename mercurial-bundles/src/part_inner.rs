@@ -63,6 +63,9 @@ pub fn validate_header(header: PartHeader) -> Result<Option<PartHeader>> {
                     unknown_params,
                 ));
             }
+            if *header.part_type() == PartHeaderType::Changegroup {
+                changegroup::CgVersion::from_param(changegroup_version(&header))?;
+            }
             Ok(Some(header))
         }
         None => {
@@ -74,6 +77,10 @@ pub fn validate_header(header: PartHeader) -> Result<Option<PartHeader>> {
     }
 }
 
+fn changegroup_version(header: &PartHeader) -> Option<&[u8]> {
+    header.mparams().get("version").map(|v| v.as_ref())
+}
+
 /// Convert an OuterStream into an InnerStream using the part header.
 pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
     header: PartHeader,
@@ -87,14 +94,18 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
 
     let bundle2item = match header.part_type() {
         &PartHeaderType::Changegroup => {
-            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::Cg2Unpacker::new(
+            let version = changegroup::CgVersion::from_param(changegroup_version(&header))
+                .expect("changegroup version should have been validated");
+            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::CgUnpacker::new(
                 logger.new(o!("stream" => "cg2")),
+                version,
             ));
             Bundle2Item::Changegroup(header, Box::new(cg2_stream))
         }
         &PartHeaderType::B2xInfinitepush => {
-            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::Cg2Unpacker::new(
+            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::CgUnpacker::new(
                 logger.new(o!("stream" => "cg2")),
+                changegroup::CgVersion::Cg2,
             ));
             Bundle2Item::B2xInfinitepush(header, Box::new(cg2_stream))
         }
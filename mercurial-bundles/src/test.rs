@@ -7,7 +7,7 @@
 use std::collections::HashMap;
 use std::convert::From;
 use std::fmt::Debug;
-use std::io::{self, BufRead, BufReader, Cursor};
+use std::io::{self, BufRead, BufReader, Cursor, Write};
 use std::iter::Iterator;
 use std::str::FromStr;
 
@@ -18,7 +18,7 @@ use slog_term;
 use tokio_core::reactor::Core;
 use tokio_io::AsyncRead;
 
-use async_compression::{Bzip2Compression, CompressorType, FlateCompression};
+use async_compression::{Bzip2Compression, Compressor, CompressorType, FlateCompression};
 use async_compression::membuf::MemBuf;
 use mercurial_types::{MPath, NodeHash, RepoPath, NULL_HASH};
 use partial_io::{GenWouldBlock, PartialAsyncRead, PartialWithErrors};
@@ -26,6 +26,7 @@ use quickcheck::{QuickCheck, StdGen};
 use rand;
 
 use Bundle2Item;
+use bundle10::bundle10_stream;
 use bundle2::{Bundle2Stream, StreamEvent};
 use bundle2_encode::Bundle2EncodeBuilder;
 use changegroup;
@@ -381,6 +382,139 @@ fn verify_cg2(core: &mut Core, stream: BoxStream<changegroup::Part, Error>) {
     );
 }
 
+#[test]
+fn test_parse_bundle10_uncompressed() {
+    let mut bundle = b"HG10UN".to_vec();
+    bundle.extend_from_slice(&make_cg1());
+    parse_bundle10(bundle);
+}
+
+#[test]
+fn test_parse_bundle10_bzip2() {
+    let mut compressor = Compressor::new(
+        Cursor::new(Vec::new()),
+        CompressorType::Bzip2(Bzip2Compression::Default),
+    );
+    compressor.write_all(&make_cg1()).unwrap();
+    let compressed = compressor.try_finish().unwrap().into_inner();
+
+    // HG10BZ bundles share the "BZ" between the compression type and the bzip2 stream.
+    assert_eq!(&compressed[..2], b"BZ");
+    let mut bundle = b"HG10".to_vec();
+    bundle.extend_from_slice(&compressed);
+    parse_bundle10(bundle);
+}
+
+#[test]
+fn test_parse_bundle10_bad_magic() {
+    let mut core = Core::new().unwrap();
+    let stream = bundle10_stream(Cursor::new(b"HG20UN".to_vec()), make_root_logger());
+    let err = core.run(stream.collect()).unwrap_err();
+    assert_matches!(err.downcast::<ErrorKind>().unwrap(),
+                    ErrorKind::Bundle2Decode(ref msg) if msg == "invalid bundle10 magic string");
+}
+
+#[test]
+fn test_cg_version_from_param() {
+    use changegroup::CgVersion;
+
+    assert_eq!(
+        CgVersion::from_param(Some(&b"01"[..])).unwrap(),
+        CgVersion::Cg1
+    );
+    assert_eq!(
+        CgVersion::from_param(Some(&b"02"[..])).unwrap(),
+        CgVersion::Cg2
+    );
+    assert_eq!(CgVersion::from_param(None).unwrap(), CgVersion::Cg2);
+    for bad in &[&b"03"[..], &b"garbage"[..], &b""[..]] {
+        assert_matches!(
+            CgVersion::from_param(Some(*bad)).unwrap_err().downcast::<ErrorKind>().unwrap(),
+            ErrorKind::Bundle2Decode(_)
+        );
+    }
+}
+
+/// A changegroup1 stream with two linear changesets, and no manifests or files.
+fn make_cg1() -> Vec<u8> {
+    let changeset1_hash = NodeHash::from_str(CHANGESET1_HASH_STR).unwrap();
+    let changeset2_hash = NodeHash::from_str(CHANGESET2_HASH_STR).unwrap();
+
+    let mut cg1 = Vec::new();
+    for &(node, p1, text) in &[
+        (changeset1_hash, NULL_HASH, &b"changeset1"[..]),
+        (changeset2_hash, changeset1_hash, &b"changeset2"[..]),
+    ] {
+        // Chunk length, node, p1, p2 and linknode, then a single fragment delta.
+        cg1.extend_from_slice(&be_u32(4 + 80 + 12 + text.len()));
+        cg1.extend_from_slice(node.as_ref());
+        cg1.extend_from_slice(p1.as_ref());
+        cg1.extend_from_slice(NULL_HASH.as_ref());
+        cg1.extend_from_slice(node.as_ref());
+        cg1.extend_from_slice(&be_u32(0));
+        cg1.extend_from_slice(&be_u32(0));
+        cg1.extend_from_slice(&be_u32(text.len()));
+        cg1.extend_from_slice(text);
+    }
+    // End of changesets, end of manifests, end of filelogs.
+    cg1.extend_from_slice(&[0u8; 12]);
+    cg1
+}
+
+fn be_u32(n: usize) -> [u8; 4] {
+    let n = n as u32;
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+fn parse_bundle10(bundle: Vec<u8>) {
+    let mut core = Core::new().unwrap();
+    let stream = bundle10_stream(Cursor::new(bundle), make_root_logger());
+
+    let (res, stream) = core.next_stream(stream);
+    assert_matches!(res, Some(Bundle2Item::Start(_)));
+
+    let (res, stream) = core.next_stream(stream);
+    let parts = match res {
+        Some(Bundle2Item::Changegroup(header, parts)) => {
+            assert_eq!(
+                header.mparams().get("version").map(|v| v.as_ref()),
+                Some(&b"01"[..])
+            );
+            parts
+        }
+        bad => panic!("Unexpected bundle2 item: {:?}", bad),
+    };
+    let (res, _) = core.next_stream(stream);
+    assert!(res.is_none());
+
+    let parts = core.run(parts.collect()).unwrap();
+    assert_eq!(parts.len(), 5);
+
+    let changeset1_hash = NodeHash::from_str(CHANGESET1_HASH_STR).unwrap();
+    let changeset2_hash = NodeHash::from_str(CHANGESET2_HASH_STR).unwrap();
+
+    // The first chunk of a section is a delta against p1, and later ones against the previous
+    // chunk.
+    assert_eq!(*parts[0].section(), changegroup::Section::Changeset);
+    assert_eq!(parts[0].chunk().node, changeset1_hash);
+    assert_eq!(parts[0].chunk().base, NULL_HASH);
+    assert_eq!(parts[0].chunk().linknode, changeset1_hash);
+    assert_eq!(parts[1].chunk().node, changeset2_hash);
+    assert_eq!(parts[1].chunk().p1, changeset1_hash);
+    assert_eq!(parts[1].chunk().base, changeset1_hash);
+    assert_eq!(parts[1].chunk().delta.fragments()[0].content, b"changeset2".to_vec());
+
+    assert_matches!(
+        parts[2],
+        changegroup::Part::SectionEnd(changegroup::Section::Changeset)
+    );
+    assert_matches!(
+        parts[3],
+        changegroup::Part::SectionEnd(changegroup::Section::Manifest)
+    );
+    assert_matches!(parts[4], changegroup::Part::End);
+}
+
 #[test]
 fn test_parse_wirepack() {
     let rng = StdGen::new(rand::thread_rng(), 20);
@@ -46,9 +46,179 @@ pub fn apply(text: &[u8], deltas: &[Delta]) -> Vec<u8> {
     ret
 }
 
+/// Beyond this many edits, `diff` gives up looking for a minimal diff and replaces everything
+/// between the common prefix and suffix. This bounds the memory used for very different texts.
+const MAX_EDITS: isize = 1000;
+
+/// Compute the `Delta`s that turn `old` into `new`, comparing them line by line.
+///
+/// The result is sorted and non-overlapping, so it can be passed straight to `apply`. Like
+/// Mercurial's bdiff it is a line-based diff, although the exact hunks may differ.
+pub fn diff(old: &[u8], new: &[u8]) -> Vec<Delta> {
+    let old_lines = split_lines(old);
+    let new_lines = split_lines(new);
+
+    // Line i of old starts at byte old_offsets[i]; the extra entry at the end is old.len().
+    let mut old_offsets = Vec::with_capacity(old_lines.len() + 1);
+    let mut off = 0;
+    for line in &old_lines {
+        old_offsets.push(off);
+        off += line.len();
+    }
+    old_offsets.push(off);
+
+    diff_lines(&old_lines, &new_lines)
+        .into_iter()
+        .map(|hunk| Delta {
+            start: old_offsets[hunk.old_start],
+            end: old_offsets[hunk.old_end],
+            content: new_lines[hunk.new_start..hunk.new_end].concat(),
+        })
+        .collect()
+}
+
+/// Split a text into lines, keeping the trailing newlines.
+pub fn split_lines(text: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (idx, byte) in text.iter().enumerate() {
+        if *byte == b'\n' {
+            lines.push(&text[start..idx + 1]);
+            start = idx + 1;
+        }
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+/// Lines `old_start..old_end` of the old text are replaced by `new_start..new_end` of the new.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_end: usize,
+    pub new_start: usize,
+    pub new_end: usize,
+}
+
+/// Compute the `Hunk`s that turn the lines `old` into `new`, in increasing order. Lines that
+/// aren't in any hunk are the same in both.
+pub fn diff_lines(old: &[&[u8]], new: &[&[u8]]) -> Vec<Hunk> {
+    // Strip the common prefix and suffix first: they're cheap to find, and typically cover
+    // most of the text.
+    let prefix = old.iter()
+        .zip(new.iter())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let matches = match myers_matches(old_mid, new_mid) {
+        Some(matches) => matches,
+        None => vec![],
+    };
+
+    let mut hunks = Vec::new();
+    let (mut old_pos, mut new_pos) = (0, 0);
+    let ends = (old_mid.len(), new_mid.len());
+    for (old_idx, new_idx) in matches.into_iter().chain(Some(ends)) {
+        if old_idx > old_pos || new_idx > new_pos {
+            hunks.push(Hunk {
+                old_start: prefix + old_pos,
+                old_end: prefix + old_idx,
+                new_start: prefix + new_pos,
+                new_end: prefix + new_idx,
+            });
+        }
+        old_pos = old_idx + 1;
+        new_pos = new_idx + 1;
+    }
+    hunks
+}
+
+/// Find a longest common subsequence of lines using Myers' O(ND) algorithm, returned as the
+/// pairs of matching line indices in increasing order. Returns `None` if the texts need more
+/// than `MAX_EDITS` edits.
+fn myers_matches(old: &[&[u8]], new: &[&[u8]]) -> Option<Vec<(usize, usize)>> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = n + m;
+    if max == 0 {
+        return Some(vec![]);
+    }
+
+    // v[k + max] is the furthest x reached on diagonal k = x - y. Each round's values for
+    // k in -d..=d are kept so that the path can be traced back afterwards.
+    let diag = |k: isize| (k + max) as usize;
+    let mut v = vec![0isize; 2 * max as usize + 2];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    let mut found = None;
+    'outer: for d in 0..(max.min(MAX_EDITS) + 1) {
+        let mut k = -d;
+        while k <= d {
+            let mut x = if k == -d || (k != d && v[diag(k - 1)] < v[diag(k + 1)]) {
+                v[diag(k + 1)]
+            } else {
+                v[diag(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[diag(k)] = x;
+            if x >= n && y >= m {
+                found = Some(d);
+                break 'outer;
+            }
+            k += 2;
+        }
+        trace.push(v[diag(-d)..diag(d) + 1].to_vec());
+    }
+    let edits = found?;
+
+    let mut matches = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (1..edits + 1).rev() {
+        let prev = &trace[(d - 1) as usize];
+        let get = |k: isize| prev[(k + d - 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && get(k - 1) < get(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = get(prev_k);
+        let prev_y = prev_x - prev_k;
+        // The single edit takes (prev_x, prev_y) to the start of the snake ending at (x, y).
+        let snake_x = if prev_k == k + 1 { prev_x } else { prev_x + 1 };
+        while x > snake_x {
+            x -= 1;
+            y -= 1;
+            matches.push((x as usize, y as usize));
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    while x > 0 && y > 0 {
+        x -= 1;
+        y -= 1;
+        matches.push((x as usize, y as usize));
+    }
+    matches.reverse();
+    Some(matches)
+}
+
 #[cfg(test)]
 mod test {
-    use super::{apply, Delta};
+    use super::{apply, diff, diff_lines, split_lines, Delta, Hunk};
 
     #[test]
     fn test_1() {
@@ -154,4 +324,70 @@ mod test {
         assert_eq!(&res[..], b"aaaa\ncccc\n");
     }
 
+    #[test]
+    fn test_diff_replace() {
+        let old = b"aaaa\nbbbb\ncccc\n";
+        let new = b"aaaa\nxxxx\ncccc\n";
+        assert_eq!(
+            diff(old, new),
+            vec![
+                Delta {
+                    start: 5,
+                    end: 10,
+                    content: (&b"xxxx\n"[..]).into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_identical() {
+        let text = b"aaaa\nbbbb\n";
+        assert_eq!(diff(text, text), vec![]);
+    }
+
+    #[test]
+    fn test_diff_insert_and_delete() {
+        let old = b"aaaa\nbbbb\ncccc\ndddd\n";
+        let new = b"zzzz\naaaa\ncccc\ndddd";
+        let deltas = diff(old, new);
+        assert_eq!(deltas.len(), 3);
+        assert_eq!(&apply(old, &deltas)[..], &new[..]);
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let old = split_lines(b"aaaa\nbbbb\ncccc\n");
+        let new = split_lines(b"aaaa\nxxxx\nyyyy\ncccc");
+        assert_eq!(
+            diff_lines(&old, &new),
+            vec![
+                Hunk {
+                    old_start: 1,
+                    old_end: 3,
+                    new_start: 1,
+                    new_end: 4,
+                },
+            ]
+        );
+    }
+
+    quickcheck! {
+        fn diff_apply_roundtrip(old: Vec<Vec<u8>>, new: Vec<Vec<u8>>) -> bool {
+            // Build texts out of a small alphabet of lines so that they have plenty in common.
+            let to_text = |lines: Vec<Vec<u8>>| -> Vec<u8> {
+                lines
+                    .into_iter()
+                    .flat_map(|line| {
+                        let mut line: Vec<u8> = line.into_iter().map(|b| b'a' + b % 3).collect();
+                        line.push(b'\n');
+                        line
+                    })
+                    .collect()
+            };
+            let old = to_text(old);
+            let new = to_text(new);
+            apply(&old, &diff(&old, &new)) == new
+        }
+    }
 }
@@ -22,7 +22,8 @@ pub const NULL_HASH: NodeHash = NodeHash(hash::NULL);
 pub const NULL_CSID: ChangesetId = ChangesetId(NULL_HASH);
 
 #[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
-#[derive(HeapSizeOf)]
+#[derive(HeapSizeOf, FromSqlRow, AsExpression)]
+#[sql_type = "NodeHashSql"]
 pub struct NodeHash(Sha1);
 
 impl NodeHash {
@@ -21,6 +21,26 @@ use errors::*;
 #[sqlite_type = "Binary"]
 pub struct NodeHashSql;
 
+impl<DB: Backend> ToSql<NodeHashSql, DB> for NodeHash {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, DB>) -> serialize::Result {
+        out.write_all(self.as_ref())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl<DB: Backend> FromSql<NodeHashSql, DB> for NodeHash
+where
+    *const [u8]: FromSql<Binary, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> deserialize::Result<Self> {
+        // Using unsafe here saves on a heap allocation. See https://goo.gl/K6hapb.
+        let raw_bytes: *const [u8] = FromSql::<Binary, DB>::from_sql(bytes)?;
+        let raw_bytes: &[u8] = unsafe { &*raw_bytes };
+        let hash = NodeHash::from_bytes(raw_bytes).compat()?;
+        Ok(hash)
+    }
+}
+
 impl<DB: Backend> ToSql<NodeHashSql, DB> for ChangesetId {
     fn to_sql<W: Write>(&self, out: &mut Output<W, DB>) -> serialize::Result {
         out.write_all(self.as_nodehash().as_ref())?;
@@ -13,4 +13,5 @@ pub enum ErrorKind {
     #[fail(display = "Repo: {}", _0)] Repo(String),
     #[fail(display = "Path: {}", _0)] Path(String),
     #[fail(display = "Unknown requirement: {}", _0)] UnknownReq(String),
+    #[fail(display = "Largefile: {}", _0)] Largefile(String),
 }
@@ -0,0 +1,100 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Support for repos that use the largefiles extension.
+//!
+//! A largefile at `path` is tracked by a small standin file at `.hglf/path`, whose content is
+//! the hex SHA-1 of the largefile's content followed by a newline. The content itself is not in
+//! the revlogs: a repo keeps the largefiles it has locally in `.hg/largefiles/HASH`.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::{self, FromStr};
+
+use bytes::Bytes;
+use mercurial_types::MPath;
+use mercurial_types::hash::Sha1;
+
+use errors::*;
+
+/// The directory at the root of the repo that standins live in.
+pub const STANDIN_DIR: &[u8] = b".hglf";
+
+/// Return the path of the largefile that `path` stands in for, or `None` if `path` is not a
+/// standin.
+pub fn standin_target(path: &MPath) -> Option<MPath> {
+    let mut elements = path.into_iter();
+    match elements.next() {
+        Some(first) if first.as_bytes() == STANDIN_DIR => {
+            let target = MPath::empty().join(elements);
+            if target.is_empty() {
+                None
+            } else {
+                Some(target)
+            }
+        }
+        _ => None,
+    }
+}
+
+pub fn is_standin(path: &MPath) -> bool {
+    standin_target(path).is_some()
+}
+
+/// Parse the content of a standin into the hash of the largefile it stands in for.
+pub fn parse_standin(content: &[u8]) -> Result<Sha1> {
+    let invalid = || ErrorKind::Largefile(format!("invalid standin {:?}", content));
+    let hex = str::from_utf8(content).map_err(|_| invalid())?;
+    Ok(Sha1::from_str(hex.trim_right()).map_err(|_| invalid())?)
+}
+
+/// Read a largefile from the local store of the repo whose `.hg` directory is `basepath`,
+/// checking that its content matches `hash`.
+pub fn read_largefile(basepath: &Path, hash: &Sha1) -> Result<Bytes> {
+    let path = basepath.join("largefiles").join(hash.to_string());
+    let mut content = Vec::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_end(&mut content))
+        .with_context(|_| {
+            ErrorKind::Largefile(format!("{} is not in the local store at {:?}", hash, path))
+        })?;
+
+    let actual = Sha1::from(content.as_ref());
+    if actual != *hash {
+        bail_err!(ErrorKind::Largefile(format!(
+            "{:?} has content with hash {}",
+            path, actual
+        )));
+    }
+    Ok(Bytes::from(content))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn standin_paths() {
+        let path = |p: &str| MPath::new(p).expect("bad path");
+        assert_eq!(
+            standin_target(&path(".hglf/dir/big.bin")),
+            Some(path("dir/big.bin"))
+        );
+        assert_eq!(standin_target(&path(".hglf")), None);
+        assert_eq!(standin_target(&path("dir/.hglf/big.bin")), None);
+        assert_eq!(standin_target(&path("big.bin")), None);
+    }
+
+    #[test]
+    fn parse_standins() {
+        let hash = Sha1::from(&b"content"[..]);
+        let standin = format!("{}\n", hash);
+        assert_eq!(parse_standin(standin.as_bytes()).expect("bad standin"), hash);
+        assert!(parse_standin(b"not a hash\n").is_err());
+        assert!(parse_standin(b"\xff\n").is_err());
+    }
+}
@@ -32,6 +32,8 @@ extern crate time;
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
+#[cfg(test)]
+extern crate tempdir;
 
 extern crate asyncmemo;
 extern crate bookmarks;
@@ -39,12 +41,14 @@ extern crate mercurial_types;
 extern crate pylz4;
 extern crate stockbookmarks;
 extern crate storage_types;
+extern crate zstd;
 
 pub mod revlog;
 pub mod manifest;
 pub mod changeset;
 pub mod revlogrepo;
 pub mod file;
+pub mod largefiles;
 pub mod symlink;
 mod errors;
 pub use errors::*;
@@ -0,0 +1,516 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Conversion of flat manifests to tree manifests.
+//!
+//! Directory nodes are computed the way Mercurial's treemanifest computes them, so converting
+//! a flat manifest here gives the same hashes as converting the repo with Mercurial. In
+//! particular, a directory's parents are the same directory in the parent manifests, and a
+//! directory whose text is unchanged from one of those reuses its node. The root tree keeps the
+//! flat manifest's node and parents.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use failure::err_msg;
+
+use RevlogRepo;
+use errors::*;
+use mercurial_types::{MPath, MPathElement, NodeHash, Parents, Type};
+use mercurial_types::hash::{self, Context, Sha1};
+
+use super::revlog::{Details, ManifestContent};
+
+/// A tree manifest node synthesised from a flat manifest.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TreeNode {
+    /// The directory this is the manifest of; empty for the root.
+    pub path: MPath,
+    pub node: NodeHash,
+    pub text: Bytes,
+    pub parents: Parents,
+}
+
+/// The node of every directory in a tree manifest, along with a hash of its text, which is
+/// what a child manifest needs to spot the directories it left unchanged.
+#[derive(Debug, Default)]
+pub struct ConvertedTree {
+    dirs: HashMap<MPath, (NodeHash, Sha1)>,
+}
+
+impl ConvertedTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the node and text of a directory of an existing tree manifest, so that it can be
+    /// used as a parent. The root is under the empty path.
+    pub fn add_dir(&mut self, path: MPath, node: NodeHash, text: &[u8]) {
+        self.dirs.insert(path, (node, Sha1::from(text)));
+    }
+
+    /// The node of the given directory, if the tree has it.
+    pub fn get_dir(&self, path: &MPath) -> Option<&NodeHash> {
+        self.dirs.get(path).map(|&(ref node, _)| node)
+    }
+
+    /// The hash of the text of the given directory, if the tree has it.
+    pub fn get_dir_text_hash(&self, path: &MPath) -> Option<&Sha1> {
+        self.dirs.get(path).map(|&(_, ref hash)| hash)
+    }
+}
+
+/// Convert the flat manifest `mfid` given the trees of its parent manifests. Returns its tree,
+/// along with the tree nodes that it introduces: the root, and every directory that is not the
+/// same as in one of the parents.
+pub fn convert_flat(
+    mfid: &NodeHash,
+    parents: &Parents,
+    content: &ManifestContent,
+    p1: Option<&ConvertedTree>,
+    p2: Option<&ConvertedTree>,
+) -> Result<(ConvertedTree, Vec<TreeNode>)> {
+    // The entries of every directory: files have their details, subdirectories have None.
+    let mut dirs: HashMap<MPath, BTreeMap<MPathElement, Option<Details>>> = HashMap::new();
+    dirs.insert(MPath::empty(), BTreeMap::new());
+    for (path, details) in &content.files {
+        let elements: Vec<_> = path.into_iter().collect();
+        let (name, parent_elements) = match elements.split_last() {
+            Some(split) => split,
+            None => bail_msg!("empty path in flat manifest {}", mfid),
+        };
+        let mut dir = MPath::empty();
+        for element in parent_elements {
+            let previous = dirs.get_mut(&dir)
+                .expect("parent directory is missing")
+                .insert((*element).clone(), None);
+            dir = dir.join(Some(*element));
+            if let Some(Some(_)) = previous {
+                bail_msg!("{} is both a file and a directory in manifest {}", dir, mfid);
+            }
+            dirs.entry(dir.clone()).or_insert_with(BTreeMap::new);
+        }
+        let previous = dirs.get_mut(&dir)
+            .expect("directory is missing")
+            .insert((*name).clone(), Some(*details));
+        if let Some(None) = previous {
+            bail_msg!("{} is both a file and a directory in manifest {}", path, mfid);
+        }
+    }
+
+    // Subdirectories have to be hashed before the directories that contain them.
+    let mut paths: Vec<_> = dirs.keys().cloned().collect();
+    paths.sort_by_key(|path| Reverse(path.into_iter().count()));
+
+    let mut tree = ConvertedTree {
+        dirs: HashMap::with_capacity(paths.len()),
+    };
+    let mut nodes = Vec::new();
+    for path in paths {
+        let mut text = Vec::new();
+        for (name, details) in &dirs[&path] {
+            text.extend_from_slice(name.as_bytes());
+            text.push(b'\0');
+            match *details {
+                Some(ref details) => {
+                    write!(text, "{}{}\n", details.entryid().into_nodehash(), details.flag())?
+                }
+                None => {
+                    let subdir = path.join(Some(name));
+                    let &(subnode, _) = tree.dirs
+                        .get(&subdir)
+                        .expect("subdirectory should be converted first");
+                    write!(text, "{}{}\n", subnode, Type::Tree)?
+                }
+            }
+        }
+        let text_hash = Sha1::from(&text[..]);
+
+        let node = if path.is_empty() {
+            nodes.push(TreeNode {
+                path: path.clone(),
+                node: *mfid,
+                text: Bytes::from(text),
+                parents: *parents,
+            });
+            *mfid
+        } else {
+            let dir_p1 = p1.and_then(|tree| tree.dirs.get(&path)).cloned();
+            let dir_p2 = p2.and_then(|tree| tree.dirs.get(&path)).cloned();
+            match (dir_p1, dir_p2) {
+                (Some((node, hash)), _) if hash == text_hash => node,
+                (_, Some((node, hash))) if hash == text_hash => node,
+                (dir_p1, dir_p2) => {
+                    let (dir_p1, dir_p2) = match (dir_p1, dir_p2) {
+                        (None, dir_p2) => (dir_p2, None),
+                        parents => parents,
+                    };
+                    let dir_p1 = dir_p1.map(|(node, _)| node);
+                    let dir_p2 = dir_p2.map(|(node, _)| node);
+                    let node = hg_node(&text, dir_p1.as_ref(), dir_p2.as_ref());
+                    nodes.push(TreeNode {
+                        path: path.clone(),
+                        node,
+                        text: Bytes::from(text),
+                        parents: Parents::new(dir_p1.as_ref(), dir_p2.as_ref()),
+                    });
+                    node
+                }
+            }
+        };
+        tree.dirs.insert(path, (node, text_hash));
+    }
+
+    Ok((tree, nodes))
+}
+
+/// Number of converted flat manifests kept for their children to convert against.
+const CONVERTED_CACHE_SIZE: usize = 1000;
+
+/// `FlatConverter` converts flat manifests to tree manifests, so that importing a flat repo
+/// gives the same hashes as converting it to treemanifest with Mercurial and importing that.
+///
+/// Since a manifest's tree depends on its parent manifests' trees, recently converted
+/// manifests are kept around. Changesets are imported roughly in revlog order, so a manifest's
+/// parents have almost always just been converted.
+pub struct FlatConverter {
+    repo: RevlogRepo,
+    cache: Mutex<(HashMap<NodeHash, Arc<ConvertedTree>>, VecDeque<NodeHash>)>,
+}
+
+impl FlatConverter {
+    pub fn new(repo: RevlogRepo) -> Self {
+        FlatConverter {
+            repo,
+            cache: Mutex::new((HashMap::new(), VecDeque::new())),
+        }
+    }
+
+    /// Convert the flat manifest `mfid`. Returns its tree, along with the tree nodes that it
+    /// introduces: the root, and every directory that is not the same as in one of the parent
+    /// manifests.
+    pub fn convert(
+        &self,
+        mfid: &NodeHash,
+        parents: &Parents,
+        content: &ManifestContent,
+    ) -> Result<(Arc<ConvertedTree>, Vec<TreeNode>)> {
+        let (p1, p2) = parents.get_nodes();
+        let p1 = match p1 {
+            Some(p1) => Some(self.get_converted(p1)?),
+            None => None,
+        };
+        let p2 = match p2 {
+            Some(p2) => Some(self.get_converted(p2)?),
+            None => None,
+        };
+
+        let (tree, nodes) = convert_flat(
+            mfid,
+            parents,
+            content,
+            p1.as_ref().map(|p1| &**p1),
+            p2.as_ref().map(|p2| &**p2),
+        )?;
+        let tree = Arc::new(tree);
+        self.insert(*mfid, tree.clone());
+        Ok((tree, nodes))
+    }
+
+    fn get_converted(&self, mfid: &NodeHash) -> Result<Arc<ConvertedTree>> {
+        if let Some(tree) = self.get_cached(mfid) {
+            return Ok(tree);
+        }
+
+        // Convert all the ancestors that haven't been converted (or have been evicted from the
+        // cache) oldest first, holding on to each one only until its last child is converted.
+        let revlog = self.repo.get_manifest_revlog();
+        let mut missing = BTreeMap::new();
+        let mut converted = HashMap::new();
+        let mut children = HashMap::new();
+        let mut queue = vec![revlog.get_idx_by_nodeid(mfid)?];
+        while let Some(idx) = queue.pop() {
+            if missing.contains_key(&idx) || converted.contains_key(&idx) {
+                continue;
+            }
+            let entry = revlog.get_entry(idx)?;
+            if let Some(tree) = self.get_cached(&entry.nodeid) {
+                converted.insert(idx, tree);
+                continue;
+            }
+            for parent in entry.p1.iter().chain(entry.p2.iter()) {
+                *children.entry(*parent).or_insert(0) += 1;
+                queue.push(*parent);
+            }
+            missing.insert(idx, entry);
+        }
+
+        let mut last = None;
+        for (idx, entry) in missing {
+            let blob = revlog.get_rev(idx)?;
+            let content = blob.as_blob()
+                .as_slice()
+                .ok_or(err_msg("missing blob data"))
+                .and_then(ManifestContent::parse)?;
+            let tree = {
+                let p1 = entry.p1.and_then(|p1| converted.get(&p1)).map(|p1| &**p1);
+                let p2 = entry.p2.and_then(|p2| converted.get(&p2)).map(|p2| &**p2);
+                let (tree, _) = convert_flat(&entry.nodeid, blob.parents(), &content, p1, p2)?;
+                Arc::new(tree)
+            };
+            for parent in entry.p1.iter().chain(entry.p2.iter()) {
+                let count = children.get_mut(parent).expect("parent has no children");
+                *count -= 1;
+                if *count == 0 {
+                    converted.remove(parent);
+                }
+            }
+
+            self.insert(entry.nodeid, tree.clone());
+            if children.contains_key(&idx) {
+                converted.insert(idx, tree.clone());
+            }
+            last = Some(tree);
+        }
+
+        // mfid is a descendant of every other missing manifest, so it's converted last.
+        Ok(last.expect("no manifests converted"))
+    }
+
+    fn get_cached(&self, mfid: &NodeHash) -> Option<Arc<ConvertedTree>> {
+        let cache = self.cache.lock().expect("lock poisoned");
+        cache.0.get(mfid).cloned()
+    }
+
+    fn insert(&self, mfid: NodeHash, tree: Arc<ConvertedTree>) {
+        let mut cache = self.cache.lock().expect("lock poisoned");
+        let (ref mut trees, ref mut order) = *cache;
+        if trees.insert(mfid, tree).is_none() {
+            order.push_back(mfid);
+            if order.len() > CONVERTED_CACHE_SIZE {
+                let oldest = order.pop_front().expect("cache order is empty");
+                trees.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Generate the text of the flat manifest with the given files. Unlike
+/// `ManifestContent::generate`, entries are sorted by their full path as bytes, like Mercurial
+/// does, so that the text hashes to the flat manifest node.
+pub fn generate_flat<W: Write>(content: &ManifestContent, out: &mut W) -> io::Result<()> {
+    let mut files: Vec<_> = content
+        .files
+        .iter()
+        .map(|(path, details)| (path.to_vec(), details))
+        .collect();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    for (path, details) in files {
+        out.write_all(&path)?;
+        write!(out, "\0{}{}\n", details.entryid().into_nodehash(), details.flag())?;
+    }
+    Ok(())
+}
+
+/// Compute a revlog node the way Mercurial does. Unlike `BlobNode::nodeid`, this keeps both
+/// parents if they're the same, which happens when a merge changes a directory that both
+/// sides left alone.
+fn hg_node(text: &[u8], p1: Option<&NodeHash>, p2: Option<&NodeHash>) -> NodeHash {
+    let p1 = p1.map(NodeHash::sha1).unwrap_or(&hash::NULL);
+    let p2 = p2.map(NodeHash::sha1).unwrap_or(&hash::NULL);
+    let (p1, p2) = if p1 > p2 { (p2, p1) } else { (p1, p2) };
+
+    let mut context = Context::new();
+    context.update(p1);
+    context.update(p2);
+    context.update(text);
+    NodeHash::new(context.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    use mercurial_types::{Blob, BlobNode};
+
+    use revlog::{Compression, RevIdx, RevlogWriter};
+
+    const FILE1: &str = "1111111111111111111111111111111111111111";
+    const FILE2: &str = "2222222222222222222222222222222222222222";
+
+    fn flat_text(content: &ManifestContent) -> Vec<u8> {
+        let mut text = Vec::new();
+        generate_flat(content, &mut text).expect("generate failed");
+        text
+    }
+
+    fn node(hex: &str) -> NodeHash {
+        hex.parse().expect("bad hash")
+    }
+
+    fn convert(
+        mfid: &NodeHash,
+        text: &str,
+        parents: Parents,
+        p1: Option<&ConvertedTree>,
+    ) -> (ConvertedTree, Vec<TreeNode>) {
+        let content = ManifestContent::parse(text.as_bytes()).expect("bad manifest");
+        convert_flat(mfid, &parents, &content, p1, None).expect("conversion failed")
+    }
+
+    fn hash(text: &str, p1: Option<&NodeHash>) -> NodeHash {
+        BlobNode::new(Blob::from(Bytes::from(text)), p1, None)
+            .nodeid()
+            .expect("blob has data")
+    }
+
+    #[test]
+    fn convert_nested() {
+        let mfid = node("abababababababababababababababababababab");
+        let flat = format!("a.txt\0{}\na/b/c\0{}x\n", FILE1, FILE2);
+        let (tree, nodes) = convert(&mfid, &flat, Parents::None, None);
+
+        let b_text = format!("c\0{}x\n", FILE2);
+        let b_node = hash(&b_text, None);
+        let a_text = format!("b\0{}t\n", b_node);
+        let a_node = hash(&a_text, None);
+        // Directories sort by name along with files, without a trailing slash.
+        let root_text = format!("a\0{}t\na.txt\0{}\n", a_node, FILE1);
+
+        assert_eq!(
+            nodes,
+            vec![
+                TreeNode {
+                    path: MPath::new("a/b").unwrap(),
+                    node: b_node,
+                    text: Bytes::from(b_text),
+                    parents: Parents::None,
+                },
+                TreeNode {
+                    path: MPath::new("a").unwrap(),
+                    node: a_node,
+                    text: Bytes::from(a_text),
+                    parents: Parents::None,
+                },
+                TreeNode {
+                    path: MPath::empty(),
+                    node: mfid,
+                    text: Bytes::from(root_text),
+                    parents: Parents::None,
+                },
+            ]
+        );
+        assert_eq!(tree.get_dir(&MPath::empty()), Some(&mfid));
+        assert_eq!(tree.get_dir(&MPath::new("a").unwrap()), Some(&a_node));
+    }
+
+    #[test]
+    fn convert_child() {
+        let parent_mfid = node("abababababababababababababababababababab");
+        let flat = format!("a/b\0{}\nc/d\0{}\n", FILE1, FILE1);
+        let (parent, _) = convert(&parent_mfid, &flat, Parents::None, None);
+
+        let mfid = node("cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd");
+        let flat = format!("a/b\0{}\nc/d\0{}\n", FILE1, FILE2);
+        let (tree, nodes) = convert(&mfid, &flat, Parents::One(parent_mfid), Some(&parent));
+
+        // a is unchanged, so it keeps its node and isn't written again. c changed, and has the
+        // parent's c as its parent.
+        let c = MPath::new("c").unwrap();
+        let parent_c = *parent.get_dir(&c).unwrap();
+        let c_text = format!("d\0{}\n", FILE2);
+        let c_node = hash(&c_text, Some(&parent_c));
+
+        let paths: Vec<_> = nodes.iter().map(|tree| tree.path.clone()).collect();
+        assert_eq!(paths, vec![c.clone(), MPath::empty()]);
+        assert_eq!(nodes[0].node, c_node);
+        assert_eq!(nodes[0].parents, Parents::One(parent_c));
+        assert_eq!(
+            tree.get_dir(&MPath::new("a").unwrap()),
+            parent.get_dir(&MPath::new("a").unwrap())
+        );
+        assert_eq!(tree.get_dir(&c), Some(&c_node));
+    }
+
+    #[test]
+    fn file_and_directory() {
+        let mfid = node("abababababababababababababababababababab");
+        let flat = format!("a\0{}\na/b\0{}\n", FILE1, FILE2);
+        let content = ManifestContent::parse(flat.as_bytes()).expect("bad manifest");
+        assert!(convert_flat(&mfid, &Parents::None, &content, None, None).is_err());
+    }
+
+    #[test]
+    fn generate_flat_order() {
+        // "a.txt" sorts before "a/b" as bytes, but after it as an MPath.
+        let flat = format!("a.txt\0{}\na/b\0{}l\n", FILE1, FILE2);
+        let content = ManifestContent::parse(flat.as_bytes()).expect("bad manifest");
+        let mut text = Vec::new();
+        generate_flat(&content, &mut text).expect("generate failed");
+        assert_eq!(String::from_utf8(text).unwrap(), flat);
+    }
+
+    #[test]
+    fn flat_converter_from_revlog() {
+        let parent = ManifestContent::parse(format!("a/b\0{}\nc/d\0{}\n", FILE1, FILE1).as_bytes())
+            .expect("bad manifest");
+        let child = ManifestContent::parse(format!("a/b\0{}\nc/d\0{}\n", FILE1, FILE2).as_bytes())
+            .expect("bad manifest");
+
+        // A flat repo with just the two manifests; the converter never looks at the changelog.
+        let dir = TempDir::new("flat_converter").expect("tempdir failed");
+        let store = dir.path().join("store");
+        fs::create_dir(&store).expect("mkdir failed");
+        File::create(dir.path().join("requires"))
+            .and_then(|mut requires| requires.write_all(b"revlogv1\nstore\n"))
+            .expect("writing requires failed");
+        let mut changelog = RevlogWriter::new(Compression::None);
+        changelog
+            .add_revision(b"changeset", None, None, RevIdx::from(0))
+            .expect("add changeset failed");
+        changelog
+            .save(store.join("00changelog.i"))
+            .expect("saving changelog failed");
+        let mut manifests = RevlogWriter::new(Compression::Zlib);
+        let (_, parent_mfid) = manifests
+            .add_revision(&flat_text(&parent), None, None, RevIdx::from(0))
+            .expect("add manifest failed");
+        let (_, child_mfid) = manifests
+            .add_revision(&flat_text(&child), Some(&parent_mfid), None, RevIdx::from(1))
+            .expect("add manifest failed");
+        manifests
+            .save(store.join("00manifest.i"))
+            .expect("saving manifests failed");
+
+        // The parent isn't cached, so the converter has to read and convert it from the revlog.
+        let converter = FlatConverter::new(RevlogRepo::open(dir.path()).expect("open failed"));
+        let parents = Parents::One(parent_mfid);
+        let (_, nodes) = converter
+            .convert(&child_mfid, &parents, &child)
+            .expect("conversion failed");
+
+        let (parent_tree, _) = convert_flat(&parent_mfid, &Parents::None, &parent, None, None)
+            .expect("conversion failed");
+        let c = MPath::new("c").unwrap();
+        let paths: Vec<_> = nodes.iter().map(|node| node.path.clone()).collect();
+        // a is the same as in the parent, so only c and the root are new.
+        assert_eq!(paths, vec![c.clone(), MPath::empty()]);
+        assert_eq!(
+            nodes[0].parents,
+            Parents::One(*parent_tree.get_dir(&c).unwrap())
+        );
+        // The root keeps the node and parents of the flat manifest.
+        assert_eq!(nodes[1].node, child_mfid);
+        assert_eq!(nodes[1].parents, parents);
+    }
+}
@@ -4,6 +4,7 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+pub mod convert;
 pub mod revlog;
 
 pub use self::revlog::{Details, RevlogManifest};
@@ -29,13 +29,16 @@ use mercurial_types::nodehash::EntryId;
 mod parser;
 mod revidx;
 mod lz4;
+mod writer;
+mod zstd;
 
 #[cfg(test)]
 mod test;
 
-use self::parser::{Header, Version};
+use self::parser::{Header, IdxFlags, Version};
 pub use self::parser::Entry;
 pub use self::revidx::RevIdx;
+pub use self::writer::{Compression, RevlogWriter, RevlogWriterOptions};
 
 #[derive(Debug)]
 enum Datafile {
@@ -128,15 +131,16 @@ impl Revlog {
         let mut off = 0;
         let mut i = RevIdx::zero();
         loop {
-            let ent = inner.parse_entry(off);
-            if let Ok(entry) = ent {
-                idxoff.insert(i, off);
-                nodeidx.insert(entry.nodeid, i);
-                i = i.succ();
-                off += inner.entry_size(Some(&entry));
-            } else {
-                break;
-            }
+            let entry = match inner.parse_entry(off) {
+                Ok(entry) => entry,
+                // The index ends at the last complete entry.
+                Err(_) if off + inner.fixed_entry_size() > inner.idx.as_slice().len() => break,
+                Err(err) => return Err(err),
+            };
+            idxoff.insert(i, off);
+            nodeidx.insert(entry.nodeid, i);
+            i = i.succ();
+            off += inner.entry_size(Some(&entry));
         }
         inner.idxoff = idxoff;
         inner.nodeidx = nodeidx;
@@ -514,6 +518,16 @@ impl RevlogInner {
         }
 
         let entry = self.get_entry(tgtidx)?;
+        // The stored text of these revisions isn't their content: it's elsewhere, stripped
+        // down, or has sidedata in front of it.
+        let unsupported =
+            entry.flags & (IdxFlags::EXTSTORED | IdxFlags::ELLIPSIS | IdxFlags::SIDEDATA);
+        if !unsupported.is_empty() {
+            return Err(ErrorKind::Revlog(format!(
+                "rev {:?} has unsupported flags {:?}",
+                tgtidx, unsupported
+            )).into());
+        }
 
         let data = if self.is_general_delta() {
             self.construct_general(tgtidx)?
@@ -18,6 +18,7 @@ use mercurial_types::bdiff::Delta;
 use revlog::revidx::RevIdx;
 
 use super::lz4;
+use super::zstd;
 
 // #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 // pub enum Badness {
@@ -39,6 +40,8 @@ pub mod Badness {
     pub const Features: Error = 2;
     pub const BadZlib: Error = 3;
     pub const BadLZ4: Error = 4;
+    pub const BadZstd: Error = 5;
+    pub const Flags: Error = 6;
 }
 
 /// `Revlog` features
@@ -53,6 +56,10 @@ bitflags! {
 bitflags! {
     pub struct IdxFlags: u16 {
         const CENSORED      = 1 << 15;
+        const ELLIPSIS      = 1 << 14;
+        const EXTSTORED     = 1 << 13;
+        const SIDEDATA      = 1 << 12;
+        const HASCOPIESINFO = 1 << 11;
     }
 }
 
@@ -74,7 +81,7 @@ pub struct Header {
 #[derive(Copy, Clone, Debug)]
 pub struct Entry {
     pub offset: u64,         // offset of content (delta/literal) in datafile (or inlined)
-    pub flags: IdxFlags,     // unused?
+    pub flags: IdxFlags,     // per-revision flags
     pub compressed_len: u32, // compressed content size
     pub len: Option<u32>,    // size of final file (after applying deltas)
     pub baserev: Option<RevIdx>, // base/previous rev for deltas (None if literal)
@@ -90,30 +97,29 @@ impl Entry {
     }
 }
 
-/// Parse the revlog header
+/// Parse the revlog header. Unknown versions, such as the experimental revlogv2, and unknown
+/// features are errors rather than being misread.
 named!(pub header<Header>,
     do_parse!(
-        features: return_error!(ErrorKind::Custom(Badness::IO), be_u16) >>
-        version: return_error!(ErrorKind::Custom(Badness::IO), be_u16) >>
-        ({
-            let vers = match version {
-                0 => Version::Revlog0,
-                1 => Version::RevlogNG,
-                _ => panic!("bad version"),
-            };
-
-            let features = match Features::from_bits(features) {
-                Some(f) => f,
-                None => panic!("bad features"),
-            };
-
-            Header {
-                version: vers,
-                features: features,
-            }
-        }))
+        features: return_error!(ErrorKind::Custom(Badness::Features),
+            map_opt!(be_u16, Features::from_bits)) >>
+        version: return_error!(ErrorKind::Custom(Badness::Version),
+            map_opt!(be_u16, version)) >>
+        (Header {
+            version: version,
+            features: features,
+        })
+    )
 );
 
+fn version(version: u16) -> Option<Version> {
+    match version {
+        0 => Some(Version::Revlog0),
+        1 => Some(Version::RevlogNG),
+        _ => None,
+    }
+}
+
 pub fn indexng_size() -> usize {
     6 + 2 + 4 + 4 + 4 + 4 + 4 + 4 + 32
 }
@@ -122,7 +128,8 @@ pub fn indexng_size() -> usize {
 named!(pub indexng<Entry>,
     do_parse!(
         offset: return_error!(ErrorKind::Custom(Badness::IO), be_u48) >>    // XXX if first, then only 2 bytes, implied 0 in top 4
-        flags: return_error!(ErrorKind::Custom(Badness::IO), be_u16) >>     // ?
+        flags: return_error!(ErrorKind::Custom(Badness::Flags),
+            map_opt!(be_u16, IdxFlags::from_bits)) >>
         compressed_length: return_error!(ErrorKind::Custom(Badness::IO), be_u32) >>
         uncompressed_length: return_error!(ErrorKind::Custom(Badness::IO), be_u32) >>
         baserev: return_error!(ErrorKind::Custom(Badness::IO), be_u32) >>
@@ -133,7 +140,7 @@ named!(pub indexng<Entry>,
         ({
             Entry {
                 offset: offset,
-                flags: IdxFlags::from_bits(flags).expect("bad rev idx flags"),
+                flags: flags,
                 compressed_len: compressed_length,
                 len: Some(uncompressed_length),
                 baserev: if baserev == !0 { None } else { Some(baserev.into()) },
@@ -205,7 +212,8 @@ named!(pub deltachunk<Vec<Delta> >,
                 do_parse!(tag!(b"u") >> d: deltas >> (d)) |                                  // uncompressed with explicit 'u' header
                 do_parse!(peek!(tag!(b"\0")) >> d: deltas >> (d)) |                          // uncompressed with included initial 0x00
                 do_parse!(peek!(tag!(b"x")) >> d: apply!(zlib_decompress, deltas) >> (d)) |  // compressed; 'x' part of the zlib stream
-                do_parse!(tag!(b"4") >> d: apply!(lz4::lz4_decompress, deltas) >> (d))       // compressed w/ lz4
+                do_parse!(tag!(b"4") >> d: apply!(lz4::lz4_decompress, deltas) >> (d)) |     // compressed w/ lz4
+                do_parse!(peek!(tag!(b"(")) >> d: apply!(zstd::zstd_decompress, deltas) >> (d)) // compressed; '(' part of the zstd frame
             )
         ),
         |dv: Vec<_>| dv.into_iter().flat_map(|x| x).collect())
@@ -223,6 +231,7 @@ named!(pub literal<Vec<u8> >,
         do_parse!(peek!(tag!(b"\0")) >> d: remains >> (d.into())) |
         do_parse!(peek!(tag!(b"x")) >> d: apply!(zlib_decompress, remains_owned) >> (d)) |
         do_parse!(tag!(b"4") >> d: apply!(lz4::lz4_decompress, remains_owned) >> (d)) |
+        do_parse!(peek!(tag!(b"(")) >> d: apply!(zstd::zstd_decompress, remains_owned) >> (d)) |
         do_parse!(tag!(b"u") >> d: remains >> (d.into()))
     )
 );
@@ -337,6 +346,19 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_header_bad_version() {
+        // The experimental revlogv2 format isn't supported.
+        let d = [0x00, 0x00, 0xde, 0xad];
+        assert!(header(&d[..]).is_err());
+    }
+
+    #[test]
+    fn test_header_bad_features() {
+        let d = [0x00, 0x04, 0x00, 0x01];
+        assert!(header(&d[..]).is_err());
+    }
+
     #[test]
     fn test_header_feat_3() {
         let d = [0x00, 0x03, 0x00, 0x01];
@@ -32,6 +32,11 @@ impl RevIdx {
         RevIdx(self.0 - 1)
     }
 
+    /// Return the index as a number, as it's stored in a revlog.
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+
     /// Return iterator for a range from index to `lim`.
     pub fn range_to(&self, lim: Self) -> RevIdxRange {
         RevIdxRange(self.0, lim.0)
@@ -4,6 +4,10 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use bytes::BufMut;
+use quickcheck::{Arbitrary, Gen, TestResult};
+use zstd;
+
 use super::*;
 
 static EMPTY: &[u8] = include_bytes!("empty.i.bin");
@@ -17,3 +21,437 @@ fn emptyrev() {
 
     assert_eq!(node.size(), Some(0));
 }
+
+fn all_options() -> Vec<RevlogWriterOptions> {
+    let mut all = Vec::new();
+    for &compression in &[
+        Compression::None,
+        Compression::Zlib,
+        Compression::Lz4,
+        Compression::Zstd,
+    ] {
+        for &inline in &[true, false] {
+            for &generaldelta in &[false, true] {
+                all.push(RevlogWriterOptions {
+                    compression,
+                    inline,
+                    generaldelta,
+                });
+            }
+        }
+    }
+    all
+}
+
+fn write_and_read(options: RevlogWriterOptions, texts: &[&[u8]]) {
+    let mut writer = RevlogWriter::with_options(options);
+    let mut nodes: Vec<NodeHash> = Vec::new();
+    for (linkrev, text) in texts.iter().enumerate() {
+        let p1 = nodes.last().cloned();
+        let (idx, node) = writer
+            .add_revision(text, p1.as_ref(), None, RevIdx::from(linkrev))
+            .expect("add_revision failed");
+        assert_eq!(idx, RevIdx::from(linkrev));
+        nodes.push(node);
+    }
+
+    let (index, data) = writer.into_parts();
+    assert_eq!(data.is_none(), options.inline);
+    let revlog = Revlog::new(index, data).expect("construction failed");
+    for (idx, (text, node)) in texts.iter().zip(nodes.iter()).enumerate() {
+        let idx = RevIdx::from(idx);
+        let entry = revlog.get_entry(idx).expect("failed to get entry");
+        assert_eq!(entry.nodeid, *node);
+        assert_eq!(entry.linkrev, idx);
+        assert_eq!(entry.len, Some(text.len() as u32));
+
+        let blobnode = revlog.get_rev(idx).expect("failed to get rev");
+        assert_eq!(blobnode.as_blob().as_slice(), Some(*text));
+        assert_eq!(blobnode.nodeid(), Some(*node));
+    }
+}
+
+#[test]
+fn write_deltas() {
+    let texts: &[&[u8]] = &[
+        b"",
+        b"aaaa\nbbbb\ncccc\n",
+        b"aaaa\nbbbb\ncccc\ndddd\n",
+        b"aaaa\nbbbb\ncccc\ndddd\n",
+        b"\0binary\nstarts with a NUL\n",
+        b"zzzz\naaaa\ncccc\ndddd\n",
+    ];
+    for options in all_options() {
+        write_and_read(options, texts);
+    }
+}
+
+#[test]
+fn write_compressed() {
+    let long_text: Vec<u8> = (0..50)
+        .flat_map(|_| b"a line that is repeated many times\n".iter().cloned())
+        .collect();
+    let mut longer_text = long_text.clone();
+    longer_text.extend_from_slice(b"and then a different line\n");
+    for options in all_options() {
+        write_and_read(options, &[&long_text, &longer_text]);
+    }
+}
+
+#[test]
+fn write_existing_revision() {
+    let mut writer = RevlogWriter::new(Compression::Zlib);
+    let (idx, node) = writer
+        .add_revision(b"text", None, None, RevIdx::zero())
+        .expect("add_revision failed");
+    let again = writer
+        .add_revision(b"text", None, None, RevIdx::zero().succ())
+        .expect("add_revision failed");
+    assert_eq!(again, (idx, node));
+    assert_eq!(writer.len(), 1);
+}
+
+#[test]
+fn write_missing_parent() {
+    let mut writer = RevlogWriter::new(Compression::Zlib);
+    let parent = NodeHash::from_bytes(&[1; 20]).expect("bad hash");
+    assert!(
+        writer
+            .add_revision(b"text", Some(&parent), None, RevIdx::zero())
+            .is_err()
+    );
+}
+
+#[test]
+fn write_split() {
+    let options = RevlogWriterOptions {
+        inline: false,
+        ..Default::default()
+    };
+    let mut writer = RevlogWriter::with_options(options);
+    writer
+        .add_revision(b"text\n", None, None, RevIdx::zero())
+        .expect("add_revision failed");
+    let (index, data) = writer.into_parts();
+    let data = data.expect("split revlog has no data");
+
+    // Every index entry is the same size, and the data lives only in the data file.
+    assert_eq!(index.len(), 64);
+    assert!(!data.is_empty());
+}
+
+#[test]
+fn write_taken_parts() {
+    let texts: &[&[u8]] = &[b"aaaa\n", b"aaaa\nbbbb\n", b"cccc\n"];
+    for options in all_options() {
+        let mut whole = RevlogWriter::with_options(options);
+        let mut taken = RevlogWriter::with_options(options);
+        let mut index = Vec::new();
+        let mut data = Vec::new();
+        let mut p1 = None;
+        for (linkrev, text) in texts.iter().enumerate() {
+            let linkrev = RevIdx::from(linkrev);
+            let (_, node) = whole
+                .add_revision(text, p1.as_ref(), None, linkrev)
+                .expect("add_revision failed");
+            taken
+                .add_revision(text, p1.as_ref(), None, linkrev)
+                .expect("add_revision failed");
+            let (index_part, data_part) = taken.take_parts();
+            index.extend(index_part);
+            data.extend(data_part.unwrap_or_default());
+            p1 = Some(node);
+        }
+
+        // Appending the parts as they're taken gives the same revlog as writing it at once.
+        let (whole_index, whole_data) = whole.into_parts();
+        assert_eq!(index, whole_index);
+        assert_eq!(data, whole_data.unwrap_or_default());
+    }
+}
+
+/// A revlog with a single revision, with the given flags in its index entry.
+fn revlog_with_flags(flags: u16) -> Vec<u8> {
+    let mut writer = RevlogWriter::new(Compression::None);
+    writer
+        .add_revision(b"text\n", None, None, RevIdx::zero())
+        .expect("add_revision failed");
+    let mut index = writer.into_bytes();
+    // The flags follow the 48 bit offset.
+    index[6..8].copy_from_slice(&[(flags >> 8) as u8, flags as u8]);
+    index
+}
+
+#[test]
+fn unknown_flags() {
+    assert!(Revlog::new(revlog_with_flags(1), None).is_err());
+}
+
+#[test]
+fn unsupported_flags() {
+    for flags in &[IdxFlags::EXTSTORED, IdxFlags::ELLIPSIS, IdxFlags::SIDEDATA] {
+        let revlog = Revlog::new(revlog_with_flags(flags.bits()), None).expect("bad revlog");
+        let entry = revlog.get_entry(RevIdx::zero()).expect("failed to get entry");
+        assert_eq!(entry.flags, *flags);
+        assert!(revlog.get_rev(RevIdx::zero()).is_err());
+    }
+
+    // Censored revisions and copy information don't change how the text is stored.
+    let flags = IdxFlags::CENSORED | IdxFlags::HASCOPIESINFO;
+    let revlog = Revlog::new(revlog_with_flags(flags.bits()), None).expect("bad revlog");
+    let rev = revlog.get_rev(RevIdx::zero()).expect("failed to get rev");
+    assert_eq!(rev.as_blob().as_slice(), Some(&b"text\n"[..]));
+}
+
+#[test]
+fn write_generaldelta_parent() {
+    let options = RevlogWriterOptions {
+        generaldelta: true,
+        ..Default::default()
+    };
+    let base: Vec<u8> = (0..50)
+        .flat_map(|i| format!("line {}\n", i).into_bytes())
+        .collect();
+    let mut branch = base.clone();
+    branch.extend_from_slice(b"on a branch\n");
+    let unrelated: Vec<u8> = (0..50)
+        .flat_map(|i| format!("unrelated {}\n", i).into_bytes())
+        .collect();
+
+    let mut writer = RevlogWriter::with_options(options);
+    let (_, base_node) = writer
+        .add_revision(&base, None, None, RevIdx::zero())
+        .expect("add_revision failed");
+    writer
+        .add_revision(&unrelated, None, None, RevIdx::from(1u32))
+        .expect("add_revision failed");
+    writer
+        .add_revision(&branch, Some(&base_node), None, RevIdx::from(2u32))
+        .expect("add_revision failed");
+
+    let (index, data) = writer.into_parts();
+    let revlog = Revlog::new(index, data).expect("construction failed");
+    let entry = revlog
+        .get_entry(RevIdx::from(2u32))
+        .expect("failed to get entry");
+    // The delta is against the parent rather than the unrelated revision before it.
+    assert_eq!(entry.baserev, Some(RevIdx::zero()));
+    let blobnode = revlog.get_rev(RevIdx::from(2u32)).expect("failed to get rev");
+    assert_eq!(blobnode.as_blob().as_slice(), Some(&branch[..]));
+}
+
+#[test]
+fn write_generaldelta_old_parent() {
+    let options = RevlogWriterOptions {
+        generaldelta: true,
+        ..Default::default()
+    };
+    let text = |i: usize| -> Vec<u8> {
+        (0..50)
+            .flat_map(|line| format!("line {} of {}\n", line, i).into_bytes())
+            .collect()
+    };
+
+    // The writer doesn't keep the text of a parent this far back, so the last revision is
+    // deltaed against the one before it instead.
+    let mut writer = RevlogWriter::with_options(options);
+    let (_, first) = writer
+        .add_revision(&text(0), None, None, RevIdx::zero())
+        .expect("add_revision failed");
+    for i in 1..200 {
+        writer
+            .add_revision(&text(i), None, None, RevIdx::from(i))
+            .expect("add_revision failed");
+    }
+    let mut last = text(0);
+    last.extend_from_slice(b"much later\n");
+    writer
+        .add_revision(&last, Some(&first), None, RevIdx::from(200u32))
+        .expect("add_revision failed");
+
+    let (index, data) = writer.into_parts();
+    let revlog = Revlog::new(index, data).expect("construction failed");
+    let entry = revlog
+        .get_entry(RevIdx::from(200u32))
+        .expect("failed to get entry");
+    assert_ne!(entry.baserev, Some(RevIdx::zero()));
+    let blobnode = revlog
+        .get_rev(RevIdx::from(200u32))
+        .expect("failed to get rev");
+    assert_eq!(blobnode.as_blob().as_slice(), Some(&last[..]));
+}
+
+fn push_entry(
+    index: &mut Vec<u8>,
+    data: &mut Vec<u8>,
+    chunk: &[u8],
+    text: &[u8],
+    baserev: u32,
+    p1: Option<u32>,
+) {
+    let rev = (index.len() / parser::indexng_size()) as u32;
+    index.put_u64_be((data.len() as u64) << 16);
+    index.put_u32_be(chunk.len() as u32);
+    index.put_u32_be(text.len() as u32);
+    index.put_u32_be(baserev);
+    index.put_u32_be(rev);
+    index.put_u32_be(p1.unwrap_or(!0));
+    index.put_u32_be(!0);
+    index.extend_from_slice(&[rev as u8; 32]);
+    data.extend_from_slice(chunk);
+}
+
+fn append_delta(base: &[u8], line: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    delta.put_u32_be(base.len() as u32);
+    delta.put_u32_be(base.len() as u32);
+    delta.put_u32_be(line.len() as u32);
+    delta.extend_from_slice(line);
+    delta
+}
+
+#[test]
+fn read_sparse_zstd() {
+    let text0: Vec<u8> = (0..50)
+        .flat_map(|i| format!("line {}\n", i).into_bytes())
+        .collect();
+    let mut text1 = text0.clone();
+    text1.extend_from_slice(b"snapshot\n");
+    let text2: Vec<u8> = (0..500)
+        .flat_map(|i| format!("unrelated {}\n", i).into_bytes())
+        .collect();
+    let mut text3 = text1.clone();
+    text3.extend_from_slice(b"tip\n");
+
+    // Rev 1 is an intermediate snapshot against rev 0, and rev 3 is a delta against that
+    // snapshot rather than against its parent, so its chain skips over rev 2's data. Chunks
+    // are a mix of zstd and uncompressed, as Mercurial writes them.
+    let mut index = Vec::new();
+    let mut data = Vec::new();
+    let chunk0 = zstd::encode_all(&text0[..], 3).expect("zstd failed");
+    assert_eq!(chunk0[0], b'(');
+    push_entry(&mut index, &mut data, &chunk0, &text0, 0, None);
+    let chunk1 = append_delta(&text0, b"snapshot\n");
+    push_entry(&mut index, &mut data, &chunk1, &text1, 0, Some(0));
+    let mut chunk2 = b"u".to_vec();
+    chunk2.extend_from_slice(&text2);
+    push_entry(&mut index, &mut data, &chunk2, &text2, 2, Some(1));
+    let delta3 = append_delta(&text1, b"tip\n");
+    let chunk3 = zstd::encode_all(&delta3[..], 3).expect("zstd failed");
+    push_entry(&mut index, &mut data, &chunk3, &text3, 1, Some(2));
+    // Revlog version 1, generaldelta, not inline.
+    index[..4].copy_from_slice(&[0, 2, 0, 1]);
+
+    let revlog = Revlog::new(index, Some(data)).expect("construction failed");
+    for (idx, text) in [text0, text1, text2, text3].iter().enumerate() {
+        let blobnode = revlog.get_rev(RevIdx::from(idx)).expect("failed to get rev");
+        assert_eq!(blobnode.as_blob().as_slice(), Some(&text[..]));
+    }
+}
+
+/// A sequence of revisions to write, where each one is an edit of its first parent so that
+/// there are deltas worth storing.
+#[derive(Clone, Debug)]
+struct RevlogSpec {
+    options: RevlogWriterOptions,
+    revs: Vec<(Vec<u8>, Option<usize>, Option<usize>)>,
+}
+
+impl Arbitrary for RevlogSpec {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let compression = *g.choose(&[
+            Compression::None,
+            Compression::Zlib,
+            Compression::Lz4,
+            Compression::Zstd,
+        ]).unwrap();
+        let options = RevlogWriterOptions {
+            compression,
+            inline: g.gen(),
+            generaldelta: g.gen(),
+        };
+
+        let size = g.size();
+        let count = g.gen_range(0, size);
+        let mut revs: Vec<(Vec<u8>, Option<usize>, Option<usize>)> = Vec::new();
+        for idx in 0..count {
+            let p1 = if idx > 0 && g.gen() {
+                Some(g.gen_range(0, idx))
+            } else {
+                None
+            };
+            let p2 = if p1.is_some() && g.gen() {
+                Some(g.gen_range(0, idx))
+            } else {
+                None
+            };
+
+            let mut lines: Vec<Vec<u8>> = match p1 {
+                Some(p1) => revs[p1]
+                    .0
+                    .split(|b| *b == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(|line| line.to_vec())
+                    .collect(),
+                None => Vec::new(),
+            };
+            for _ in 0..g.gen_range(0, 4) {
+                if !lines.is_empty() && g.gen() {
+                    let pos = g.gen_range(0, lines.len());
+                    lines.remove(pos);
+                } else {
+                    let pos = g.gen_range(0, lines.len() + 1);
+                    let line = format!("line {}", g.gen_range(0, size * 4)).into_bytes();
+                    lines.insert(pos, line);
+                }
+            }
+
+            let text = lines
+                .into_iter()
+                .flat_map(|mut line| {
+                    line.push(b'\n');
+                    line
+                })
+                .collect();
+            revs.push((text, p1, p2));
+        }
+
+        RevlogSpec { options, revs }
+    }
+}
+
+quickcheck! {
+    fn write_roundtrip(spec: RevlogSpec) -> TestResult {
+        // An empty revlog is an empty file, which has no header to parse.
+        if spec.revs.is_empty() {
+            return TestResult::discard();
+        }
+
+        let mut writer = RevlogWriter::with_options(spec.options);
+        let mut nodes: Vec<NodeHash> = Vec::new();
+        for (linkrev, &(ref text, p1, p2)) in spec.revs.iter().enumerate() {
+            let p1 = p1.map(|p| nodes[p]);
+            let p2 = p2.map(|p| nodes[p]);
+            let (_, node) = writer
+                .add_revision(text, p1.as_ref(), p2.as_ref(), RevIdx::from(linkrev))
+                .expect("add_revision failed");
+            nodes.push(node);
+        }
+
+        let (index, data) = writer.into_parts();
+        let revlog = Revlog::new(index, data).expect("construction failed");
+        for (text, node) in spec.revs.iter().map(|rev| &rev.0).zip(nodes.iter()) {
+            let idx = match revlog.get_idx_by_nodeid(node) {
+                Ok(idx) => idx,
+                Err(_) => return TestResult::failed(),
+            };
+            let blobnode = revlog.get_rev(idx).expect("failed to get rev");
+            if blobnode.as_blob().as_slice() != Some(&text[..])
+                || blobnode.nodeid() != Some(*node)
+            {
+                return TestResult::failed();
+            }
+        }
+        TestResult::passed()
+    }
+}
@@ -0,0 +1,452 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Writer for Mercurial revlogs
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+
+use bytes::{BufMut, Bytes};
+use flate2::Compression as ZlibCompression;
+use flate2::write::ZlibEncoder;
+
+use mercurial_types::{Blob, BlobNode, NodeHash};
+use mercurial_types::bdiff::{self, Delta};
+use pylz4;
+use zstd;
+
+use errors::*;
+
+use super::parser::{self, Features, Version};
+use super::revidx::RevIdx;
+
+/// How `RevlogWriter` compresses the chunks it writes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    /// Store every chunk uncompressed.
+    None,
+    /// zlib, which any Mercurial can read.
+    Zlib,
+    /// lz4; reading it needs the `lz4revlog` extension and requirement.
+    Lz4,
+    /// zstd, at Mercurial's default level; reading it needs the `revlog-compression-zstd`
+    /// requirement.
+    Zstd,
+}
+
+/// How `RevlogWriter` lays out the revlog it builds.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RevlogWriterOptions {
+    pub compression: Compression,
+    /// Interleave the data with the index in a single `.i` file, rather than writing a
+    /// separate `.d` file.
+    pub inline: bool,
+    /// Store each revision as a delta against one of its parents where that's worthwhile,
+    /// rather than always against the revision before it. This needs the `generaldelta`
+    /// requirement.
+    pub generaldelta: bool,
+}
+
+impl Default for RevlogWriterOptions {
+    fn default() -> Self {
+        RevlogWriterOptions {
+            compression: Compression::Zlib,
+            inline: true,
+            generaldelta: false,
+        }
+    }
+}
+
+// Mercurial doesn't bother compressing chunks shorter than this.
+const MIN_COMPRESS_LEN: usize = 44;
+// The zstd level Mercurial uses unless it's configured otherwise.
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug)]
+struct WriterEntry {
+    offset: u64,
+    compressed_len: u32,
+    // The delta base as written to the index. Without generaldelta this is the start of the
+    // delta chain rather than the revision the delta is against.
+    baserev: RevIdx,
+    // The literal revision at the start of the delta chain, and the total size of the chunks
+    // from there up to and including this one.
+    chainbase: RevIdx,
+    chainsize: u64,
+}
+
+/// Number of recent full texts kept with generaldelta, for later revisions to delta against
+/// their parents. A revision whose parents are all older than that is deltaed against the
+/// previous revision instead.
+const TEXT_CACHE_SIZE: usize = 100;
+
+/// `RevlogWriter` builds a RevlogNG revlog in memory.
+///
+/// Revisions are appended in order, and each one is stored either as a literal or as a delta.
+/// Without generaldelta, deltas are against the revision before, and like Mercurial a new
+/// literal is started once reading a delta chain back would mean reading more than twice the
+/// size of the text. With generaldelta, the closer parent is tried as the base first, then the
+/// previous revision, using the limits of Mercurial's `_isgooddelta`.
+#[derive(Debug)]
+pub struct RevlogWriter {
+    options: RevlogWriterOptions,
+    index: Vec<u8>,
+    data: Vec<u8>,
+    entries: Vec<WriterEntry>,
+    nodeidx: HashMap<NodeHash, RevIdx>,
+    // Full texts of the revisions deltas may be against, oldest first in `text_order`: the
+    // last `TEXT_CACHE_SIZE` with generaldelta, and just the last one without.
+    texts: HashMap<RevIdx, Vec<u8>>,
+    text_order: VecDeque<RevIdx>,
+    data_len: u64,
+}
+
+impl RevlogWriter {
+    /// Create a writer for an inline revlog without generaldelta.
+    pub fn new(compression: Compression) -> Self {
+        Self::with_options(RevlogWriterOptions {
+            compression,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_options(options: RevlogWriterOptions) -> Self {
+        RevlogWriter {
+            options,
+            index: Vec::new(),
+            data: Vec::new(),
+            entries: Vec::new(),
+            nodeidx: HashMap::new(),
+            texts: HashMap::new(),
+            text_order: VecDeque::new(),
+            data_len: 0,
+        }
+    }
+
+    /// Number of revisions written so far.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Return the index of the revision with the given nodeid, if it has been written.
+    pub fn get_idx_by_nodeid(&self, nodeid: &NodeHash) -> Option<RevIdx> {
+        self.nodeidx.get(nodeid).cloned()
+    }
+
+    /// Append a revision with the given full text and parents, and return its index and nodeid.
+    ///
+    /// Both parents must already be in the revlog. Adding a revision that's already present is
+    /// a no-op, just as it is in Mercurial.
+    pub fn add_revision(
+        &mut self,
+        text: &[u8],
+        p1: Option<&NodeHash>,
+        p2: Option<&NodeHash>,
+        linkrev: RevIdx,
+    ) -> Result<(RevIdx, NodeHash)> {
+        let nodeid = BlobNode::new(Blob::from(Bytes::from(text)), p1, p2)
+            .nodeid()
+            .expect("blob has data");
+        if let Some(idx) = self.get_idx_by_nodeid(&nodeid) {
+            return Ok((idx, nodeid));
+        }
+
+        let p1 = self.parent_idx(&nodeid, p1)?;
+        let p2 = self.parent_idx(&nodeid, p2)?;
+
+        let idx = RevIdx::from(self.entries.len());
+        let (chunk, entry) = match self.build_delta(text, p1, p2) {
+            Some(delta) => delta,
+            None => {
+                let chunk = self.compress(text.to_vec());
+                let entry = WriterEntry {
+                    offset: self.data_len,
+                    compressed_len: chunk.len() as u32,
+                    baserev: idx,
+                    chainbase: idx,
+                    chainsize: chunk.len() as u64,
+                };
+                (chunk, entry)
+            }
+        };
+        self.write_entry(&entry, text.len(), linkrev, p1, p2, &nodeid);
+        self.push_chunk(&chunk);
+
+        self.insert_text(idx, text.to_vec());
+        self.nodeidx.insert(nodeid, idx);
+        self.entries.push(entry);
+
+        Ok((idx, nodeid))
+    }
+
+    /// Return the contents of the index file. For a revlog that isn't inline, this doesn't
+    /// include the data; use `into_parts` to get both.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.into_parts().0
+    }
+
+    /// Return the contents of the index file, and of the data file if the revlog isn't inline.
+    pub fn into_parts(self) -> (Vec<u8>, Option<Vec<u8>>) {
+        // An empty revlog is an empty file; there's no entry for the header to live in.
+        let index = if self.entries.is_empty() {
+            vec![]
+        } else {
+            self.index
+        };
+        let data = if self.options.inline {
+            None
+        } else {
+            Some(self.data)
+        };
+        (index, data)
+    }
+
+    /// Return what has been written since the last call, in the same form as `into_parts`, and
+    /// forget it. Appending the parts to the files as they're taken builds the revlog without
+    /// holding all of it in memory; the writer only keeps the texts of the last few revisions
+    /// for later deltas.
+    pub fn take_parts(&mut self) -> (Vec<u8>, Option<Vec<u8>>) {
+        let index = mem::replace(&mut self.index, Vec::new());
+        let data = if self.options.inline {
+            None
+        } else {
+            Some(mem::replace(&mut self.data, Vec::new()))
+        };
+        (index, data)
+    }
+
+    /// Write the revlog out to an index file at `path`. If the revlog isn't inline, the data
+    /// goes next to it, with the extension changed to `.d`.
+    pub fn save<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let (index, data) = self.into_parts();
+        File::create(path)?.write_all(&index)?;
+        if let Some(data) = data {
+            File::create(path.with_extension("d"))?.write_all(&data)?;
+        }
+        Ok(())
+    }
+
+    /// Find a delta to store the next revision as, along with its index entry. Returns None if
+    /// it should be stored as a literal.
+    fn build_delta(
+        &self,
+        text: &[u8],
+        p1: Option<RevIdx>,
+        p2: Option<RevIdx>,
+    ) -> Option<(Vec<u8>, WriterEntry)> {
+        let prev = match self.entries.len() {
+            0 => return None,
+            len => RevIdx::from(len - 1),
+        };
+
+        if !self.options.generaldelta {
+            let chunk = self.compress(encode_deltas(&bdiff::diff(&self.texts[&prev], text)));
+            let prev_entry = &self.entries[prev.as_u32() as usize];
+            let chainbase = &self.entries[prev_entry.chainbase.as_u32() as usize];
+            let dist = self.data_len - chainbase.offset + chunk.len() as u64;
+            if dist > 2 * text.len() as u64 {
+                return None;
+            }
+            let entry = WriterEntry {
+                offset: self.data_len,
+                compressed_len: chunk.len() as u32,
+                baserev: prev_entry.chainbase,
+                chainbase: prev_entry.chainbase,
+                chainsize: prev_entry.chainsize + chunk.len() as u64,
+            };
+            return Some((chunk, entry));
+        }
+
+        // Try the closer parent, as that's the least likely to need a new literal, and then
+        // the previous revision, as that's the cheapest delta to read back. The previous
+        // revision's text is always kept, but the parent's may be gone.
+        let mut candidates = Vec::with_capacity(2);
+        if let Some(parent) = cmp::max(p1, p2) {
+            if self.texts.contains_key(&parent) {
+                candidates.push(parent);
+            }
+        }
+        if !candidates.contains(&prev) {
+            candidates.push(prev);
+        }
+
+        candidates
+            .into_iter()
+            .filter_map(|base| {
+                let base_entry = &self.entries[base.as_u32() as usize];
+                let deltas = bdiff::diff(&self.texts[&base], text);
+                let chunk = self.compress(encode_deltas(&deltas));
+                let chainbase = &self.entries[base_entry.chainbase.as_u32() as usize];
+                let dist = self.data_len - chainbase.offset + chunk.len() as u64;
+                let chainsize = base_entry.chainsize + chunk.len() as u64;
+
+                let textlen = text.len() as u64;
+                if dist > 4 * textlen || chunk.len() as u64 > textlen || chainsize > 2 * textlen
+                {
+                    return None;
+                }
+                let entry = WriterEntry {
+                    offset: self.data_len,
+                    compressed_len: chunk.len() as u32,
+                    baserev: base,
+                    chainbase: base_entry.chainbase,
+                    chainsize,
+                };
+                Some((chunk, entry))
+            })
+            .next()
+    }
+
+    fn insert_text(&mut self, idx: RevIdx, text: Vec<u8>) {
+        let cache_size = if self.options.generaldelta {
+            TEXT_CACHE_SIZE
+        } else {
+            1
+        };
+        self.texts.insert(idx, text);
+        self.text_order.push_back(idx);
+        if self.text_order.len() > cache_size {
+            let oldest = self.text_order.pop_front().expect("text order is empty");
+            self.texts.remove(&oldest);
+        }
+    }
+
+    fn push_chunk(&mut self, chunk: &[u8]) {
+        if self.options.inline {
+            self.index.extend_from_slice(chunk);
+        } else {
+            self.data.extend_from_slice(chunk);
+        }
+        self.data_len += chunk.len() as u64;
+    }
+
+    fn parent_idx(&self, nodeid: &NodeHash, parent: Option<&NodeHash>) -> Result<Option<RevIdx>> {
+        match parent {
+            None => Ok(None),
+            Some(parent) => match self.get_idx_by_nodeid(parent) {
+                Some(idx) => Ok(Some(idx)),
+                None => Err(ErrorKind::Revlog(format!(
+                    "parent {} of {} is not in the revlog",
+                    parent, nodeid
+                )).into()),
+            },
+        }
+    }
+
+    fn write_entry(
+        &mut self,
+        entry: &WriterEntry,
+        len: usize,
+        linkrev: RevIdx,
+        p1: Option<RevIdx>,
+        p2: Option<RevIdx>,
+        nodeid: &NodeHash,
+    ) {
+        let start = self.index.len();
+        let first = self.entries.is_empty();
+        // 48 bit offset and 16 bits of flags
+        self.index.put_u64_be(entry.offset << 16);
+        self.index.put_u32_be(entry.compressed_len);
+        self.index.put_u32_be(len as u32);
+        self.index.put_u32_be(revidx_to_u32(Some(entry.baserev)));
+        self.index.put_u32_be(revidx_to_u32(Some(linkrev)));
+        self.index.put_u32_be(revidx_to_u32(p1));
+        self.index.put_u32_be(revidx_to_u32(p2));
+        self.index.extend_from_slice(nodeid.sha1().as_ref());
+        self.index.extend_from_slice(&[0; 12]);
+        debug_assert_eq!(self.index.len() - start, parser::indexng_size());
+
+        if first {
+            // The header overlaps the top of the first entry's offset, which is always 0.
+            let mut features = Features::empty();
+            if self.options.inline {
+                features |= Features::INLINE;
+            }
+            if self.options.generaldelta {
+                features |= Features::GENERAL_DELTA;
+            }
+            let mut header = Vec::with_capacity(4);
+            header.put_u16_be(features.bits());
+            header.put_u16_be(Version::RevlogNG as u16);
+            self.index[start..start + 4].copy_from_slice(&header);
+        }
+    }
+
+    /// Compress a chunk the way Mercurial's `revlog.compress` does: compression is only used if
+    /// it actually saves space, and uncompressed data gets a `u` marker unless it starts with
+    /// a NUL byte.
+    fn compress(&self, data: Vec<u8>) -> Vec<u8> {
+        if data.is_empty() {
+            return data;
+        }
+
+        if data.len() >= MIN_COMPRESS_LEN {
+            let compressed = match self.options.compression {
+                Compression::None => None,
+                Compression::Zlib => {
+                    let mut encoder = ZlibEncoder::new(Vec::new(), ZlibCompression::default());
+                    encoder
+                        .write_all(&data)
+                        .and_then(|()| encoder.finish())
+                        .ok()
+                }
+                Compression::Lz4 => pylz4::compress(&data).ok().map(|compressed| {
+                    let mut chunk = Vec::with_capacity(compressed.len() + 1);
+                    chunk.push(b'4');
+                    chunk.extend_from_slice(&compressed);
+                    chunk
+                }),
+                // The zstd frame starts with `(`, which doubles as the chunk's marker.
+                Compression::Zstd => zstd::encode_all(&data[..], ZSTD_LEVEL).ok(),
+            };
+            if let Some(compressed) = compressed {
+                if compressed.len() < data.len() {
+                    return compressed;
+                }
+            }
+        }
+
+        if data[0] == b'\0' {
+            data
+        } else {
+            let mut chunk = Vec::with_capacity(data.len() + 1);
+            chunk.push(b'u');
+            chunk.extend_from_slice(&data);
+            chunk
+        }
+    }
+}
+
+fn revidx_to_u32(idx: Option<RevIdx>) -> u32 {
+    idx.map(RevIdx::as_u32).unwrap_or(!0)
+}
+
+/// Encode deltas in the format used by revlogs and bundles.
+fn encode_deltas(deltas: &[Delta]) -> Vec<u8> {
+    if deltas.is_empty() {
+        // An identical revision still needs a (no-op) delta, so that the chunk isn't empty.
+        return vec![0; 12];
+    }
+
+    let len = deltas.iter().map(|delta| 12 + delta.content.len()).sum();
+    let mut out = Vec::with_capacity(len);
+    for delta in deltas {
+        out.put_u32_be(delta.start as u32);
+        out.put_u32_be(delta.end as u32);
+        out.put_u32_be(delta.content.len() as u32);
+        out.extend_from_slice(&delta.content);
+    }
+    out
+}
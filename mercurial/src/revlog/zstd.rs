@@ -0,0 +1,25 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Support for revlog-compression-zstd
+
+use super::parser::{detach_result, Error};
+use nom::{self, IResult};
+use zstd::decode_all;
+
+/// A zstd chunk is a single zstd frame, including the `(` of its magic number, which takes up
+/// the rest of the chunk.
+pub fn zstd_decompress<P, R>(i: &[u8], parse: P) -> IResult<&[u8], R, Error>
+where
+    for<'a> P: Fn(&'a [u8]) -> IResult<&'a [u8], R, Error> + 'a,
+{
+    match decode_all(i) {
+        Ok(decompressed) => detach_result(parse(&decompressed[..]), &i[i.len()..]),
+        Err(_err) => {
+            return IResult::Error(nom::ErrorKind::Custom(super::parser::Badness::BadZstd));
+        }
+    }
+}
@@ -11,7 +11,7 @@ use std::collections::hash_map::{Entry, HashMap};
 use std::fmt::{self, Display};
 use std::fs;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 
@@ -24,6 +24,7 @@ use asyncmemo::{Asyncmemo, Filler};
 use bookmarks::Bookmarks;
 use mercurial_types::{fncache_fsencode, simple_fsencode, BlobNode, MPath, MPathElement, NodeHash,
                       RepoPath, NULL_HASH};
+use mercurial_types::hash::Sha1;
 use mercurial_types::nodehash::{ChangesetId, EntryId};
 use stockbookmarks::StockBookmarks;
 use storage_types::Version;
@@ -31,6 +32,7 @@ use storage_types::Version;
 use bytes::Bytes;
 pub use changeset::RevlogChangeset;
 use errors::*;
+use largefiles;
 pub use manifest::RevlogManifest;
 use revlog::{self, Revlog, RevlogIter};
 
@@ -53,6 +55,11 @@ pub enum Required {
     SqlDirstate,
     HgSql,
     TreeDirstate,
+    Sparserevlog,
+    RevlogCompressionZstd,
+    ShareSafe,
+    PersistentNodemap,
+    DirstateV2,
 }
 
 impl Display for Required {
@@ -73,6 +80,11 @@ impl Display for Required {
             &SqlDirstate => "sqldirstate",
             &HgSql => "hgsql",
             &TreeDirstate => "treedirstate",
+            &Sparserevlog => "sparserevlog",
+            &RevlogCompressionZstd => "revlog-compression-zstd",
+            &ShareSafe => "share-safe",
+            &PersistentNodemap => "persistent-nodemap",
+            &DirstateV2 => "dirstate-v2",
         };
         write!(fmt, "{}", s)
     }
@@ -98,11 +110,25 @@ impl FromStr for Required {
             "sqldirstate" => Ok(SqlDirstate),
             "hgsql" => Ok(HgSql),
             "treedirstate" => Ok(TreeDirstate),
+            "sparserevlog" => Ok(Sparserevlog),
+            "revlog-compression-zstd" => Ok(RevlogCompressionZstd),
+            "share-safe" => Ok(ShareSafe),
+            "persistent-nodemap" => Ok(PersistentNodemap),
+            "dirstate-v2" => Ok(DirstateV2),
             unk => Err(ErrorKind::UnknownReq(unk.into()).into()),
         }
     }
 }
 
+fn read_requires(path: &Path) -> Result<HashSet<Required>> {
+    let mut req = HashSet::new();
+    let file = fs::File::open(path).with_context(|_| format!("Can't open {:?}", path))?;
+    for line in BufReader::new(file).lines() {
+        req.insert(line.context("Line read failed")?.parse()?);
+    }
+    Ok(req)
+}
+
 /// Representation of a whole Mercurial repo
 ///
 /// `Repo` represents a whole repo: ie, the complete history of a set of files.
@@ -123,6 +149,7 @@ pub struct RevlogRepo {
     requirements: HashSet<Required>, // requirements
     changelog: Revlog,               // changes
     manifest: Revlog,                // manifest
+    tree_manifests: bool,            // whether the repo has tree manifests
     inner: Arc<RwLock<RevlogInner>>, // Inner parts
     inmemory_logs_capacity: usize,   // Limit on the number of filelogs and tree revlogs in memory.
                                      // Note: there can be 2 * inmemory_logs_capacity revlogs in
@@ -164,24 +191,28 @@ impl RevlogRepo {
 
         let changelog = Revlog::from_idx_data(store.join("00changelog.i"), None as Option<String>)?;
         let tree_manifest_path = store.join("00manifesttree.i");
-        let manifest = if tree_manifest_path.exists() {
+        let has_tree_manifest_log = tree_manifest_path.exists();
+        let manifest = if has_tree_manifest_log {
             Revlog::from_idx_data(tree_manifest_path, None as Option<String>)?
         } else {
             // Fallback to flat manifest
             Revlog::from_idx_data(store.join("00manifest.i"), None as Option<String>)?
         };
 
-        let mut req = HashSet::new();
-        let file = fs::File::open(base.join("requires")).context("Can't open `requires`")?;
-        for line in BufReader::new(file).lines() {
-            req.insert(line.context("Line read failed")?.parse()?);
+        let mut req = read_requires(&base.join("requires"))?;
+        if req.contains(&Required::ShareSafe) {
+            // With share-safe, the requirements of the store live in the store.
+            req.extend(read_requires(&store.join("requires"))?);
         }
 
+        let tree_manifests = has_tree_manifest_log || req.contains(&Required::Treemanifest);
+
         Ok(RevlogRepo {
             basepath: base.into(),
             requirements: req,
             changelog: changelog,
             manifest: manifest,
+            tree_manifests,
             inner: Arc::new(RwLock::new(RevlogInner {
                 filelogcache: HashMap::new(),
                 treelogcache: HashMap::new(),
@@ -202,6 +233,20 @@ impl RevlogRepo {
         &self.changelog
     }
 
+    /// The revlog of root manifests: the root tree manifests if the repo has them, otherwise
+    /// the flat manifests.
+    #[inline]
+    pub fn get_manifest_revlog(&self) -> &Revlog {
+        &self.manifest
+    }
+
+    /// Whether the repo stores tree manifests. Repos without them only have flat manifests, and
+    /// `get_manifest_revlog` returns the flat manifest revlog.
+    #[inline]
+    pub fn has_tree_manifests(&self) -> bool {
+        self.tree_manifests
+    }
+
     pub fn changeset_exists(&self, changesetid: &ChangesetId) -> FutureResult<bool> {
         let nodeid = changesetid.clone().into_nodehash();
         Ok(self.changelog.get_idx_by_nodeid(&nodeid).is_ok()).into_future()
@@ -278,6 +323,18 @@ impl RevlogRepo {
         &self.requirements
     }
 
+    /// Whether the repo uses the largefiles extension, and so may have standins in `.hglf/`.
+    #[inline]
+    pub fn has_largefiles(&self) -> bool {
+        self.requirements.contains(&Required::Largefiles)
+    }
+
+    /// Read the content of a largefile from the repo's local largefiles store, given the hash
+    /// from its standin.
+    pub fn get_largefile(&self, hash: &Sha1) -> FutureResult<Bytes> {
+        largefiles::read_largefile(&self.basepath, hash).into_future()
+    }
+
     pub fn get_path_revlog(&self, path: &RepoPath) -> Result<Revlog> {
         match *path {
             // TODO avoid creating a new MPath here
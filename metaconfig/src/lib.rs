@@ -25,7 +25,7 @@ extern crate vfs;
 pub mod errors;
 pub mod repoconfig;
 
-pub use repoconfig::RepoConfigs;
+pub use repoconfig::{ConfigRepoCommit, RepoConfigs};
 
 pub use errors::{Error, ErrorKind};
 
@@ -9,8 +9,8 @@
 
 use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
-use std::path::PathBuf;
-use std::str::from_utf8;
+use std::path::{Path, PathBuf};
+use std::str::{from_utf8, FromStr};
 
 use futures::{future, Future, IntoFuture};
 
@@ -35,6 +35,75 @@ pub struct RepoConfig {
     pub repoid: i32,
     /// Scuba table for logging performance of operations
     pub scuba_table: Option<String>,
+    /// Who can access the repo. Anyone can if there is no ACL
+    pub acl: Option<RepoAcl>,
+}
+
+/// Access control list of a repository
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RepoAcl {
+    /// Who can read from the repo
+    pub readers: Vec<AclEntry>,
+    /// Who can read from and write to the repo
+    pub writers: Vec<AclEntry>,
+}
+
+/// An identity or a group of identities in an ACL
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AclEntry {
+    /// A single identity, written as "user:NAME"
+    User(String),
+    /// Any identity in the group, written as "group:NAME"
+    Group(String),
+}
+
+/// Kinds of access to a repository
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Access {
+    /// Reading from the repo
+    Read,
+    /// Writing to the repo
+    Write,
+}
+
+impl AclEntry {
+    fn matches(&self, user: &str, groups: &[String]) -> bool {
+        match *self {
+            AclEntry::User(ref name) => name == user,
+            AclEntry::Group(ref name) => groups.contains(name),
+        }
+    }
+}
+
+impl RepoAcl {
+    /// Whether the identity `user`, which belongs to `groups`, has `access` to the repo. Writers
+    /// can read the repo too.
+    pub fn allows(&self, access: Access, user: &str, groups: &[String]) -> bool {
+        let is_writer = self.writers.iter().any(|entry| entry.matches(user, groups));
+        match access {
+            Access::Read => {
+                is_writer || self.readers.iter().any(|entry| entry.matches(user, groups))
+            }
+            Access::Write => is_writer,
+        }
+    }
+}
+
+impl FromStr for AclEntry {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("user:") && s.len() > "user:".len() {
+            Ok(AclEntry::User(s["user:".len()..].to_string()))
+        } else if s.starts_with("group:") && s.len() > "group:".len() {
+            Ok(AclEntry::Group(s["group:".len()..].to_string()))
+        } else {
+            Err(ErrorKind::InvalidConfig(format!(
+                "acl entry must be 'user:NAME' or 'group:NAME': {}",
+                s
+            )).into())
+        }
+    }
 }
 
 /// Types of repositories supported
@@ -47,12 +116,24 @@ pub enum RepoType {
     /// Blob repository with path pointing to on-disk files with data. The files are stored in a
     /// RocksDb database
     BlobRocks(PathBuf),
+    /// Blob repository with path pointing to on-disk files with data. The blobs are stored in a
+    /// SQLite database
+    BlobSqlite(PathBuf),
     /// Blob repository with path pointing to the directory where a server socket is going to be.
     /// Blobs are stored in Manifold, first parameter is Manifold bucket.
     /// Bookmarks and heads are stored in memory
     TestBlobManifold(String, PathBuf),
 }
 
+/// The commit of a config repo that the configs are read from
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConfigRepoCommit {
+    /// The commit that the bookmark points to
+    Bookmark(String),
+    /// The given commit
+    Changeset(ChangesetId),
+}
+
 /// Configuration of a metaconfig repository
 #[derive(Debug, Eq, PartialEq)]
 pub struct MetaConfig {}
@@ -97,6 +178,32 @@ impl RepoConfigs {
         )
     }
 
+    /// Open the revlog config repo whose working copy is at `path`, and read the RepoConfigs
+    /// from `commit`. Yields the changeset that they were read from along with them.
+    pub fn read_revlog_config_repo_at<P: AsRef<Path>>(
+        path: P,
+        commit: ConfigRepoCommit,
+    ) -> Box<Future<Item = (ChangesetId, Self), Error = Error> + Send> {
+        let repo = match RevlogRepo::open(path.as_ref().join(".hg")) {
+            Ok(repo) => repo,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let changesetid: Box<Future<Item = ChangesetId, Error = Error> + Send> = match commit {
+            ConfigRepoCommit::Bookmark(bookmark) => Box::new(
+                repo.get_bookmark_value(&bookmark)
+                    .and_then(move |value| match value {
+                        Some((changesetid, _)) => Ok(changesetid),
+                        None => bail_err!(ErrorKind::BookmarkNotFound(bookmark)),
+                    }),
+            ),
+            ConfigRepoCommit::Changeset(changesetid) => Box::new(future::ok(changesetid)),
+        };
+        Box::new(changesetid.and_then(move |changesetid| {
+            Self::read_revlog_config_repo(repo, changesetid)
+                .map(move |configs| (changesetid, configs))
+        }))
+    }
+
     /// Read the given manifest of metaconfig repo and yield the RepoConfigs for it
     fn read_manifest<M>(manifest: &M) -> Box<Future<Item = Self, Error = Error> + Send>
     where
@@ -186,6 +293,32 @@ struct RawRepoConfig {
     manifold_bucket: Option<String>,
     repoid: i32,
     scuba_table: Option<String>,
+    acl: Option<RawRepoAcl>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawRepoAcl {
+    readers: Option<Vec<String>>,
+    writers: Option<Vec<String>>,
+}
+
+impl TryFrom<RawRepoAcl> for RepoAcl {
+    type Error = Error;
+
+    fn try_from(this: RawRepoAcl) -> Result<Self> {
+        let parse_entries = |entries: Option<Vec<String>>| -> Result<Vec<AclEntry>> {
+            entries
+                .unwrap_or_default()
+                .iter()
+                .map(|entry| entry.parse())
+                .collect()
+        };
+
+        Ok(RepoAcl {
+            readers: parse_entries(this.readers)?,
+            writers: parse_entries(this.writers)?,
+        })
+    }
 }
 
 /// Types of repositories supported
@@ -194,6 +327,7 @@ enum RawRepoType {
     #[serde(rename = "revlog")] Revlog,
     #[serde(rename = "blob:files")] BlobFiles,
     #[serde(rename = "blob:rocks")] BlobRocks,
+    #[serde(rename = "blob:sqlite")] BlobSqlite,
     #[serde(rename = "blob:testmanifold")] TestBlobManifold,
 }
 
@@ -207,6 +341,7 @@ impl TryFrom<RawRepoConfig> for RepoConfig {
             Revlog => RepoType::Revlog(this.path),
             BlobFiles => RepoType::BlobFiles(this.path),
             BlobRocks => RepoType::BlobRocks(this.path),
+            BlobSqlite => RepoType::BlobSqlite(this.path),
             TestBlobManifold => {
                 let manifold_bucket = this.manifold_bucket.ok_or(ErrorKind::InvalidConfig(
                     "manifold bucket must be specified".into(),
@@ -218,12 +353,17 @@ impl TryFrom<RawRepoConfig> for RepoConfig {
         let generation_cache_size = this.generation_cache_size.unwrap_or(10 * 1024 * 1024);
         let repoid = this.repoid;
         let scuba_table = this.scuba_table;
+        let acl = match this.acl {
+            Some(acl) => Some(acl.try_into()?),
+            None => None,
+        };
 
         Ok(RepoConfig {
             repotype,
             generation_cache_size,
             repoid,
             scuba_table,
+            acl,
         })
     }
 }
@@ -245,6 +385,9 @@ mod test {
             generation_cache_size=1048576
             repoid=0
             scuba_table="scuba_table"
+            [acl]
+            readers=["group:engineers"]
+            writers=["user:alice"]
         "#;
         let www_content = r#"
             path="/tmp/www"
@@ -284,6 +427,10 @@ mod test {
                 generation_cache_size: 1024 * 1024,
                 repoid: 0,
                 scuba_table: Some("scuba_table".to_string()),
+                acl: Some(RepoAcl {
+                    readers: vec![AclEntry::Group("engineers".to_string())],
+                    writers: vec![AclEntry::User("alice".to_string())],
+                }),
             },
         );
         repos.insert(
@@ -293,6 +440,7 @@ mod test {
                 generation_cache_size: 10 * 1024 * 1024,
                 repoid: 1,
                 scuba_table: Some("scuba_table".to_string()),
+                acl: None,
             },
         );
         assert_eq!(
@@ -303,4 +451,24 @@ mod test {
             }
         )
     }
+
+    #[test]
+    fn test_acl() {
+        let acl = RepoAcl {
+            readers: vec!["group:engineers".parse().unwrap()],
+            writers: vec!["user:alice".parse().unwrap()],
+        };
+        let engineers = vec!["engineers".to_string()];
+
+        assert!(acl.allows(Access::Read, "bob", &engineers));
+        assert!(!acl.allows(Access::Write, "bob", &engineers));
+        assert!(acl.allows(Access::Read, "alice", &[]));
+        assert!(acl.allows(Access::Write, "alice", &[]));
+        assert!(!acl.allows(Access::Read, "mallory", &[]));
+        assert!(!acl.allows(Access::Read, "engineers", &[]));
+
+        assert!("alice".parse::<AclEntry>().is_err());
+        assert!("user:".parse::<AclEntry>().is_err());
+        assert!("team:alice".parse::<AclEntry>().is_err());
+    }
 }
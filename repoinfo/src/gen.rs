@@ -31,6 +31,13 @@ use nodehashkey::Key;
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, HeapSizeOf)]
 pub struct Generation(u64);
 
+impl Generation {
+    /// The generation number as an integer
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
 /// Cache of generation numbers
 ///
 /// Allows generation numbers for a changeset to be computed lazily and cached.
@@ -59,7 +59,6 @@ mod listener;
 
 use std::io;
 use std::panic;
-use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
@@ -78,9 +77,9 @@ use slog_logview::LogViewDrain;
 
 use bytes::Bytes;
 use hgproto::{sshproto, HgProtoHandler};
-use mercurial::RevlogRepo;
 use mercurial_types::RepositoryId;
-use metaconfig::RepoConfigs;
+use mercurial_types::nodehash::ChangesetId;
+use metaconfig::{ConfigRepoCommit, RepoConfigs};
 use metaconfig::repoconfig::RepoType;
 
 use errors::*;
@@ -200,28 +199,20 @@ fn start_thrift_service<'a>(
 
 fn get_config<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<RepoConfigs> {
     // TODO: This needs to cope with blob repos, too
-    let mut crpath = PathBuf::from(matches.value_of("crpath").unwrap());
-    crpath.push(".hg");
-    let config_repo = RevlogRepo::open(crpath)?;
-
-    let changesetid = if let Some(bookmark) = matches.value_of("crbookmark") {
-        config_repo
-            .get_bookmark_value(&bookmark)
-            .wait()?
-            .ok_or_else(|| failure::err_msg("bookmark for config repo not found"))?
-            .0
-    } else {
-        mercurial_types::nodehash::ChangesetId::from_str(matches.value_of("crhash").unwrap())?
+    let commit = match matches.value_of("crbookmark") {
+        Some(bookmark) => ConfigRepoCommit::Bookmark(bookmark.to_string()),
+        None => {
+            let hash = matches.value_of("crhash").unwrap();
+            ConfigRepoCommit::Changeset(ChangesetId::from_str(hash)?)
+        }
     };
-
+    let crpath = matches.value_of("crpath").unwrap();
+    let (changesetid, config) = RepoConfigs::read_revlog_config_repo_at(crpath, commit).wait()?;
     info!(
         logger,
-        "Config repository will be read from commit: {}", changesetid
+        "Config repository was read from commit: {}", changesetid
     );
-
-    RepoConfigs::read_revlog_config_repo(config_repo, changesetid)
-        .from_err()
-        .wait()
+    Ok(config)
 }
 
 fn start_repo_listeners<I>(repos: I, root_log: &Logger) -> Result<Vec<JoinHandle<!>>>
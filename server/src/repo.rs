@@ -6,7 +6,7 @@
 
 //! State for a single source control Repo
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug};
 use std::io::{Cursor, Write};
 use std::mem;
@@ -25,7 +25,7 @@ use tokio_core::reactor::Remote;
 
 use slog::Logger;
 
-use blobrepo::BlobChangeset;
+use blobrepo::{get_file_history, BlobChangeset};
 use bundle2_resolver;
 use mercurial;
 use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item};
@@ -47,6 +47,9 @@ use revset::{AncestorsNodeStream, IntersectNodeStream, NodeStream, SetDifference
 const METAKEYFLAG: &str = "f";
 const METAKEYSIZE: &str = "s";
 
+/// Number of manifest entries whose linknodes are looked up together in gettreepack.
+const LINKNODE_BATCH_SIZE: usize = 100;
+
 mod ops {
     pub const HELLO: &str = "hello";
     pub const UNBUNDLE: &str = "unbundle";
@@ -99,6 +102,7 @@ impl OpenableRepoType for RepoType {
             Revlog(_) => Err(ErrorKind::CantServeRevlogRepo)?,
             BlobFiles(ref path) => BlobRepo::new_files(logger, &path, repoid)?,
             BlobRocks(ref path) => BlobRepo::new_rocksdb(logger, &path, repoid)?,
+            BlobSqlite(ref path) => BlobRepo::new_sqlite(logger, &path, repoid)?,
             TestBlobManifold(ref bucket, _) => {
                 BlobRepo::new_test_manifold(logger, bucket, remote, repoid)?
             }
@@ -112,6 +116,7 @@ impl OpenableRepoType for RepoType {
 
         match *self {
             Revlog(ref path) | BlobFiles(ref path) | BlobRocks(ref path) => path.as_ref(),
+            BlobSqlite(ref path) => path.as_ref(),
             TestBlobManifold(_, ref path) => path.as_ref(),
         }
     }
@@ -708,11 +713,13 @@ fn get_changed_entry_stream(
             }
             EntryStatus::Deleted(..) => None,
         })
+        .chunks(LINKNODE_BATCH_SIZE)
         .and_then({
             let hgrepo = repo.clone();
-            move |(entry, path)| fetch_linknode(hgrepo.clone(), entry, path)
+            move |entries| fetch_linknodes(hgrepo.clone(), entries)
         })
-        .map(|(entry, linknode, basepath)| (entry, linknode, basepath));
+        .map(stream::iter_ok)
+        .flatten();
 
     // Append root manifest
     let root_entry_stream = Ok(repo.get_root_entry(&ManifestId::new(*mfid)))
@@ -727,12 +734,8 @@ fn get_changed_entry_stream(
     changed_entries.chain(root_entry_stream).boxify()
 }
 
-fn fetch_linknode(
-    repo: Arc<BlobRepo>,
-    entry: Box<Entry + Sync>,
-    basepath: MPath,
-) -> BoxFuture<(Box<Entry + Sync>, NodeHash, MPath), Error> {
-    let path = match entry.get_name() {
+fn entry_repo_path(entry: &Entry, basepath: &MPath) -> RepoPath {
+    match entry.get_name() {
         &Some(ref name) => {
             let path = basepath.clone().join(name.clone().into_iter());
             if entry.get_type() == Type::Tree {
@@ -742,51 +745,44 @@ fn fetch_linknode(
             }
         }
         &None => RepoPath::RootPath,
-    };
+    }
+}
 
+fn fetch_linknode(
+    repo: Arc<BlobRepo>,
+    entry: Box<Entry + Sync>,
+    basepath: MPath,
+) -> BoxFuture<(Box<Entry + Sync>, NodeHash, MPath), Error> {
+    let path = entry_repo_path(&*entry, &basepath);
     let linknode_fut = repo.get_linknode(path, &entry.get_hash().into_nodehash());
     linknode_fut
         .map(|linknode| (entry, linknode, basepath))
         .boxify()
 }
 
-fn get_file_history(
+/// Like `fetch_linknode`, but looks up the linknodes for all the entries at once.
+fn fetch_linknodes(
     repo: Arc<BlobRepo>,
-    startnode: NodeHash,
-    path: MPath,
-) -> BoxStream<(NodeHash, Parents, NodeHash, Option<(MPath, NodeHash)>), Error> {
-    if startnode == NULL_HASH {
-        return stream::empty().boxify();
-    }
-    let mut startstate = VecDeque::new();
-    startstate.push_back(startnode);
-    let seen_nodes: HashSet<_> = [startnode].iter().cloned().collect();
-
-    stream::unfold(
-        (startstate, seen_nodes),
-        move |cur_data: (VecDeque<NodeHash>, HashSet<NodeHash>)| {
-            let (mut nodes, mut seen_nodes) = cur_data;
-            let node = nodes.pop_front()?;
-
-            let parents = repo.get_parents(&node);
-            let copy = repo.get_file_copy(&node);
-
-            let linknode = RepoPath::file(path.clone()).into_future().and_then({
-                let repo = repo.clone();
-                move |path| repo.get_linknode(path, &node)
-            });
-
-            let joined = parents
-                .join(linknode)
-                .join(copy)
-                .map(|(pl, c)| (pl.0, pl.1, c));
-
-            Some(joined.map(move |(parents, linknode, copy)| {
-                nodes.extend(parents.into_iter().filter(|p| seen_nodes.insert(*p)));
-                ((node, parents, linknode, copy), (nodes, seen_nodes))
-            }))
-        },
-    ).boxify()
+    entries: Vec<(Box<Entry + Sync>, MPath)>,
+) -> BoxFuture<Vec<(Box<Entry + Sync>, NodeHash, MPath)>, Error> {
+    let keys = entries
+        .iter()
+        .map(|&(ref entry, ref basepath)| {
+            (
+                entry_repo_path(&**entry, basepath),
+                entry.get_hash().into_nodehash(),
+            )
+        })
+        .collect();
+    repo.get_linknodes(keys)
+        .map(move |linknodes| {
+            entries
+                .into_iter()
+                .zip(linknodes)
+                .map(|((entry, basepath), linknode)| (entry, linknode, basepath))
+                .collect()
+        })
+        .boxify()
 }
 
 fn create_remotefilelog_blob(
@@ -794,29 +790,39 @@ fn create_remotefilelog_blob(
     node: NodeHash,
     path: MPath,
 ) -> BoxFuture<Bytes, Error> {
-    // raw_content includes copy information
-    let raw_content_bytes = repo.get_file_content(&node).and_then(move |raw_content| {
-        // requires digit counting to know for sure, use reasonable approximation
-        let approximate_header_size = 12;
-        let mut writer = Cursor::new(Vec::with_capacity(
-            approximate_header_size + raw_content.len(),
-        ));
-
-        // Write header
-        // TODO(stash): support LFS files using METAKEYFLAG
-        let res = write!(
-            writer,
-            "v1\n{}{}\n{}{}\0",
-            METAKEYSIZE,
-            raw_content.len(),
-            METAKEYFLAG,
-            0,
-        );
+    // The content is streamed from the blobstore straight into a buffer of the right size, so
+    // chunked content is never reassembled in memory on its own. The blob itself still has to be
+    // buffered whole: it is compressed as a single lz4 block, and getfiles frames each blob with
+    // its compressed size.
+    let raw_content_bytes = repo
+        .get_sized_file_content_stream(&node)
+        .and_then(move |(size, chunks)| {
+            // requires digit counting to know for sure, use reasonable approximation
+            let approximate_header_size = 12;
+            let mut writer = Cursor::new(Vec::with_capacity(
+                approximate_header_size + size as usize,
+            ));
 
-        res.and_then(|_| writer.write_all(&raw_content))
-            .map_err(Error::from)
-            .map(|_| writer.into_inner())
-    });
+            // Write header
+            // TODO(stash): support LFS files using METAKEYFLAG
+            let res = write!(
+                writer,
+                "v1\n{}{}\n{}{}\0",
+                METAKEYSIZE,
+                size,
+                METAKEYFLAG,
+                0,
+            );
+
+            res.map_err(Error::from)
+                .into_future()
+                .and_then(move |_| {
+                    chunks.fold(writer, |mut writer, chunk| {
+                        writer.write_all(&chunk).map(|_| writer).map_err(Error::from)
+                    })
+                })
+                .map(|writer| writer.into_inner())
+        });
 
     let file_history_bytes = get_file_history(repo, node, path)
         .collect()
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS bookmarks (
+  repo_id INTEGER NOT NULL,
+  name VARBINARY(512) NOT NULL,
+  changeset_id BINARY(20) NOT NULL,
+  version BIGINT NOT NULL,
+  PRIMARY KEY (repo_id, name)
+);
@@ -0,0 +1,326 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! SQL-backed bookmarks store.
+//!
+//! Every update runs in a transaction that checks the stored `Version` of the bookmark before
+//! writing, so several processes can safely share the same database.
+
+#![deny(warnings)]
+#![feature(try_from)]
+
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate bookmarks;
+extern crate db;
+extern crate futures_ext;
+extern crate mercurial_types;
+extern crate storage_types;
+
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+use diesel::{delete, insert_into, update, Connection, MysqlConnection, SqliteConnection};
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use failure::ResultExt;
+use futures::{future, stream, Future, Stream};
+
+use bookmarks::{Bookmarks, BookmarksMut};
+use db::ConnectionParams;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use mercurial_types::{ChangesetId, RepositoryId};
+use storage_types::Version;
+
+mod errors;
+mod schema;
+mod models;
+
+pub use errors::*;
+use models::BookmarkRow;
+use schema::bookmarks as bookmarks_table;
+
+/// A single change to apply as part of `set_many`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct BookmarkUpdate {
+    pub name: Vec<u8>,
+    /// New value for the bookmark, or `None` to delete it.
+    pub value: Option<ChangesetId>,
+    /// Version the bookmark must currently be at for the update to go ahead.
+    pub expected: Version,
+}
+
+impl BookmarkUpdate {
+    pub fn set<K: AsRef<[u8]>>(name: K, value: ChangesetId, expected: Version) -> Self {
+        BookmarkUpdate {
+            name: name.as_ref().to_vec(),
+            value: Some(value),
+            expected,
+        }
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(name: K, expected: Version) -> Self {
+        BookmarkUpdate {
+            name: name.as_ref().to_vec(),
+            value: None,
+            expected,
+        }
+    }
+}
+
+pub struct SqliteBookmarks {
+    connection: Mutex<SqliteConnection>,
+    repo_id: RepositoryId,
+}
+
+impl SqliteBookmarks {
+    /// Open a SQLite database. This is synchronous because the SQLite backend hits local
+    /// disk or memory.
+    pub fn open<P: AsRef<str>>(path: P, repo_id: RepositoryId) -> Result<Self> {
+        let path = path.as_ref();
+        let conn = SqliteConnection::establish(path)?;
+        Ok(Self {
+            connection: Mutex::new(conn),
+            repo_id,
+        })
+    }
+
+    /// Create a new SQLite database.
+    pub fn create<P: AsRef<str>>(path: P, repo_id: RepositoryId) -> Result<Self> {
+        let bookmarks = Self::open(path, repo_id)?;
+
+        let up_query = include_str!("../schemas/sqlite-bookmarks.sql");
+        bookmarks
+            .connection
+            .lock()
+            .expect("lock poisoned")
+            .batch_execute(&up_query)?;
+
+        Ok(bookmarks)
+    }
+
+    /// Open a SQLite database, creating the bookmarks table if it doesn't have one yet.
+    pub fn open_or_create<P: AsRef<str>>(path: P, repo_id: RepositoryId) -> Result<Self> {
+        // The schema only creates the table if it is missing.
+        Self::create(path, repo_id)
+    }
+
+    /// Copy the bookmarks of `other` that this store doesn't have yet, in a single transaction.
+    /// Returns the number of bookmarks that were copied. This blocks until `other` has been
+    /// read.
+    pub fn import_from(&self, other: &Bookmarks) -> Result<usize> {
+        let names = other.keys().collect().wait()?;
+        let mut updates = vec![];
+        for name in names {
+            if self.get(&name).wait()?.is_some() {
+                continue;
+            }
+            // A bookmark can be deleted between listing and reading it
+            if let Some((value, _)) = other.get(&name).wait()? {
+                updates.push(BookmarkUpdate::set(name, value, Version::absent()));
+            }
+        }
+        match self.set_many(&updates).wait()? {
+            Some(_) => Ok(updates.len()),
+            None => bail_msg!("bookmarks were modified while they were imported"),
+        }
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory(repo_id: RepositoryId) -> Result<Self> {
+        Self::create(":memory:", repo_id)
+    }
+}
+
+pub struct MysqlBookmarks {
+    connection: Mutex<MysqlConnection>,
+    repo_id: RepositoryId,
+}
+
+impl MysqlBookmarks {
+    pub fn open(params: ConnectionParams, repo_id: RepositoryId) -> Result<Self> {
+        let url = params.to_diesel_url()?;
+        let conn = MysqlConnection::establish(&url)?;
+        Ok(Self {
+            connection: Mutex::new(conn),
+            repo_id,
+        })
+    }
+
+    pub fn create_test_db<P: AsRef<str>>(prefix: P, repo_id: RepositoryId) -> Result<Self> {
+        let params = db::create_test_db(prefix)?;
+        Self::create(params, repo_id)
+    }
+
+    fn create(params: ConnectionParams, repo_id: RepositoryId) -> Result<Self> {
+        let bookmarks = Self::open(params, repo_id)?;
+
+        let up_query = include_str!("../schemas/mysql-bookmarks.sql");
+        bookmarks
+            .connection
+            .lock()
+            .expect("lock poisoned")
+            .batch_execute(&up_query)?;
+
+        Ok(bookmarks)
+    }
+}
+
+/// Using a macro here is unfortunate, but it appears to be the only way to share this code
+/// between SQLite and MySQL.
+macro_rules! impl_bookmarks {
+    ($struct: ty, $conn: ty) => {
+        impl $struct {
+            /// Apply several updates atomically: either every bookmark is moved, or none is.
+            /// Resolves to the new versions in the same order as `updates`, or to `None` if any
+            /// of the expected versions didn't match what's stored.
+            pub fn set_many(
+                &self,
+                updates: &[BookmarkUpdate],
+            ) -> BoxFuture<Option<Vec<Version>>, Error> {
+                // TODO: don't block -- send this to another thread
+                let connection = self.connection.lock().expect("lock poisoned");
+                let txn_result = connection.transaction::<_, Error, _>(|| {
+                    updates
+                        .iter()
+                        .map(|change| Self::apply_update(&*connection, self.repo_id, change))
+                        .collect()
+                });
+
+                let result = match txn_result {
+                    Ok(versions) => Ok(Some(versions)),
+                    Err(err) => match err.downcast::<ErrorKind>() {
+                        Ok(ErrorKind::VersionMismatch) => Ok(None),
+                        Ok(kind) => Err(kind.into()),
+                        Err(err) => Err(err),
+                    },
+                };
+                future::result(result).boxify()
+            }
+
+            /// Must be called from within a transaction. Fails with `VersionMismatch` (so that
+            /// the transaction is rolled back) if the bookmark isn't at the expected version.
+            fn apply_update(
+                connection: &$conn,
+                repo_id: RepositoryId,
+                change: &BookmarkUpdate,
+            ) -> Result<Version> {
+                let current = bookmarks_table::table
+                    .filter(bookmarks_table::repo_id.eq(repo_id))
+                    .filter(bookmarks_table::name.eq(&change.name))
+                    .select(bookmarks_table::version)
+                    .first::<i64>(connection)
+                    .optional()?;
+                let current_version = match current {
+                    Some(version) => to_version(version)?,
+                    None => Version::absent(),
+                };
+                if current_version != change.expected {
+                    bail_err!(ErrorKind::VersionMismatch);
+                }
+
+                let row = bookmarks_table::table
+                    .filter(bookmarks_table::repo_id.eq(repo_id))
+                    .filter(bookmarks_table::name.eq(&change.name));
+                match (current, change.value) {
+                    (_, None) => {
+                        delete(row).execute(connection)?;
+                        Ok(Version::absent())
+                    }
+                    (None, Some(value)) => {
+                        let insert = BookmarkRow {
+                            repo_id,
+                            name: change.name.clone(),
+                            changeset_id: value,
+                            version: 0,
+                        };
+                        insert_into(bookmarks_table::table)
+                            .values(&insert)
+                            .execute(connection)?;
+                        Ok(Version::from(0))
+                    }
+                    (Some(version), Some(value)) => {
+                        update(row)
+                            .set((
+                                bookmarks_table::changeset_id.eq(value),
+                                bookmarks_table::version.eq(version + 1),
+                            ))
+                            .execute(connection)?;
+                        to_version(version + 1)
+                    }
+                }
+            }
+        }
+
+        impl Bookmarks for $struct {
+            fn get(&self, key: &AsRef<[u8]>) -> BoxFuture<Option<(ChangesetId, Version)>, Error> {
+                // TODO: don't block -- send this to another thread
+                let connection = self.connection.lock().expect("lock poisoned");
+                let row = bookmarks_table::table
+                    .filter(bookmarks_table::repo_id.eq(self.repo_id))
+                    .filter(bookmarks_table::name.eq(key.as_ref()))
+                    .first::<BookmarkRow>(&*connection)
+                    .optional();
+                // This code is written in this style to allow easy porting to futures.
+                let result = row.map_err(Error::from).and_then(|row| match row {
+                    None => Ok(None),
+                    Some(row) => Ok(Some((row.changeset_id, to_version(row.version)?))),
+                });
+                future::result(result).boxify()
+            }
+
+            fn keys(&self) -> BoxStream<Vec<u8>, Error> {
+                let connection = self.connection.lock().expect("lock poisoned");
+                let names = bookmarks_table::table
+                    .filter(bookmarks_table::repo_id.eq(self.repo_id))
+                    .select(bookmarks_table::name)
+                    .load::<Vec<u8>>(&*connection)
+                    .map_err(Error::from);
+                future::result(names)
+                    .map(stream::iter_ok)
+                    .flatten_stream()
+                    .boxify()
+            }
+        }
+
+        impl BookmarksMut for $struct {
+            fn set(
+                &self,
+                key: &AsRef<[u8]>,
+                value: &ChangesetId,
+                version: &Version,
+            ) -> BoxFuture<Option<Version>, Error> {
+                self.set_many(&[BookmarkUpdate::set(key.as_ref(), *value, *version)])
+                    .map(|versions| versions.and_then(|mut versions| versions.pop()))
+                    .boxify()
+            }
+
+            fn delete(
+                &self,
+                key: &AsRef<[u8]>,
+                version: &Version,
+            ) -> BoxFuture<Option<Version>, Error> {
+                self.set_many(&[BookmarkUpdate::delete(key.as_ref(), *version)])
+                    .map(|versions| versions.and_then(|mut versions| versions.pop()))
+                    .boxify()
+            }
+        }
+    }
+}
+
+impl_bookmarks!(MysqlBookmarks, MysqlConnection);
+impl_bookmarks!(SqliteBookmarks, SqliteConnection);
+
+#[inline]
+fn to_version(version: i64) -> Result<Version> {
+    // Diesel can't express unsigned ints, so convert manually.
+    let version = u64::try_from(version).context(ErrorKind::InvalidStoredData)?;
+    Ok(Version::from(version))
+}
@@ -0,0 +1,234 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the SQL bookmarks store. Basic `Bookmarks`/`BookmarksMut` behaviour is covered by
+//! the tests shared between all bookmarks implementations.
+
+#![deny(warnings)]
+
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate bookmarks;
+extern crate futures_ext;
+extern crate mercurial_types;
+extern crate mercurial_types_mocks;
+extern crate sqlbookmarks;
+extern crate storage_types;
+extern crate tempdir;
+
+use futures::{Future, Stream};
+
+use bookmarks::{Bookmarks, BookmarksMut};
+use failure::Error;
+use futures_ext::BoxFuture;
+use mercurial_types::RepositoryId;
+use mercurial_types_mocks::nodehash::*;
+use mercurial_types_mocks::repo::*;
+use sqlbookmarks::{BookmarkUpdate, MysqlBookmarks, SqliteBookmarks};
+use storage_types::Version;
+use tempdir::TempDir;
+
+fn set_many_moves_all<B: SetMany>(bookmarks: B) {
+    let foo_v0 = bookmarks
+        .create(&"foo", &ONES_CSID)
+        .wait()
+        .expect("Creating foo failed")
+        .expect("foo already exists");
+    let bar_v0 = bookmarks
+        .create(&"bar", &ONES_CSID)
+        .wait()
+        .expect("Creating bar failed")
+        .expect("bar already exists");
+
+    let updates = vec![
+        BookmarkUpdate::set("foo", TWOS_CSID, foo_v0),
+        BookmarkUpdate::delete("bar", bar_v0),
+        BookmarkUpdate::set("baz", THREES_CSID, Version::absent()),
+    ];
+    let versions = bookmarks
+        .set_many(&updates)
+        .wait()
+        .expect("set_many failed")
+        .expect("set_many had a version mismatch");
+    assert_eq!(
+        versions,
+        vec![Version::from(1), Version::absent(), Version::from(0)]
+    );
+
+    assert_eq!(
+        bookmarks.get(&"foo").wait().expect("Get foo failed"),
+        Some((TWOS_CSID, Version::from(1)))
+    );
+    assert_eq!(bookmarks.get(&"bar").wait().expect("Get bar failed"), None);
+    assert_eq!(
+        bookmarks.get(&"baz").wait().expect("Get baz failed"),
+        Some((THREES_CSID, Version::from(0)))
+    );
+}
+
+fn set_many_mismatch_rolls_back<B: SetMany>(bookmarks: B) {
+    let foo_v0 = bookmarks
+        .create(&"foo", &ONES_CSID)
+        .wait()
+        .expect("Creating foo failed")
+        .expect("foo already exists");
+
+    // The second update expects "foo" to be absent, so nothing should be applied.
+    let updates = vec![
+        BookmarkUpdate::set("bar", TWOS_CSID, Version::absent()),
+        BookmarkUpdate::set("foo", TWOS_CSID, Version::absent()),
+    ];
+    assert_eq!(
+        bookmarks.set_many(&updates).wait().expect("set_many failed"),
+        None
+    );
+
+    assert_eq!(bookmarks.get(&"bar").wait().expect("Get bar failed"), None);
+    assert_eq!(
+        bookmarks.get(&"foo").wait().expect("Get foo failed"),
+        Some((ONES_CSID, foo_v0))
+    );
+}
+
+fn repos_are_separate<B: BookmarksMut>(bookmarks: B, other: B) {
+    bookmarks
+        .create(&"foo", &ONES_CSID)
+        .wait()
+        .expect("Creating foo failed")
+        .expect("foo already exists");
+
+    assert_eq!(other.get(&"foo").wait().expect("Get foo failed"), None);
+    assert_eq!(
+        other.keys().collect().wait().expect("Listing keys failed"),
+        Vec::<Vec<u8>>::new()
+    );
+}
+
+// set_many is an inherent method on each store, so abstract over it for the tests.
+trait SetMany: BookmarksMut {
+    fn set_many(&self, updates: &[BookmarkUpdate]) -> BoxFuture<Option<Vec<Version>>, Error>;
+}
+
+impl SetMany for SqliteBookmarks {
+    fn set_many(&self, updates: &[BookmarkUpdate]) -> BoxFuture<Option<Vec<Version>>, Error> {
+        SqliteBookmarks::set_many(self, updates)
+    }
+}
+
+impl SetMany for MysqlBookmarks {
+    fn set_many(&self, updates: &[BookmarkUpdate]) -> BoxFuture<Option<Vec<Version>>, Error> {
+        MysqlBookmarks::set_many(self, updates)
+    }
+}
+
+macro_rules! sqlbookmarks_test_impl {
+    ($mod_name: ident => {
+        new: $new_cb: expr,
+    }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_set_many_moves_all() {
+                set_many_moves_all($new_cb(REPO_ZERO));
+            }
+
+            #[test]
+            fn test_set_many_mismatch_rolls_back() {
+                set_many_mismatch_rolls_back($new_cb(REPO_ZERO));
+            }
+        }
+    }
+}
+
+sqlbookmarks_test_impl! {
+    sqlite_test => {
+        new: new_sqlite,
+    }
+}
+
+sqlbookmarks_test_impl! {
+    mysql_test => {
+        new: new_mysql,
+    }
+}
+
+#[test]
+fn test_sqlite_repos_are_separate() {
+    // Both stores need to share the same database, so use a file rather than memory.
+    let dir = TempDir::new("sqlbookmarks_test").expect("Creating a temp dir failed");
+    let path = dir.as_ref().join("bookmarks");
+    let bookmarks = SqliteBookmarks::create(path.to_string_lossy(), REPO_ZERO)
+        .expect("Creating a SQLite database failed");
+    let other = SqliteBookmarks::open(path.to_string_lossy(), REPO_ONE)
+        .expect("Opening a SQLite database failed");
+    repos_are_separate(bookmarks, other);
+}
+
+#[test]
+fn test_sqlite_open_or_create() {
+    let dir = TempDir::new("sqlbookmarks_test").expect("Creating a temp dir failed");
+    let path = dir.as_ref().join("bookmarks");
+    let bookmarks = SqliteBookmarks::open_or_create(path.to_string_lossy(), REPO_ZERO)
+        .expect("Creating a SQLite database failed");
+    bookmarks
+        .create(&"foo", &ONES_CSID)
+        .wait()
+        .expect("Creating foo failed")
+        .expect("foo already exists");
+
+    // Opening the database again keeps its bookmarks.
+    let bookmarks = SqliteBookmarks::open_or_create(path.to_string_lossy(), REPO_ZERO)
+        .expect("Opening a SQLite database failed");
+    assert_eq!(
+        bookmarks.get(&"foo").wait().expect("Get foo failed"),
+        Some((ONES_CSID, Version::from(0)))
+    );
+}
+
+#[test]
+fn test_sqlite_import_from() {
+    let source = new_sqlite(REPO_ZERO);
+    source
+        .create(&"foo", &ONES_CSID)
+        .wait()
+        .expect("Creating foo failed")
+        .expect("foo already exists");
+    source
+        .create(&"bar", &TWOS_CSID)
+        .wait()
+        .expect("Creating bar failed")
+        .expect("bar already exists");
+
+    // Bookmarks that are already in the store are left alone.
+    let bookmarks = new_sqlite(REPO_ZERO);
+    bookmarks
+        .create(&"bar", &THREES_CSID)
+        .wait()
+        .expect("Creating bar failed")
+        .expect("bar already exists");
+
+    assert_eq!(bookmarks.import_from(&source).expect("import failed"), 1);
+    assert_eq!(
+        bookmarks.get(&"foo").wait().expect("Get foo failed"),
+        Some((ONES_CSID, Version::from(0)))
+    );
+    assert_eq!(
+        bookmarks.get(&"bar").wait().expect("Get bar failed"),
+        Some((THREES_CSID, Version::from(0)))
+    );
+    assert_eq!(bookmarks.import_from(&source).expect("import failed"), 0);
+}
+
+fn new_sqlite(repo_id: RepositoryId) -> SqliteBookmarks {
+    SqliteBookmarks::in_memory(repo_id).expect("Creating an in-memory SQLite database failed")
+}
+
+fn new_mysql(repo_id: RepositoryId) -> MysqlBookmarks {
+    MysqlBookmarks::create_test_db("sqlbookmarks_test", repo_id)
+        .expect("Failed to create test database")
+}
@@ -22,12 +22,14 @@ from .third_party import hg_run_tests
 
 TESTDIR_PATH = 'scm/mononoke/tests/integration'
 
+MONONOKE_BLOBEXPORT_TARGET = '//scm/mononoke:blobexport'
 MONONOKE_BLOBIMPORT_TARGET = '//scm/mononoke:blobimport'
 MONONOKE_EDEN_SERVER_TARGET = '//scm/mononoke/eden_server:eden_server'
 DUMMYSSH_TARGET = '//scm/mononoke/tests/integration:dummyssh'
 BINARY_HG_TARGET = '//scm/hg:hg'
 MONONOKE_HGCLI_TARGET = '//scm/mononoke/hgcli:hgcli'
 MONONOKE_SERVER_TARGET = '//scm/mononoke:mononoke'
+MONONOKE_VERIFY_TARGET = '//scm/mononoke:mononoke-verify'
 
 
 @click.command()
@@ -94,6 +96,7 @@ def run(ctx, tests, dry_run, interactive, output, verbose, debug, keep_tmpdir):
         output = None
     _fp, xunit_output = tempfile.mkstemp(dir=output)
 
+    add_to_environ('MONONOKE_BLOBEXPORT', MONONOKE_BLOBEXPORT_TARGET)
     add_to_environ('MONONOKE_BLOBIMPORT', MONONOKE_BLOBIMPORT_TARGET)
     add_to_environ(
         'DUMMYSSH', DUMMYSSH_TARGET, pathutils.BuildRuleTypes.PYTHON_BINARY
@@ -101,6 +104,7 @@ def run(ctx, tests, dry_run, interactive, output, verbose, debug, keep_tmpdir):
     add_to_environ('MONONOKE_EDEN_SERVER', MONONOKE_EDEN_SERVER_TARGET)
     add_to_environ('MONONOKE_HGCLI', MONONOKE_HGCLI_TARGET)
     add_to_environ('MONONOKE_SERVER', MONONOKE_SERVER_TARGET)
+    add_to_environ('MONONOKE_VERIFY', MONONOKE_VERIFY_TARGET)
 
     # Provide an output directory so that we don't write to a xar's read-only
     # filesystem.
@@ -59,7 +59,15 @@ function blobimport {
   $MONONOKE_BLOBIMPORT "$@" >> "$TESTTMP/blobimport.out" 2>&1
   reponame=$_
   mkdir -p "$reponame"/.hg
-  mkdir -p "$reponame"/books
+}
+
+function blobexport {
+  $MONONOKE_BLOBEXPORT "$@" >> "$TESTTMP/blobexport.out" 2>&1
+}
+
+# Mismatches are printed to stdout, so only the log goes to verify.out.
+function mononoke_verify {
+  $MONONOKE_VERIFY "$@" 2>> "$TESTTMP/verify.out"
 }
 
 function edenserver {
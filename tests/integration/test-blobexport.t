@@ -0,0 +1,86 @@
+  $ . $TESTDIR/library.sh
+
+setup a repo with tree manifests, so that exporting it gives back the same repo
+
+  $ hg init --config experimental.treemanifest=True repo-hg
+  $ cd repo-hg
+  $ echo a > a
+  $ mkdir dir
+  $ echo b > dir/b
+  $ hg ci -Aqm a
+  $ hg cp dir/b dir/copy
+  $ echo c >> dir/b
+  $ hg ci -qm 'copy and change'
+  $ hg up -q 0
+  $ ln -s dir/b link
+  $ echo x > exec
+  $ chmod +x exec
+  $ hg ci -Aqm 'link and exec'
+  $ hg merge -q 1
+  $ hg ci -m merge
+  $ cd $TESTTMP
+
+  $ blobimport --blobstore files --linknodes repo-hg repo
+  $ blobexport --blobstore files repo repo-export
+  $ grep -o "Wrote .*" blobexport.out
+  Wrote 4 changesets, 2 manifest revlogs and 5 filelogs
+
+the export is a valid repo with the same history and content
+
+  $ hg -R repo-export verify -q
+  $ hg -R repo-export log -T '{rev} {node|short} {desc}: {p1rev} {p2rev}\n' > export.log
+  $ hg -R repo-hg log -T '{rev} {node|short} {desc}: {p1rev} {p2rev}\n' | diff - export.log
+  $ cat export.log
+  3 * merge: 2 1 (glob)
+  2 * link and exec: 0 -1 (glob)
+  1 * copy and change: 0 -1 (glob)
+  0 * a: -1 -1 (glob)
+  $ hg -R repo-export status --change 1 -C
+  M dir/b
+  A dir/copy
+    dir/b
+  $ hg -R repo-export manifest -v -r 3
+  644   a
+  644   dir/b
+  644   dir/copy
+  755 * exec
+  644 @ link
+  $ hg -R repo-export cat -r 3 dir/b
+  b
+  c
+
+a repo imported from flat manifests is exported with flat manifests again
+
+  $ hg init repo-flat-hg
+  $ cd repo-flat-hg
+  $ echo a > a
+  $ mkdir dir
+  $ echo b > dir/b
+  $ hg ci -Aqm a
+  $ echo c >> dir/b
+  $ echo x > exec
+  $ chmod +x exec
+  $ hg ci -Aqm 'change and exec'
+  $ cd $TESTTMP
+
+  $ blobimport --blobstore files --linknodes repo-flat-hg repo-flat
+  $ blobexport --blobstore files repo-flat repo-flat-export
+  $ grep -o "Wrote .*" blobexport.out
+  Wrote 4 changesets, 2 manifest revlogs and 5 filelogs
+  Wrote 2 changesets, 1 manifest revlogs and 3 filelogs
+  $ grep treemanifest repo-flat-export/.hg/requires
+  [1]
+  $ hg -R repo-flat-export verify -q
+  $ hg -R repo-flat-export log -T '{node} {manifest}\n' > flat-export.log
+  $ hg -R repo-flat-hg log -T '{node} {manifest}\n' | diff - flat-export.log
+  $ hg -R repo-flat-export manifest -v -r 1
+  644   a
+  644   dir/b
+  755 * exec
+
+blobexport won't overwrite a repo
+
+  $ $MONONOKE_BLOBEXPORT --blobstore files repo repo-export > overwrite.out 2>&1
+  [1]
+  $ grep -o "already contains a Mercurial repo" overwrite.out
+  already contains a Mercurial repo
@@ -0,0 +1,64 @@
+  $ . $TESTDIR/library.sh
+
+setup a repo with a largefile that changes, next to a normal file
+
+  $ hg init repo-hg
+  $ cd repo-hg
+  $ cat >> .hg/hgrc <<EOF
+  > [extensions]
+  > largefiles=
+  > EOF
+  $ echo normal > normal
+  $ echo large > big
+  $ hg add -q normal
+  $ hg add -q --large big
+  $ hg ci -qm a
+  $ echo larger > big
+  $ hg ci -qm b
+  $ grep largefiles .hg/requires
+  largefiles
+  $ hg cat -r 0 .hglf/big
+  7f7097b041ccf68cc5561e9600da4655d21c6d18
+  $ hg cat -r 1 .hglf/big
+  e523ff5864ef23442faa29af47c248cc3681543f
+  $ cd $TESTTMP
+
+by default, only the standins are imported, and blobimport says so
+
+  $ blobimport --blobstore files repo-hg repo-standin
+  $ grep -o "importing only the standins.*" blobimport.out
+  importing only the standins of largefiles; see --largefiles to import content
+  $ ls repo-standin/blobs | grep -c "sha1-7f7097b041ccf68cc5561e9600da4655d21c6d18\|sha1-e523ff5864ef23442faa29af47c248cc3681543f"
+  0
+  [1]
+
+external stores the content of every revision of the largefile as a blob of its own
+
+  $ blobimport --blobstore files --largefiles external repo-hg repo-external
+  $ grep -c "importing only the standins" blobimport.out
+  1
+  $ ls repo-external/blobs | grep "sha1-7f7097b041ccf68cc5561e9600da4655d21c6d18\|sha1-e523ff5864ef23442faa29af47c248cc3681543f"
+  blob-sha1-7f7097b041ccf68cc5561e9600da4655d21c6d18
+  blob-sha1-e523ff5864ef23442faa29af47c248cc3681543f
+
+inline stores the content of every revision of the largefile in place of its standin, so
+the standin's own text isn't stored
+
+  $ blobimport --blobstore files --largefiles inline repo-hg repo-inline
+  $ ls repo-inline/blobs | grep "sha1-7f7097b041ccf68cc5561e9600da4655d21c6d18\|sha1-e523ff5864ef23442faa29af47c248cc3681543f"
+  blob-sha1-7f7097b041ccf68cc5561e9600da4655d21c6d18
+  blob-sha1-e523ff5864ef23442faa29af47c248cc3681543f
+  $ STANDIN_BLOB="sha1-$(hg -R repo-hg cat -r 0 .hglf/big | sha1sum | cut -d' ' -f1)"
+  $ ls repo-external/blobs | grep -c "$STANDIN_BLOB"
+  1
+  $ ls repo-inline/blobs | grep -c "$STANDIN_BLOB"
+  0
+  [1]
+
+the import fails if a largefile is missing from the repo's store
+
+  $ rm repo-hg/.hg/largefiles/7f7097b041ccf68cc5561e9600da4655d21c6d18
+  $ $MONONOKE_BLOBIMPORT --blobstore files --largefiles external repo-hg repo-missing > missing.out 2>&1
+  [1]
+  $ grep -o "cannot copy largefile for FilePath" missing.out
+  cannot copy largefile for FilePath
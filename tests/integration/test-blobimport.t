@@ -0,0 +1,86 @@
+  $ . $TESTDIR/library.sh
+
+setup a repo with a merge, and with flat manifests so that blobimport converts them
+
+  $ hg init repo-hg
+  $ cd repo-hg
+  $ echo a > a
+  $ mkdir dir
+  $ echo b > dir/b
+  $ hg ci -Aqm a
+  $ echo c > dir/c
+  $ hg ci -Aqm b
+  $ hg up -q 0
+  $ echo d > d
+  $ hg ci -Aqm c
+  $ hg merge -q 1
+  $ hg ci -m merge
+  $ hg log -T '{rev} {desc}: {p1rev} {p2rev}\n'
+  3 merge: 2 1
+  2 c: 0 -1
+  1 b: 0 -1
+  0 a: -1 -1
+  $ hg book -r 1 master
+  $ hg book -r 2 feature
+  $ cd $TESTTMP
+
+an incremental import of an empty blob repo imports everything, up to the commits limit
+
+  $ blobimport --blobstore files --linknodes --incremental --commits-limit 2 repo-hg repo
+  $ grep -o "[0-9]* changesets to import" blobimport.out
+  2 changesets to import
+
+the next incremental import only imports the rest
+
+  $ blobimport --blobstore files --linknodes --incremental repo-hg repo
+  $ grep -o "[0-9]* changesets to import" blobimport.out
+  2 changesets to import
+  2 changesets to import
+
+and there is nothing left to import after that
+
+  $ blobimport --blobstore files --linknodes --incremental repo-hg repo
+  $ grep -o "[0-9]* changesets to import" blobimport.out
+  2 changesets to import
+  2 changesets to import
+  0 changesets to import
+
+the blob repo matches the revlog repo, linknodes included
+
+  $ mononoke_verify --blobstore files repo-hg/.hg repo
+  $ grep -o "Verified .*" verify.out
+  Verified 4 changesets, found 0 mismatches
+
+--skip can't tell which commits have already been imported
+
+  $ $MONONOKE_BLOBIMPORT --blobstore files --incremental --skip 1 repo-hg repo > skip.out 2>&1
+  [1]
+  $ grep -o "\-\-skip cannot be used with \-\-incremental" skip.out
+  --skip cannot be used with --incremental
+
+bookmarks are only imported once all the changesets they may point to are
+
+  $ grep -o "not importing bookmarks.*\|imported [0-9]* bookmark changes" blobimport.out
+  not importing bookmarks because not all changesets were imported
+  imported 2 bookmark changes
+  imported 0 bookmark changes
+
+moved and deleted bookmarks are imported too
+
+  $ hg -R repo-hg book -f -r 3 master
+  $ hg -R repo-hg book -d feature
+  $ blobimport --blobstore files --linknodes --incremental repo-hg repo
+  $ grep -o "imported [0-9]* bookmark changes" blobimport.out | tail -1
+  imported 2 bookmark changes
+
+the changesets and bookmarks stores are scoped to the repo id, so importing the same repo with
+another id starts from scratch
+
+  $ blobimport --blobstore files --incremental --repo-id 1 repo-hg repo
+  $ grep -o "[0-9]* changesets to import\|imported [0-9]* bookmark changes" blobimport.out \
+  >   | tail -2
+  4 changesets to import
+  imported 1 bookmark changes
+  $ mononoke_verify --blobstore files --repo-id 1 --skip-linknodes repo-hg/.hg repo
+  $ grep -o "Verified .*" verify.out | tail -1
+  Verified 4 changesets, found 0 mismatches
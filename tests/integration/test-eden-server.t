@@ -14,6 +14,29 @@
   >   python $TESTTMP/json_pretty_print.py
   > }
 
+Prints the frames of a batch response, sorted as they can arrive in any order
+  $ cat >> $TESTTMP/print_frames.py <<EOF
+  > import binascii
+  > import struct
+  > import sys
+  > data = getattr(sys.stdin, 'buffer', sys.stdin).read()
+  > frames = []
+  > while data:
+  >     status, path_len = struct.unpack('>BI', data[:5])
+  >     path = data[5:5 + path_len].decode()
+  >     node = binascii.hexlify(data[5 + path_len:25 + path_len]).decode()
+  >     (data_len,) = struct.unpack('>Q', data[25 + path_len:33 + path_len])
+  >     content = data[33 + path_len:33 + path_len + data_len].decode()
+  >     frames.append((path, node, status, content.splitlines()[0]))
+  >     data = data[33 + path_len + data_len:]
+  > for frame in sorted(frames):
+  >     print('%s %s %d %s' % frame)
+  > EOF
+
+  $ print_frames() {
+  >   python $TESTTMP/print_frames.py
+  > }
+
 From https://unix.stackexchange.com/questions/55913/whats-the-easiest-way-to-find-an-unused-local-port
   $ cat >> $TESTTMP/get_free_socket.py <<EOF
   > import socket
@@ -74,11 +97,29 @@ Add commit with a directory
   $ hg add dir/content
   $ hg ci -m 'commit with dir'
 
+Modify the file in the directory, so that it has a history
+  $ echo more >> dir/content
+  $ hg ci -m 'modify content'
+
+Make it executable, which only changes its mode
+  $ chmod +x dir/content
+  $ hg ci -m 'make content executable'
+
   $ hg log
-  changeset:   5:617e87e2aa2f
+  changeset:   7:f0e5d749030c
   tag:         tip
   user:        test
   date:        Thu Jan 01 00:00:00 1970 +0000
+  summary:     make content executable
+  
+  changeset:   6:9d4b6d23adb2
+  user:        test
+  date:        Thu Jan 01 00:00:00 1970 +0000
+  summary:     modify content
+  
+  changeset:   5:617e87e2aa2f
+  user:        test
+  date:        Thu Jan 01 00:00:00 1970 +0000
   summary:     commit with dir
   
   changeset:   4:7f48e9c786d1
@@ -102,14 +143,11 @@ Add commit with a directory
   date:        Thu Jan 01 00:00:00 1970 +0000
   summary:     a
   
+  $ hg bookmark -r 617e87e2aa2f master
   $ cd ..
   $ SOCKET=`python $TESTTMP/get_free_socket.py`
   $ mkdir $TESTTMP/blobrepo
-  $ echo 'reponame="repo"' >> $TESTTMP/config
-  $ echo "path=\"$TESTTMP/blobrepo\"" >> $TESTTMP/config
   $ echo "addr='127.0.0.1:$SOCKET'" >> $TESTTMP/config
-  $ echo 'repotype="blob:rocks"' >> $TESTTMP/config
-  $ echo 'repoid=0' >> $TESTTMP/config
   $ echo "[ssl]" >> $TESTTMP/config
   $ echo "cert=\"$TESTDIR/edenservertest.crt\"" >> $TESTTMP/config
   $ echo "private_key=\"$TESTDIR/edenservertest.key\"" >> $TESTTMP/config
@@ -123,23 +161,104 @@ Add commit with a directory
   D* 3: changeset 813c7514ad5e14493de885987c241c14c5cd3153 (glob)
   D* 4: changeset 7f48e9c786d1cbab525424e45139585724f84e28 (glob)
   D* 5: changeset 617e87e2aa2fe36508e8d5e15a162bcd2e79808e (glob)
+  D* 6: changeset 9d4b6d23adb27eb245a3df6fd5190c4dcf3187b0 (glob)
+  D* 7: changeset f0e5d749030cc6c7087e46046312726481771da2 (glob)
   * filling up changesets changesets store (glob)
 
 Heads output order is unpredictable, let's sort them by commit hash
   $ grep "head " < $TESTTMP/blobimport.out | sort -k 6
   D* head 533267b0e203537fa53d2aec834b062f0b2249cd (glob)
-  D* head 617e87e2aa2fe36508e8d5e15a162bcd2e79808e (glob)
   D* head 813c7514ad5e14493de885987c241c14c5cd3153 (glob)
+  D* head f0e5d749030cc6c7087e46046312726481771da2 (glob)
   $ grep compaction < $TESTTMP/blobimport.out
   I* compaction started (glob)
   I* compaction finished (glob)
-  $ edenserver --config-file $TESTTMP/config
+
+A second copy of the repo that the test certificate can't read, and a third one without an ACL
+  $ mkdir $TESTTMP/restricted
+  $ blobimport --blobstore rocksdb repo $TESTTMP/restricted
+  $ mkdir $TESTTMP/public
+  $ blobimport --blobstore rocksdb repo $TESTTMP/public
+
+Repos are read from the config repo
+  $ hg init mononoke-config
+  $ cd mononoke-config
+  $ mkdir repos
+  $ cat > repos/repo <<CONFIG
+  > path="$TESTTMP/blobrepo"
+  > repotype="blob:rocks"
+  > repoid=0
+  > [acl]
+  > readers=["user:localhost"]
+  > CONFIG
+  $ cat > repos/restricted <<CONFIG
+  > path="$TESTTMP/restricted"
+  > repotype="blob:rocks"
+  > repoid=1
+  > [acl]
+  > readers=["user:someone-else"]
+  > writers=["group:admins"]
+  > CONFIG
+  $ cat > repos/public <<CONFIG
+  > path="$TESTTMP/public"
+  > repotype="blob:rocks"
+  > repoid=2
+  > CONFIG
+  $ hg add -q repos
+  $ hg ci -ma
+  $ hg bookmark test-config
+  $ cd ..
+
+  $ edenserver --config-file $TESTTMP/config -P $TESTTMP/mononoke-config -B test-config
 
 Temporary hack to make sure server is ready
   $ sleep 1
 
 Curl and debugdata output should match
   $ alias curl="curl --cert $TESTDIR/edenservertest.crt --key $TESTDIR/edenservertest.key --cacert $TESTDIR/edenservertest.crt"
+  $ curl https://localhost:$SOCKET/repos 2> /dev/null
+  ["public","repo"] (no-eol)
+
+Bookmarks and changeset metadata
+  $ curl https://localhost:$SOCKET/repo/bookmarks 2> /dev/null
+  {"master":"617e87e2aa2fe36508e8d5e15a162bcd2e79808e"} (no-eol)
+  $ curl https://localhost:$SOCKET/repo/bookmark/master 2> /dev/null
+  {"name":"master","hash":"617e87e2aa2fe36508e8d5e15a162bcd2e79808e"} (no-eol)
+  $ curl https://localhost:$SOCKET/repo/bookmark/missing 2> /dev/null
+  Error: bookmark not found: missing
+  $ curl https://localhost:$SOCKET/repo/cs/533267b0e203537fa53d2aec834b062f0b2249cd 2> /dev/null | python -m json.tool
+  {
+      "date": [
+          0,
+          0
+      ],
+      "extras": {},
+      "files": [
+          "d"
+      ],
+      "hash": "533267b0e203537fa53d2aec834b062f0b2249cd",
+      "manifest": "47827ecc7f12d2ed0c387de75947e73cf1c53afe",
+      "message": "c",
+      "parents": [
+          "4dabaf45f54add88ca2797dfdeb00a7d55144243"
+      ],
+      "user": "test"
+  }
+
+Commit graph queries
+  $ curl "https://localhost:$SOCKET/repo/graph/ancestors?heads=533267b0e203537fa53d2aec834b062f0b2249cd" 2> /dev/null
+  {"nodes":[{"hash":"533267b0e203537fa53d2aec834b062f0b2249cd","generation":3},{"hash":"4dabaf45f54add88ca2797dfdeb00a7d55144243","generation":2},{"hash":"3903775176ed42b1458a6281db4a0ccf4d9f287a","generation":1}],"next":null} (no-eol)
+  $ curl "https://localhost:$SOCKET/repo/graph/ancestors?heads=533267b0e203537fa53d2aec834b062f0b2249cd&common=3903775176ed42b1458a6281db4a0ccf4d9f287a&limit=1" 2> /dev/null
+  {"nodes":[{"hash":"533267b0e203537fa53d2aec834b062f0b2249cd","generation":3}],"next":1} (no-eol)
+  $ curl "https://localhost:$SOCKET/repo/graph/ancestors?heads=533267b0e203537fa53d2aec834b062f0b2249cd&common=3903775176ed42b1458a6281db4a0ccf4d9f287a&offset=1" 2> /dev/null
+  {"nodes":[{"hash":"4dabaf45f54add88ca2797dfdeb00a7d55144243","generation":2}],"next":null} (no-eol)
+  $ curl "https://localhost:$SOCKET/repo/graph/range?from=4dabaf45f54add88ca2797dfdeb00a7d55144243&to=533267b0e203537fa53d2aec834b062f0b2249cd" 2> /dev/null
+  {"nodes":[{"hash":"533267b0e203537fa53d2aec834b062f0b2249cd","generation":3},{"hash":"4dabaf45f54add88ca2797dfdeb00a7d55144243","generation":2}],"next":null} (no-eol)
+  $ curl "https://localhost:$SOCKET/repo/graph/gca?nodes=533267b0e203537fa53d2aec834b062f0b2249cd,4dabaf45f54add88ca2797dfdeb00a7d55144243" 2> /dev/null
+  {"nodes":[{"hash":"4dabaf45f54add88ca2797dfdeb00a7d55144243","generation":2}],"next":null} (no-eol)
+  $ curl -w '\n%{http_code}' "https://localhost:$SOCKET/repo/graph/gca?nodes=533267b0e203537fa53d2aec834b062f0b2249cd" 2> /dev/null
+  'nodes' needs at least two hashes
+  400 (no-eol)
   $ curl https://localhost:$SOCKET/repo/cs/3903775176ed42b1458a6281db4a0ccf4d9f287a/roottreemanifestid 2> /dev/null
   8515d4bfda768e04af4c13a69a72e28c7effbea7 (no-eol)
   $ cd repo
@@ -275,13 +394,179 @@ treenode_simple - do not request sizes
   $ curl https://localhost:$SOCKET/repo/blob/7108421418404a937c684d2479a34a24d2ce4757 2> /dev/null
   content
 
+Trees and files can be looked up by their path in a changeset
+  $ curl https://localhost:$SOCKET/repo/cs/617e87e2aa2fe36508e8d5e15a162bcd2e79808e/tree 2> /dev/null | json_print
+  [
+    {
+      "hash": null,
+      "path": "dir",
+      "size": null,
+      "type": "Tree"
+    }
+  ]
+  $ curl https://localhost:$SOCKET/repo/cs/617e87e2aa2fe36508e8d5e15a162bcd2e79808e/tree/dir/ 2> /dev/null | json_print
+  [
+    {
+      "hash": "7108421418404a937c684d2479a34a24d2ce4757",
+      "path": "content",
+      "size": 8,
+      "type": "File"
+    }
+  ]
+  $ curl https://localhost:$SOCKET/repo/cs/9d4b6d23adb27eb245a3df6fd5190c4dcf3187b0/file/dir/content 2> /dev/null
+  content
+  more
+  $ curl -o /dev/null -w '%{http_code}' https://localhost:$SOCKET/repo/cs/617e87e2aa2fe36508e8d5e15a162bcd2e79808e/file/dir/missing 2> /dev/null
+  404 (no-eol)
+  $ curl https://localhost:$SOCKET/repo/cs/617e87e2aa2fe36508e8d5e15a162bcd2e79808e/file/dir/missing 2> /dev/null | head -n 1
+  *while looking up 'dir/missing'* (glob)
+  $ curl -o /dev/null -w '%{http_code}' https://localhost:$SOCKET/repo/cs/617e87e2aa2fe36508e8d5e15a162bcd2e79808e/tree/dir/content 2> /dev/null
+  404 (no-eol)
+
+The history of a file is paginated, and records copies
+  $ curl "https://localhost:$SOCKET/repo/cs/9d4b6d23adb27eb245a3df6fd5190c4dcf3187b0/history/dir/content?limit=1" 2> /dev/null
+  {"entries":[{"node":"6a420752fd850b4637d68816055a60e164f92278","parents":["7108421418404a937c684d2479a34a24d2ce4757"],"linknode":"9d4b6d23adb27eb245a3df6fd5190c4dcf3187b0","copyfrom":null,"user":"test","date":[0,0],"message":"modify content"}],"next":1} (no-eol)
+  $ curl "https://localhost:$SOCKET/repo/cs/9d4b6d23adb27eb245a3df6fd5190c4dcf3187b0/history/dir/content?limit=1&offset=1" 2> /dev/null
+  {"entries":[{"node":"7108421418404a937c684d2479a34a24d2ce4757","parents":[],"linknode":"617e87e2aa2fe36508e8d5e15a162bcd2e79808e","copyfrom":null,"user":"test","date":[0,0],"message":"commit with dir"}],"next":null} (no-eol)
+  $ curl https://localhost:$SOCKET/repo/cs/533267b0e203537fa53d2aec834b062f0b2249cd/history/d 2> /dev/null
+  {"entries":[{"node":"fc702583f9c961dea176fd367862c299b4a551f2","parents":[],"linknode":"533267b0e203537fa53d2aec834b062f0b2249cd","copyfrom":{"path":"c","node":"5d9299349fc01ddd25d0070d149b124d8f10411e"},"user":"test","date":[0,0],"message":"c"}],"next":null} (no-eol)
+  $ curl -w '\n%{http_code}' "https://localhost:$SOCKET/repo/cs/9d4b6d23adb27eb245a3df6fd5190c4dcf3187b0/history/dir/content?limit=0" 2> /dev/null
+  limit must be between 1 and * (glob)
+  400 (no-eol)
+
+Blame attributes each line to the revision that introduced it
+  $ curl https://localhost:$SOCKET/repo/cs/9d4b6d23adb27eb245a3df6fd5190c4dcf3187b0/blame/dir/content 2> /dev/null
+  [{"node":"7108421418404a937c684d2479a34a24d2ce4757","linknode":"617e87e2aa2fe36508e8d5e15a162bcd2e79808e","lineno":1,"line":"content\n"},{"node":"6a420752fd850b4637d68816055a60e164f92278","linknode":"9d4b6d23adb27eb245a3df6fd5190c4dcf3187b0","lineno":2,"line":"more\n"}] (no-eol)
+
+Diffs list the changed files with the hashes of their content and their filenodes, or are
+rendered as git-style patches
+  $ curl https://localhost:$SOCKET/repo/cs/533267b0e203537fa53d2aec834b062f0b2249cd/diff 2> /dev/null | json_print
+  [
+    {
+      "basefilenode": null,
+      "basehash": null,
+      "filenode": "fc702583f9c961dea176fd367862c299b4a551f2",
+      "hash": "7448d8798a4380162d4b56f9b452e2f6f9e24e7a",
+      "path": "d",
+      "status": "added",
+      "type": "File"
+    }
+  ]
+  $ curl "https://localhost:$SOCKET/repo/cs/9d4b6d23adb27eb245a3df6fd5190c4dcf3187b0/diff?patch=1" 2> /dev/null
+  diff --git a/dir/content b/dir/content
+  --- a/dir/content
+  +++ b/dir/content
+  @@ -1 +1,2 @@
+   content
+  +more
+
+A change of mode is a modification of the file, rather than a deletion and an addition
+  $ curl https://localhost:$SOCKET/repo/cs/f0e5d749030cc6c7087e46046312726481771da2/diff 2> /dev/null | json_print
+  [
+    {
+      "basefilenode": "6a420752fd850b4637d68816055a60e164f92278",
+      "basehash": "17cdfbcace1d1c19cead60932a2ad57c58539b80",
+      "filenode": "6a420752fd850b4637d68816055a60e164f92278",
+      "hash": "17cdfbcace1d1c19cead60932a2ad57c58539b80",
+      "path": "dir/content",
+      "status": "modified",
+      "type": "Executable"
+    }
+  ]
+  $ curl "https://localhost:$SOCKET/repo/cs/f0e5d749030cc6c7087e46046312726481771da2/diff?patch=1" 2> /dev/null
+  diff --git a/dir/content b/dir/content
+  old mode 100644
+  new mode 100755
+  $ curl "https://localhost:$SOCKET/repo/cs/f0e5d749030cc6c7087e46046312726481771da2/diff?base=617e87e2aa2fe36508e8d5e15a162bcd2e79808e&patch=1" 2> /dev/null
+  diff --git a/dir/content b/dir/content
+  old mode 100644
+  new mode 100755
+  --- a/dir/content
+  +++ b/dir/content
+  @@ -1 +1,2 @@
+   content
+  +more
+
+Batches of trees and blobs are fetched with POSTs, with a frame per key
+  $ curl --data-binary '[{"path": "dir", "node": "e7405b0462d8b2dd80219b713a93aea2c9a3c468"}, {"path": "missing", "node": "0000000000000000000000000000000000000001"}]' https://localhost:$SOCKET/repo/trees 2> /dev/null | print_frames
+  dir e7405b0462d8b2dd80219b713a93aea2c9a3c468 0 [{"hash":"7108421418404a937c684d2479a34a24d2ce4757","path":"content","type":"File","size":null}]
+  missing 0000000000000000000000000000000000000001 1 * (glob)
+  $ curl --data-binary '[{"path": "dir/content", "node": "6a420752fd850b4637d68816055a60e164f92278"}, {"path": "c", "node": "5d9299349fc01ddd25d0070d149b124d8f10411e"}, {"path": "missing", "node": "0000000000000000000000000000000000000001"}]' https://localhost:$SOCKET/repo/blobs 2> /dev/null | print_frames
+  c 5d9299349fc01ddd25d0070d149b124d8f10411e 0 2
+  dir/content 6a420752fd850b4637d68816055a60e164f92278 0 content
+  missing 0000000000000000000000000000000000000001 1 * (glob)
+  $ curl -w '\n%{http_code}' https://localhost:$SOCKET/repo/blobs 2> /dev/null
+  batch requests must be POSTs
+  405 (no-eol)
+  $ curl -w '%{http_code}' --data-binary '[{"path": "c", "node": "nothex"}]' https://localhost:$SOCKET/repo/blobs 2> /dev/null
+  Error: invalid batch request: * (glob)
+  400 (no-eol)
+  $ python -c "
+  > import json
+  > print(json.dumps([{'path': 'c', 'node': '5d9299349fc01ddd25d0070d149b124d8f10411e'}] * 1001))
+  > " > $TESTTMP/keys.json
+  $ curl -w '%{http_code}' --data-binary @$TESTTMP/keys.json https://localhost:$SOCKET/repo/blobs 2> /dev/null
+  Error: invalid batch request: 1001 keys requested, at most 1000 allowed
+  400 (no-eol)
+
+Responses for hashes can be cached, by proxies too unless the repo has an ACL
+  $ curl -D - -o /dev/null https://localhost:$SOCKET/public/blob/7108421418404a937c684d2479a34a24d2ce4757 2> /dev/null | grep -i -e etag -e cache-control | tr -d '\r' | sort
+  Cache-Control: public, max-age=31536000, immutable
+  ETag: "7108421418404a937c684d2479a34a24d2ce4757"
+  $ curl -D - -o /dev/null https://localhost:$SOCKET/repo/blob/7108421418404a937c684d2479a34a24d2ce4757 2> /dev/null | grep -i -e etag -e cache-control | tr -d '\r' | sort
+  Cache-Control: private, max-age=31536000, immutable
+  ETag: "7108421418404a937c684d2479a34a24d2ce4757"
+  $ curl -D - -o /dev/null -H 'If-None-Match: "7108421418404a937c684d2479a34a24d2ce4757"' https://localhost:$SOCKET/repo/blob/7108421418404a937c684d2479a34a24d2ce4757 2> /dev/null | grep -i -e cache-control | tr -d '\r'
+  Cache-Control: private, max-age=31536000, immutable
+  $ curl -o /dev/null -w '%{http_code}' -H 'If-None-Match: "7108421418404a937c684d2479a34a24d2ce4757"' https://localhost:$SOCKET/repo/blob/7108421418404a937c684d2479a34a24d2ce4757 2> /dev/null
+  304 (no-eol)
+  $ curl -o /dev/null -w '%{http_code}' -H 'If-None-Match: "8515d4bfda768e04af4c13a69a72e28c7effbea7"' https://localhost:$SOCKET/repo/blob/7108421418404a937c684d2479a34a24d2ce4757 2> /dev/null
+  200 (no-eol)
+  $ curl -D - -o /dev/null https://localhost:$SOCKET/repos 2> /dev/null | grep -i -e etag -e cache-control
+  [1]
+
+Responses are compressed if the client accepts it
+  $ curl -H 'Accept-Encoding: gzip' -D $TESTTMP/headers https://localhost:$SOCKET/repo/blob/7108421418404a937c684d2479a34a24d2ce4757 2> /dev/null | gunzip
+  content
+  $ grep -i -e etag -e content-encoding $TESTTMP/headers | tr -d '\r' | sort
+  Content-Encoding: gzip
+  ETag: "7108421418404a937c684d2479a34a24d2ce4757-gzip"
+
+Archives of a changeset, or of a directory in it
+  $ curl -D $TESTTMP/headers https://localhost:$SOCKET/repo/cs/533267b0e203537fa53d2aec834b062f0b2249cd/archive.tar.gz 2> /dev/null | tar -tvz | awk '{print $1, $3, $6}'
+  -rw-r--r-- 0 a
+  -rw-r--r-- 2 b
+  -rw-r--r-- 2 c
+  -rw-r--r-- 2 d
+  $ grep -i -e content-type -e content-disposition -e content-encoding $TESTTMP/headers | tr -d '\r' | sort
+  Content-Disposition: attachment; filename="repo-533267b0e203537fa53d2aec834b062f0b2249cd.tar.gz"
+  Content-Type: application/gzip
+  $ curl "https://localhost:$SOCKET/repo/cs/617e87e2aa2fe36508e8d5e15a162bcd2e79808e/archive.tar.gz?path=dir" 2> /dev/null | tar -xzO content
+  content
+  $ curl https://localhost:$SOCKET/repo/cs/533267b0e203537fa53d2aec834b062f0b2249cd/archive.zip > $TESTTMP/archive.zip 2> /dev/null
+  $ python -c "
+  > import zipfile
+  > archive = zipfile.ZipFile('$TESTTMP/archive.zip')
+  > for info in archive.infolist():
+  >     print('%s %o %d %r' % (info.filename, info.external_attr >> 16, info.file_size, archive.read(info)))
+  > print(archive.testzip())
+  > "
+  a 100644 0 ''
+  b 100644 2 'b\n'
+  c 100644 2 'c\n'
+  d 100644 2 'd\n'
+  None
+  $ curl "https://localhost:$SOCKET/repo/cs/617e87e2aa2fe36508e8d5e15a162bcd2e79808e/archive.zip?path=dir/content" 2> /dev/null
+  Error: 'dir/content' is not a directory
+
 Send incorrect requests
   $ curl https://localhost:$SOCKET/repo/cs/hash/roottreemanifestid 2> /dev/null
   invalid sha-1 input: need at least 40 hex digits (no-eol)
   $ curl https://localhost:$SOCKET/badrepo/cs/3903775176ed42b1458a6281db4a0ccf4d9f287a/roottreemanifestid 2> /dev/null
-  Error: unknown repo
-  $ curl https://localhost:$SOCKET/badrepo/treenode/8515d4bfda768e04af4c13a69a72e28c7effbea7/ 2> /dev/null
-  Error: unknown repo
+  {"error":"unknown repo: badrepo"} (no-eol)
+  $ curl -w '\n%{http_code}' https://localhost:$SOCKET/badrepo/treenode/8515d4bfda768e04af4c13a69a72e28c7effbea7/ 2> /dev/null
+  {"error":"unknown repo: badrepo"}
+  404 (no-eol)
   $ curl https://localhost:$SOCKET/repo/BADURL/3903775176ed42b1458a6281db4a0ccf4d9f287a/roottreemanifestid 2> /dev/null
   malformed url (no-eol)
   $ curl https://localhost:$SOCKET/repo/cs/3903775176ed42b1458a6281db4a0ccf4d9f287a/roottreemanifestid/more 2> /dev/null
@@ -291,6 +576,36 @@ Send incorrect requests
   $ curl https://localhost:$SOCKET/ 2> /dev/null
   malformed url (no-eol)
 
+Clients need a certificate, and can only read the repos that they are allowed to
+  $ command curl -w '\n%{http_code}' --cacert $TESTDIR/edenservertest.crt https://localhost:$SOCKET/repos 2> /dev/null
+  {"error":"a client certificate is required"}
+  401 (no-eol)
+  $ for path in public/blob/7108421418404a937c684d2479a34a24d2ce4757 \
+  >     repo/cs/617e87e2aa2fe36508e8d5e15a162bcd2e79808e/file/dir/content \
+  >     repo/cs/617e87e2aa2fe36508e8d5e15a162bcd2e79808e/archive.zip \
+  >     "repo/graph/ancestors?heads=533267b0e203537fa53d2aec834b062f0b2249cd" \
+  >     restricted/bookmarks badrepo/bookmarks BADURL; do
+  >   command curl -o /dev/null -w "%{http_code} $path\n" --cacert $TESTDIR/edenservertest.crt "https://localhost:$SOCKET/$path" 2> /dev/null
+  > done
+  401 public/blob/7108421418404a937c684d2479a34a24d2ce4757
+  401 repo/cs/617e87e2aa2fe36508e8d5e15a162bcd2e79808e/file/dir/content
+  401 repo/cs/617e87e2aa2fe36508e8d5e15a162bcd2e79808e/archive.zip
+  401 repo/graph/ancestors?heads=533267b0e203537fa53d2aec834b062f0b2249cd
+  401 restricted/bookmarks
+  401 badrepo/bookmarks
+  401 BADURL
+  $ command curl -o /dev/null -w '%{http_code}' --cacert $TESTDIR/edenservertest.crt --data-binary '[]' https://localhost:$SOCKET/repo/blobs 2> /dev/null
+  401 (no-eol)
+  $ curl -w '\n%{http_code}' https://localhost:$SOCKET/restricted/cs/3903775176ed42b1458a6281db4a0ccf4d9f287a/roottreemanifestid 2> /dev/null
+  {"error":"localhost is not allowed to read repo restricted"}
+  403 (no-eol)
+  $ curl https://localhost:$SOCKET/repos 2> /dev/null
+  ["public","repo"] (no-eol)
+
 Make sure there are no errors on the server
-  $ cat $TESTTMP/edenserver.out
-  I*scm/mononoke/eden_server/src/main.rs:*] started eden server (glob)
+  $ grep -v "^I" $TESTTMP/edenserver.out
+  [1]
+  $ grep "opened repo" $TESTTMP/edenserver.out | sed 's/.*\] //' | sort
+  opened repo public
+  opened repo repo
+  opened repo restricted
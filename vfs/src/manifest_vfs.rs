@@ -151,21 +151,27 @@ impl Clone for ManifestVfsFile {
     }
 }
 
-impl VfsFile for ManifestVfsFile {
-    type TDir = ManifestVfsDir;
-
-    fn read(&self) -> Box<Future<Item = Content, Error = Error> + Send> {
+impl ManifestVfsFile {
+    /// Returns the manifest entry that this file was created from
+    pub fn entry(&self) -> &(Entry + Sync) {
         let &TEntryId(entryid) = self.root
             .path_tree
             .get_value(self.nodeid)
             .expect(INCONCISTENCY)
             .get_leaf()
             .expect("Expected a leaf, not an internal node");
-        self.root
+        &**self.root
             .entries
             .get(entryid)
             .expect("EntryId not found in entries list")
-            .get_content()
+    }
+}
+
+impl VfsFile for ManifestVfsFile {
+    type TDir = ManifestVfsDir;
+
+    fn read(&self) -> Box<Future<Item = Content, Error = Error> + Send> {
+        self.entry().get_content()
     }
 
     fn parent_dir(&self) -> Self::TDir {
@@ -277,7 +283,9 @@ mod test {
 
         cmp(dir_c_d.read(), vec!["da", "e"]);
 
-        unwrap_file(dir_c_d.step(&pel("da")).unwrap());
+        let file_da = unwrap_file(dir_c_d.step(&pel("da")).unwrap());
+        assert_eq!(file_da.entry().get_name(), &Some(pel("da")));
+        assert_eq!(file_da.entry().get_type(), Type::File);
     }
 
     #[test]